@@ -0,0 +1,261 @@
+//! Ancestry queries over a revision graph.
+//!
+//! Every parent has a lower rev than its children, so ancestors can be
+//! walked from the highest rev down and descendants from the lowest rev
+//! up, stopping as soon as nothing further can be reached. The iterators
+//! here are lazy and only look at the part of the index they need.
+
+use std::collections::{BinaryHeap, HashSet};
+
+use graph::Graph;
+use util::Result;
+
+/// Ancestors of a set of revs, in decreasing order.
+pub struct Ancestors<'a, 'b: 'a> {
+    graph: &'a Graph<'b>,
+    heap: BinaryHeap<i32>,
+    seen: HashSet<i32>,
+}
+
+impl<'a, 'b> Iterator for Ancestors<'a, 'b> {
+    type Item = Result<i32>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let rev = match self.heap.pop() {
+            Some(rev) => rev,
+            None => return None,
+        };
+        let parents = match self.graph.parents(rev) {
+            Ok(parents) => parents,
+            Err(e) => {
+                self.heap.clear();
+                return Some(Err(e));
+            }
+        };
+        for p in parents {
+            if self.seen.insert(p) {
+                self.heap.push(p);
+            }
+        }
+        Some(Ok(rev))
+    }
+}
+
+/// The ancestors of `revs`, including `revs` themselves if `inclusive`.
+/// The null rev is never included.
+pub fn ancestors<'a, 'b>(graph: &'a Graph<'b>, revs: &[i32], inclusive: bool) -> Result<Ancestors<'a, 'b>> {
+    let mut result = Ancestors {
+        graph: graph,
+        heap: BinaryHeap::new(),
+        seen: HashSet::new(),
+    };
+    for &rev in revs {
+        // Without `inclusive` the walk starts from the parents, but a rev
+        // in `revs` can still be reached from another one
+        let start = if inclusive { vec![rev] } else { try!(graph.parents(rev)) };
+        for r in start {
+            if r != -1 && result.seen.insert(r) {
+                result.heap.push(r);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Descendants of a set of revs, in increasing order.
+pub struct Descendants<'a, 'b: 'a> {
+    graph: &'a Graph<'b>,
+    next: i32,
+    /// `revs` and the descendants found so far
+    seen: HashSet<i32>,
+    revs: HashSet<i32>,
+    inclusive: bool,
+}
+
+impl<'a, 'b> Iterator for Descendants<'a, 'b> {
+    type Item = Result<i32>;
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < self.graph.len() {
+            let rev = self.next;
+            self.next += 1;
+            if self.inclusive && self.revs.contains(&rev) {
+                return Some(Ok(rev));
+            }
+            let parents = match self.graph.parents(rev) {
+                Ok(parents) => parents,
+                Err(e) => {
+                    self.next = self.graph.len();
+                    return Some(Err(e));
+                }
+            };
+            // Roots of the graph only have the null rev as a parent
+            let reached = if parents.is_empty() {
+                self.seen.contains(&-1)
+            } else {
+                parents.iter().any(|p| self.seen.contains(p))
+            };
+            if reached {
+                self.seen.insert(rev);
+                return Some(Ok(rev));
+            }
+        }
+        None
+    }
+}
+
+/// The descendants of `revs`, including `revs` themselves if
+/// `inclusive`. Everything descends from the null rev.
+pub fn descendants<'a, 'b>(graph: &'a Graph<'b>, revs: &[i32], inclusive: bool) -> Descendants<'a, 'b> {
+    let revs: HashSet<i32> = revs.iter().cloned().collect();
+    Descendants {
+        graph: graph,
+        next: revs.iter().cloned().min().map_or(graph.len(), |r| r.max(0)),
+        seen: revs.clone(),
+        revs: revs,
+        inclusive: inclusive,
+    }
+}
+
+/// Whether `a` is an ancestor of `b`. A rev is its own ancestor, and the
+/// null rev is an ancestor of everything.
+pub fn is_ancestor(graph: &Graph, a: i32, b: i32) -> Result<bool> {
+    if a == -1 || a == b {
+        return Ok(true);
+    }
+    if a > b {
+        return Ok(false);
+    }
+    for rev in try!(ancestors(graph, &[b], false)) {
+        let rev = try!(rev);
+        if rev == a {
+            return Ok(true);
+        }
+        if rev < a {
+            break;
+        }
+    }
+    Ok(false)
+}
+
+/// The heads of the set of revs that are ancestors of all of `revs`,
+/// in increasing order.
+pub fn common_ancestor_heads(graph: &Graph, revs: &[i32]) -> Result<Vec<i32>> {
+    common_heads(revs, |rev| graph.parents(rev))
+}
+
+/// The greatest common ancestor of two revs, or the null rev if they
+/// have none. When there are several candidates, the one with the lowest
+/// node id is picked, as Mercurial does.
+pub fn common_ancestor(graph: &Graph, a: i32, b: i32) -> Result<i32> {
+    let heads = try!(common_ancestor_heads(graph, &[a, b]));
+    let mut best: Option<(&[u8], i32)> = None;
+    for rev in heads {
+        let node = try!(graph.revlog().node(rev));
+        if best.map_or(true, |(n, _)| node < n) {
+            best = Some((node, rev));
+        }
+    }
+    Ok(best.map_or(-1, |(_, rev)| rev))
+}
+
+/// Mercurial's `commonancestorsheads`. Each input rev marks its
+/// ancestors with its own bit. A rev that has every bit is a common
+/// ancestor, and it poisons its own ancestors, which can't be heads. The
+/// walk stops once no rev still waiting to be visited is unpoisoned.
+fn common_heads<F>(revs: &[i32], parents: F) -> Result<Vec<i32>>
+    where F: Fn(i32) -> Result<Vec<i32>>
+{
+    if revs.contains(&-1) {
+        return Ok(vec![]);
+    }
+    let mut revs: Vec<i32> = revs.to_vec();
+    revs.sort();
+    revs.dedup();
+    if revs.len() <= 1 {
+        return Ok(revs);
+    }
+    expect!(revs.len() < 63, "too many revs for a common ancestor query");
+
+    let all_seen: u64 = (1 << revs.len()) - 1;
+    let poison: u64 = 1 << revs.len();
+    let max = *revs.last().unwrap();
+    let mut seen = vec![0u64; max as usize + 1];
+    for (i, &rev) in revs.iter().enumerate() {
+        seen[rev as usize] |= 1 << i;
+    }
+
+    let mut result = vec![];
+    let mut interesting = revs.len();
+    let mut v = max;
+    while v >= 0 && interesting > 0 {
+        let mut sv = seen[v as usize];
+        if sv == 0 {
+            v -= 1;
+            continue;
+        }
+        if sv < poison {
+            interesting -= 1;
+            if sv == all_seen {
+                result.push(v);
+                sv |= poison;
+                if revs.contains(&v) {
+                    // One input is an ancestor of all the others
+                    return Ok(vec![v]);
+                }
+            }
+        }
+        for p in try!(parents(v)) {
+            let sp = seen[p as usize];
+            if sv < poison {
+                if sp == 0 {
+                    seen[p as usize] = sv;
+                    interesting += 1;
+                } else if sp != sv {
+                    seen[p as usize] |= sv;
+                }
+            } else {
+                if sp != 0 && sp < poison {
+                    interesting -= 1;
+                }
+                seen[p as usize] = sv;
+            }
+        }
+        v -= 1;
+    }
+    result.reverse();
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::common_heads;
+    use util::Result;
+
+    //     5   6
+    //     |\ /|
+    //     | X |
+    //     |/ \|
+    //     3   4
+    //     |   |
+    //     1   2
+    //      \ /
+    //       0
+    fn parents(rev: i32) -> Result<Vec<i32>> {
+        Ok(match rev {
+            0 => vec![],
+            1 | 2 => vec![0],
+            3 => vec![1],
+            4 => vec![2],
+            5 | 6 => vec![3, 4],
+            _ => unreachable!(),
+        })
+    }
+
+    #[test]
+    fn test_common_heads() {
+        assert_eq!(vec![0], common_heads(&[3, 4], parents).unwrap());
+        assert_eq!(vec![3, 4], common_heads(&[5, 6], parents).unwrap());
+        assert_eq!(vec![1], common_heads(&[1, 5], parents).unwrap());
+        assert_eq!(vec![4], common_heads(&[4, 4], parents).unwrap());
+        assert_eq!(Vec::<i32>::new(), common_heads(&[-1, 4], parents).unwrap());
+    }
+}
@@ -0,0 +1,303 @@
+//! Attributing each line of a file to the changeset which introduced
+//! it, like `hg annotate`.
+//!
+//! The file revisions the file descends from are visited parents first,
+//! following copies and renames into the history of their source. Each
+//! revision starts out claiming all of its lines, and then gives back
+//! every line it has in common with a parent, as found by a line diff,
+//! to whoever that line belongs to in the parent. As in Mercurial, a
+//! line in common with both parents of a merge goes to the second. A
+//! revision's lines are kept only until its last child is done.
+//!
+//! A filelog revision's linkrev is the first changeset which added it,
+//! which needn't be an ancestor of the changeset being annotated when
+//! the same change was also made on another branch. As in Mercurial,
+//! each revision is instead credited to the changeset found by walking
+//! back from its child's.
+
+use std::collections::HashMap;
+
+use ancestor;
+use diff;
+use filelog::{self, Filelog};
+use graph::Graph;
+use repo::Repo;
+use revlog::TextCache;
+use util::Result;
+
+/// How many texts of each filelog to keep while annotating.
+const TEXT_CACHE_SIZE: usize = 16;
+
+/// A line of the annotated file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Line {
+    /// The changeset which introduced the line
+    pub rev: i32,
+    /// The file's name in that changeset
+    pub path: String,
+    /// The line's number in that changeset, from 1
+    pub number: usize,
+    pub text: Vec<u8>,
+}
+
+/// A file revision in the history being annotated.
+struct Node {
+    path: String,
+    filerev: i32,
+    /// The changeset which introduced this revision
+    rev: i32,
+    /// Indexes of the parents, with a copy source first
+    parents: Vec<usize>,
+}
+
+/// Annotate a file revision as found in changeset `rev`.
+pub fn annotate(repo: &Repo, path: &str, filerev: i32, rev: i32) -> Result<Vec<Line>> {
+    let mut filelogs: HashMap<String, Filelog> = HashMap::new();
+    filelogs.insert(String::from(path), try!(repo.filelog(path)));
+    let (nodes, order) = try!(history(repo, &mut filelogs, path, filerev, rev));
+
+    // How many children still need each revision's lines
+    let mut needed = vec![0; nodes.len()];
+    for node in &nodes {
+        for &p in &node.parents {
+            needed[p] += 1;
+        }
+    }
+
+    // The text of each revision, and the revision and line number each
+    // of its lines come from
+    let mut done: HashMap<usize, (Vec<u8>, Vec<(usize, usize)>)> = HashMap::new();
+    let mut caches: HashMap<&str, TextCache> = HashMap::new();
+    for i in order {
+        let node = &nodes[i];
+        let text = {
+            let cache = caches.entry(&node.path).or_insert_with(|| TextCache::new(TEXT_CACHE_SIZE));
+            let text = try!(filelogs[&node.path].revlog().cached_text(node.filerev, cache));
+            let (_, offset) = try!(filelog::parse_meta(&text));
+            Vec::from(&text[offset..])
+        };
+        let mut owners: Vec<(usize, usize)> = {
+            let lines = diff::split_lines(&text);
+            (0..lines.len()).map(|n| (i, n)).collect()
+        };
+        for &p in &node.parents {
+            {
+                let &(ref parent_text, ref parent_owners) = &done[&p];
+                for block in diff::blocks(parent_text, &text) {
+                    owners[block.b..block.b + block.len]
+                        .copy_from_slice(&parent_owners[block.a..block.a + block.len]);
+                }
+            }
+            needed[p] -= 1;
+            if needed[p] == 0 {
+                done.remove(&p);
+            }
+        }
+        done.insert(i, (text, owners));
+    }
+
+    // The first node is the one asked for
+    let (text, owners) = done.remove(&0).unwrap();
+    let mut result = vec![];
+    for (line, (owner, number)) in diff::split_lines(&text).into_iter().zip(owners) {
+        let node = &nodes[owner];
+        result.push(Line {
+            rev: node.rev,
+            path: node.path.clone(),
+            number: number + 1,
+            text: Vec::from(line),
+        });
+    }
+    Ok(result)
+}
+
+/// Find the file revisions a revision descends from, and an order with
+/// parents before children.
+fn history(repo: &Repo,
+           filelogs: &mut HashMap<String, Filelog>,
+           path: &str,
+           filerev: i32,
+           rev: i32)
+           -> Result<(Vec<Node>, Vec<usize>)> {
+    let graph = Graph::new(repo.changelog.revlog());
+    let mut nodes = vec![];
+    let mut index: HashMap<(String, i32), usize> = HashMap::new();
+    index.insert((String::from(path), filerev), 0);
+    nodes.push(Node {
+        path: String::from(path),
+        filerev: filerev,
+        rev: try!(introduced(repo, &graph, &filelogs[path], path, filerev, rev, true)),
+        parents: vec![],
+    });
+
+    let mut todo = vec![0];
+    while let Some(i) = todo.pop() {
+        let mut parents = vec![];
+        {
+            let filelog = &filelogs[&nodes[i].path];
+            let entry = try!(filelog.revlog().index(nodes[i].filerev));
+            let (p1, p2) = (entry.chunk.parent_1(), entry.chunk.parent_2());
+            if let Some((source, node)) = try!(filelog.renamed(nodes[i].filerev)) {
+                parents.push((source, None, Some(node)));
+            } else if p1 != -1 {
+                parents.push((nodes[i].path.clone(), Some(p1), None));
+            }
+            if p2 != -1 {
+                parents.push((nodes[i].path.clone(), Some(p2), None));
+            }
+        }
+
+        for (parent_path, rev, node) in parents {
+            if !filelogs.contains_key(&parent_path) {
+                filelogs.insert(parent_path.clone(), try!(repo.filelog(&parent_path)));
+            }
+            let rev = match (rev, node) {
+                (Some(rev), _) => rev,
+                (None, Some(node)) => {
+                    match try!(filelogs[&parent_path].revlog().rev(&node)) {
+                        Some(rev) => rev,
+                        None => return Err(From::from(format!("{}: copy source not found", parent_path))),
+                    }
+                }
+                (None, None) => unreachable!(),
+            };
+            let key = (parent_path.clone(), rev);
+            let p = match index.get(&key) {
+                Some(&p) => p,
+                None => {
+                    let introduced = try!(introduced(repo,
+                                                     &graph,
+                                                     &filelogs[&parent_path],
+                                                     &parent_path,
+                                                     rev,
+                                                     nodes[i].rev,
+                                                     false));
+                    nodes.push(Node {
+                        path: parent_path,
+                        filerev: rev,
+                        rev: introduced,
+                        parents: vec![],
+                    });
+                    index.insert(key, nodes.len() - 1);
+                    todo.push(nodes.len() - 1);
+                    nodes.len() - 1
+                }
+            };
+            nodes[i].parents.push(p);
+        }
+    }
+
+    // Depth first, emitting a node once its parents are out
+    let mut order = vec![];
+    let mut emitted = vec![false; nodes.len()];
+    let mut stack = vec![0];
+    while let Some(&i) = stack.last() {
+        if emitted[i] {
+            stack.pop();
+            continue;
+        }
+        match nodes[i].parents.iter().find(|&&p| !emitted[p]) {
+            Some(&p) => stack.push(p),
+            None => {
+                emitted[i] = true;
+                order.push(i);
+                stack.pop();
+            }
+        }
+    }
+    Ok((nodes, order))
+}
+
+/// The changeset which introduced a file revision, as seen from `rev`
+/// or, unless `inclusive`, from its parents. That's the linkrev when it
+/// is an ancestor, and otherwise the latest ancestor which touched the
+/// file and has this revision in its manifest, like Mercurial's
+/// `_adjustlinkrev`.
+fn introduced(repo: &Repo,
+              graph: &Graph,
+              filelog: &Filelog,
+              path: &str,
+              filerev: i32,
+              rev: i32,
+              inclusive: bool)
+              -> Result<i32> {
+    let entry = try!(filelog.revlog().index(filerev));
+    let link_rev = entry.chunk.link_rev();
+    for a in try!(ancestor::ancestors(graph, &[rev], inclusive)) {
+        let a = try!(a);
+        if a == link_rev {
+            return Ok(link_rev);
+        } else if a < link_rev {
+            break;
+        }
+    }
+    let node = entry.chunk.c_node_id();
+    for a in try!(ancestor::ancestors(graph, &[rev], inclusive)) {
+        let a = try!(a);
+        if !try!(repo.changeset(a)).files.iter().any(|f| f == path) {
+            continue;
+        }
+        if try!(repo.manifest(a)).get(path).map_or(false, |e| &e.node[..] == node) {
+            return Ok(a);
+        }
+    }
+    Ok(link_rev)
+}
+
+#[cfg(test)]
+mod test {
+    use testutil::{Commit, RepoBuilder};
+    use super::annotate;
+
+    #[test]
+    fn test_annotate() {
+        // Changeset revs are one ahead of f's filelog revs.
+        let mut builder = RepoBuilder::new("annotate");
+        builder.commit(Commit::new(-1, -1).file("h", "h\n"));
+        builder.commit(Commit::new(0, -1).file("f", "a\n"));
+        builder.commit(Commit::new(1, -1).file("f", "x\na\n"));
+        builder.commit(Commit::new(1, -1).file("f", "x\na\ny\n"));
+        builder.commit(Commit::new(2, 3).file("f", "x\na\n"));
+        builder.commit(Commit::new(4, -1).copy("f", "g", "x\na\nz\n").remove("f"));
+        builder.commit(Commit::new(5, -1).file("h", "h2\n"));
+        let repo = builder.open();
+
+        let lines = |path: &str, filerev: i32, rev: i32| -> Vec<(i32, String, usize, String)> {
+            annotate(&repo, path, filerev, rev)
+                .unwrap()
+                .into_iter()
+                .map(|l| (l.rev, l.path, l.number, String::from_utf8(l.text).unwrap()))
+                .collect()
+        };
+        let line = |rev: i32, path: &str, number: usize, text: &str| {
+            (rev, String::from(path), number, String::from(text))
+        };
+
+        assert_eq!(vec![line(1, "f", 1, "a\n")], lines("f", 0, 1));
+        assert_eq!(vec![line(2, "f", 1, "x\n"), line(1, "f", 1, "a\n")], lines("f", 1, 2));
+        // x is in both parents of the merge, and goes to the second.
+        assert_eq!(vec![line(3, "f", 1, "x\n"), line(1, "f", 1, "a\n")], lines("f", 3, 4));
+        // g follows f through the rename, but its own line is its own.
+        assert_eq!(vec![line(3, "f", 1, "x\n"), line(1, "f", 1, "a\n"), line(5, "g", 3, "z\n")],
+                   lines("g", 0, 5));
+    }
+
+    #[test]
+    fn test_annotate_same_change_on_two_branches() {
+        // Changesets 1 and 2 both add b, making the same filelog rev,
+        // whose linkrev is 1.
+        let mut builder = RepoBuilder::new("annotate-linkrev");
+        builder.commit(Commit::new(-1, -1).file("f", "a\n"));
+        builder.commit(Commit::new(0, -1).file("f", "a\nb\n"));
+        builder.commit(Commit::new(0, -1).file("f", "a\nb\n"));
+        builder.commit(Commit::new(2, -1).file("f", "a\nb\nc\n"));
+        let repo = builder.open();
+
+        let revs = |filerev: i32, rev: i32| -> Vec<i32> {
+            annotate(&repo, "f", filerev, rev).unwrap().into_iter().map(|l| l.rev).collect()
+        };
+        assert_eq!(vec![0, 1], revs(1, 1));
+        assert_eq!(vec![0, 2], revs(1, 2));
+        assert_eq!(vec![0, 2, 3], revs(2, 3));
+    }
+}
@@ -0,0 +1,728 @@
+//! Writing the files of a changeset out as a directory, a tarball or a
+//! zip file, like `hg archive`.
+//!
+//! File modes come from the manifest flags: executables get `0755`,
+//! other files `0644`, and symlinks are written as links whose target
+//! is the file's content. Every file carries the changeset's date.
+//! Archives also get a `.hg_archival.txt` describing the changeset:
+//!
+//! ```text
+//! repo: <node of rev 0>
+//! node: <node>
+//! branch: <branch>
+//! tag: <tag>                           (one per tag, if it's tagged)
+//! latesttag: <tag>                     (otherwise, one per tag of the
+//! latesttagdistance: <distance>         nearest tagged ancestor)
+//! changessincelatesttag: <count>
+//! ```
+//!
+//! Manifests may come from stores nobody checked, so like Mercurial's
+//! path auditor, paths which are absolute or have `..` or `.hg`
+//! components are refused, and so are files under a symlink when
+//! archiving to a directory.
+
+extern crate flate2;
+
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+use rustc_serialize::hex::ToHex;
+
+use archive::flate2::{Compression, Crc, GzBuilder};
+use archive::flate2::write::DeflateEncoder;
+use date;
+use manifest::Flags;
+use repo::Repo;
+use tags;
+use util::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// A directory of files
+    Files,
+    /// An uncompressed tarball
+    Tar,
+    /// A gzipped tarball
+    Tgz,
+    /// A zip file whose members are stored
+    Uzip,
+    /// A zip file whose members are deflated
+    Zip,
+}
+
+impl Kind {
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "files" => Some(Kind::Files),
+            "tar" => Some(Kind::Tar),
+            "tgz" => Some(Kind::Tgz),
+            "uzip" => Some(Kind::Uzip),
+            "zip" => Some(Kind::Zip),
+            _ => None,
+        }
+    }
+
+    /// The kind implied by a destination's extension, which defaults to
+    /// a directory.
+    pub fn guess(dest: &str) -> Kind {
+        if dest.ends_with(".tar") {
+            Kind::Tar
+        } else if dest.ends_with(".tgz") || dest.ends_with(".tar.gz") {
+            Kind::Tgz
+        } else if dest.ends_with(".zip") {
+            Kind::Zip
+        } else {
+            Kind::Files
+        }
+    }
+}
+
+/// The default prefix of an archive's members: its file name without
+/// the extension.
+pub fn default_prefix(dest: &str) -> String {
+    let name = Path::new(dest).file_name().map_or(String::new(), |n| n.to_string_lossy().into_owned());
+    for ext in &[".tar.gz", ".tgz", ".tar", ".zip"] {
+        if name.ends_with(ext) {
+            return String::from(&name[..name.len() - ext.len()]);
+        }
+    }
+    name
+}
+
+/// The text of `.hg_archival.txt` for a changeset.
+pub fn metadata(repo: &Repo, rev: i32) -> Result<Vec<u8>> {
+    let revlog = repo.changelog.revlog();
+    let cs = try!(repo.changeset(rev));
+    let mut text = format!("repo: {}\nnode: {}\nbranch: {}\n",
+                           try!(revlog.node(0)).to_hex(),
+                           try!(revlog.node(rev)).to_hex(),
+                           cs.branch());
+    let latest = try!(tags::latest_tag(repo, rev));
+    if latest.distance == 0 {
+        for tag in &latest.tags {
+            text.push_str(&format!("tag: {}\n", tag));
+        }
+    } else {
+        for tag in &latest.tags {
+            text.push_str(&format!("latesttag: {}\n", tag));
+        }
+        text.push_str(&format!("latesttagdistance: {}\nchangessincelatesttag: {}\n",
+                               latest.distance,
+                               latest.changes));
+    }
+    Ok(text.into_bytes())
+}
+
+/// Where the members of an archive go.
+trait Archiver {
+    /// Add a member. For a symlink, `data` is the target.
+    fn add(&mut self, path: &str, flags: Flags, data: &[u8]) -> Result<()>;
+
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Archive the files of a changeset for which `wanted` is true at
+/// `dest`. Members of a tarball or zip file are put in the directory
+/// `prefix`.
+pub fn archive(repo: &Repo, rev: i32, kind: Kind, dest: &Path, prefix: &str,
+               wanted: &Fn(&str) -> bool)
+               -> Result<()> {
+    let cs = try!(repo.changeset(rev));
+    let manifest = try!(repo.manifest(rev));
+    let prefix = prefix.trim_matches('/');
+    let mut archiver: Box<Archiver> = match kind {
+        Kind::Files => {
+            expect!(prefix.is_empty(), "cannot give a prefix when archiving to files");
+            Box::new(FileArchiver { dest: dest.to_path_buf() })
+        }
+        Kind::Tar => Box::new(TarArchiver::new(try!(fs::File::create(dest)), prefix, cs.time)),
+        Kind::Tgz => {
+            let gz = GzBuilder::new().mtime(cs.time as u32).write(try!(fs::File::create(dest)), Compression::Default);
+            Box::new(TarArchiver::new(gz, prefix, cs.time))
+        }
+        Kind::Uzip | Kind::Zip => {
+            Box::new(ZipArchiver::new(try!(fs::File::create(dest)), prefix, cs.time, cs.tz, kind == Kind::Zip))
+        }
+    };
+
+    if wanted(".hg_archival.txt") {
+        try!(archiver.add(".hg_archival.txt", Flags::Regular, &try!(metadata(repo, rev))));
+    }
+    for &(ref path, ref entry) in manifest.iter() {
+        if !wanted(path) {
+            continue;
+        }
+        try!(audit_path(path));
+        let filelog = try!(repo.filelog(path));
+        let filerev = match try!(filelog.revlog().rev(&entry.node)) {
+            Some(filerev) => filerev,
+            None => return Err(From::from(format!("{}: filelog node not found", path))),
+        };
+        try!(archiver.add(path, entry.flags, &try!(filelog.content(filerev))));
+    }
+    archiver.finish()
+}
+
+/// Check that a path from a manifest names a file inside the working
+/// directory. `.hg` is matched in any case, as on case-insensitive
+/// filesystems it's the same directory.
+fn audit_path(path: &str) -> Result<()> {
+    expect!(!path.starts_with('/'), "path {:?} is absolute", path);
+    for part in path.split('/') {
+        expect!(!part.is_empty() && part != "." && part != "..",
+                "path {:?} has an empty, '.' or '..' component",
+                path);
+        expect!(part.to_lowercase() != ".hg", "path {:?} is inside '.hg'", path);
+    }
+    Ok(())
+}
+
+fn mode(flags: Flags) -> u32 {
+    match flags {
+        Flags::Regular => 0o100644,
+        Flags::Executable => 0o100755,
+        Flags::Symlink => 0o120777,
+    }
+}
+
+fn member_name(prefix: &str, path: &str) -> String {
+    if prefix.is_empty() {
+        String::from(path)
+    } else {
+        format!("{}/{}", prefix, path)
+    }
+}
+
+struct FileArchiver {
+    dest: PathBuf,
+}
+
+impl Archiver for FileArchiver {
+    fn add(&mut self, path: &str, flags: Flags, data: &[u8]) -> Result<()> {
+        // Creating the file would follow a symlink among its directories
+        let parts: Vec<&str> = path.split('/').collect();
+        let mut dir = self.dest.clone();
+        for part in &parts[..parts.len() - 1] {
+            dir.push(part);
+            if let Ok(metadata) = fs::symlink_metadata(&dir) {
+                expect!(!metadata.file_type().is_symlink(),
+                        "path {:?} traverses symbolic link {:?}",
+                        path,
+                        dir);
+            }
+        }
+        let full = self.dest.join(path);
+        if let Some(dir) = full.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        // Replace whatever an earlier archive left
+        if fs::symlink_metadata(&full).is_ok() {
+            try!(fs::remove_file(&full));
+        }
+        if flags == Flags::Symlink {
+            try!(symlink(&*String::from_utf8_lossy(data), &full));
+        } else {
+            try!(try!(fs::File::create(&full)).write_all(data));
+            try!(fs::set_permissions(&full, fs::Permissions::from_mode(mode(flags) & 0o777)));
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes a ustar archive, with GNU long name records for paths which
+/// don't fit in the header.
+struct TarArchiver<W: Write> {
+    out: W,
+    prefix: String,
+    mtime: i64,
+}
+
+impl<W: Write> TarArchiver<W> {
+    fn new(out: W, prefix: &str, mtime: i64) -> TarArchiver<W> {
+        TarArchiver {
+            out: out,
+            prefix: String::from(prefix),
+            mtime: mtime,
+        }
+    }
+
+    fn write_header(&mut self, name: &[u8], mode: u32, size: usize, kind: u8, link: &[u8]) -> Result<()> {
+        let mut header = [0u8; 512];
+        let (prefix, name) = split_tar_name(name);
+        header[..name.len()].copy_from_slice(name);
+        octal(&mut header[100..108], (mode & 0o7777) as u64);
+        octal(&mut header[108..116], 0);
+        octal(&mut header[116..124], 0);
+        octal(&mut header[124..136], size as u64);
+        octal(&mut header[136..148], self.mtime.max(0) as u64);
+        header[156] = kind;
+        header[157..157 + link.len().min(100)].copy_from_slice(&link[..link.len().min(100)]);
+        header[257..265].copy_from_slice(b"ustar\x0000");
+        header[265..269].copy_from_slice(b"root");
+        header[297..301].copy_from_slice(b"root");
+        header[345..345 + prefix.len()].copy_from_slice(prefix);
+        // The checksum is computed with its own field as spaces
+        for b in &mut header[148..156] {
+            *b = b' ';
+        }
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        octal(&mut header[148..155], sum as u64);
+        try!(self.out.write_all(&header));
+        Ok(())
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<()> {
+        try!(self.out.write_all(data));
+        let padding = (512 - data.len() % 512) % 512;
+        try!(self.out.write_all(&vec![0; padding]));
+        Ok(())
+    }
+}
+
+/// Split a name between the prefix and name fields of a ustar header,
+/// or keep it whole if it needs a long name record.
+fn split_tar_name(name: &[u8]) -> (&[u8], &[u8]) {
+    if name.len() <= 100 {
+        return (b"", name);
+    }
+    for (i, &c) in name.iter().enumerate().rev() {
+        if c == b'/' && i <= 155 && name.len() - i - 1 <= 100 {
+            return (&name[..i], &name[i + 1..]);
+        }
+    }
+    (b"", &name[..100])
+}
+
+/// Write a number as zero padded octal digits followed by a NUL.
+fn octal(field: &mut [u8], n: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", n, width = width);
+    field[..width].copy_from_slice(&digits.as_bytes()[digits.len() - width..]);
+    field[width] = 0;
+}
+
+impl<W: Write> Archiver for TarArchiver<W> {
+    fn add(&mut self, path: &str, flags: Flags, data: &[u8]) -> Result<()> {
+        let name = member_name(&self.prefix, path).into_bytes();
+        let long_link = flags == Flags::Symlink && data.len() > 100;
+        if long_link {
+            let mut link = data.to_vec();
+            link.push(0);
+            try!(self.write_header(b"././@LongLink", 0o644, link.len(), b'K', b""));
+            try!(self.write_data(&link));
+        }
+        if name.len() > 100 && split_tar_name(&name).0.is_empty() {
+            let mut long = name.clone();
+            long.push(0);
+            try!(self.write_header(b"././@LongLink", 0o644, long.len(), b'L', b""));
+            try!(self.write_data(&long));
+        }
+        if flags == Flags::Symlink {
+            self.write_header(&name, mode(flags), 0, b'2', data)
+        } else {
+            try!(self.write_header(&name, mode(flags), data.len(), b'0', b""));
+            self.write_data(data)
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        // A gzip stream gets its trailer when dropped
+        try!(self.out.write_all(&[0; 1024]));
+        try!(self.out.flush());
+        Ok(())
+    }
+}
+
+/// The most members a zip file without zip64 records can hold, and its
+/// largest size or offset, since the maximum values mean "see zip64".
+const ZIP_MAX_ENTRIES: usize = 0xfffe;
+const ZIP_MAX_SIZE: u64 = 0xfffffffe;
+
+/// A size or offset as a zip file without zip64 records holds it.
+fn zip32(n: u64) -> Result<u32> {
+    expect!(n <= ZIP_MAX_SIZE, "archive is too large for a zip file without zip64");
+    Ok(n as u32)
+}
+
+/// A zip member as recorded in the central directory.
+struct ZipEntry {
+    name: Vec<u8>,
+    crc: u32,
+    method: u16,
+    compressed: u32,
+    size: u32,
+    mode: u32,
+    offset: u32,
+}
+
+/// Writes a zip file, recording Unix modes and the changeset's mtime.
+struct ZipArchiver<W: Write> {
+    out: W,
+    prefix: String,
+    /// Seconds since the epoch, for the extended timestamp field
+    mtime: i64,
+    /// MS-DOS time and date, in the changeset's timezone
+    dos_time: u16,
+    dos_date: u16,
+    deflate: bool,
+    offset: u64,
+    entries: Vec<ZipEntry>,
+}
+
+impl<W: Write> ZipArchiver<W> {
+    fn new(out: W, prefix: &str, mtime: i64, tz: i32, deflate: bool) -> ZipArchiver<W> {
+        // MS-DOS dates start in 1980
+        let tm = date::to_tm(mtime.max(315532800), tz);
+        ZipArchiver {
+            out: out,
+            prefix: String::from(prefix),
+            mtime: mtime,
+            dos_time: ((tm.hour << 11) | (tm.minute << 5) | (tm.second / 2)) as u16,
+            dos_date: ((((tm.year - 1980) as u32) << 9) | (tm.month << 5) | tm.day) as u16,
+            deflate: deflate,
+            offset: 0,
+            entries: vec![],
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<()> {
+        try!(self.out.write_all(data));
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+
+    /// The extended timestamp extra field, holding the mtime.
+    fn extra(&self) -> Vec<u8> {
+        let mut extra = vec![];
+        extra.extend(&le16(0x5455));
+        extra.extend(&le16(5));
+        extra.push(1);
+        extra.extend(&le32(self.mtime as u32));
+        extra
+    }
+}
+
+fn le16(n: u16) -> [u8; 2] {
+    [n as u8, (n >> 8) as u8]
+}
+
+fn le32(n: u32) -> [u8; 4] {
+    [n as u8, (n >> 8) as u8, (n >> 16) as u8, (n >> 24) as u8]
+}
+
+impl<W: Write> Archiver for ZipArchiver<W> {
+    fn add(&mut self, path: &str, flags: Flags, data: &[u8]) -> Result<()> {
+        expect!(self.entries.len() < ZIP_MAX_ENTRIES, "too many files for a zip file without zip64");
+        let mut crc = Crc::new();
+        crc.update(data);
+        let (method, body) = if self.deflate {
+            let mut encoder = DeflateEncoder::new(vec![], Compression::Default);
+            try!(encoder.write_all(data));
+            (8, try!(encoder.finish()))
+        } else {
+            (0, data.to_vec())
+        };
+        let entry = ZipEntry {
+            name: member_name(&self.prefix, path).into_bytes(),
+            crc: crc.sum(),
+            method: method,
+            compressed: try!(zip32(body.len() as u64)),
+            size: try!(zip32(data.len() as u64)),
+            mode: mode(flags),
+            offset: try!(zip32(self.offset)),
+        };
+
+        let mut header = vec![];
+        header.extend(&le32(0x04034b50));
+        header.extend(&le16(20));
+        header.extend(&le16(0));
+        header.extend(&le16(entry.method));
+        header.extend(&le16(self.dos_time));
+        header.extend(&le16(self.dos_date));
+        header.extend(&le32(entry.crc));
+        header.extend(&le32(entry.compressed));
+        header.extend(&le32(entry.size));
+        header.extend(&le16(entry.name.len() as u16));
+        let extra = self.extra();
+        header.extend(&le16(extra.len() as u16));
+        header.extend(&entry.name);
+        header.extend(&extra);
+        try!(self.write(&header));
+        try!(self.write(&body));
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        let start = self.offset;
+        let extra = self.extra();
+        let entries = ::std::mem::replace(&mut self.entries, vec![]);
+        for entry in &entries {
+            let mut header = vec![];
+            header.extend(&le32(0x02014b50));
+            // Made by Unix, version 2.0
+            header.extend(&le16(0x0314));
+            header.extend(&le16(20));
+            header.extend(&le16(0));
+            header.extend(&le16(entry.method));
+            header.extend(&le16(self.dos_time));
+            header.extend(&le16(self.dos_date));
+            header.extend(&le32(entry.crc));
+            header.extend(&le32(entry.compressed));
+            header.extend(&le32(entry.size));
+            header.extend(&le16(entry.name.len() as u16));
+            header.extend(&le16(extra.len() as u16));
+            // Comment length, disk number, internal attributes
+            header.extend(&[0; 6]);
+            header.extend(&le32(entry.mode << 16));
+            header.extend(&le32(entry.offset));
+            header.extend(&entry.name);
+            header.extend(&extra);
+            try!(self.write(&header));
+        }
+        let size = try!(zip32(self.offset - start));
+        let start = try!(zip32(start));
+        let mut end = vec![];
+        end.extend(&le32(0x06054b50));
+        end.extend(&[0; 4]);
+        end.extend(&le16(entries.len() as u16));
+        end.extend(&le16(entries.len() as u16));
+        end.extend(&le32(size));
+        end.extend(&le32(start));
+        end.extend(&le16(0));
+        try!(self.write(&end));
+        try!(self.out.flush());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{self, Read};
+    use std::os::unix::fs::symlink;
+    use std::str;
+    use manifest::Flags;
+    use testutil::{Commit, RepoBuilder};
+    use super::{Archiver, Kind, ZipArchiver, ZIP_MAX_ENTRIES, archive, audit_path, default_prefix, octal,
+                split_tar_name, zip32};
+    use super::flate2::Crc;
+    use super::flate2::read::DeflateDecoder;
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Kind::Tgz, Kind::guess("out/x-1.0.tar.gz"));
+        assert_eq!(Kind::Files, Kind::guess("out/x-1.0"));
+        assert_eq!("x-1.0", default_prefix("out/x-1.0.tar.gz"));
+        let long = format!("{}/{}", "d".repeat(120), "f".repeat(50));
+        assert_eq!((&long.as_bytes()[..120], &long.as_bytes()[121..]), split_tar_name(long.as_bytes()));
+        let mut field = [0xff; 8];
+        octal(&mut field, 0o644);
+        assert_eq!(b"0000644\0", &field);
+    }
+
+    #[test]
+    fn test_audit_path() {
+        assert!(audit_path("a/b.txt").is_ok());
+        assert!(audit_path(".hgtags").is_ok());
+        for path in &["/etc/passwd", "../x", "a/../../x", "a//b", "./a", ".hg/hgrc", "a/.HG/x", "a/"] {
+            assert!(audit_path(path).is_err(), "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_files_stay_in_dest() {
+        let mut builder = RepoBuilder::new("archive-audit");
+        let outside = builder.hg_path().parent().unwrap().join("outside");
+        fs::create_dir(&outside).unwrap();
+        builder.commit(Commit::new(-1, -1)
+            .symlink("a", outside.to_str().unwrap())
+            .file("a/passwd", "x"));
+        builder.commit(Commit::new(-1, -1).file("../escaped", "x"));
+        let repo = builder.open();
+
+        let dest = builder.hg_path().parent().unwrap().join("dest");
+        let error = archive(&repo, 0, Kind::Files, &dest, "", &|_| true).unwrap_err();
+        assert!(error.to_string().contains("traverses symbolic link"), "{}", error);
+        assert!(!outside.join("passwd").exists());
+        assert!(archive(&repo, 1, Kind::Files, &dest, "", &|_| true).is_err());
+        assert!(!dest.parent().unwrap().join("escaped").exists());
+
+        // Nor through a symlink already in the destination
+        let dest = builder.hg_path().parent().unwrap().join("dest2");
+        fs::create_dir(&dest).unwrap();
+        symlink(&outside, dest.join("a")).unwrap();
+        assert!(archive(&repo, 0, Kind::Files, &dest, "", &|p| p == "a/passwd").is_err());
+        assert!(!outside.join("passwd").exists());
+    }
+
+    /// A member's name, mode, and content or symlink target.
+    type Member = (String, u32, Vec<u8>);
+
+    /// Read the members of a tarball, checking its headers.
+    fn read_tar(data: &[u8]) -> Vec<Member> {
+        assert_eq!(0, data.len() % 512);
+        let mut members = vec![];
+        let mut long_name = None;
+        let mut long_link = None;
+        let mut pos = 0;
+        loop {
+            let header = &data[pos..pos + 512];
+            pos += 512;
+            if header.iter().all(|&b| b == 0) {
+                assert!(data[pos..].iter().all(|&b| b == 0));
+                return members;
+            }
+            let field = |start: usize, end: usize| -> Vec<u8> {
+                header[start..end].iter().cloned().take_while(|&b| b != 0).collect()
+            };
+            let number = |start: usize, end: usize| {
+                u64::from_str_radix(str::from_utf8(&field(start, end)).unwrap(), 8).unwrap()
+            };
+            let sum: u64 = header.iter().enumerate().map(|(i, &b)| if i >= 148 && i < 156 { 32 } else { b as u64 }).sum();
+            assert_eq!(sum, number(148, 156));
+            assert_eq!(b"ustar\x0000", &header[257..265]);
+            let size = number(124, 136) as usize;
+            let mut body = data[pos..pos + size].to_vec();
+            pos += (size + 511) / 512 * 512;
+            match header[156] {
+                b'L' | b'K' => {
+                    assert_eq!(Some(0), body.pop());
+                    if header[156] == b'L' {
+                        long_name = Some(body);
+                    } else {
+                        long_link = Some(body);
+                    }
+                }
+                kind => {
+                    let mut name = field(345, 500);
+                    if !name.is_empty() {
+                        name.push(b'/');
+                    }
+                    name.extend(field(0, 100));
+                    let name = long_name.take().unwrap_or(name);
+                    let content = if kind == b'2' {
+                        long_link.take().unwrap_or(field(157, 257))
+                    } else {
+                        assert_eq!(b'0', kind);
+                        body
+                    };
+                    members.push((String::from_utf8(name).unwrap(), number(100, 108) as u32, content));
+                }
+            }
+        }
+    }
+
+    /// Read the members of a zip file through its central directory,
+    /// checking their local headers and CRCs.
+    fn read_zip(data: &[u8]) -> Vec<Member> {
+        let le16 = |at: usize| data[at] as usize | (data[at + 1] as usize) << 8;
+        let le32 = |at: usize| le16(at) | le16(at + 2) << 16;
+        let end = data.len() - 22;
+        assert_eq!(0x06054b50, le32(end));
+        let count = le16(end + 10);
+        let mut pos = le32(end + 16);
+        assert_eq!(end, pos + le32(end + 12));
+        let mut members = vec![];
+        for _ in 0..count {
+            assert_eq!(0x02014b50, le32(pos));
+            let (method, crc, compressed, size) = (le16(pos + 10), le32(pos + 16), le32(pos + 20), le32(pos + 24));
+            let name = data[pos + 46..pos + 46 + le16(pos + 28)].to_vec();
+            let mode = le32(pos + 38) >> 16;
+            let offset = le32(pos + 42);
+            pos += 46 + le16(pos + 28) + le16(pos + 30) + le16(pos + 32);
+
+            assert_eq!(0x04034b50, le32(offset));
+            assert_eq!(&name[..], &data[offset + 30..offset + 30 + le16(offset + 26)]);
+            let start = offset + 30 + le16(offset + 26) + le16(offset + 28);
+            let body = &data[start..start + compressed];
+            let mut content = vec![];
+            if method == 8 {
+                DeflateDecoder::new(body).read_to_end(&mut content).unwrap();
+            } else {
+                assert_eq!(0, method);
+                content.extend_from_slice(body);
+            }
+            assert_eq!(size, content.len());
+            let mut sum = Crc::new();
+            sum.update(&content);
+            assert_eq!(crc as u32, sum.sum());
+            members.push((String::from_utf8(name).unwrap(), mode as u32, content));
+        }
+        members
+    }
+
+    fn fixture(name: &str) -> RepoBuilder {
+        let mut builder = RepoBuilder::new(name);
+        builder.commit(Commit::new(-1, -1)
+            .file("a.txt", "hello\n")
+            .executable("bin/run.sh", "#!/bin/sh\n")
+            .file(&format!("{}/{}", "d".repeat(120), "f".repeat(50)), "deep\n")
+            .file(&"n".repeat(160), "long\n")
+            .symlink("link", "a.txt")
+            .symlink("long", &"t".repeat(150)));
+        builder
+    }
+
+    fn check_members(members: &[Member], prefix: &str, file_type: u32) {
+        let names: Vec<String> = vec![".hg_archival.txt",
+                                      "a.txt",
+                                      "bin/run.sh",
+                                      &format!("{}/{}", "d".repeat(120), "f".repeat(50)),
+                                      "link",
+                                      "long",
+                                      &"n".repeat(160)]
+            .into_iter()
+            .map(|n| format!("{}{}", prefix, n))
+            .collect();
+        assert_eq!(names, members.iter().map(|m| m.0.clone()).collect::<Vec<_>>());
+        assert!(str::from_utf8(&members[0].2).unwrap().starts_with("repo: "));
+        assert_eq!((file_type | 0o644, &b"hello\n"[..]), (members[1].1, &members[1].2[..]));
+        assert_eq!((file_type | 0o755, &b"#!/bin/sh\n"[..]), (members[2].1, &members[2].2[..]));
+        assert_eq!(&b"deep\n"[..], &members[3].2[..]);
+        let link_type = if file_type == 0 { 0 } else { 0o120000 };
+        assert_eq!((link_type | 0o777, &b"a.txt"[..]), (members[4].1, &members[4].2[..]));
+        assert_eq!("t".repeat(150).as_bytes(), &members[5].2[..]);
+        assert_eq!(&b"long\n"[..], &members[6].2[..]);
+    }
+
+    #[test]
+    fn test_tar() {
+        let builder = fixture("archive-tar");
+        let repo = builder.open();
+        let dest = builder.hg_path().join("out.tar");
+        archive(&repo, 0, Kind::Tar, &dest, "p/", &|_| true).unwrap();
+        let mut data = vec![];
+        fs::File::open(&dest).unwrap().read_to_end(&mut data).unwrap();
+        check_members(&read_tar(&data), "p/", 0);
+    }
+
+    #[test]
+    fn test_zip() {
+        let builder = fixture("archive-zip");
+        let repo = builder.open();
+        for &kind in &[Kind::Zip, Kind::Uzip] {
+            let dest = builder.hg_path().join("out.zip");
+            archive(&repo, 0, kind, &dest, "p", &|_| true).unwrap();
+            let mut data = vec![];
+            fs::File::open(&dest).unwrap().read_to_end(&mut data).unwrap();
+            check_members(&read_zip(&data), "p/", 0o100000);
+        }
+    }
+
+    #[test]
+    fn test_zip_limits() {
+        assert_eq!(0xfffffffe, zip32(0xfffffffe).unwrap());
+        assert!(zip32(0xffffffff).is_err());
+        let mut zip = ZipArchiver::new(io::sink(), "", 0, 0, false);
+        for _ in 0..ZIP_MAX_ENTRIES {
+            zip.add("a", Flags::Regular, b"").unwrap();
+        }
+        assert!(zip.add("a", Flags::Regular, b"").is_err());
+    }
+}
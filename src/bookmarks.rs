@@ -0,0 +1,40 @@
+//! Bookmarks are kept in `.hg/bookmarks` as lines of `<node, hex>
+//! <name>`, or in the store when a share uses `bookmarksinstore`. The
+//! active bookmark, which moves with commits, is named in
+//! `.hg/bookmarks.current`.
+
+use std::collections::BTreeMap;
+
+use repo::{self, Repo};
+use util::Result;
+
+/// Bookmarks and their nodes. Bookmarks pointing to unknown changesets
+/// are left out, as Mercurial does.
+pub fn bookmarks(repo: &Repo) -> Result<BTreeMap<String, Vec<u8>>> {
+    let path = if repo.requires.iter().any(|r| r == "bookmarksinstore") {
+        repo.store().path().join("bookmarks")
+    } else {
+        repo.hg_path().join("bookmarks")
+    };
+    let mut result = BTreeMap::new();
+    if let Some(data) = try!(repo::read_optional(&path)) {
+        for (name, node) in try!(repo::parse_node_names(&data)) {
+            if try!(repo.changelog.revlog().rev(&node)).is_some() {
+                result.insert(name, node);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// The active bookmark, if it still exists.
+pub fn active(repo: &Repo) -> Result<Option<String>> {
+    let name = match try!(repo::read_optional(&repo.hg_path().join("bookmarks.current"))) {
+        Some(data) => String::from_utf8_lossy(&data).trim().to_string(),
+        None => return Ok(None),
+    };
+    if name.is_empty() || !try!(bookmarks(repo)).contains_key(&name) {
+        return Ok(None);
+    }
+    Ok(Some(name))
+}
@@ -0,0 +1,269 @@
+//! Named branches and their heads.
+//!
+//! A branch head is a changeset with no descendants on its own branch.
+//! Mercurial caches the heads of every branch in `.hg/cache/branch2-*`,
+//! one file per repo filter:
+//!
+//! ```text
+//! <tip node, hex> <tip rev>[ <filtered hash, hex>]\n
+//! <head node, hex> <o|c> <branch>\n
+//! ...
+//! ```
+//!
+//! where `c` marks a head which closes its branch. The filtered hash is
+//! only written when the filter hid some revs, in which case the cache
+//! doesn't describe the whole repo and is ignored here. A usable cache
+//! is brought up to date with the changesets added after its tip. When
+//! the repo's view hides changesets, the heads are always computed.
+
+use std::collections::{BTreeMap, HashSet};
+use rustc_serialize::hex::FromHex;
+
+use ancestor;
+use graph::Graph;
+use repo::{self, Repo};
+use util::Result;
+
+/// Cache files, most complete first.
+const CACHE_FILES: &'static [&'static str] = &["branch2", "branch2-visible", "branch2-served",
+                                               "branch2-immutable", "branch2-base"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Head {
+    pub rev: i32,
+    /// Whether this changeset closes its branch
+    pub closed: bool,
+}
+
+pub struct BranchMap {
+    /// The last rev accounted for
+    tip: i32,
+    /// Heads of each branch, in increasing order
+    branches: BTreeMap<String, Vec<Head>>,
+}
+
+impl BranchMap {
+    /// The branch heads of a repo, from the cache if it can be used.
+    pub fn read(repo: &Repo) -> Result<BranchMap> {
+        if !try!(repo.hidden()).is_empty() {
+            return BranchMap::compute(repo);
+        }
+        let mut best: Option<BranchMap> = None;
+        for name in CACHE_FILES {
+            let path = repo.hg_path().join("cache").join(name);
+            let data = match try!(repo::read_optional(&path)) {
+                Some(data) => data,
+                None => continue,
+            };
+            // A broken or stale cache is no worse than no cache
+            if let Ok(Some(map)) = BranchMap::from_cache(repo, &data) {
+                if best.as_ref().map_or(true, |b| map.tip > b.tip) {
+                    best = Some(map);
+                }
+            }
+        }
+        let mut map = best.unwrap_or(BranchMap {
+            tip: -1,
+            branches: BTreeMap::new(),
+        });
+        try!(map.update(repo));
+        Ok(map)
+    }
+
+    /// Work out the branch heads in the repo's view from the changelog
+    /// alone.
+    pub fn compute(repo: &Repo) -> Result<BranchMap> {
+        let mut map = BranchMap {
+            tip: -1,
+            branches: BTreeMap::new(),
+        };
+        try!(map.update(repo));
+        Ok(map)
+    }
+
+    /// Parse a cache file, or None if it's unusable for this repo.
+    fn from_cache(repo: &Repo, data: &[u8]) -> Result<Option<BranchMap>> {
+        let (tip_node, tip, filtered, entries) = try!(parse_cache(data));
+        let revlog = repo.changelog.revlog();
+        if filtered || tip >= repo.changelog.len() || try!(revlog.node(tip)) != &tip_node[..] {
+            return Ok(None);
+        }
+        let mut branches: BTreeMap<String, Vec<Head>> = BTreeMap::new();
+        for (node, closed, branch) in entries {
+            let rev = match try!(revlog.rev(&node)) {
+                Some(rev) if rev <= tip => rev,
+                _ => return Ok(None),
+            };
+            branches.entry(branch).or_insert_with(Vec::new).push(Head {
+                rev: rev,
+                closed: closed,
+            });
+        }
+        for heads in branches.values_mut() {
+            heads.sort_by_key(|h| h.rev);
+        }
+        Ok(Some(BranchMap {
+            tip: tip,
+            branches: branches,
+        }))
+    }
+
+    /// Account for the changesets after `self.tip`.
+    fn update(&mut self, repo: &Repo) -> Result<()> {
+        let len = repo.changelog.len();
+        if self.tip >= len - 1 {
+            return Ok(());
+        }
+        let hidden = try!(repo.hidden());
+        let mut new_revs: BTreeMap<String, Vec<Head>> = BTreeMap::new();
+        for rev in self.tip + 1..len {
+            if hidden.contains(&rev) {
+                continue;
+            }
+            let cs = try!(repo.changeset(rev));
+            new_revs.entry(String::from(cs.branch())).or_insert_with(Vec::new).push(Head {
+                rev: rev,
+                closed: cs.closes_branch(),
+            });
+        }
+
+        let graph = Graph::new(repo.changelog.revlog());
+        for (branch, new_heads) in new_revs {
+            let heads = self.branches.entry(branch).or_insert_with(Vec::new);
+            let floor = heads.first().unwrap_or(&new_heads[0]).rev;
+            let new: Vec<i32> = new_heads.iter().map(|h| h.rev).collect();
+            heads.extend(new_heads);
+
+            // Remove the old heads that the new revs descend from, and
+            // the new revs that are ancestors of other new revs
+            let mut candidates: HashSet<i32> = heads.iter().map(|h| h.rev).collect();
+            for rev in try!(ancestor::ancestors(&graph, &new, false)) {
+                let rev = try!(rev);
+                if rev < floor {
+                    break;
+                }
+                candidates.remove(&rev);
+            }
+            heads.retain(|h| candidates.contains(&h.rev));
+        }
+        self.tip = len - 1;
+        Ok(())
+    }
+
+    /// The names of all branches, closed or not, in sorted order.
+    pub fn branches(&self) -> Vec<&str> {
+        self.branches.keys().map(|b| &b[..]).collect()
+    }
+
+    /// The heads of a branch, in increasing order.
+    pub fn heads(&self, branch: &str) -> Option<&[Head]> {
+        self.branches.get(branch).map(|heads| &heads[..])
+    }
+
+    /// Whether every head of a branch is closed.
+    pub fn is_closed(&self, branch: &str) -> bool {
+        self.heads(branch).map_or(false, |heads| heads.iter().all(|h| h.closed))
+    }
+
+    /// The head a branch name refers to: the most recent open head, or
+    /// the most recent head if they are all closed.
+    pub fn tip(&self, branch: &str) -> Option<i32> {
+        let heads = match self.heads(branch) {
+            Some(heads) => heads,
+            None => return None,
+        };
+        heads.iter().rev().find(|h| !h.closed).or(heads.last()).map(|h| h.rev)
+    }
+}
+
+/// Split a cache file into its tip node and rev, whether it has a
+/// filtered hash, and its heads.
+fn parse_cache(data: &[u8]) -> Result<(Vec<u8>, i32, bool, Vec<(Vec<u8>, bool, String)>)> {
+    let text = String::from_utf8_lossy(data);
+    let mut lines = text.lines();
+    let header: Vec<&str> = lines.next().unwrap_or("").split(' ').collect();
+    expect!(header.len() == 2 || header.len() == 3, "bad branch cache header");
+    let tip_node = try!(header[0].from_hex());
+    let tip = try!(header[1].parse::<i32>());
+    let mut entries = vec![];
+    for line in lines {
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.splitn(3, ' ').collect();
+        expect!(fields.len() == 3 && (fields[1] == "o" || fields[1] == "c"),
+                "bad branch cache line {:?}",
+                line);
+        entries.push((try!(fields[0].from_hex()), fields[1] == "c", String::from(fields[2])));
+    }
+    Ok((tip_node, tip, header.len() == 3, entries))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+    use rustc_serialize::hex::ToHex;
+    use testutil::{Commit, RepoBuilder};
+    use super::{parse_cache, BranchMap, Head};
+
+    #[test]
+    fn test_parse_cache() {
+        let data = b"0101010101010101010101010101010101010101 4\n\
+                     0202020202020202020202020202020202020202 o default\n\
+                     0303030303030303030303030303030303030303 c old stuff\n";
+        let (tip_node, tip, filtered, entries) = parse_cache(data).unwrap();
+        assert_eq!(vec![1; 20], tip_node);
+        assert_eq!(4, tip);
+        assert!(!filtered);
+        assert_eq!((vec![3; 20], true, String::from("old stuff")), entries[1]);
+        assert!(parse_cache(b"0101 4 ab cd\n").is_err());
+    }
+
+    fn head(rev: i32, closed: bool) -> Head {
+        Head {
+            rev: rev,
+            closed: closed,
+        }
+    }
+
+    #[test]
+    fn test_update() {
+        let mut builder = RepoBuilder::new("branchmap-update");
+        builder.commit(Commit::new(-1, -1).file("a", "0"));
+        builder.commit(Commit::new(0, -1).file("a", "1"));
+        builder.commit(Commit::new(0, -1).file("b", "2").branch("stable"));
+        builder.commit(Commit::new(1, -1).file("a", "3"));
+        builder.commit(Commit::new(2, -1).file("b", "4").branch("stable"));
+        builder.commit(Commit::new(4, -1).file("b", "5").branch("stable").close());
+        builder.commit(Commit::new(0, -1).file("c", "6"));
+
+        // A cache up to rev 2, with a branch the changelog doesn't have
+        // to show that it was extended rather than recomputed
+        fs::create_dir(builder.hg_path().join("cache")).unwrap();
+        let cache = format!("{} 2\n{} o default\n{} o stable\n{} o ghost\n",
+                            builder.node(2).to_hex(),
+                            builder.node(1).to_hex(),
+                            builder.node(2).to_hex(),
+                            builder.node(0).to_hex());
+        fs::File::create(builder.hg_path().join("cache/branch2-served"))
+            .unwrap()
+            .write_all(cache.as_bytes())
+            .unwrap();
+        let repo = builder.open();
+
+        let map = BranchMap::read(&repo).unwrap();
+        assert_eq!(vec!["default", "ghost", "stable"], map.branches());
+        assert_eq!(&[head(3, false), head(6, false)], map.heads("default").unwrap());
+        assert_eq!(&[head(5, true)], map.heads("stable").unwrap());
+        assert!(map.is_closed("stable"));
+        assert!(!map.is_closed("default"));
+        assert_eq!(Some(5), map.tip("stable"));
+        assert_eq!(Some(6), map.tip("default"));
+
+        let computed = BranchMap::compute(&repo).unwrap();
+        assert_eq!(vec!["default", "stable"], computed.branches());
+        assert_eq!(map.heads("default"), computed.heads("default"));
+        assert_eq!(map.heads("stable"), computed.heads("stable"));
+    }
+}
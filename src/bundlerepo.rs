@@ -0,0 +1,177 @@
+//! A repo with a bundle's changesets on top, the way `hg incoming
+//! --bundle` and `hg log -R bundle.hg` see it before it's applied.
+//!
+//! Every revision of the bundle the repo doesn't have is added to its
+//! revlog after the stored ones, in the order the bundle has them, so
+//! that revs, parents and linkrevs are as they would be once applied.
+//! Their texts are kept in memory. Phases, bookmarks and obsolescence
+//! markers in the bundle are checked but not applied, and any other
+//! mandatory part is an error.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use exchange::bundle::Bundle;
+use exchange::changegroup::{Kind, Revision};
+use repo::Repo;
+use revlog::{ExtraRevision, Revlog, NULL_ID};
+use store::{Overlay, Store};
+use util::Result;
+
+/// The revisions being added to one revlog.
+struct Added {
+    /// The revlog as stored, if it exists
+    revlog: Option<Revlog>,
+    revisions: Vec<ExtraRevision>,
+    /// The revs of the added revisions
+    revs: HashMap<Vec<u8>, i32>,
+}
+
+impl Added {
+    fn new(store: &Store, name: &str) -> Added {
+        Added {
+            revlog: store.revlog(name).ok(),
+            revisions: vec![],
+            revs: HashMap::new(),
+        }
+    }
+
+    fn rev(&self, node: &[u8]) -> Result<Option<i32>> {
+        if let Some(&rev) = self.revs.get(node) {
+            return Ok(Some(rev));
+        }
+        match self.revlog {
+            Some(ref revlog) => revlog.rev(node),
+            None if node == NULL_ID => Ok(Some(-1)),
+            None => Ok(None),
+        }
+    }
+
+    /// Add a revision, whose linkrev is its own rev if it's a changeset.
+    fn add(&mut self, revision: &Revision, link_rev: Option<i32>, name: &str) -> Result<()> {
+        let mut parents = vec![];
+        for node in &[&revision.p1, &revision.p2] {
+            match try!(self.rev(node)) {
+                Some(rev) => parents.push(rev),
+                None => return Err(From::from(format!("{}: unknown parent of bundle revision", name))),
+            }
+        }
+        let stored = self.revlog.as_ref().map_or(0, |revlog| revlog.stored_len());
+        let rev = stored + self.revisions.len() as i32;
+        self.revisions.push(ExtraRevision::new(rev,
+                                               &revision.node,
+                                               parents[0],
+                                               parents[1],
+                                               link_rev.unwrap_or(rev),
+                                               revision.flags,
+                                               &revision.text));
+        self.revs.insert(revision.node.clone(), rev);
+        Ok(())
+    }
+}
+
+/// Open `repo` with the changesets of the bundle at `path` added.
+pub fn open(repo: Repo, path: &Path) -> Result<Repo> {
+    let mut added: HashMap<String, Added> = HashMap::new();
+    added.insert(String::from("00changelog"), Added::new(repo.store(), "00changelog"));
+    let mut bundle = try!(Bundle::open(path));
+    while let Some(mut part) = try!(bundle.next_header()) {
+        match &part.name[..] {
+            "changegroup" => (),
+            // Known parts which only matter once the bundle is applied
+            "phase-heads" | "bookmarks" | "obsmarkers" => {
+                try!(bundle.read_payload(&mut part));
+                match &part.name[..] {
+                    "phase-heads" => try!(part.phase_heads().map(|_| ())),
+                    "bookmarks" => try!(part.bookmarks().map(|_| ())),
+                    _ => try!(part.obsmarkers().map(|_| ())),
+                }
+                continue;
+            }
+            _ if part.mandatory => return Err(From::from(format!("unsupported bundle part {}", part.name))),
+            _ => continue,
+        }
+        for revision in try!(bundle.changegroup(&part)).revisions(Some(&repo)) {
+            let revision = try!(revision);
+            let name = match revision.kind {
+                Kind::Changelog => String::from("00changelog"),
+                Kind::Manifest if revision.path.is_empty() => String::from("00manifest"),
+                Kind::Manifest => format!("meta/{}00manifest", revision.path),
+                Kind::File => Store::filelog_name(&revision.path),
+            };
+            let link_rev = if revision.kind == Kind::Changelog {
+                None
+            } else {
+                match try!(added["00changelog"].rev(&revision.linknode)) {
+                    Some(rev) => Some(rev),
+                    None => return Err(From::from(format!("{}: unknown changeset for bundle revision", name))),
+                }
+            };
+            if !added.contains_key(&name) {
+                let revlog = Added::new(repo.store(), &name);
+                added.insert(name.clone(), revlog);
+            }
+            let revlog = added.get_mut(&name).unwrap();
+            // The repo may have it already, as two branches can make the
+            // same change
+            if try!(revlog.rev(&revision.node)).is_some() {
+                continue;
+            }
+            try!(revlog.add(&revision, link_rev, &name));
+        }
+    }
+
+    let mut overlay = Overlay::new();
+    for (name, revlog) in added {
+        if !revlog.revisions.is_empty() {
+            overlay.insert(name, Arc::new(revlog.revisions));
+        }
+    }
+    repo.with_overlay(overlay)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use exchange::bundle::write_part;
+    use exchange::changegroup::{self, Version};
+    use repo::Repo;
+    use testutil::{Commit, RepoBuilder};
+    use super::open;
+
+    #[test]
+    fn test_open() {
+        let empty = RepoBuilder::new("bundlerepo-empty");
+        let mut builder = RepoBuilder::new("bundlerepo");
+        builder.commit(Commit::new(-1, -1).file("a", "a\n"));
+        builder.commit(Commit::new(0, -1).file("a", "b\n"));
+        let source = builder.open();
+        let repo = || Repo::open(empty.hg_path().parent().unwrap()).unwrap();
+
+        // A bundle of both revs, with another part
+        let bundle = |name: &str| {
+            let path = empty.hg_path().join(format!("{}.hg", name));
+            let mut data = Vec::from(&b"HG20\0\0\0\0"[..]);
+            let version = vec![(String::from("version"), String::from("02"))];
+            write_part(&mut data,
+                       "CHANGEGROUP",
+                       &version,
+                       &[],
+                       &mut |payload| changegroup::write(&source, &[0, 1], Version::V2, payload))
+                .unwrap();
+            write_part(&mut data, name, &[], &[], &mut |payload| Ok(try!(payload.write_all(&[0; 24])))).unwrap();
+            data.extend_from_slice(b"\0\0\0\0");
+            File::create(&path).unwrap().write_all(&data).unwrap();
+            path
+        };
+
+        let bundled = open(repo(), &bundle("PHASE-HEADS")).unwrap();
+        assert_eq!(1, bundled.tip().unwrap());
+        assert_eq!(&builder.node(1)[..], bundled.changelog.revlog().node(1).unwrap());
+        assert_eq!(Some(b"b\n".to_vec()), bundled.file_content(1, "a").unwrap());
+        assert!(open(repo(), &bundle("other")).is_ok());
+        assert!(open(repo(), &bundle("OTHER")).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! The changelog is the revlog of changesets. Each text looks like:
+//!
+//! ```text
+//! <manifest node, hex>\n
+//! <user>\n
+//! <time> <timezone>[ <extra>]\n
+//! <file>\n
+//! ...
+//! \n
+//! <description>
+//! ```
+//!
+//! The timezone is the offset of local time in seconds *west* of UTC.
+//! `extra` is a `\0` separated list of escaped `key:value` pairs, of
+//! which the most important is the named branch.
+
+use std::collections::BTreeMap;
+use rustc_serialize::hex::FromHex;
+
+use matcher::Matcher;
+use revlog::Revlog;
+use util::Result;
+
+pub struct Changeset {
+    /// Node id of this changeset's manifest
+    pub manifest: Vec<u8>,
+    pub user: String,
+    /// Seconds since the epoch
+    pub time: i64,
+    /// Seconds west of UTC
+    pub tz: i32,
+    pub extra: BTreeMap<String, String>,
+    /// Files touched by this changeset
+    pub files: Vec<String>,
+    pub description: String,
+}
+
+impl Changeset {
+    pub fn parse(text: &[u8]) -> Result<Changeset> {
+        let (header, description) = match find(text, b"\n\n") {
+            Some(i) => (&text[..i], &text[i + 2..]),
+            None => (text, &b""[..]),
+        };
+        let mut lines = header.split(|&c| c == b'\n');
+
+        let manifest = match lines.next() {
+            Some(line) => try!(String::from_utf8_lossy(line).from_hex()),
+            None => return Err(From::from("empty changeset")),
+        };
+        expect!(manifest.len() == 20 || manifest.is_empty(),
+                "bad manifest node in changeset");
+        let user = match lines.next() {
+            Some(line) => String::from_utf8_lossy(line).into_owned(),
+            None => return Err(From::from("changeset has no user")),
+        };
+        let date = match lines.next() {
+            Some(line) => String::from_utf8_lossy(line).into_owned(),
+            None => return Err(From::from("changeset has no date")),
+        };
+
+        let mut fields = date.splitn(3, ' ');
+        let time = match fields.next().map(|s| s.parse::<f64>()) {
+            Some(Ok(t)) => t as i64,
+            _ => return Err(From::from(format!("bad changeset date {:?}", date))),
+        };
+        let tz = match fields.next().map(|s| s.parse::<i32>()) {
+            Some(Ok(tz)) => tz,
+            None => 0,
+            Some(Err(_)) => return Err(From::from(format!("bad changeset date {:?}", date))),
+        };
+        let extra = match fields.next() {
+            Some(extra) => decode_extra(extra),
+            None => BTreeMap::new(),
+        };
+
+        let files = lines.filter(|l| !l.is_empty())
+            .map(|l| String::from_utf8_lossy(l).into_owned())
+            .collect();
+
+        Ok(Changeset {
+            manifest: manifest,
+            user: user,
+            time: time,
+            tz: tz,
+            extra: extra,
+            files: files,
+            description: String::from_utf8_lossy(description).into_owned(),
+        })
+    }
+
+    /// The empty changeset which is the parent of all roots.
+    pub fn null() -> Changeset {
+        Changeset {
+            manifest: vec![0; 20],
+            user: String::new(),
+            time: 0,
+            tz: 0,
+            extra: BTreeMap::new(),
+            files: vec![],
+            description: String::new(),
+        }
+    }
+
+    /// The named branch, which is `default` unless recorded in extra.
+    pub fn branch(&self) -> &str {
+        match self.extra.get("branch") {
+            Some(branch) => branch,
+            None => "default",
+        }
+    }
+
+    /// Whether this changeset closes its branch head.
+    pub fn closes_branch(&self) -> bool {
+        self.extra.contains_key("close")
+    }
+
+    /// Whether any of the files touched matches.
+    pub fn touches(&self, matcher: &Matcher) -> bool {
+        self.files.iter().any(|file| matcher.matches(file))
+    }
+}
+
+/// Undo the escaping of `\0`, `\\`, `\n` and `\r` in extra values.
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('0') => result.push('\0'),
+            Some('n') => result.push('\n'),
+            Some('r') => result.push('\r'),
+            Some('t') => result.push('\t'),
+            Some('\\') => result.push('\\'),
+            Some(c) => {
+                result.push('\\');
+                result.push(c);
+            }
+            None => result.push('\\'),
+        }
+    }
+    result
+}
+
+fn decode_extra(text: &str) -> BTreeMap<String, String> {
+    let mut extra = BTreeMap::new();
+    for item in text.split('\0') {
+        let item = unescape(item);
+        if let Some(i) = item.find(':') {
+            extra.insert(String::from(&item[..i]), String::from(&item[i + 1..]));
+        }
+    }
+    extra
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+pub struct Changelog {
+    revlog: Revlog,
+}
+
+impl Changelog {
+    pub fn new(revlog: Revlog) -> Changelog {
+        Changelog { revlog: revlog }
+    }
+
+    pub fn revlog(&self) -> &Revlog {
+        &self.revlog
+    }
+
+    pub fn len(&self) -> i32 {
+        self.revlog.len() as i32
+    }
+
+    /// The parent revs of a changeset, -1 standing for none.
+    pub fn parents(&self, rev: i32) -> Result<(i32, i32)> {
+        if rev == -1 {
+            return Ok((-1, -1));
+        }
+        let entry = try!(self.revlog.index(rev));
+        Ok((entry.chunk.parent_1(), entry.chunk.parent_2()))
+    }
+
+    pub fn changeset(&self, rev: i32) -> Result<Changeset> {
+        if rev == -1 {
+            return Ok(Changeset::null());
+        }
+        let entry = try!(self.revlog.index(rev));
+        Changeset::parse(&try!(entry.text()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Changeset;
+
+    #[test]
+    fn test_parse() {
+        let text = b"0123456789abcdef0123456789abcdef01234567\n\
+                     Josh <josh@example.com>\n\
+                     1460000000 14400 branch:stable\0close:1\0note:a\\nb\n\
+                     a.txt\n\
+                     dir/b.txt\n\
+                     \n\
+                     Fix things\n\nMore detail";
+        let cs = Changeset::parse(text).unwrap();
+        assert_eq!("Josh <josh@example.com>", cs.user);
+        assert_eq!(1460000000, cs.time);
+        assert_eq!(14400, cs.tz);
+        assert_eq!("stable", cs.branch());
+        assert!(cs.closes_branch());
+        assert_eq!("a\nb", cs.extra["note"]);
+        assert_eq!(vec!["a.txt", "dir/b.txt"], cs.files);
+        assert_eq!("Fix things\n\nMore detail", cs.description);
+    }
+
+    #[test]
+    fn test_default_branch() {
+        let text = b"0123456789abcdef0123456789abcdef01234567\nx\n0 0\n\nempty";
+        let cs = Changeset::parse(text).unwrap();
+        assert_eq!("default", cs.branch());
+        assert!(cs.files.is_empty());
+    }
+}
@@ -0,0 +1,110 @@
+//! `cinnabar annotate [-r REV] [-u] [-n] [-c] [-d] [-f] [-l] FILE...`
+//!
+//! Show, for each line of files as of a changeset, the changeset which
+//! last changed it, like `hg annotate`. Lines keep their origin across
+//! copies and renames. The columns shown are chosen with `-u` (user),
+//! `-n` (revision number, the default), `-c` (changeset), `-d` (date),
+//! `-f` (file name) and `-l` (line number in that changeset).
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use rustc_serialize::hex::ToHex;
+
+use annotate;
+use changelog::Changeset;
+use cmd::{self, Args};
+use date;
+use matcher::Matcher;
+use template;
+use util::Result;
+
+const USAGE: &'static str = "usage: cinnabar annotate [-r REV] [-u] [-n] [-c] [-d] [-f] [-l] FILE...";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args,
+                                &["-u", "--user", "-n", "--number", "-c", "--changeset", "-d", "--date",
+                                  "-f", "--file", "-l", "--line-number"],
+                                &["-r", "--rev"]));
+    expect!(!args.free.is_empty(), "{}", USAGE);
+    let repo = try!(cmd::open_repo(&args));
+    let rev = try!(repo.lookup(args.value(&["-r", "--rev"]).unwrap_or(".")));
+    let manifest = try!(repo.manifest(rev));
+
+    let user = args.flag(&["-u", "--user"]);
+    let changeset = args.flag(&["-c", "--changeset"]);
+    let show_date = args.flag(&["-d", "--date"]);
+    let file = args.flag(&["-f", "--file"]);
+    let number = args.flag(&["-n", "--number"]) || !(user || changeset || show_date || file);
+    let line_number = args.flag(&["-l", "--line-number"]);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut changesets: HashMap<i32, Changeset> = HashMap::new();
+    let mut missing = vec![];
+    for pattern in try!(cmd::file_patterns(&repo, &args.free)) {
+        let matcher = try!(Matcher::new(&[pattern.clone()]));
+        let mut found = false;
+        for &(ref path, ref entry) in manifest.iter().filter(|&&(ref path, _)| matcher.matches(path)) {
+            found = true;
+            let filelog = try!(repo.filelog(path));
+            let filerev = match try!(filelog.revlog().rev(&entry.node)) {
+                Some(filerev) => filerev,
+                None => return Err(From::from(format!("{}: filelog node not found", path))),
+            };
+            if try!(filelog.content(filerev)).contains(&0) {
+                try!(writeln!(out, "{}: binary file", path));
+                continue;
+            }
+            let lines = try!(annotate::annotate(&repo, path, filerev, rev));
+
+            // Each column, right-aligned to its widest value
+            let mut columns: Vec<Vec<String>> = vec![];
+            for line in &lines {
+                if !changesets.contains_key(&line.rev) {
+                    changesets.insert(line.rev, try!(repo.changelog.changeset(line.rev)));
+                }
+                let cs = &changesets[&line.rev];
+                let mut row = vec![];
+                if user {
+                    row.push(template::user(&cs.user));
+                }
+                if number {
+                    row.push(line.rev.to_string());
+                }
+                if changeset {
+                    row.push(try!(repo.changelog.revlog().node(line.rev)).to_hex()[..12].to_string());
+                }
+                if show_date {
+                    row.push(date::format_date(cs.time, cs.tz));
+                }
+                if file {
+                    row.push(line.path.clone());
+                }
+                columns.push(row);
+            }
+            let widths: Vec<usize> = (0..columns.first().map_or(0, |row| row.len()))
+                .map(|i| columns.iter().map(|row| row[i].len()).max().unwrap_or(0))
+                .collect();
+            for (line, row) in lines.iter().zip(columns) {
+                let fields: Vec<String> = row.iter()
+                    .zip(&widths)
+                    .map(|(field, &width)| format!("{:>1$}", field, width))
+                    .collect();
+                try!(write!(out, "{}", fields.join(" ")));
+                if line_number {
+                    try!(write!(out, ":{}", line.number));
+                }
+                try!(write!(out, ": "));
+                try!(out.write_all(&line.text));
+                if !line.text.ends_with(b"\n") {
+                    try!(writeln!(out, ""));
+                }
+            }
+        }
+        if !found && pattern.is_literal() {
+            missing.push(pattern.text);
+        }
+    }
+    expect!(missing.is_empty(), "no such file in rev {}: {}", rev, missing.join(", "));
+    Ok(())
+}
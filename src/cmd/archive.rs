@@ -0,0 +1,58 @@
+//! `cinnabar archive [-r REV] [-t TYPE] [-p PREFIX] [-I PATTERN]... [-X PATTERN]... DEST`
+//!
+//! Write the files of a changeset, by default the parent of the working
+//! directory, to `DEST`. The type is one of `files`, `tar`, `tgz`,
+//! `uzip` and `zip`, and is otherwise guessed from the extension of
+//! `DEST`. Members of a tarball or zip file are put in a directory
+//! named after `DEST` unless a prefix is given. `-I` and `-X` include
+//! and exclude files by pattern.
+
+use std::path::Path;
+
+use archive::{self, Kind};
+use cmd::{self, Args};
+use matcher::Matcher;
+use util::Result;
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args,
+                                &[],
+                                &["-r", "--rev", "-t", "--type", "-p", "--prefix", "-I", "--include",
+                                  "-X", "--exclude"]));
+    expect!(args.free.len() == 1, "usage: cinnabar archive [-r REV] [-t TYPE] [-p PREFIX] DEST");
+    let repo = try!(cmd::open_repo(&args));
+    let rev = try!(repo.lookup(args.value(&["-r", "--rev"]).unwrap_or(".")));
+    expect!(rev != -1, "no working directory: please specify a revision");
+
+    let dest = &args.free[0];
+    let kind = match args.value(&["-t", "--type"]) {
+        Some(name) => {
+            match Kind::from_name(name) {
+                Some(kind) => kind,
+                None => return Err(From::from(format!("unknown archive type {:?}", name))),
+            }
+        }
+        None => Kind::guess(dest),
+    };
+    let prefix = match args.value(&["-p", "--prefix"]) {
+        Some(prefix) => String::from(prefix),
+        None if kind == Kind::Files => String::new(),
+        None => archive::default_prefix(dest),
+    };
+    let dest = Path::new(dest);
+    expect!(dest.canonicalize().ok().map_or(true, |d| d != repo.root()),
+            "repository root cannot be destination");
+
+    let includes = try!(cmd::file_patterns(&repo, &args.values(&["-I", "--include"])
+        .iter()
+        .map(|s| String::from(*s))
+        .collect::<Vec<_>>()));
+    let excludes = try!(cmd::file_patterns(&repo, &args.values(&["-X", "--exclude"])
+        .iter()
+        .map(|s| String::from(*s))
+        .collect::<Vec<_>>()));
+    let include = try!(Matcher::new(&includes));
+    let exclude = try!(Matcher::new(&excludes));
+    let wanted = |path: &str| (includes.is_empty() || include.matches(path)) && !exclude.matches(path);
+    archive::archive(&repo, rev, kind, dest, &prefix, &wanted)
+}
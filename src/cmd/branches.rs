@@ -0,0 +1,53 @@
+//! `cinnabar branches [-c]`
+//!
+//! List named branches, most recently changed first, like `hg branches`.
+//! A branch is inactive when none of its open heads is a head of the
+//! repo, and closed branches are only listed with `-c`.
+
+use std::io::{self, Write};
+use rustc_serialize::hex::ToHex;
+
+use branchmap::BranchMap;
+use cmd::{self, Args};
+use util::Result;
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &["-c", "--closed"], &[]));
+    let repo = try!(cmd::open_repo(&args));
+    let map = try!(BranchMap::read(&repo));
+    let repo_heads = try!(repo.heads());
+
+    let mut branches = vec![];
+    for name in map.branches() {
+        let closed = map.is_closed(name);
+        if closed && !args.flag(&["-c", "--closed"]) {
+            continue;
+        }
+        let active = map.heads(name).unwrap().iter().any(|h| !h.closed && repo_heads.contains(&h.rev));
+        branches.push((active, map.tip(name).unwrap(), name, closed));
+    }
+    // Active branches first, then by tip
+    branches.sort_by(|a, b| (b.0, b.1).cmp(&(a.0, a.1)));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for (active, tip, name, closed) in branches {
+        let rev = tip.to_string();
+        let pad = 31usize.saturating_sub(rev.len() + name.chars().count());
+        let notice = if closed {
+            " (closed)"
+        } else if !active {
+            " (inactive)"
+        } else {
+            ""
+        };
+        try!(writeln!(out,
+                      "{}{} {}:{}{}",
+                      name,
+                      " ".repeat(pad),
+                      rev,
+                      &try!(repo.changelog.revlog().node(tip)).to_hex()[..12],
+                      notice));
+    }
+    Ok(())
+}
@@ -0,0 +1,222 @@
+//! `cinnabar bundle inspect [-a] FILE`
+//!
+//! `cinnabar bundle create [-t TYPE] (--base REVSET... | -a) [-r REVSET]... FILE`
+//!
+//! `cinnabar bundle clone FILE DEST`
+//!
+//! `inspect` describes a bundle file without applying it, like `hg debugbundle`:
+//! its format, then each part with its parameters and what's in it.
+//! Changegroups list their changesets, or with `-a` every revision as
+//!
+//! ```text
+//! node p1 p2 linknode deltabase len(delta)
+//! ```
+//!
+//! under a line naming its revlog.
+//!
+//! `create` writes a bundle of the ancestors of the `-r` changesets,
+//! by default the heads, which aren't ancestors of the `--base` ones,
+//! for a repo which has those; with `-a`, of every changeset. Secret
+//! changesets are left out. The type is one of those `hg bundle` takes,
+//! such as `bzip2-v2` (the default), `gzip-v1` or `none-v3`. With
+//! `-a -t 'none-v2;stream=v2'`, the store's files are written as they
+//! are instead, for a stream clone.
+//!
+//! `clone` creates a repo, without a working directory, from such a
+//! stream bundle, and checks the integrity of every revision.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use rustc_serialize::hex::ToHex;
+
+use ancestor;
+use cmd::{self, Args};
+use date;
+use exchange::bundle::{self, Bundle, Part, Spec};
+use exchange::changegroup::{Changegroup, Kind};
+use exchange::streamclone;
+use graph::Graph;
+use phases::{Phase, Phases};
+use revset;
+use util::Result;
+
+const USAGE: &'static str = "usage: cinnabar bundle inspect [-a] FILE\n       cinnabar bundle create [-t TYPE] \
+                             (--base REVSET... | -a) [-r REVSET]... FILE\n       cinnabar bundle clone FILE DEST";
+
+pub fn run(args: &[String]) -> Result<()> {
+    match args.first().map(|s| &s[..]) {
+        Some("inspect") => inspect(&args[1..]),
+        Some("create") => create(&args[1..]),
+        Some("clone") => clone(&args[1..]),
+        _ => Err(From::from(USAGE)),
+    }
+}
+
+fn inspect(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &["-a", "--all"], &[]));
+    expect!(args.free.len() == 1, "{}", USAGE);
+    let all = args.flag(&["-a", "--all"]);
+    let mut bundle = try!(Bundle::open(Path::new(&args.free[0])));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    try!(writeln!(out, "format: {}", bundle.format));
+    if !bundle.params.is_empty() {
+        try!(writeln!(out, "Stream params: {}", format_params(&bundle.params)));
+    }
+    while let Some(mut part) = try!(bundle.next_header()) {
+        let mut params = part.mandatory_params.clone();
+        params.extend(part.advisory_params.iter().cloned());
+        params.sort();
+        try!(writeln!(out,
+                      "{} -- {} (mandatory: {})",
+                      part.name,
+                      format_params(&params),
+                      if part.mandatory { "True" } else { "False" }));
+        if part.name == "changegroup" {
+            try!(inspect_changegroup(&mut out, try!(bundle.changegroup(&part)), all));
+        } else {
+            try!(bundle.read_payload(&mut part));
+            try!(inspect_part(&mut out, &part));
+        }
+    }
+    Ok(())
+}
+
+fn create(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &["-a", "--all"], &["-t", "--type", "--base", "-r", "--rev"]));
+    expect!(args.free.len() == 1, "{}", USAGE);
+    let all = args.flag(&["-a", "--all"]);
+    let bases = args.values(&["--base"]);
+    expect!(all != !bases.is_empty(), "either --base or --all is needed\n{}", USAGE);
+    let spec = try!(Spec::parse(args.value(&["-t", "--type"]).unwrap_or("bzip2-v2")));
+    let repo = try!(cmd::open_repo(&args));
+    if spec.stream {
+        expect!(all && args.values(&["-r", "--rev"]).is_empty(), "stream bundles are of the whole repo (-a)");
+        let mut out = io::BufWriter::new(try!(File::create(&args.free[0])));
+        let (files, size) = try!(streamclone::write(&repo, &mut out));
+        try!(out.flush());
+        let stdout = io::stdout();
+        try!(writeln!(stdout.lock(), "{} files, {} bytes of data", files, size));
+        return Ok(());
+    }
+
+    let revs = |specs: Vec<&str>| -> Result<Vec<i32>> {
+        let mut result = vec![];
+        for spec in specs {
+            result.extend(try!(revset::revs(&repo, spec)));
+        }
+        Ok(result)
+    };
+    let mut heads = try!(revs(args.values(&["-r", "--rev"])));
+    if heads.is_empty() {
+        heads = try!(repo.heads());
+    }
+    let graph = Graph::new(repo.changelog.revlog());
+    let mut common = HashSet::new();
+    for rev in try!(ancestor::ancestors(&graph, &try!(revs(bases)), true)) {
+        common.insert(try!(rev));
+    }
+    let hidden = try!(repo.hidden());
+    let phases = try!(Phases::read(&repo));
+    let mut missing = vec![];
+    for rev in try!(ancestor::ancestors(&graph, &heads, true)) {
+        let rev = try!(rev);
+        if !common.contains(&rev) && !hidden.contains(&rev) && phases.phase(rev) < Phase::Secret {
+            missing.push(rev);
+        }
+    }
+    expect!(!missing.is_empty(), "no changes found");
+    missing.sort();
+
+    try!(bundle::create(Path::new(&args.free[0]), &repo, &missing, spec));
+    let stdout = io::stdout();
+    try!(writeln!(stdout.lock(), "{} changesets found", missing.len()));
+    Ok(())
+}
+
+fn clone(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &[], &[]));
+    expect!(args.free.len() == 2, "{}", USAGE);
+    let revisions = try!(streamclone::clone(Path::new(&args.free[0]), Path::new(&args.free[1])));
+    let stdout = io::stdout();
+    try!(writeln!(stdout.lock(), "checked {} revisions", revisions));
+    Ok(())
+}
+
+fn inspect_changegroup<R: Read>(out: &mut Write, chunks: Changegroup<R>, all: bool) -> Result<()> {
+    if all {
+        try!(writeln!(out, "    format: id, p1, p2, cset, delta base, len(delta)"));
+    }
+    let mut group = None;
+    for chunk in chunks {
+        let chunk = try!(chunk);
+        if !all {
+            if chunk.kind == Kind::Changelog {
+                try!(writeln!(out, "    {}", chunk.node.to_hex()));
+            }
+            continue;
+        }
+        let name = match chunk.kind {
+            Kind::Changelog => String::from("changelog"),
+            Kind::Manifest if chunk.path.is_empty() => String::from("manifest"),
+            Kind::Manifest => format!("manifest {}", chunk.path),
+            Kind::File => chunk.path.clone(),
+        };
+        if group.as_ref() != Some(&name) {
+            try!(writeln!(out, "\n    {}", name));
+            group = Some(name);
+        }
+        try!(writeln!(out,
+                      "    {} {} {} {} {} {}",
+                      chunk.node.to_hex(),
+                      chunk.p1.to_hex(),
+                      chunk.p2.to_hex(),
+                      chunk.linknode.to_hex(),
+                      chunk.base.to_hex(),
+                      chunk.delta.len()));
+    }
+    Ok(())
+}
+
+fn inspect_part(out: &mut Write, part: &Part) -> Result<()> {
+    match &part.name[..] {
+        "phase-heads" => {
+            for (phase, node) in try!(part.phase_heads()) {
+                try!(writeln!(out, "    {} {}", node.to_hex(), phase.name()));
+            }
+        }
+        "bookmarks" => {
+            for (name, node) in try!(part.bookmarks()) {
+                try!(writeln!(out, "    {} {}", name, node.to_hex()));
+            }
+        }
+        "obsmarkers" => {
+            if !part.data.is_empty() {
+                try!(writeln!(out, "    version: {} ({} bytes)", part.data[0], part.data.len()));
+            }
+            for marker in try!(part.obsmarkers()) {
+                let successors: Vec<String> = marker.successors.iter().map(|s| s.to_hex()).collect();
+                let metadata: Vec<String> = marker.metadata
+                    .iter()
+                    .map(|&(ref k, ref v)| format!("{}: {}", k, v))
+                    .collect();
+                try!(writeln!(out,
+                              "    {} {{{}}} ({}) {{{}}}",
+                              marker.precursor.to_hex(),
+                              successors.join(" "),
+                              date::format_date(marker.date.0 as i64, marker.date.1),
+                              metadata.join(", ")));
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn format_params(params: &[(String, String)]) -> String {
+    let params: Vec<String> = params.iter().map(|&(ref k, ref v)| format!("{}: {}", k, v)).collect();
+    format!("{{{}}}", params.join(", "))
+}
@@ -0,0 +1,44 @@
+//! `cinnabar cat [-r REV] [--bundle FILE] FILE...`
+//!
+//! Print the content of files as of a changeset, which defaults to the
+//! parent of the working directory. Files may also be directories or
+//! patterns, such as `glob:*.txt`, and every file they match is printed
+//! in path order. With `--bundle`, the changeset may be one of a bundle
+//! which hasn't been applied.
+
+use std::io::{self, Write};
+
+use cmd::{self, Args};
+use matcher::Matcher;
+use util::Result;
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &[], &["-r", "--rev", "--bundle"]));
+    expect!(!args.free.is_empty(), "usage: cinnabar cat [-r REV] [--bundle FILE] FILE...");
+    let repo = try!(cmd::open_repo(&args));
+    let rev = try!(repo.lookup(args.value(&["-r", "--rev"]).unwrap_or(".")));
+    let manifest = try!(repo.manifest(rev));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut missing = vec![];
+    for pattern in try!(cmd::file_patterns(&repo, &args.free)) {
+        let matcher = try!(Matcher::new(&[pattern.clone()]));
+        let mut found = false;
+        for &(ref path, ref entry) in manifest.iter().filter(|&&(ref path, _)| matcher.matches(path)) {
+            found = true;
+            let filelog = try!(repo.filelog(path));
+            match try!(filelog.revlog().rev(&entry.node)) {
+                Some(filerev) => try!(out.write_all(&try!(filelog.content(filerev)))),
+                None => return Err(From::from(format!("{}: filelog node not found", path))),
+            }
+        }
+        // Like Mercurial, a pattern matching nothing is only an error if
+        // it names a file
+        if !found && pattern.is_literal() {
+            missing.push(pattern.text);
+        }
+    }
+    expect!(missing.is_empty(), "no such file in rev {}: {}", rev, missing.join(", "));
+    Ok(())
+}
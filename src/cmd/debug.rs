@@ -0,0 +1,430 @@
+//! `cinnabar debug index|data|chain|revlog|dirstate`
+//!
+//! Inspect a single revlog, given as the path to its `.i` file, or with
+//! `-c` or `-m` the changelog or manifest of the current repository.
+//! Every subcommand accepts `--json`.
+//!
+//! - `debug index FILE [--verify]` lists the index entries
+//! - `debug data FILE REV` prints the full text of a rev
+//! - `debug chain FILE REV` shows the delta chain needed to build a rev
+//! - `debug revlog FILE` reports statistics about delta efficiency
+//! - `debug dirstate [--all]` lists the dirstate of the current repository,
+//!   and with `--all` the directories with cached mtimes
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+
+use cmd::{self, Args};
+use date;
+use dirstate::{Dirstate, MTIME_UNSET};
+use revlog::{Revlog, RevlogEntry};
+use stats::{RevlogStats, Summary};
+use util::Result;
+
+const FLAGS: &'static [&'static str] = &["-c", "--changelog", "-m", "--manifest", "--json",
+                                         "--verify"];
+
+/// Format a dirstate mtime like `hg debugdirstate`, in UTC.
+fn format_mtime(mtime: i32) -> String {
+    if mtime == MTIME_UNSET {
+        return String::from("unset");
+    }
+    let tm = date::to_tm(mtime as i64, 0);
+    format!("{}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.year,
+            tm.month,
+            tm.day,
+            tm.hour,
+            tm.minute,
+            tm.second)
+}
+
+pub fn run(args: &[String]) -> Result<()> {
+    match args.first().map(|s| &s[..]) {
+        Some("index") => index(&args[1..]),
+        Some("data") => data(&args[1..]),
+        Some("chain") => chain(&args[1..]),
+        Some("revlog") => revlog_stats(&args[1..]),
+        Some("dirstate") => dirstate(&args[1..]),
+        _ => Err(From::from("usage: cinnabar debug index|data|chain|revlog|dirstate [-c|-m|FILE] ...")),
+    }
+}
+
+/// Open the revlog named by the arguments, and return it along with the
+/// remaining positional arguments.
+fn open_revlog(args: &Args) -> Result<(Revlog, Vec<String>)> {
+    let name = if args.flag(&["-c", "--changelog"]) {
+        Some("00changelog")
+    } else if args.flag(&["-m", "--manifest"]) {
+        Some("00manifest")
+    } else {
+        None
+    };
+    match name {
+        Some(name) => {
+            let repo = try!(cmd::open_repo(args));
+            Ok((try!(repo.store().revlog(name)), args.free.clone()))
+        }
+        None => {
+            expect!(!args.free.is_empty(), "a revlog file or -c or -m is required");
+            let revlog = try!(Revlog::open(&args.free[0]));
+            Ok((revlog, args.free[1..].to_vec()))
+        }
+    }
+}
+
+/// A rev number, counting back from the end if negative, or a node id
+/// prefix.
+fn lookup_rev(revlog: &Revlog, spec: &str) -> Result<i32> {
+    let len = revlog.len() as i32;
+    if let Ok(rev) = spec.parse::<i32>() {
+        let rev = if rev < 0 { rev + len } else { rev };
+        expect!(rev >= 0 && rev < len, "rev {} out of range", spec);
+        return Ok(rev);
+    }
+    match try!(revlog.lookup_prefix(spec)) {
+        Some(rev) if rev >= 0 => Ok(rev),
+        _ => Err(From::from(format!("unknown revision {:?}", spec))),
+    }
+}
+
+fn index(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, FLAGS, &[]));
+    let (revlog, _) = try!(open_revlog(&args));
+    let verify = args.flag(&["--verify"]);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    if args.flag(&["--json"]) {
+        let mut entries = vec![];
+        for entry in revlog.iter() {
+            let entry = try!(entry);
+            let mut obj = try!(entry_json(&entry));
+            if verify {
+                obj.insert(String::from("verified"), Json::Boolean(try!(entry.verify())));
+            }
+            entries.push(Json::Object(obj));
+        }
+        try!(writeln!(out, "{}", Json::Array(entries).pretty()));
+        return Ok(());
+    }
+
+    try!(writeln!(out,
+                  "   rev    offset  length  {} linkrev nodeid       p1           p2{}",
+                  if revlog.generaldelta { "delta" } else { " base" },
+                  if verify { "           hash" } else { "" }));
+    let mut good = 0;
+    let mut bad = 0;
+    for entry in revlog.iter() {
+        let entry = try!(entry);
+        let p1 = try!(entry.parent_1_id());
+        let p2 = try!(entry.parent_2_id());
+        try!(write!(out,
+                    "{:6} {:9} {:7} {:6} {:7} {} {} {}",
+                    entry.revno,
+                    entry.offset(),
+                    entry.chunk.comp_len(),
+                    entry.base_rev(),
+                    entry.chunk.link_rev(),
+                    &entry.chunk.c_node_id().to_hex()[..12],
+                    &p1.to_hex()[..12],
+                    &p2.to_hex()[..12]));
+        if verify {
+            if try!(entry.verify()) {
+                good += 1;
+                try!(write!(out, " ok"));
+            } else {
+                bad += 1;
+                try!(write!(out, " BAD"));
+            }
+        }
+        try!(writeln!(out, ""));
+    }
+    if verify {
+        try!(writeln!(out, "{} hashes verified", good));
+        try!(writeln!(out, "{} hashes failed", bad));
+        expect!(bad == 0, "{} revisions failed verification", bad);
+    }
+    Ok(())
+}
+
+fn entry_json(entry: &RevlogEntry) -> Result<BTreeMap<String, Json>> {
+    let mut obj = BTreeMap::new();
+    obj.insert(String::from("rev"), Json::I64(entry.revno as i64));
+    obj.insert(String::from("offset"), Json::U64(entry.offset()));
+    obj.insert(String::from("length"), Json::I64(entry.chunk.comp_len() as i64));
+    obj.insert(String::from("size"), Json::I64(entry.chunk.uncomp_len() as i64));
+    obj.insert(String::from("base"), Json::I64(entry.base_rev() as i64));
+    obj.insert(String::from("delta_parent"), Json::I64(entry.delta_parent() as i64));
+    obj.insert(String::from("linkrev"), Json::I64(entry.chunk.link_rev() as i64));
+    obj.insert(String::from("node"), Json::String(entry.chunk.c_node_id().to_hex()));
+    obj.insert(String::from("p1"), Json::String(try!(entry.parent_1_id()).to_hex()));
+    obj.insert(String::from("p2"), Json::String(try!(entry.parent_2_id()).to_hex()));
+    Ok(obj)
+}
+
+fn data(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, FLAGS, &[]));
+    let (revlog, rest) = try!(open_revlog(&args));
+    expect!(rest.len() == 1, "usage: cinnabar debug data [-c|-m|FILE] REV");
+    let entry = try!(revlog.index(try!(lookup_rev(&revlog, &rest[0]))));
+    let text = try!(entry.text());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if args.flag(&["--json"]) {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("rev"), Json::I64(entry.revno as i64));
+        obj.insert(String::from("node"), Json::String(entry.chunk.c_node_id().to_hex()));
+        obj.insert(String::from("size"), Json::U64(text.len() as u64));
+        obj.insert(String::from("text"),
+                   Json::String(String::from_utf8_lossy(&text).into_owned()));
+        try!(writeln!(out, "{}", Json::Object(obj).pretty()));
+    } else {
+        try!(out.write_all(&text));
+    }
+    Ok(())
+}
+
+fn chain(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, FLAGS, &[]));
+    let (revlog, rest) = try!(open_revlog(&args));
+    expect!(rest.len() == 1, "usage: cinnabar debug chain [-c|-m|FILE] REV");
+    let entry = try!(revlog.index(try!(lookup_rev(&revlog, &rest[0]))));
+
+    // (rev, delta parent, compressed size, uncompressed size)
+    let mut links = vec![];
+    for link in entry.delta_chain() {
+        let link = try!(link);
+        links.push((link.revno,
+                    link.delta_parent(),
+                    link.chunk.comp_len() as u64,
+                    link.data().len() as u64));
+    }
+    let compressed: u64 = links.iter().map(|l| l.2).sum();
+    let uncompressed: u64 = links.iter().map(|l| l.3).sum();
+    let text_size = try!(entry.text()).len() as u64;
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if args.flag(&["--json"]) {
+        let mut items = vec![];
+        for &(rev, parent, comp, uncomp) in &links {
+            let mut obj = BTreeMap::new();
+            obj.insert(String::from("rev"), Json::I64(rev as i64));
+            obj.insert(String::from("delta_parent"), Json::I64(parent as i64));
+            obj.insert(String::from("compressed"), Json::U64(comp));
+            obj.insert(String::from("uncompressed"), Json::U64(uncomp));
+            items.push(Json::Object(obj));
+        }
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("rev"), Json::I64(entry.revno as i64));
+        obj.insert(String::from("chain"), Json::Array(items));
+        obj.insert(String::from("chain_length"), Json::U64(links.len() as u64));
+        obj.insert(String::from("compressed_size"), Json::U64(compressed));
+        obj.insert(String::from("uncompressed_size"), Json::U64(uncompressed));
+        obj.insert(String::from("text_size"), Json::U64(text_size));
+        try!(writeln!(out, "{}", Json::Object(obj).pretty()));
+        return Ok(());
+    }
+
+    try!(writeln!(out, "   rev  delta compressed uncompressed"));
+    for &(rev, parent, comp, uncomp) in &links {
+        try!(writeln!(out, "{:6} {:6} {:10} {:12}", rev, parent, comp, uncomp));
+    }
+    try!(writeln!(out, "chain length:      {}", links.len()));
+    try!(writeln!(out, "compressed size:   {}", compressed));
+    try!(writeln!(out, "uncompressed size: {}", uncompressed));
+    try!(writeln!(out, "full text size:    {}", text_size));
+    if text_size > 0 {
+        try!(writeln!(out, "ratio:             {:.2}", compressed as f64 / text_size as f64));
+    }
+    Ok(())
+}
+
+fn revlog_stats(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, FLAGS, &[]));
+    let (revlog, _) = try!(open_revlog(&args));
+    let stats = try!(RevlogStats::compute(&revlog));
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if args.flag(&["--json"]) {
+        try!(writeln!(out, "{}", stats_json(&stats).pretty()));
+        return Ok(());
+    }
+
+    let percent = |n: u64| {
+        if stats.revs == 0 {
+            0.0
+        } else {
+            100.0 * n as f64 / stats.revs as f64
+        }
+    };
+    let mut format = vec![];
+    if stats.inline {
+        format.push("inline");
+    }
+    if stats.generaldelta {
+        format.push("generaldelta");
+    }
+    try!(writeln!(out, "format:         revlogv1 ({})", format.join(", ")));
+    try!(writeln!(out, "revisions:      {}", stats.revs));
+    try!(writeln!(out, "    merges:     {:8} ({:.2}%)", stats.merges, percent(stats.merges)));
+    try!(writeln!(out, "    empty:      {:8} ({:.2}%)", stats.empty, percent(stats.empty)));
+    try!(writeln!(out, "    full:       {:8} ({:.2}%)", stats.full.count, percent(stats.full.count)));
+    try!(writeln!(out, "    deltas:     {:8} ({:.2}%)", stats.deltas.count, percent(stats.deltas.count)));
+    try!(writeln!(out, "stored size:    {}", stats.stored_size));
+    try!(writeln!(out, "full text size: {}", stats.text_size));
+    try!(writeln!(out, "ratio:          {:.2}", stats.compression_ratio()));
+    try!(writeln!(out, ""));
+    try!(writeln!(out, "                     min        max        avg"));
+    for &(name, summary) in &[("full size:", stats.full),
+                              ("delta size:", stats.deltas),
+                              ("chain length:", stats.chain_length),
+                              ("chain span:", stats.chain_span)] {
+        try!(writeln!(out,
+                      "{:14} {:10} {:10} {:10.1}",
+                      name,
+                      summary.min,
+                      summary.max,
+                      summary.average()));
+    }
+    try!(writeln!(out, ""));
+    try!(writeln!(out, "deltas against:"));
+    let bases = stats.delta_bases;
+    for &(name, n) in &[("p1", bases.p1), ("p2", bases.p2), ("prev", bases.prev), ("other", bases.other)] {
+        let share = if stats.deltas.count == 0 {
+            0.0
+        } else {
+            100.0 * n as f64 / stats.deltas.count as f64
+        };
+        try!(writeln!(out, "    {:6}      {:8} ({:.2}%)", name, n, share));
+    }
+    try!(writeln!(out, ""));
+    try!(writeln!(out, "compression:        revs     stored    uncompressed"));
+    for (name, engine) in &stats.engines {
+        let uncompressed = match engine.uncompressed {
+            Some(n) => n.to_string(),
+            None => String::from("?"),
+        };
+        try!(writeln!(out,
+                      "    {:10} {:8} {:10} {:>15}",
+                      name,
+                      engine.revs,
+                      engine.compressed,
+                      uncompressed));
+    }
+    Ok(())
+}
+
+/// Like `hg debugdirstate`, with times in UTC.
+fn dirstate(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &["--json", "-a", "--all"], &[]));
+    expect!(args.free.is_empty(), "usage: cinnabar debug dirstate [--all] [--json]");
+    let repo = try!(cmd::open_repo(&args));
+    let dirstate = try!(Dirstate::read(&repo));
+    let all = args.flag(&["-a", "--all"]);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    if args.flag(&["--json"]) {
+        let mut files = BTreeMap::new();
+        for (path, entry) in dirstate.iter() {
+            let mut obj = BTreeMap::new();
+            obj.insert(String::from("state"), Json::String(entry.state.as_char().to_string()));
+            obj.insert(String::from("mode"), Json::I64(entry.mode as i64));
+            obj.insert(String::from("size"), Json::I64(entry.size as i64));
+            obj.insert(String::from("mtime"), Json::I64(entry.mtime as i64));
+            if let Some(source) = dirstate.copy_source(path) {
+                obj.insert(String::from("copy"), Json::String(String::from(source)));
+            }
+            files.insert(path.clone(), Json::Object(obj));
+        }
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("p1"), Json::String(dirstate.p1.to_hex()));
+        obj.insert(String::from("p2"), Json::String(dirstate.p2.to_hex()));
+        obj.insert(String::from("files"), Json::Object(files));
+        if all {
+            let mut dirs = BTreeMap::new();
+            for (path, mtime) in dirstate.directory_mtimes() {
+                let mut dir = BTreeMap::new();
+                dir.insert(String::from("mtime"), Json::U64(mtime.seconds as u64));
+                dir.insert(String::from("nanoseconds"), Json::U64(mtime.nanoseconds as u64));
+                dirs.insert(path.clone(), Json::Object(dir));
+            }
+            obj.insert(String::from("directories"), Json::Object(dirs));
+        }
+        try!(writeln!(out, "{}", Json::Object(obj).pretty()));
+        return Ok(());
+    }
+
+    // Directories are listed among the files, as `d` entries
+    let mut lines = vec![];
+    for (path, entry) in dirstate.iter() {
+        let mode = if entry.mode & 0o170000 == 0o120000 {
+            String::from("lnk")
+        } else {
+            format!("{:3o}", entry.mode & 0o777)
+        };
+        lines.push((path, entry.state.as_char(), mode, entry.size, format_mtime(entry.mtime)));
+    }
+    if all {
+        for (path, mtime) in dirstate.directory_mtimes() {
+            lines.push((path, 'd', String::from("  0"), 0, format_mtime(mtime.seconds as i32)));
+        }
+        lines.sort();
+    }
+    for (path, state, mode, size, time) in lines {
+        try!(writeln!(out, "{} {} {:10} {:<19} {}", state, mode, size, time, path));
+    }
+    for (dest, source) in dirstate.copies() {
+        try!(writeln!(out, "copy: {} -> {}", source, dest));
+    }
+    Ok(())
+}
+
+fn stats_json(stats: &RevlogStats) -> Json {
+    fn summary(s: &Summary) -> Json {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("count"), Json::U64(s.count));
+        obj.insert(String::from("total"), Json::U64(s.total));
+        obj.insert(String::from("min"), Json::U64(s.min));
+        obj.insert(String::from("max"), Json::U64(s.max));
+        obj.insert(String::from("average"), Json::F64(s.average()));
+        Json::Object(obj)
+    }
+    let mut bases = BTreeMap::new();
+    bases.insert(String::from("p1"), Json::U64(stats.delta_bases.p1));
+    bases.insert(String::from("p2"), Json::U64(stats.delta_bases.p2));
+    bases.insert(String::from("prev"), Json::U64(stats.delta_bases.prev));
+    bases.insert(String::from("other"), Json::U64(stats.delta_bases.other));
+    let mut engines = BTreeMap::new();
+    for (name, engine) in &stats.engines {
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("revs"), Json::U64(engine.revs));
+        obj.insert(String::from("compressed"), Json::U64(engine.compressed));
+        obj.insert(String::from("uncompressed"),
+                   engine.uncompressed.map_or(Json::Null, Json::U64));
+        engines.insert(String::from(*name), Json::Object(obj));
+    }
+
+    let mut obj = BTreeMap::new();
+    obj.insert(String::from("revs"), Json::U64(stats.revs));
+    obj.insert(String::from("merges"), Json::U64(stats.merges));
+    obj.insert(String::from("inline"), Json::Boolean(stats.inline));
+    obj.insert(String::from("generaldelta"), Json::Boolean(stats.generaldelta));
+    obj.insert(String::from("empty"), Json::U64(stats.empty));
+    obj.insert(String::from("full"), summary(&stats.full));
+    obj.insert(String::from("deltas"), summary(&stats.deltas));
+    obj.insert(String::from("chain_length"), summary(&stats.chain_length));
+    obj.insert(String::from("chain_span"), summary(&stats.chain_span));
+    obj.insert(String::from("stored_size"), Json::U64(stats.stored_size));
+    obj.insert(String::from("text_size"), Json::U64(stats.text_size));
+    obj.insert(String::from("compression_ratio"), Json::F64(stats.compression_ratio()));
+    obj.insert(String::from("delta_bases"), Json::Object(bases));
+    obj.insert(String::from("engines"), Json::Object(engines));
+    Json::Object(obj)
+}
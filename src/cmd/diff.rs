@@ -0,0 +1,194 @@
+//! `cinnabar diff -r REV1 -r REV2 [-U NUM] [--bundle FILE] [FILE]...`
+//!
+//! Print the changes between two changesets as a git-style unified
+//! diff, like `hg diff --git`:
+//!
+//! ```text
+//! diff --git a/old.txt b/new.txt
+//! rename from old.txt
+//! rename to new.txt
+//! --- a/old.txt
+//! +++ b/new.txt
+//! @@ -1,2 +1,2 @@
+//! ```
+//!
+//! Files added in the second changeset which were copied or renamed
+//! from a file of the first, as recorded in their filelogs, are shown
+//! as such, and changes to the executable and symlink flags as mode
+//! changes. Binary files, which contain a NUL, are only reported as
+//! changed. With files, only the changes to those are shown. As in
+//! Mercurial, a file whose content and flags are the same, though its
+//! filelog revision isn't, is left out. With `--bundle`, changesets of
+//! a bundle which hasn't been applied can be compared.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use cmd::{self, Args};
+use diff;
+use manifest::{Flags, Manifest, ManifestEntry};
+use matcher::Matcher;
+use repo::Repo;
+use util::Result;
+
+const USAGE: &'static str = "usage: cinnabar diff -r REV1 -r REV2 [-U NUM] [--bundle FILE] [FILE]...";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &[], &["-r", "--rev", "-U", "--unified", "--bundle"]));
+    let revs = args.values(&["-r", "--rev"]);
+    expect!(revs.len() == 2, "{}", USAGE);
+    let context = match args.value(&["-U", "--unified"]) {
+        Some(n) => {
+            match n.parse() {
+                Ok(n) => n,
+                Err(_) => return Err(From::from(format!("invalid context size {:?}", n))),
+            }
+        }
+        None => 3,
+    };
+    let repo = try!(cmd::open_repo(&args));
+    let (rev1, rev2) = (try!(repo.lookup(revs[0])), try!(repo.lookup(revs[1])));
+    let matcher = if args.free.is_empty() {
+        None
+    } else {
+        Some(try!(Matcher::new(&try!(cmd::file_patterns(&repo, &args.free)))))
+    };
+    let wanted = |path: &str| matcher.as_ref().map_or(true, |m| m.matches(path));
+
+    let old = try!(repo.manifest(rev1));
+    let new = try!(repo.manifest(rev2));
+    let copies = try!(copies(&repo, rev1, &old, &new));
+
+    let mut paths = BTreeSet::new();
+    for &(ref path, ref entry) in old.iter() {
+        if new.get(path) != Some(entry) {
+            paths.insert(path);
+        }
+    }
+    for &(ref path, ref entry) in new.iter() {
+        if old.get(path) != Some(entry) {
+            paths.insert(path);
+        }
+    }
+
+    // A renamed file is shown at its new name rather than as deleted,
+    // and any further copies of it as copies
+    let mut gone = BTreeSet::new();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in paths {
+        let new_entry = new.get(path);
+        if new_entry.is_none() && copies.values().any(|source| source == path) {
+            continue;
+        }
+        let old_path = copies.get(path).unwrap_or(path);
+        if !wanted(path) && !wanted(old_path) {
+            continue;
+        }
+        let old_entry = old.get(old_path);
+        let new_path = path;
+
+        // The header is only shown with something after its first line
+        let mut header = vec![format!("diff --git a/{} b/{}", old_path, new_path)];
+        match (old_entry, new_entry) {
+            (None, Some(entry)) => header.push(format!("new file mode {}", git_mode(entry.flags))),
+            (Some(entry), None) => header.push(format!("deleted file mode {}", git_mode(entry.flags))),
+            (Some(old_entry), Some(new_entry)) => {
+                if old_entry.flags != new_entry.flags {
+                    header.push(format!("old mode {}", git_mode(old_entry.flags)));
+                    header.push(format!("new mode {}", git_mode(new_entry.flags)));
+                }
+                if old_path != new_path {
+                    let op = if new.get(old_path).is_none() && gone.insert(old_path) {
+                        "rename"
+                    } else {
+                        "copy"
+                    };
+                    header.push(format!("{} from {}", op, old_path));
+                    header.push(format!("{} to {}", op, new_path));
+                }
+            }
+            (None, None) => unreachable!(),
+        }
+
+        let old_text = match old_entry {
+            Some(entry) => try!(content(&repo, old_path, entry)),
+            None => vec![],
+        };
+        let new_text = match new_entry {
+            Some(entry) => try!(content(&repo, new_path, entry)),
+            None => vec![],
+        };
+        if old_text == new_text {
+            if header.len() > 1 {
+                try!(writeln!(out, "{}", header.join("\n")));
+            }
+            continue;
+        }
+        try!(writeln!(out, "{}", header.join("\n")));
+        if old_text.contains(&0) || new_text.contains(&0) {
+            try!(writeln!(out, "Binary file {} has changed", new_path));
+            continue;
+        }
+        match old_entry {
+            Some(_) => try!(writeln!(out, "--- a/{}", old_path)),
+            None => try!(writeln!(out, "--- /dev/null")),
+        }
+        match new_entry {
+            Some(_) => try!(writeln!(out, "+++ b/{}", new_path)),
+            None => try!(writeln!(out, "+++ /dev/null")),
+        }
+        try!(out.write_all(&diff::unified(&old_text, &new_text, context)));
+    }
+    Ok(())
+}
+
+/// The mode git shows for a file with these flags.
+fn git_mode(flags: Flags) -> &'static str {
+    match flags {
+        Flags::Regular => "100644",
+        Flags::Executable => "100755",
+        Flags::Symlink => "120000",
+    }
+}
+
+fn content(repo: &Repo, path: &str, entry: &ManifestEntry) -> Result<Vec<u8>> {
+    let filelog = try!(repo.filelog(path));
+    match try!(filelog.revlog().rev(&entry.node)) {
+        Some(filerev) => filelog.content(filerev),
+        None => Err(From::from(format!("{}: filelog node not found", path))),
+    }
+}
+
+/// The files of `new` which aren't in `old`, changeset `old_rev`, but
+/// were copied from a file which is, by destination. A file's history is
+/// followed back to where it was created, which is where Mercurial
+/// records the copy, but not past revisions from `old_rev` or before.
+fn copies(repo: &Repo, old_rev: i32, old: &Manifest, new: &Manifest) -> Result<BTreeMap<String, String>> {
+    let mut result = BTreeMap::new();
+    for &(ref path, ref entry) in new.iter().filter(|&&(ref path, _)| old.get(path).is_none()) {
+        let filelog = try!(repo.filelog(path));
+        let mut filerev = match try!(filelog.revlog().rev(&entry.node)) {
+            Some(filerev) => filerev,
+            None => return Err(From::from(format!("{}: filelog node not found", path))),
+        };
+        loop {
+            let entry = try!(filelog.revlog().index(filerev));
+            if entry.chunk.link_rev() <= old_rev {
+                break;
+            }
+            let p1 = entry.chunk.parent_1();
+            if p1 != -1 {
+                filerev = p1;
+                continue;
+            }
+            if let Some((source, _)) = try!(filelog.renamed(filerev)) {
+                if old.get(&source).is_some() {
+                    result.insert(path.clone(), source);
+                }
+            }
+            break;
+        }
+    }
+    Ok(result)
+}
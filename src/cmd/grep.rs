@@ -0,0 +1,95 @@
+//! `cinnabar grep [-r REVSET]... [--all] [-i] PATTERN [FILE]...`
+//!
+//! Search file contents for lines matching a regular expression, like
+//! `hg grep`, printing each one as `path:rev:line number:line`.
+//!
+//! Files are searched as they are in the given changesets, which
+//! default to the parent of the working directory. With `--all`, every
+//! revision of every file is searched instead, as of the changeset
+//! which introduced it, so that text which has since been removed is
+//! found too; revsets then limit which changesets those are.
+
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use cmd::{self, Args};
+use grep::{self, Revisions};
+use matcher::Matcher;
+use revset;
+use util::Result;
+
+const USAGE: &'static str = "usage: cinnabar grep [-r REVSET]... [--all] [-i] PATTERN [FILE]...";
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args, &["--all", "-i", "--ignore-case"], &["-r", "--rev"]));
+    expect!(!args.free.is_empty(), "{}", USAGE);
+    let repo = try!(cmd::open_repo(&args));
+    let pattern = if args.flag(&["-i", "--ignore-case"]) {
+        format!("(?i){}", args.free[0])
+    } else {
+        args.free[0].clone()
+    };
+    let matcher = if args.free.len() > 1 {
+        Some(try!(Matcher::new(&try!(cmd::file_patterns(&repo, &args.free[1..])))))
+    } else {
+        None
+    };
+    let wanted = |path: &str| matcher.as_ref().map_or(true, |m| m.matches(path));
+
+    let mut specs = args.values(&["-r", "--rev"]);
+    let all = args.flag(&["--all"]);
+    let revs = if specs.is_empty() && all {
+        try!(repo.revs())
+    } else {
+        if specs.is_empty() {
+            specs.push(".");
+        }
+        let mut revs = vec![];
+        for spec in specs {
+            revs.extend(try!(revset::revs(&repo, spec)));
+        }
+        revs
+    };
+
+    let mut files = vec![];
+    if all {
+        // Every revision of a file is introduced by a changeset which
+        // lists the file as changed
+        let mut paths = BTreeSet::new();
+        for &rev in &revs {
+            paths.extend(try!(repo.changeset(rev)).files.into_iter().filter(|p| wanted(p)));
+        }
+        let revs: Arc<HashSet<i32>> = Arc::new(revs.into_iter().collect());
+        for path in paths {
+            files.push((path, Revisions::Linked(revs.clone())));
+        }
+    } else {
+        // Each revision of a file is searched once, however many of the
+        // changesets have it
+        let mut nodes: BTreeMap<String, BTreeMap<Vec<u8>, Vec<i32>>> = BTreeMap::new();
+        for &rev in revs.iter().collect::<BTreeSet<_>>() {
+            for &(ref path, ref entry) in try!(repo.manifest(rev)).iter() {
+                if wanted(path) {
+                    nodes.entry(path.clone())
+                        .or_insert_with(BTreeMap::new)
+                        .entry(entry.node.clone())
+                        .or_insert_with(Vec::new)
+                        .push(rev);
+                }
+            }
+        }
+        for (path, revisions) in nodes {
+            files.push((path, Revisions::Nodes(revisions.into_iter().collect())));
+        }
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for found in try!(grep::grep(repo.store(), &pattern, files)) {
+        try!(write!(out, "{}:{}:{}:", found.path, found.rev, found.number));
+        try!(out.write_all(&found.line));
+        try!(writeln!(out, ""));
+    }
+    Ok(())
+}
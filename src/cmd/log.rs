@@ -0,0 +1,262 @@
+//! `cinnabar log [-r REV]... [-l N] [-v] [-T TEMPLATE | --json] [--bundle FILE] [FILE]...`
+//!
+//! Show changeset history, newest first unless revisions are given.
+//! Revisions are revsets, such as `A:B` or `branch(stable) and 10::`,
+//! and are listed in the order they select. With files, only changesets touching
+//! them (or anything under them, for directories) are shown; files may
+//! also be patterns, such as `glob:src/*.rs`. With `--bundle`, the
+//! changesets of a bundle are shown as if it had been applied, and
+//! `bundle()` selects them.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::Json;
+
+use changelog::Changeset;
+use cmd::{self, Args};
+use date;
+use matcher::Matcher;
+use phases::Phases;
+use repo::Repo;
+use revset;
+use template::{Keywords, Template, Value};
+use util::Result;
+
+pub fn run(args: &[String]) -> Result<()> {
+    let args = try!(Args::parse(args,
+                                &["-v", "--verbose", "--json"],
+                                &["-r", "--rev", "-l", "--limit", "-T", "--template", "--bundle"]));
+    let repo = try!(cmd::open_repo(&args));
+    let verbose = args.flag(&["-v", "--verbose"]);
+    let template = match args.value(&["-T", "--template"]) {
+        Some(t) => Some(try!(Template::parse(t))),
+        None => None,
+    };
+    let limit = match args.value(&["-l", "--limit"]) {
+        Some(l) => {
+            match l.parse::<usize>() {
+                Ok(l) => Some(l),
+                Err(_) => return Err(From::from(format!("limit must be a number: {:?}", l))),
+            }
+        }
+        None => None,
+    };
+    let matcher = if args.free.is_empty() {
+        None
+    } else {
+        Some(try!(Matcher::new(&try!(cmd::file_patterns(&repo, &args.free)))))
+    };
+
+    let specs = args.values(&["-r", "--rev"]);
+    let revs = if specs.is_empty() {
+        try!(repo.revs()).into_iter().rev().collect()
+    } else {
+        try!(resolve_revsets(&repo, &specs))
+    };
+
+    let tags = try!(names_by_rev(&repo, try!(repo.tags())));
+    let bookmarks = try!(names_by_rev(&repo, try!(repo.bookmarks())));
+    let active_bookmark = try!(repo.active_bookmark());
+    let tip = try!(repo.tip());
+    let phases = try!(Phases::read(&repo));
+    let no_names = vec![];
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut json = vec![];
+    let mut shown = 0;
+    for rev in revs {
+        if limit.map_or(false, |l| shown >= l) {
+            break;
+        }
+        let cs = try!(repo.changeset(rev));
+        if matcher.as_ref().map_or(false, |m| !cs.touches(m)) {
+            continue;
+        }
+        shown += 1;
+        let mut entry_tags = tags.get(&rev).unwrap_or(&no_names).clone();
+        if rev == tip {
+            entry_tags.push(String::from("tip"));
+        }
+        let entry_bookmarks = bookmarks.get(&rev).unwrap_or(&no_names).clone();
+        let entry_active = match active_bookmark {
+            Some(ref b) if entry_bookmarks.contains(b) => b.clone(),
+            _ => String::new(),
+        };
+        let entry = LogEntry {
+            repo: &repo,
+            phases: &phases,
+            rev: rev,
+            cs: cs,
+            tags: entry_tags,
+            bookmarks: entry_bookmarks,
+            active_bookmark: entry_active,
+        };
+        if args.flag(&["--json"]) {
+            json.push(try!(entry.to_json(verbose)));
+        } else if let Some(ref template) = template {
+            try!(out.write_all(try!(template.render(&entry)).as_bytes()));
+        } else {
+            try!(entry.write_default(&mut out, verbose));
+        }
+    }
+    if args.flag(&["--json"]) {
+        try!(writeln!(out, "{}", Json::Array(json).pretty()));
+    }
+    Ok(())
+}
+
+/// Evaluate each revset in turn, keeping the first occurrence of each
+/// rev.
+fn resolve_revsets(repo: &Repo, specs: &[&str]) -> Result<Vec<i32>> {
+    let mut seen = HashSet::new();
+    let mut result = vec![];
+    for spec in specs {
+        for rev in try!(revset::revs(repo, spec)) {
+            if seen.insert(rev) {
+                result.push(rev);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Group names such as tags or bookmarks by the rev they point to.
+fn names_by_rev(repo: &Repo, names: BTreeMap<String, Vec<u8>>) -> Result<HashMap<i32, Vec<String>>> {
+    let mut result: HashMap<i32, Vec<String>> = HashMap::new();
+    for (name, node) in names {
+        if let Some(rev) = try!(repo.changelog.revlog().rev(&node)) {
+            if rev != -1 {
+                result.entry(rev).or_insert_with(Vec::new).push(name);
+            }
+        }
+    }
+    Ok(result)
+}
+
+struct LogEntry<'a> {
+    repo: &'a Repo,
+    phases: &'a Phases,
+    rev: i32,
+    cs: Changeset,
+    tags: Vec<String>,
+    bookmarks: Vec<String>,
+    /// The active bookmark if it's on this changeset, or empty
+    active_bookmark: String,
+}
+
+impl<'a> LogEntry<'a> {
+    fn node(&self) -> Result<String> {
+        Ok(try!(self.repo.changelog.revlog().node(self.rev)).to_hex())
+    }
+
+    /// The parents worth showing: none for a linear history.
+    fn parents(&self) -> Result<Vec<i32>> {
+        let (p1, p2) = try!(self.repo.changelog.parents(self.rev));
+        Ok(if p2 != -1 {
+            vec![p1, p2]
+        } else if p1 < self.rev - 1 {
+            vec![p1]
+        } else {
+            vec![]
+        })
+    }
+
+    fn format_rev(&self, rev: i32) -> Result<String> {
+        let node = try!(self.repo.changelog.revlog().node(rev)).to_hex();
+        Ok(format!("{}:{}", rev, &node[..12]))
+    }
+
+    fn write_default<W: Write>(&self, out: &mut W, verbose: bool) -> Result<()> {
+        try!(writeln!(out, "changeset:   {}", try!(self.format_rev(self.rev))));
+        if self.cs.branch() != "default" {
+            try!(writeln!(out, "branch:      {}", self.cs.branch()));
+        }
+        for tag in &self.tags {
+            try!(writeln!(out, "tag:         {}", tag));
+        }
+        for bookmark in &self.bookmarks {
+            try!(writeln!(out, "bookmark:    {}", bookmark));
+        }
+        for p in try!(self.parents()) {
+            try!(writeln!(out, "parent:      {}", try!(self.format_rev(p))));
+        }
+        try!(writeln!(out, "user:        {}", self.cs.user));
+        try!(writeln!(out, "date:        {}", date::format_date(self.cs.time, self.cs.tz)));
+        if verbose {
+            if !self.cs.files.is_empty() {
+                try!(writeln!(out, "files:       {}", self.cs.files.join(" ")));
+            }
+            try!(writeln!(out, "description:\n{}\n", self.cs.description.trim_right()));
+        } else {
+            let summary = self.cs.description.lines().next().unwrap_or("");
+            try!(writeln!(out, "summary:     {}", summary));
+        }
+        try!(writeln!(out, ""));
+        Ok(())
+    }
+
+    fn to_json(&self, verbose: bool) -> Result<Json> {
+        let strings = |v: &[String]| Json::Array(v.iter().cloned().map(Json::String).collect());
+        let (p1, p2) = try!(self.repo.changelog.parents(self.rev));
+        let mut parents = vec![];
+        for &p in &[p1, p2] {
+            if p != -1 {
+                parents.push(try!(self.repo.changelog.revlog().node(p)).to_hex());
+            }
+        }
+        let mut obj = BTreeMap::new();
+        obj.insert(String::from("rev"), Json::I64(self.rev as i64));
+        obj.insert(String::from("node"), Json::String(try!(self.node())));
+        obj.insert(String::from("branch"), Json::String(String::from(self.cs.branch())));
+        obj.insert(String::from("user"), Json::String(self.cs.user.clone()));
+        obj.insert(String::from("date"),
+                   Json::Array(vec![Json::I64(self.cs.time), Json::I64(self.cs.tz as i64)]));
+        obj.insert(String::from("desc"), Json::String(self.cs.description.clone()));
+        obj.insert(String::from("bookmarks"), strings(&self.bookmarks));
+        obj.insert(String::from("tags"), strings(&self.tags));
+        obj.insert(String::from("parents"), strings(&parents));
+        obj.insert(String::from("phase"),
+                   Json::String(String::from(self.phases.phase(self.rev).name())));
+        if verbose {
+            obj.insert(String::from("files"), strings(&self.cs.files));
+        }
+        Ok(Json::Object(obj))
+    }
+}
+
+impl<'a> Keywords for LogEntry<'a> {
+    fn keyword(&self, name: &str) -> Result<Option<Value>> {
+        let (p1, p2) = try!(self.repo.changelog.parents(self.rev));
+        let node = |rev| -> Result<Value> {
+            Ok(Value::Text(try!(self.repo.changelog.revlog().node(rev)).to_hex()))
+        };
+        Ok(Some(match name {
+            "rev" => Value::Int(self.rev as i64),
+            "node" => try!(node(self.rev)),
+            "author" => Value::Text(self.cs.user.clone()),
+            "desc" => Value::Text(self.cs.description.clone()),
+            "date" => Value::Date(self.cs.time, self.cs.tz),
+            "branch" => Value::Text(String::from(self.cs.branch())),
+            "files" => Value::List(self.cs.files.clone()),
+            "tags" => Value::List(self.tags.clone()),
+            "bookmarks" => Value::List(self.bookmarks.clone()),
+            "activebookmark" => Value::Text(self.active_bookmark.clone()),
+            "parents" => {
+                let mut parents = vec![];
+                for p in try!(self.parents()) {
+                    parents.push(try!(self.format_rev(p)));
+                }
+                Value::List(parents)
+            }
+            "p1rev" => Value::Int(p1 as i64),
+            "p2rev" => Value::Int(p2 as i64),
+            "p1node" => try!(node(p1)),
+            "p2node" => try!(node(p2)),
+            "manifest" => Value::Text(self.cs.manifest.to_hex()),
+            "phase" => Value::Text(String::from(self.phases.phase(self.rev).name())),
+            _ => return Ok(None),
+        }))
+    }
+}
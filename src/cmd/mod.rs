@@ -0,0 +1,173 @@
+//! The subcommands of the `cinnabar` binary, and what they share:
+//! option parsing and finding the repository.
+
+pub mod annotate;
+pub mod archive;
+pub mod branches;
+pub mod bundle;
+pub mod cat;
+pub mod debug;
+pub mod diff;
+pub mod grep;
+pub mod log;
+pub mod status;
+
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+use bundlerepo;
+use matcher::{Kind, Pattern};
+use repo::{Repo, View};
+use util::Result;
+
+/// Options which every command accepts.
+const GLOBAL_FLAGS: &'static [&'static str] = &["--hidden"];
+const GLOBAL_WITH_VALUE: &'static [&'static str] = &["-R", "--repository"];
+
+/// Command line arguments, split into options and positional arguments.
+pub struct Args {
+    opts: Vec<(String, Option<String>)>,
+    pub free: Vec<String>,
+}
+
+impl Args {
+    /// Parse arguments. `flags` lists the options which stand alone and
+    /// `with_value` those which take a value, either as the next
+    /// argument, after `=` for long options, or attached to a short
+    /// option as in `-r5`. Everything after `--` is positional.
+    pub fn parse(args: &[String], flags: &[&str], with_value: &[&str]) -> Result<Args> {
+        let takes_value = |name: &str| {
+            with_value.contains(&name) || GLOBAL_WITH_VALUE.contains(&name)
+        };
+        let mut result = Args {
+            opts: vec![],
+            free: vec![],
+        };
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                result.free.extend(iter.cloned());
+                break;
+            }
+            if !arg.starts_with('-') || arg == "-" {
+                result.free.push(arg.clone());
+                continue;
+            }
+            let (name, inline) = if arg.starts_with("--") {
+                match arg.find('=') {
+                    Some(i) => (&arg[..i], Some(String::from(&arg[i + 1..]))),
+                    None => (&arg[..], None),
+                }
+            } else if arg.len() > 2 && takes_value(&arg[..2]) {
+                (&arg[..2], Some(String::from(&arg[2..])))
+            } else {
+                (&arg[..], None)
+            };
+            if takes_value(name) {
+                let value = match inline {
+                    Some(value) => value,
+                    None => {
+                        match iter.next() {
+                            Some(value) => value.clone(),
+                            None => return Err(From::from(format!("option {} requires a value", name))),
+                        }
+                    }
+                };
+                result.opts.push((String::from(name), Some(value)));
+            } else if (flags.contains(&name) || GLOBAL_FLAGS.contains(&name)) && inline.is_none() {
+                result.opts.push((String::from(name), None));
+            } else {
+                return Err(From::from(format!("unknown option {}", arg)));
+            }
+        }
+        Ok(result)
+    }
+
+    /// Whether any of these spellings of a flag was given.
+    pub fn flag(&self, names: &[&str]) -> bool {
+        self.opts.iter().any(|&(ref n, _)| names.contains(&&n[..]))
+    }
+
+    /// The last value given for an option.
+    pub fn value(&self, names: &[&str]) -> Option<&str> {
+        self.values(names).pop()
+    }
+
+    /// Every value given for an option, in order.
+    pub fn values(&self, names: &[&str]) -> Vec<&str> {
+        self.opts
+            .iter()
+            .filter(|&&(ref n, _)| names.contains(&&n[..]))
+            .filter_map(|&(_, ref v)| v.as_ref().map(|v| &v[..]))
+            .collect()
+    }
+}
+
+/// The repository named by `-R`, or else the one containing the
+/// current directory. Hidden changesets are only included with
+/// `--hidden`. For commands which take `--bundle`, the changesets of
+/// that bundle are added.
+pub fn open_repo(args: &Args) -> Result<Repo> {
+    let cwd = try!(env::current_dir());
+    let mut repo = try!(match args.value(&["-R", "--repository"]) {
+        Some(path) => Repo::open(&normalize(&cwd.join(path))),
+        None => Repo::find(&cwd),
+    });
+    if let Some(path) = args.value(&["--bundle"]) {
+        repo = try!(bundlerepo::open(repo, &cwd.join(path)));
+    }
+    if args.flag(&["--hidden"]) {
+        repo.view = View::Unfiltered;
+    }
+    Ok(repo)
+}
+
+/// Convert a path given on the command line, relative to the current
+/// directory, into a path relative to the root of the repository.
+pub fn repo_path(repo: &Repo, arg: &str) -> Result<String> {
+    let cwd = try!(env::current_dir());
+    let full = normalize(&cwd.join(arg));
+    let root = normalize(repo.root());
+    let rel = match full.strip_prefix(&root) {
+        Ok(rel) => rel,
+        Err(_) => return Err(From::from(format!("{} not under root {:?}", arg, root))),
+    };
+    let parts: Vec<_> = rel.components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    Ok(parts.join("/"))
+}
+
+/// Parse file arguments as patterns. A plain argument is a `relpath:`,
+/// and it and `glob:` patterns are made relative to the root, as paths
+/// given on the command line are.
+pub fn file_patterns(repo: &Repo, args: &[String]) -> Result<Vec<Pattern>> {
+    let mut result = vec![];
+    for arg in args {
+        let pattern = Pattern::parse(arg, Kind::RelPath);
+        result.push(match pattern.kind {
+            Kind::RelPath => Pattern::new(Kind::Path, &try!(repo_path(repo, &pattern.text))),
+            Kind::Glob => Pattern::new(Kind::Glob, &try!(repo_path(repo, &pattern.text))),
+            Kind::Include | Kind::SubInclude => {
+                return Err(From::from(format!("{} is only valid in pattern files", arg)))
+            }
+            _ => pattern,
+        });
+    }
+    Ok(result)
+}
+
+/// Remove `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                result.pop();
+            }
+            c => result.push(c.as_os_str()),
+        }
+    }
+    result
+}
@@ -0,0 +1,80 @@
+//! `cinnabar status [-m] [-a] [-r] [-d] [-u] [-i] [-c] [-A] [-n] [FILE]...`
+//!
+//! Show changed files in the working directory, like `hg status`. Each
+//! line is a code and a path relative to the root:
+//!
+//! ```text
+//! M modified    A added      R removed    ! deleted (missing)
+//! ? unknown     I ignored    C clean
+//! ```
+//!
+//! Without options, all but ignored and clean files are shown. With
+//! files, only those files and the files under those directories are.
+
+use std::io::{self, Write};
+
+use cmd::{self, Args};
+use status::{self, Options};
+use util::Result;
+
+const KINDS: &'static [(&'static str, &'static str)] = &[("-m", "--modified"),
+                                                         ("-a", "--added"),
+                                                         ("-r", "--removed"),
+                                                         ("-d", "--deleted"),
+                                                         ("-u", "--unknown"),
+                                                         ("-i", "--ignored"),
+                                                         ("-c", "--clean")];
+
+pub fn run(args: &[String]) -> Result<()> {
+    let mut flags = vec!["-A", "--all", "-n", "--no-status"];
+    for &(short, long) in KINDS {
+        flags.push(short);
+        flags.push(long);
+    }
+    let args = try!(Args::parse(args, &flags, &[]));
+    let repo = try!(cmd::open_repo(&args));
+    let mut paths = vec![];
+    for arg in &args.free {
+        paths.push(try!(cmd::repo_path(&repo, arg)));
+    }
+
+    let all = args.flag(&["-A", "--all"]);
+    let mut show: Vec<bool> = KINDS.iter().map(|&(short, long)| all || args.flag(&[short, long])).collect();
+    if !show.iter().any(|&s| s) {
+        show = vec![true, true, true, true, true, false, false];
+    }
+    let options = Options {
+        unknown: show[4],
+        ignored: show[5],
+        clean: show[6],
+    };
+    let status = try!(status::status(&repo, options));
+
+    let selected = |path: &str| {
+        paths.is_empty() ||
+        paths.iter().any(|p| p.is_empty() || path == p || path.starts_with(&format!("{}/", p)))
+    };
+    let no_status = args.flag(&["-n", "--no-status"]);
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let lists = [(&status.modified, 'M'),
+                 (&status.added, 'A'),
+                 (&status.removed, 'R'),
+                 (&status.deleted, '!'),
+                 (&status.unknown, '?'),
+                 (&status.ignored, 'I'),
+                 (&status.clean, 'C')];
+    for (&(files, code), &wanted) in lists.iter().zip(&show) {
+        if !wanted {
+            continue;
+        }
+        for path in files.iter().filter(|p| selected(p)) {
+            if no_status {
+                try!(writeln!(out, "{}", path));
+            } else {
+                try!(writeln!(out, "{} {}", code, path));
+            }
+        }
+    }
+    Ok(())
+}
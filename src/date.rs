@@ -0,0 +1,297 @@
+//! Mercurial dates are a pair of seconds since the epoch and the
+//! offset of the committer's timezone in seconds *west* of UTC, so that
+//! local time is `time - tz`. These functions format them the ways
+//! `hg log` and its template filters do, and parse the date ranges used
+//! to select changesets.
+
+extern crate time;
+
+use std::i64;
+
+use self::time::Timespec;
+
+use util::Result;
+
+const DAYS: [&'static str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&'static str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug",
+                                    "Sep", "Oct", "Nov", "Dec"];
+
+/// A broken down local time.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Tm {
+    pub year: i64,
+    /// 1 to 12
+    pub month: u32,
+    /// 1 to 31
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    /// 0 is Sunday
+    pub weekday: u32,
+}
+
+/// The date of a number of days since 1970-01-01, in the proleptic
+/// Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The number of days since 1970-01-01 of a date in the proleptic
+/// Gregorian calendar. The inverse of `civil_from_days`.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+pub fn to_tm(time: i64, tz: i32) -> Tm {
+    let local = time - tz as i64;
+    let days = if local >= 0 { local / 86400 } else { (local - 86399) / 86400 };
+    let secs = (local - days * 86400) as u32;
+    let (year, month, day) = civil_from_days(days);
+    Tm {
+        year: year,
+        month: month,
+        day: day,
+        hour: secs / 3600,
+        minute: secs / 60 % 60,
+        second: secs % 60,
+        weekday: ((days % 7 + 11) % 7) as u32,
+    }
+}
+
+/// The timezone as `+hhmm`.
+pub fn tz_string(tz: i32) -> String {
+    let sign = if tz > 0 { '-' } else { '+' };
+    let minutes = tz.abs() / 60;
+    format!("{}{:02}{:02}", sign, minutes / 60, minutes % 60)
+}
+
+/// `Thu Apr 07 03:33:20 2016 +0000`, as in `hg log`.
+pub fn format_date(time: i64, tz: i32) -> String {
+    let tm = to_tm(time, tz);
+    format!("{} {} {:02} {:02}:{:02}:{:02} {} {}",
+            DAYS[tm.weekday as usize],
+            MONTHS[tm.month as usize - 1],
+            tm.day,
+            tm.hour,
+            tm.minute,
+            tm.second,
+            tm.year,
+            tz_string(tz))
+}
+
+/// `2016-04-07 03:33 +0000`
+pub fn iso_date(time: i64, tz: i32) -> String {
+    let tm = to_tm(time, tz);
+    format!("{}-{:02}-{:02} {:02}:{:02} {}",
+            tm.year,
+            tm.month,
+            tm.day,
+            tm.hour,
+            tm.minute,
+            tz_string(tz))
+}
+
+/// `2016-04-07 03:33:20 +0000`
+pub fn iso_date_sec(time: i64, tz: i32) -> String {
+    let tm = to_tm(time, tz);
+    format!("{}-{:02}-{:02} {:02}:{:02}:{:02} {}",
+            tm.year,
+            tm.month,
+            tm.day,
+            tm.hour,
+            tm.minute,
+            tm.second,
+            tz_string(tz))
+}
+
+/// `Thu, 07 Apr 2016 03:33:20 +0000`
+pub fn rfc822_date(time: i64, tz: i32) -> String {
+    let tm = to_tm(time, tz);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} {}",
+            DAYS[tm.weekday as usize],
+            tm.day,
+            MONTHS[tm.month as usize - 1],
+            tm.year,
+            tm.hour,
+            tm.minute,
+            tm.second,
+            tz_string(tz))
+}
+
+/// `2016-04-07`
+pub fn short_date(time: i64, tz: i32) -> String {
+    let tm = to_tm(time, tz);
+    format!("{}-{:02}-{:02}", tm.year, tm.month, tm.day)
+}
+
+/// The first and last second covered by a date, which may leave out
+/// any of its trailing fields: `2016`, `2016-04`, `2016-04-07`,
+/// `2016-04-07 03:33` or `2016-04-07 03:33:20`, optionally followed by
+/// a timezone such as `+0100` or `UTC`. Without a timezone the date is
+/// taken as local time. The internal format `<seconds> <offset>` is accepted
+/// too.
+pub fn parse_bounds(date: &str) -> Result<(i64, i64)> {
+    let bad = || From::from(format!("invalid date: {:?}", date));
+    let mut words: Vec<&str> = date.split_whitespace().collect();
+    expect!(!words.is_empty(), "invalid date: {:?}", date);
+
+    if words.len() <= 2 && words[0].len() > 4 && words[0].chars().all(|c| c.is_digit(10)) {
+        let time = try!(words[0].parse::<i64>().map_err(|_| bad()));
+        if words.len() == 2 {
+            try!(words[1].parse::<i32>().map_err(|_| bad()));
+        }
+        return Ok((time, time));
+    }
+
+    // Seconds to add to the local time to get UTC
+    let mut offset = None;
+    if words.len() > 1 {
+        let last = words[words.len() - 1];
+        let tz = match last {
+            "UTC" | "GMT" | "Z" => Some(0),
+            _ if last.len() == 5 && (last.starts_with('+') || last.starts_with('-')) => {
+                let hhmm = try!(last[1..].parse::<i64>().map_err(|_| bad()));
+                let secs = hhmm / 100 * 3600 + hhmm % 100 * 60;
+                Some(if last.starts_with('+') { -secs } else { secs })
+            }
+            _ => None,
+        };
+        if let Some(tz) = tz {
+            offset = Some(tz);
+            words.pop();
+        }
+    }
+    expect!(words.len() <= 2, "invalid date: {:?}", date);
+
+    let mut fields = vec![];
+    for part in words[0].split('-') {
+        fields.push(try!(part.parse::<i64>().map_err(|_| bad())));
+    }
+    if words.len() == 2 {
+        expect!(fields.len() == 3, "invalid date: {:?}", date);
+        for part in words[1].split(':') {
+            fields.push(try!(part.parse::<i64>().map_err(|_| bad())));
+        }
+        expect!(fields.len() >= 5, "invalid date: {:?}", date);
+    }
+    expect!(fields.len() <= 6, "invalid date: {:?}", date);
+    let limits = [(i64::MIN, i64::MAX), (1, 12), (1, 31), (0, 23), (0, 59), (0, 60)];
+    for (value, &(low, high)) in fields.iter().zip(limits.iter()) {
+        if *value < low || *value > high {
+            return Err(bad());
+        }
+    }
+
+    let year = fields[0];
+    let month = *fields.get(1).unwrap_or(&1) as u32;
+    let day = *fields.get(2).unwrap_or(&1) as u32;
+    let start = days_from_civil(year, month, day) * 86400 +
+                fields.get(3).unwrap_or(&0) * 3600 + fields.get(4).unwrap_or(&0) * 60 +
+                fields.get(5).unwrap_or(&0);
+    let end = match fields.len() {
+        1 => days_from_civil(year + 1, 1, 1) * 86400 - 1,
+        2 if month == 12 => days_from_civil(year + 1, 1, 1) * 86400 - 1,
+        2 => days_from_civil(year, month + 1, 1) * 86400 - 1,
+        3 => start + 86399,
+        5 => start + 59,
+        _ => start,
+    };
+    Ok(match offset {
+        Some(offset) => (start + offset, end + offset),
+        None => (start + local_offset(start), end + local_offset(end)),
+    })
+}
+
+/// The seconds to add to a local time to get UTC, in the timezone of the
+/// process.
+fn local_offset(local: i64) -> i64 {
+    let west = |time: i64| -(time::at(Timespec::new(time, 0)).tm_utcoff as i64);
+    // The offset at the local time taken as UTC is off by the change in
+    // offset, if one happens between the two
+    west(local + west(local))
+}
+
+/// The first and last second matched by a date specification, as in
+/// `hg log -d`: a date, `<DATE`, `>DATE`, `DATE to DATE`, or `-DAYS` for
+/// the last so many days before `now`.
+pub fn parse_range(spec: &str, now: i64) -> Result<(i64, i64)> {
+    let spec = spec.trim();
+    if spec.starts_with('<') {
+        let (_, end) = try!(parse_bounds(&spec[1..]));
+        return Ok((i64::MIN, end));
+    }
+    if spec.starts_with('>') {
+        let (start, _) = try!(parse_bounds(&spec[1..]));
+        return Ok((start, i64::MAX));
+    }
+    if spec.starts_with('-') {
+        return match spec[1..].trim().parse::<i64>() {
+            Ok(days) => Ok((now - days * 86400, i64::MAX)),
+            Err(_) => Err(From::from(format!("invalid day spec: {:?}", spec))),
+        };
+    }
+    if let Some(i) = spec.find(" to ") {
+        let (start, _) = try!(parse_bounds(&spec[..i]));
+        let (_, end) = try!(parse_bounds(&spec[i + 4..]));
+        return Ok((start, end));
+    }
+    parse_bounds(spec)
+}
+
+#[cfg(test)]
+mod test {
+    use std::i64;
+    use super::time::{self, Timespec};
+    use super::{days_from_civil, format_date, iso_date, parse_range, rfc822_date};
+
+    #[test]
+    fn test_format() {
+        assert_eq!("Thu Jan 01 00:00:00 1970 +0000", format_date(0, 0));
+        assert_eq!("Wed Dec 31 19:00:00 1969 -0500", format_date(0, 18000));
+        assert_eq!("Thu Apr 07 05:33:20 2016 +0100", format_date(1460003600, -3600));
+        assert_eq!("2016-04-06 23:33 -0400", iso_date(1460000000, 14400));
+        assert_eq!("Tue, 29 Feb 2000 12:00:00 +0000", rfc822_date(951825600, 0));
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(11017, days_from_civil(2000, 3, 1));
+        assert_eq!((951782400, 951868799), parse_range("2000-02-29 UTC", 0).unwrap());
+        assert_eq!((951782400, 954547199), parse_range("2000-02-29 +0000 to 2000-03 Z", 0).unwrap());
+        assert_eq!((i64::MIN, 978307199), parse_range("<2000 GMT", 0).unwrap());
+        assert_eq!((951825600, i64::MAX), parse_range(">2000-02-29 13:00 +0100", 0).unwrap());
+        assert_eq!((951825600, 951825600), parse_range("951825600 0", 0).unwrap());
+        assert_eq!((1000 - 86400, i64::MAX), parse_range("-1", 1000).unwrap());
+        assert!(parse_range("2000-13", 0).is_err());
+        assert!(parse_range("yesterday", 0).is_err());
+    }
+
+    #[test]
+    fn test_local_time() {
+        let (start, end) = parse_range("2000-07-01 12:30", 0).unwrap();
+        let tm = time::at(Timespec::new(start, 0));
+        assert_eq!((100, 6, 1, 12, 30, 0), (tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec));
+        assert_eq!(start + 59, end);
+        let (start, end) = parse_range("2000", 0).unwrap();
+        let tm = time::at(Timespec::new(start, 0));
+        assert_eq!((100, 0, 1, 0, 0), (tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min));
+        let tm = time::at(Timespec::new(end, 0));
+        assert_eq!((100, 11, 31, 23, 59, 59), (tm.tm_year, tm.tm_mon, tm.tm_mday, tm.tm_hour, tm.tm_min, tm.tm_sec));
+    }
+}
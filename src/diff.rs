@@ -0,0 +1,417 @@
+//! Line diffs.
+//!
+//! Texts are split into lines, each keeping its `\n`, and compared
+//! with Myers' algorithm in its linear space form: the middle snake of
+//! the edit graph is found by searching from both ends at once, and the
+//! halves on either side of it are compared recursively. The result is
+//! the list of blocks the two texts have in common, as Mercurial's
+//! `bdiff.blocks` returns it.
+//!
+//! Like xdiff, lines found in only one of the texts are set aside before
+//! comparing, and the search for a middle snake gives up after a while
+//! on very different texts, settling for a diff which may not be the
+//! shortest.
+//!
+//! `unified` turns the blocks into the hunks of a unified diff, and
+//! `delta` into a binary delta like those revlogs store.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Lines `a..a + len` of the old text equal lines `b..b + len` of the
+/// new text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Block {
+    pub a: usize,
+    pub b: usize,
+    pub len: usize,
+}
+
+/// Split a text into lines, each ending with `\n` except perhaps the
+/// last.
+pub fn split_lines(text: &[u8]) -> Vec<&[u8]> {
+    let mut lines = vec![];
+    let mut start = 0;
+    for (i, &c) in text.iter().enumerate() {
+        if c == b'\n' {
+            lines.push(&text[start..i + 1]);
+            start = i + 1;
+        }
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+/// The blocks of lines two texts have in common, in order, followed by
+/// an empty block at the end of both.
+pub fn blocks(a: &[u8], b: &[u8]) -> Vec<Block> {
+    line_blocks(&split_lines(a), &split_lines(b))
+}
+
+/// Like `blocks`, for texts already split into lines.
+pub fn line_blocks<'t>(a: &[&'t [u8]], b: &[&'t [u8]]) -> Vec<Block> {
+    // Revisions of a file mostly differ in a few places, so take the
+    // common ends out before anything costlier
+    let mut prefix = 0;
+    while prefix < a.len() && prefix < b.len() && a[prefix] == b[prefix] {
+        prefix += 1;
+    }
+    let mut suffix = 0;
+    while suffix < a.len() - prefix && suffix < b.len() - prefix &&
+          a[a.len() - 1 - suffix] == b[b.len() - 1 - suffix] {
+        suffix += 1;
+    }
+    let (a_mid, b_mid) = (&a[prefix..a.len() - suffix], &b[prefix..b.len() - suffix]);
+
+    // Compare numbers rather than lines
+    let mut ids: HashMap<&'t [u8], usize> = HashMap::new();
+    let mut a_ids = Vec::with_capacity(a_mid.len());
+    for &line in a_mid {
+        let next = ids.len();
+        a_ids.push(*ids.entry(line).or_insert(next));
+    }
+    let mut b_ids = Vec::with_capacity(b_mid.len());
+    for &line in b_mid {
+        let next = ids.len();
+        b_ids.push(*ids.entry(line).or_insert(next));
+    }
+
+    // Only lines in both texts can match, so compare those, remembering
+    // where they came from
+    let mut in_a = vec![false; ids.len()];
+    let mut in_b = vec![false; ids.len()];
+    for &id in &a_ids {
+        in_a[id] = true;
+    }
+    for &id in &b_ids {
+        in_b[id] = true;
+    }
+    let a_index: Vec<usize> = (0..a_mid.len()).filter(|&i| in_b[a_ids[i]]).collect();
+    let b_index: Vec<usize> = (0..b_mid.len()).filter(|&i| in_a[b_ids[i]]).collect();
+    let a_ids: Vec<usize> = a_index.iter().map(|&i| a_ids[i]).collect();
+    let b_ids: Vec<usize> = b_index.iter().map(|&i| b_ids[i]).collect();
+
+    let mut matches = vec![];
+    let size = 2 * (a_ids.len() + b_ids.len()) + 4;
+    let mut forward = vec![0; size];
+    let mut backward = vec![0; size];
+    compare(&a_ids,
+            &b_ids,
+            (0, a_ids.len()),
+            (0, b_ids.len()),
+            &mut forward,
+            &mut backward,
+            &mut matches);
+
+    let mut result: Vec<Block> = vec![];
+    if prefix > 0 {
+        result.push(Block { a: 0, b: 0, len: prefix });
+    }
+    let matches = matches.into_iter()
+        .map(|(x, y)| (prefix + a_index[x], prefix + b_index[y]))
+        .chain((0..suffix).map(|i| (a.len() - suffix + i, b.len() - suffix + i)));
+    for (x, y) in matches {
+        if let Some(last) = result.last_mut() {
+            if last.a + last.len == x && last.b + last.len == y {
+                last.len += 1;
+                continue;
+            }
+        }
+        result.push(Block { a: x, b: y, len: 1 });
+    }
+    result.push(Block {
+        a: a.len(),
+        b: b.len(),
+        len: 0,
+    });
+    result
+}
+
+/// The hunks of a unified diff between two texts, with `context`
+/// unchanged lines around each change, as `hg diff` prints them after
+/// the file headers. Changes closer than twice the context share a
+/// hunk. Identical texts give an empty diff.
+pub fn unified(old: &[u8], new: &[u8], context: usize) -> Vec<u8> {
+    let a = split_lines(old);
+    let b = split_lines(new);
+    // The ranges of lines changed between blocks, as (a, a_end, b, b_end)
+    let mut changes = vec![];
+    let (mut x, mut y) = (0, 0);
+    for block in line_blocks(&a, &b) {
+        if block.a > x || block.b > y {
+            changes.push((x, block.a, y, block.b));
+        }
+        x = block.a + block.len;
+        y = block.b + block.len;
+    }
+
+    let mut out = vec![];
+    let mut i = 0;
+    while i < changes.len() {
+        let mut j = i;
+        while j + 1 < changes.len() && changes[j + 1].0 - changes[j].1 <= 2 * context {
+            j += 1;
+        }
+        // The lines around a change are the same in both texts
+        let before = ::std::cmp::min(context, changes[i].0 - if i == 0 { 0 } else { changes[i - 1].1 });
+        let after = ::std::cmp::min(context, a.len() - changes[j].1);
+        let (a_start, b_start) = (changes[i].0 - before, changes[i].2 - before);
+        let a_len = changes[j].1 + after - a_start;
+        let b_len = changes[j].3 + after - b_start;
+        // An empty range starts at the line before it
+        write!(out,
+               "@@ -{},{} +{},{} @@\n",
+               if a_len == 0 { a_start } else { a_start + 1 },
+               a_len,
+               if b_len == 0 { b_start } else { b_start + 1 },
+               b_len)
+            .unwrap();
+        let mut line = a_start;
+        for &(a_lo, a_hi, b_lo, b_hi) in &changes[i..j + 1] {
+            for text in &a[line..a_lo] {
+                push_line(&mut out, b' ', text);
+            }
+            for text in &a[a_lo..a_hi] {
+                push_line(&mut out, b'-', text);
+            }
+            for text in &b[b_lo..b_hi] {
+                push_line(&mut out, b'+', text);
+            }
+            line = a_hi;
+        }
+        for text in &a[line..line + after] {
+            push_line(&mut out, b' ', text);
+        }
+        i = j + 1;
+    }
+    out
+}
+
+fn push_line(out: &mut Vec<u8>, prefix: u8, line: &[u8]) {
+    out.push(prefix);
+    out.extend_from_slice(line);
+    if !line.ends_with(b"\n") {
+        out.extend_from_slice(b"\n\\ No newline at end of file\n");
+    }
+}
+
+/// A delta from one text to another in the form `patch::apply` takes:
+/// hunks of a start and end offset in the old text and the length of
+/// the data which replaces that range, each a big endian u32, followed
+/// by the data.
+pub fn delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let a = split_lines(old);
+    let b = split_lines(new);
+    let offsets = |lines: &[&[u8]]| {
+        let mut result = vec![0];
+        for line in lines {
+            let last = result[result.len() - 1];
+            result.push(last + line.len());
+        }
+        result
+    };
+    let (a_offsets, b_offsets) = (offsets(&a), offsets(&b));
+    let mut out = vec![];
+    let (mut x, mut y) = (0, 0);
+    for block in line_blocks(&a, &b) {
+        if block.a > x || block.b > y {
+            let data = &new[b_offsets[y]..b_offsets[block.b]];
+            for &n in &[a_offsets[x], a_offsets[block.a], data.len()] {
+                out.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+            }
+            out.extend_from_slice(data);
+        }
+        x = block.a + block.len;
+        y = block.b + block.len;
+    }
+    out
+}
+
+/// Add the matching line pairs of `a[a_range]` and `b[b_range]` to
+/// `matches`, in order.
+fn compare(a: &[usize],
+           b: &[usize],
+           a_range: (usize, usize),
+           b_range: (usize, usize),
+           forward: &mut [usize],
+           backward: &mut [usize],
+           matches: &mut Vec<(usize, usize)>) {
+    let (mut a_lo, mut a_hi) = a_range;
+    let (mut b_lo, mut b_hi) = b_range;
+    while a_lo < a_hi && b_lo < b_hi && a[a_lo] == b[b_lo] {
+        matches.push((a_lo, b_lo));
+        a_lo += 1;
+        b_lo += 1;
+    }
+    let mut suffix = 0;
+    while a_lo < a_hi && b_lo < b_hi && a[a_hi - 1] == b[b_hi - 1] {
+        a_hi -= 1;
+        b_hi -= 1;
+        suffix += 1;
+    }
+    if a_lo < a_hi && b_lo < b_hi {
+        let (x0, y0, x1, y1) = middle_snake(a, b, (a_lo, a_hi), (b_lo, b_hi), forward, backward);
+        compare(a, b, (a_lo, x0), (b_lo, y0), forward, backward, matches);
+        for i in 0..x1 - x0 {
+            matches.push((x0 + i, y0 + i));
+        }
+        compare(a, b, (x1, a_hi), (y1, b_hi), forward, backward, matches);
+    }
+    for i in 0..suffix {
+        matches.push((a_hi + i, b_hi + i));
+    }
+}
+
+/// How many edits to search through before settling for a split which
+/// may not be optimal.
+const MAX_COST: isize = 1024;
+
+/// Find the snake in the middle of a shortest edit script between two
+/// ranges which have neither a common prefix nor a common suffix. The
+/// `forward` and `backward` arrays hold the furthest reaching x of each
+/// diagonal, offset so that negative diagonals fit.
+fn middle_snake(a: &[usize],
+                b: &[usize],
+                a_range: (usize, usize),
+                b_range: (usize, usize),
+                forward: &mut [usize],
+                backward: &mut [usize])
+                -> (usize, usize, usize, usize) {
+    let (a_lo, a_hi) = a_range;
+    let (b_lo, b_hi) = b_range;
+    let n = (a_hi - a_lo) as isize;
+    let m = (b_hi - b_lo) as isize;
+    let delta = n - m;
+    let odd = delta & 1 == 1;
+    let max_d = (n + m + 1) / 2;
+    let offset = max_d + 1;
+    let at = |k: isize| (k + offset) as usize;
+    // Diagonal k of the reversed texts is diagonal delta - k here
+    forward[at(1)] = 0;
+    backward[at(1)] = 0;
+
+    for d in 0..max_d + 1 {
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && forward[at(k - 1)] < forward[at(k + 1)]) {
+                forward[at(k + 1)]
+            } else {
+                forward[at(k - 1)] + 1
+            } as isize;
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[a_lo + x as usize] == b[b_lo + y as usize] {
+                x += 1;
+                y += 1;
+            }
+            forward[at(k)] = x as usize;
+            let rk = delta - k;
+            if odd && rk >= -(d - 1) && rk <= d - 1 && x + backward[at(rk)] as isize >= n {
+                return (a_lo + x0 as usize, b_lo + y0 as usize, a_lo + x as usize, b_lo + y as usize);
+            }
+            k += 2;
+        }
+
+        let mut k = -d;
+        while k <= d {
+            let mut x = if k == -d || (k != d && backward[at(k - 1)] < backward[at(k + 1)]) {
+                backward[at(k + 1)]
+            } else {
+                backward[at(k - 1)] + 1
+            } as isize;
+            let mut y = x - k;
+            let (x0, y0) = (x, y);
+            while x < n && y < m && a[a_hi - 1 - x as usize] == b[b_hi - 1 - y as usize] {
+                x += 1;
+                y += 1;
+            }
+            backward[at(k)] = x as usize;
+            let fk = delta - k;
+            if !odd && fk >= -d && fk <= d && x + forward[at(fk)] as isize >= n {
+                return (a_hi - x as usize, b_hi - y as usize, a_hi - x0 as usize, b_hi - y0 as usize);
+            }
+            k += 2;
+        }
+
+        // Split at the furthest point reached from the start. Its x + y
+        // is at least d, so both halves are smaller than the whole
+        if d >= MAX_COST {
+            let mut best = (-1, 0, 0);
+            let mut k = -d;
+            while k <= d {
+                let x = forward[at(k)] as isize;
+                let y = x - k;
+                if x <= n && y >= 0 && y <= m && x + y > best.0 {
+                    best = (x + y, x, y);
+                }
+                k += 2;
+            }
+            let (x, y) = (a_lo + best.1 as usize, b_lo + best.2 as usize);
+            return (x, y, x, y);
+        }
+    }
+    unreachable!("no middle snake")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Block, blocks, delta, split_lines, unified};
+    use patch;
+
+    fn block(a: usize, b: usize, len: usize) -> Block {
+        Block { a: a, b: b, len: len }
+    }
+
+    #[test]
+    fn test_split_lines() {
+        assert_eq!(vec![&b"a\n"[..], b"\n", b"b"], split_lines(b"a\n\nb"));
+        assert!(split_lines(b"").is_empty());
+    }
+
+    #[test]
+    fn test_blocks() {
+        assert_eq!(vec![block(0, 0, 1), block(2, 1, 1), block(4, 3, 0)],
+                   blocks(b"a\nb\nc\nd\n", b"a\nc\ne\n"));
+        assert_eq!(vec![block(0, 0, 0)], blocks(b"", b""));
+        assert_eq!(vec![block(0, 1, 2), block(2, 3, 0)], blocks(b"x\ny\n", b"w\nx\ny\n"));
+        // Every line of the output is accounted for exactly once
+        let a = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+        let b = b"0\n2\n3\n9\n4\n5\n7\n8\n8\n";
+        let result = blocks(a, b);
+        let matched: usize = result.iter().map(|b| b.len).sum();
+        assert_eq!(6, matched);
+        for pair in result.windows(2) {
+            assert!(pair[0].a + pair[0].len <= pair[1].a && pair[0].b + pair[0].len <= pair[1].b);
+        }
+    }
+
+    #[test]
+    fn test_unified() {
+        let old = b"1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n12\n13\n14\n15";
+        let new = b"1\ntwo\n3\n4\n5\n6\n7\n8\n9\n10\n11\ntwelve\n13\n14\n15";
+        assert_eq!(&b"@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+                     @@ -9,7 +9,7 @@\n 9\n 10\n 11\n-12\n+twelve\n 13\n 14\n\
+                     \x2015\n\\ No newline at end of file\n"[..],
+                   &unified(old, new, 3)[..]);
+        // With more context, the changes share a hunk
+        assert!(unified(old, new, 5).starts_with(b"@@ -1,15 +1,15 @@\n 1\n-2\n"));
+        assert_eq!(&b"@@ -1,1 +1,1 @@\n-a\n\\ No newline at end of file\n+a\n"[..],
+                   &unified(b"a", b"a\n", 3)[..]);
+        assert_eq!(&b"@@ -0,0 +1,1 @@\n+a\n"[..], &unified(b"", b"a\n", 3)[..]);
+        assert!(unified(old, old, 3).is_empty());
+    }
+
+    #[test]
+    fn test_delta() {
+        let texts: [&[u8]; 4] = [b"", b"a\nb\nc\n", b"a\nB\nc\nd", b"x\na\n"];
+        for old in &texts {
+            for new in &texts {
+                assert_eq!(new.to_vec(), patch::apply(old.to_vec(), vec![delta(old, new)]).unwrap());
+            }
+        }
+        assert!(delta(b"a\n", b"a\n").is_empty());
+        assert_eq!(&b"\0\0\0\x02\0\0\0\x04\0\0\0\x02B\n"[..], &delta(b"a\nb\nc\n", b"a\nB\nc\n")[..]);
+    }
+}
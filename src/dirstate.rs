@@ -0,0 +1,529 @@
+//! The dirstate records the parents of the working directory and what
+//! Mercurial last knew about each tracked file. Version 1 of
+//! `.hg/dirstate` is:
+//!
+//! ```text
+//! <p1> <p2>
+//! <state, u8> <mode, i32> <size, i32> <mtime, i32> <name length, i32> <name>
+//! ...
+//! ```
+//!
+//! with integers big endian. A name of the form `<path>\0<source>`
+//! records that the file was copied from `source`.
+//!
+//! Repos with the `dirstate-v2` requirement instead keep a docket in
+//! `.hg/dirstate` naming a data file `.hg/dirstate.<uuid>`:
+//!
+//! ```text
+//! docket: "dirstate-v2\n" <p1, 32 bytes> <p2, 32 bytes> <tree metadata, 44 bytes>
+//!         <data size, u32> <uuid length, u8> <uuid>
+//! tree metadata: <root nodes: start u32, count u32> <nodes with entries, u32>
+//!                <nodes with copy sources, u32> <unreachable bytes, u32>
+//!                <unused, 4 bytes> <ignore patterns hash, 20 bytes>
+//! node: <full path: start u32, length u16> <base name start, u16>
+//!       <copy source: start u32, length u16> <children: start u32, count u32>
+//!       <descendants with entries, u32> <tracked descendants, u32>
+//!       <flags, u16> <size, u32> <mtime: seconds u32, nanoseconds u32>
+//! ```
+//!
+//! Every node of the tree is a file or a directory. Entries are
+//! converted to their v1 equivalents, and directories may carry the
+//! mtime they had when `hg status` last listed them. That mtime is only
+//! of use if the listing recorded all of their unknown files.
+
+extern crate byteorder;
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use dirstate::byteorder::{BigEndian, ReadBytesExt};
+
+use repo::{self, Repo};
+use revlog::NULL_ID;
+use util::Result;
+
+/// A size meaning the file must be compared with its content
+pub const SIZE_NONNORMAL: i32 = -1;
+/// A size meaning the file comes from the second parent of a merge
+pub const SIZE_FROM_P2: i32 = -2;
+/// An mtime meaning the file must be compared with its content
+pub const MTIME_UNSET: i32 = -1;
+
+const V2_MARKER: &'static [u8] = b"dirstate-v2\n";
+const V2_DOCKET_SIZE: usize = 125;
+const V2_TREE_METADATA_SIZE: usize = 44;
+const V2_NODE_SIZE: usize = 44;
+
+// Flags of a v2 node
+const WDIR_TRACKED: u16 = 1 << 0;
+const P1_TRACKED: u16 = 1 << 1;
+const P2_INFO: u16 = 1 << 2;
+const MODE_EXEC_PERM: u16 = 1 << 3;
+const MODE_IS_SYMLINK: u16 = 1 << 4;
+const EXPECTED_STATE_IS_MODIFIED: u16 = 1 << 9;
+const HAS_MODE_AND_SIZE: u16 = 1 << 10;
+const HAS_MTIME: u16 = 1 << 11;
+const MTIME_SECOND_AMBIGUOUS: u16 = 1 << 12;
+const DIRECTORY: u16 = 1 << 13;
+const ALL_UNKNOWN_RECORDED: u16 = 1 << 14;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum State {
+    /// `n`: tracked
+    Normal,
+    /// `a`: added
+    Added,
+    /// `r`: removed
+    Removed,
+    /// `m`: merged
+    Merged,
+}
+
+impl State {
+    pub fn from_char(c: u8) -> Option<State> {
+        match c {
+            b'n' => Some(State::Normal),
+            b'a' => Some(State::Added),
+            b'r' => Some(State::Removed),
+            b'm' => Some(State::Merged),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(&self) -> char {
+        match *self {
+            State::Normal => 'n',
+            State::Added => 'a',
+            State::Removed => 'r',
+            State::Merged => 'm',
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub state: State,
+    pub mode: i32,
+    pub size: i32,
+    /// Seconds since the epoch
+    pub mtime: i32,
+}
+
+/// A v2 modification time, with seconds truncated to 31 bits
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub seconds: u32,
+    pub nanoseconds: u32,
+}
+
+/// The fixed size part of a v2 dirstate.
+#[derive(Debug)]
+pub struct Docket {
+    pub p1: Vec<u8>,
+    pub p2: Vec<u8>,
+    /// How much of the data file is in use
+    pub data_size: u32,
+    root_start: u32,
+    root_count: u32,
+    pub ignore_patterns_hash: Vec<u8>,
+    /// Names the data file, `dirstate.<uuid>`
+    pub uuid: String,
+}
+
+impl Docket {
+    pub fn parse(data: &[u8]) -> Result<Docket> {
+        expect!(data.starts_with(V2_MARKER), "not a dirstate-v2 docket");
+        expect!(data.len() >= V2_DOCKET_SIZE, "dirstate docket is truncated");
+        let metadata = V2_MARKER.len() + 64;
+        let mut cursor = Cursor::new(&data[metadata..]);
+        let root_start = try!(cursor.read_u32::<BigEndian>());
+        let root_count = try!(cursor.read_u32::<BigEndian>());
+        // The counts of entries and copies, and unreachable bytes, are
+        // only needed to write the file
+        let hash_start = metadata + 24;
+        let mut cursor = Cursor::new(&data[metadata + V2_TREE_METADATA_SIZE..]);
+        let data_size = try!(cursor.read_u32::<BigEndian>());
+        let uuid_len = data[V2_DOCKET_SIZE - 1] as usize;
+        expect!(data.len() >= V2_DOCKET_SIZE + uuid_len, "dirstate docket is truncated");
+        Ok(Docket {
+            p1: Vec::from(&data[12..32]),
+            p2: Vec::from(&data[44..64]),
+            data_size: data_size,
+            root_start: root_start,
+            root_count: root_count,
+            ignore_patterns_hash: Vec::from(&data[hash_start..hash_start + 20]),
+            uuid: String::from_utf8_lossy(&data[V2_DOCKET_SIZE..V2_DOCKET_SIZE + uuid_len]).into_owned(),
+        })
+    }
+
+    pub fn data_filename(&self) -> String {
+        format!("dirstate.{}", self.uuid)
+    }
+}
+
+#[derive(Debug)]
+pub struct Dirstate {
+    pub p1: Vec<u8>,
+    pub p2: Vec<u8>,
+    entries: BTreeMap<String, Entry>,
+    /// Copy sources by destination
+    copies: BTreeMap<String, String>,
+    /// Cached directory mtimes, from v2 only
+    directories: BTreeMap<String, Timestamp>,
+}
+
+impl Dirstate {
+    /// The dirstate of a repo, in the format its requirements call for.
+    /// Without one, the working directory is empty and its parent is
+    /// null.
+    pub fn read(repo: &Repo) -> Result<Dirstate> {
+        let data = match try!(repo::read_optional(&repo.hg_path().join("dirstate"))) {
+            Some(data) => data,
+            None => return Ok(Dirstate::empty()),
+        };
+        if repo.requires.iter().any(|r| r == "dirstate-v2") {
+            Dirstate::read_v2(repo.hg_path(), &data)
+        } else {
+            Dirstate::parse(&data)
+        }
+    }
+
+    /// Read a v2 dirstate given its docket.
+    pub fn read_v2(hg: &Path, docket: &[u8]) -> Result<Dirstate> {
+        if docket.is_empty() {
+            return Ok(Dirstate::empty());
+        }
+        let docket = try!(Docket::parse(docket));
+        let data = match try!(repo::read_optional(&hg.join(docket.data_filename()))) {
+            Some(data) => data,
+            None => return Err(From::from(format!("dirstate data file {} is missing", docket.data_filename()))),
+        };
+        expect!(data.len() >= docket.data_size as usize, "dirstate data file is truncated");
+        Dirstate::parse_v2(&docket, &data[..docket.data_size as usize])
+    }
+
+    /// Parse a v2 data file.
+    pub fn parse_v2(docket: &Docket, data: &[u8]) -> Result<Dirstate> {
+        let mut result = Dirstate::empty();
+        result.p1 = docket.p1.clone();
+        result.p2 = docket.p2.clone();
+        // Walk the tree without recursion, since it's as deep as the
+        // directory hierarchy
+        let mut pending = vec![(docket.root_start, docket.root_count)];
+        while let Some((start, count)) = pending.pop() {
+            let end = start as usize + count as usize * V2_NODE_SIZE;
+            expect!(end <= data.len(), "dirstate node out of bounds");
+            for i in 0..count as usize {
+                let offset = start as usize + i * V2_NODE_SIZE;
+                let children = try!(result.read_node(data, &data[offset..offset + V2_NODE_SIZE]));
+                if children.1 > 0 {
+                    pending.push(children);
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// Add one v2 node, and return where its children are.
+    fn read_node(&mut self, data: &[u8], node: &[u8]) -> Result<(u32, u32)> {
+        let mut cursor = Cursor::new(node);
+        let path_start = try!(cursor.read_u32::<BigEndian>());
+        let path_len = try!(cursor.read_u16::<BigEndian>());
+        try!(cursor.read_u16::<BigEndian>());
+        let copy_start = try!(cursor.read_u32::<BigEndian>());
+        let copy_len = try!(cursor.read_u16::<BigEndian>());
+        let children_start = try!(cursor.read_u32::<BigEndian>());
+        let children_count = try!(cursor.read_u32::<BigEndian>());
+        try!(cursor.read_u32::<BigEndian>());
+        try!(cursor.read_u32::<BigEndian>());
+        let flags = try!(cursor.read_u16::<BigEndian>());
+        let size = try!(cursor.read_u32::<BigEndian>());
+        let mtime = Timestamp {
+            seconds: try!(cursor.read_u32::<BigEndian>()),
+            nanoseconds: try!(cursor.read_u32::<BigEndian>()),
+        };
+
+        let slice = |start: u32, len: u16| -> Result<String> {
+            let (start, end) = (start as usize, start as usize + len as usize);
+            expect!(end <= data.len(), "dirstate path out of bounds");
+            Ok(String::from_utf8_lossy(&data[start..end]).into_owned())
+        };
+        let path = try!(slice(path_start, path_len));
+        if copy_len > 0 {
+            self.copies.insert(path.clone(), try!(slice(copy_start, copy_len)));
+        }
+
+        if flags & (WDIR_TRACKED | P1_TRACKED | P2_INFO) != 0 {
+            self.entries.insert(path, v1_entry(flags, size, mtime));
+        } else if flags & (DIRECTORY | HAS_MTIME | ALL_UNKNOWN_RECORDED) ==
+                  DIRECTORY | HAS_MTIME | ALL_UNKNOWN_RECORDED {
+            self.directories.insert(path, mtime);
+        }
+        Ok((children_start, children_count))
+    }
+
+    pub fn empty() -> Dirstate {
+        Dirstate {
+            p1: Vec::from(NULL_ID),
+            p2: Vec::from(NULL_ID),
+            entries: BTreeMap::new(),
+            copies: BTreeMap::new(),
+            directories: BTreeMap::new(),
+        }
+    }
+
+    /// Parse the v1 format.
+    pub fn parse(data: &[u8]) -> Result<Dirstate> {
+        if data.is_empty() {
+            return Ok(Dirstate::empty());
+        }
+        expect!(data.len() >= 40, "dirstate is truncated");
+        let mut result = Dirstate::empty();
+        result.p1 = Vec::from(&data[..20]);
+        result.p2 = Vec::from(&data[20..40]);
+        let mut cursor = Cursor::new(&data[40..]);
+        while (cursor.position() as usize) < data.len() - 40 {
+            let c = try!(cursor.read_u8());
+            let state = match State::from_char(c) {
+                Some(state) => state,
+                None => return Err(From::from(format!("unknown dirstate state {:?}", c as char))),
+            };
+            let mode = try!(cursor.read_i32::<BigEndian>());
+            let size = try!(cursor.read_i32::<BigEndian>());
+            let mtime = try!(cursor.read_i32::<BigEndian>());
+            let len = try!(cursor.read_i32::<BigEndian>());
+            expect!(len >= 0, "bad dirstate name length {}", len);
+            let mut name = vec![0; len as usize];
+            try!(cursor.read_exact(&mut name));
+
+            let (path, source) = match name.iter().position(|&c| c == 0) {
+                Some(i) => (&name[..i], Some(&name[i + 1..])),
+                None => (&name[..], None),
+            };
+            let path = String::from_utf8_lossy(path).into_owned();
+            if let Some(source) = source {
+                result.copies.insert(path.clone(), String::from_utf8_lossy(source).into_owned());
+            }
+            result.entries.insert(path,
+                                  Entry {
+                                      state: state,
+                                      mode: mode,
+                                      size: size,
+                                      mtime: mtime,
+                                  });
+        }
+        Ok(result)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn get(&self, path: &str) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+
+    /// Files and their entries, in path order.
+    pub fn iter(&self) -> btree_map::Iter<String, Entry> {
+        self.entries.iter()
+    }
+
+    /// Where a file was copied from, if it was.
+    pub fn copy_source(&self, path: &str) -> Option<&str> {
+        self.copies.get(path).map(|s| &s[..])
+    }
+
+    /// Copy destinations and their sources, in path order.
+    pub fn copies(&self) -> btree_map::Iter<String, String> {
+        self.copies.iter()
+    }
+
+    /// The mtime a directory had when its contents were last listed,
+    /// if that's been cached. Its unknown files can't have changed
+    /// while it still has this mtime.
+    pub fn directory_mtime(&self, path: &str) -> Option<Timestamp> {
+        self.directories.get(path).cloned()
+    }
+
+    /// Directories with cached mtimes, in path order.
+    pub fn directory_mtimes(&self) -> btree_map::Iter<String, Timestamp> {
+        self.directories.iter()
+    }
+}
+
+/// The v1 view of a v2 entry, as Mercurial converts it.
+fn v1_entry(flags: u16, size: u32, mtime: Timestamp) -> Entry {
+    let wdir = flags & WDIR_TRACKED != 0;
+    let p1 = flags & P1_TRACKED != 0;
+    let p2 = flags & P2_INFO != 0;
+    let removed = !wdir && (p1 || p2);
+    let added = wdir && !p1 && !p2;
+    let state = if removed {
+        State::Removed
+    } else if wdir && p1 && p2 {
+        State::Merged
+    } else if added {
+        State::Added
+    } else {
+        State::Normal
+    };
+
+    let reliable = flags & EXPECTED_STATE_IS_MODIFIED == 0;
+    let has_mode = flags & HAS_MODE_AND_SIZE != 0 && reliable;
+    let mode = if !has_mode {
+        0
+    } else {
+        let file_type = if flags & MODE_IS_SYMLINK != 0 { 0o120000 } else { 0o100000 };
+        file_type | if flags & MODE_EXEC_PERM != 0 { 0o755 } else { 0o644 }
+    };
+    let size = if removed && p1 && p2 {
+        SIZE_NONNORMAL
+    } else if p2 {
+        SIZE_FROM_P2
+    } else if removed {
+        0
+    } else if added || !has_mode {
+        SIZE_NONNORMAL
+    } else {
+        size as i32
+    };
+    let has_mtime = flags & HAS_MTIME != 0 && flags & MTIME_SECOND_AMBIGUOUS == 0 && reliable;
+    let mtime = if removed {
+        0
+    } else if p2 || !p1 || !has_mtime {
+        MTIME_UNSET
+    } else {
+        mtime.seconds as i32
+    };
+    Entry {
+        state: state,
+        mode: mode,
+        size: size,
+        mtime: mtime,
+    }
+}
+
+/// The parents of the working directory, reading no more of the
+/// dirstate than needed.
+pub fn read_parents(repo: &Repo) -> Result<(Vec<u8>, Vec<u8>)> {
+    let data = match try!(repo::read_optional(&repo.hg_path().join("dirstate"))) {
+        Some(data) => data,
+        None => return Ok((Vec::from(NULL_ID), Vec::from(NULL_ID))),
+    };
+    if data.starts_with(V2_MARKER) {
+        let docket = try!(Docket::parse(&data));
+        return Ok((docket.p1, docket.p2));
+    }
+    if data.is_empty() {
+        return Ok((Vec::from(NULL_ID), Vec::from(NULL_ID)));
+    }
+    expect!(data.len() >= 40, "dirstate is truncated");
+    Ok((Vec::from(&data[..20]), Vec::from(&data[20..40])))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Dirstate, Docket, State, Timestamp, MTIME_UNSET, SIZE_NONNORMAL};
+
+    fn entry(state: u8, mode: i32, size: i32, mtime: i32, name: &[u8]) -> Vec<u8> {
+        let mut data = vec![state];
+        for &n in &[mode, size, mtime, name.len() as i32] {
+            data.extend(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+        }
+        data.extend(name);
+        data
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut data = vec![1; 20];
+        data.extend(vec![0; 20]);
+        data.extend(entry(b'n', 0o100644, 12, 1460000000, b"b.txt"));
+        data.extend(entry(b'a', 0, -1, -1, b"c.txt\0a.txt"));
+        let dirstate = Dirstate::parse(&data).unwrap();
+        assert_eq!(vec![1; 20], dirstate.p1);
+        assert_eq!(2, dirstate.len());
+        let b = dirstate.get("b.txt").unwrap();
+        assert_eq!((State::Normal, 0o100644, 12), (b.state, b.mode, b.size));
+        assert_eq!(MTIME_UNSET, dirstate.get("c.txt").unwrap().mtime);
+        assert_eq!(Some("a.txt"), dirstate.copy_source("c.txt"));
+        assert_eq!(vec!["b.txt", "c.txt"], dirstate.iter().map(|(p, _)| &p[..]).collect::<Vec<_>>());
+        assert!(Dirstate::parse(&data[..50]).is_err());
+    }
+
+    fn be(n: u32, len: usize) -> Vec<u8> {
+        (0..len).rev().map(|i| (n >> (8 * i)) as u8).collect()
+    }
+
+    fn node(path: (u32, u16), copy: (u32, u16), children: (u32, u32), flags: u16, size: u32,
+            mtime: u32)
+            -> Vec<u8> {
+        let mut data = be(path.0, 4);
+        data.extend(be(path.1 as u32, 2));
+        data.extend(be(0, 2));
+        data.extend(be(copy.0, 4));
+        data.extend(be(copy.1 as u32, 2));
+        data.extend(be(children.0, 4));
+        data.extend(be(children.1, 4));
+        data.extend(be(0, 4));
+        data.extend(be(0, 4));
+        data.extend(be(flags as u32, 2));
+        data.extend(be(size, 4));
+        data.extend(be(mtime, 4));
+        data.extend(be(0, 4));
+        data
+    }
+
+    #[test]
+    fn test_parse_v2() {
+        // Paths, then the root nodes, then the child of `dir`
+        let mut data = b"a.txtdirdir/b.txtdir2".to_vec();
+        // Tracked everywhere, with mode, size and mtime
+        data.extend(node((0, 5), (0, 0), (0, 0), 1 | 2 | 1 << 3 | 1 << 10 | 1 << 11, 7, 1460000000));
+        // A directory with a cached mtime
+        data.extend(node((5, 3), (0, 0), (153, 1), 1 << 11 | 1 << 13 | 1 << 14, 0, 1470000000));
+        // A directory with an mtime but not all of its unknown files
+        data.extend(node((17, 4), (0, 0), (0, 0), 1 << 11 | 1 << 13, 0, 1470000000));
+        // Added, copied from a.txt
+        data.extend(node((8, 9), (0, 5), (0, 0), 1, 0, 0));
+
+        // Packed as Mercurial's docket.py does, with struct format
+        // ">12s32s32s44sLB": the parents, the tree metadata (root nodes at
+        // 21, 3 of them, 2 nodes with entries, 1 copy, no unreachable
+        // bytes, the ignore patterns hash a0..b3), 197 bytes of data and
+        // the uuid
+        let docket = b"dirstate-v2\n\
+                                \x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\
+                                \x01\x01\x01\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                                \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                                \x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                                \x00\x00\x00\x15\x00\x00\x00\x03\x00\x00\x00\x02\x00\x00\x00\x01\
+                                \x00\x00\x00\x00\x00\x00\x00\x00\xa0\xa1\xa2\xa3\xa4\xa5\xa6\xa7\
+                                \xa8\xa9\xaa\xab\xac\xad\xae\xaf\xb0\xb1\xb2\xb3\x00\x00\x00\xc5\
+                                \x04\
+                                abcd";
+
+        let docket = Docket::parse(docket).unwrap();
+        assert_eq!(vec![1; 20], docket.p1);
+        assert_eq!(vec![0; 20], docket.p2);
+        assert_eq!(data.len() as u32, docket.data_size);
+        assert_eq!((0xa0..0xb4).collect::<Vec<u8>>(), docket.ignore_patterns_hash);
+        assert_eq!("dirstate.abcd", docket.data_filename());
+        let dirstate = Dirstate::parse_v2(&docket, &data).unwrap();
+        assert_eq!(2, dirstate.len());
+        let a = dirstate.get("a.txt").unwrap();
+        assert_eq!((State::Normal, 0o100755, 7, 1460000000), (a.state, a.mode, a.size, a.mtime));
+        let b = dirstate.get("dir/b.txt").unwrap();
+        assert_eq!((State::Added, SIZE_NONNORMAL, MTIME_UNSET), (b.state, b.size, b.mtime));
+        assert_eq!(Some("a.txt"), dirstate.copy_source("dir/b.txt"));
+        assert_eq!(Some(Timestamp {
+                       seconds: 1470000000,
+                       nanoseconds: 0,
+                   }),
+                   dirstate.directory_mtime("dir"));
+        assert_eq!(None, dirstate.directory_mtime("dir2"));
+        assert!(Dirstate::parse_v2(&docket, &data[..100]).is_err());
+    }
+}
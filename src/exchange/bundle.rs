@@ -0,0 +1,812 @@
+//! Reading and writing bundle files, as written by `hg bundle` and left
+//! behind by `hg strip` as backups.
+//!
+//! A version 1 bundle is `HG10`, a compression (`UN`, `GZ` for zlib or
+//! `BZ` for bzip2 without its leading `BZ`), then a version 01
+//! changegroup. Version 2, `HG20`, is a container of parts:
+//!
+//! ```text
+//! HG20 <params size, i32> <stream params>
+//! <header size, i32> <part header> <payload chunks>... <0, i32>
+//! ...
+//! <0, i32>
+//! ```
+//!
+//! Stream parameters are URL-quoted `name=value` pairs separated by
+//! spaces; a `Compression` parameter compresses everything after them.
+//! A part header is its type, id and parameters, and its payload is
+//! split into chunks, each preceded by its size and ending with an
+//! empty one. A part whose type has capitals is mandatory: a reader
+//! which doesn't know it must fail rather than skip it. Integers are
+//! big endian.
+//!
+//! Both versions are read as a sequence of parts, a version 1 bundle
+//! being a single `changegroup` part. Bundles are written with a single
+//! `changegroup` part too.
+
+extern crate bzip2;
+extern crate byteorder;
+extern crate flate2;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+use std::str;
+
+use self::bzip2::read::BzDecoder;
+use self::bzip2::write::BzEncoder;
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use self::flate2::read::ZlibDecoder;
+use self::flate2::write::ZlibEncoder;
+
+use exchange::changegroup::{self, Changegroup, Version};
+use obsolete::{self, Marker};
+use phases::Phase;
+use repo::Repo;
+use util::Result;
+
+/// The size of the payload chunks of the parts we write.
+const CHUNK_SIZE: usize = 32768;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+}
+
+/// The kind of bundle to write, named as for `hg bundle --type`: a
+/// compression, `none`, `gzip` or `bzip2`, and a version, `v1` for
+/// `HG10` or `v2` or `v3` for `HG20` with a changegroup of that
+/// version, joined by `-`. Either may be left out, defaulting to
+/// `bzip2-v2`. `none-v2;stream=v2` is a stream clone bundle instead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Spec {
+    pub compression: Compression,
+    pub version: Version,
+    pub stream: bool,
+}
+
+impl Spec {
+    pub fn parse(spec: &str) -> Result<Spec> {
+        let mut result = Spec {
+            compression: Compression::Bzip2,
+            version: Version::V2,
+            stream: false,
+        };
+        let mut params = spec.split(';');
+        let spec = params.next().unwrap();
+        for param in params {
+            match param {
+                "stream=v2" => result.stream = true,
+                _ => return Err(From::from(format!("unsupported bundle parameter {:?}", param))),
+            }
+        }
+        let (compression, version) = match spec.find('-') {
+            Some(i) => (Some(&spec[..i]), Some(&spec[i + 1..])),
+            None if spec.starts_with('v') => (None, Some(spec)),
+            None => (Some(spec), None),
+        };
+        match compression {
+            None => (),
+            Some("none") => result.compression = Compression::None,
+            Some("gzip") => result.compression = Compression::Gzip,
+            Some("bzip2") => result.compression = Compression::Bzip2,
+            Some(c) => return Err(From::from(format!("unknown bundle compression {:?}", c))),
+        }
+        match version {
+            None => (),
+            Some("v1") => result.version = Version::V1,
+            Some("v2") => result.version = Version::V2,
+            Some("v3") => result.version = Version::V3,
+            Some(v) => return Err(From::from(format!("unknown bundle version {:?}", v))),
+        }
+        expect!(!result.stream || (result.compression == Compression::None && result.version == Version::V2),
+                "stream bundles must be none-v2");
+        Ok(result)
+    }
+}
+
+/// A part of a bundle, with its payload.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Part {
+    /// The type, in lower case
+    pub name: String,
+    pub mandatory: bool,
+    pub id: u32,
+    pub mandatory_params: Vec<(String, String)>,
+    pub advisory_params: Vec<(String, String)>,
+    /// The payload, once read into memory
+    pub data: Vec<u8>,
+}
+
+impl Part {
+    /// The value of a parameter, mandatory or advisory.
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.mandatory_params
+            .iter()
+            .chain(&self.advisory_params)
+            .find(|&&(ref n, _)| n == name)
+            .map(|&(_, ref v)| &v[..])
+    }
+
+    /// The heads of each phase in a `phase-heads` part.
+    pub fn phase_heads(&self) -> Result<Vec<(Phase, Vec<u8>)>> {
+        expect!(self.name == "phase-heads", "not a phase-heads part: {}", self.name);
+        expect!(self.data.len() % 24 == 0, "bad phase-heads part size {}", self.data.len());
+        let mut result = vec![];
+        for entry in self.data.chunks(24) {
+            let n = try!((&entry[..4]).read_u32::<BigEndian>());
+            match Phase::from_number(n) {
+                Some(phase) => result.push((phase, Vec::from(&entry[4..]))),
+                None => return Err(From::from(format!("unknown phase {}", n))),
+            }
+        }
+        Ok(result)
+    }
+
+    /// The bookmarks of a `bookmarks` part, by name. A null node means
+    /// the bookmark is deleted.
+    pub fn bookmarks(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        expect!(self.name == "bookmarks", "not a bookmarks part: {}", self.name);
+        let mut result = vec![];
+        let mut rest = &self.data[..];
+        while !rest.is_empty() {
+            expect!(rest.len() >= 22, "truncated bookmarks part");
+            let len = try!((&rest[20..22]).read_u16::<BigEndian>()) as usize;
+            expect!(rest.len() >= 22 + len, "truncated bookmarks part");
+            let name = try!(String::from_utf8(Vec::from(&rest[22..22 + len])));
+            result.push((name, Vec::from(&rest[..20])));
+            rest = &rest[22 + len..];
+        }
+        Ok(result)
+    }
+
+    /// The markers of an `obsmarkers` part, which holds them as an
+    /// obsstore does.
+    pub fn obsmarkers(&self) -> Result<Vec<Marker>> {
+        expect!(self.name == "obsmarkers", "not an obsmarkers part: {}", self.name);
+        obsolete::parse_markers(&self.data)
+    }
+}
+
+/// A bundle being read, as an iterator over its parts with their
+/// payloads in memory, or part by part with `next_header`, reading
+/// each payload as it goes.
+pub struct Bundle {
+    /// The header, such as `HG10BZ` or `HG20`
+    pub format: String,
+    /// The stream parameters of a version 2 bundle
+    pub params: Vec<(String, String)>,
+    reader: Box<Read>,
+    done: bool,
+    /// Whether the payload of the last part has more to read
+    in_payload: bool,
+    /// What's left of the payload's current chunk
+    left: usize,
+}
+
+impl Bundle {
+    pub fn open(path: &Path) -> Result<Bundle> {
+        let file = try!(File::open(path));
+        Bundle::read(Box::new(BufReader::new(file)))
+    }
+
+    /// Read a bundle's header, leaving the parts to iterate over.
+    pub fn read(mut reader: Box<Read>) -> Result<Bundle> {
+        let mut magic = [0; 4];
+        try!(read_exact(&mut reader, &mut magic));
+        match &magic {
+            b"HG10" => {
+                let mut compression = [0; 2];
+                try!(read_exact(&mut reader, &mut compression));
+                // The bzip2 stream's own magic is taken to be part of
+                // the header
+                let reader = match &compression {
+                    b"UN" => reader,
+                    b"GZ" => Box::new(ZlibDecoder::new(reader)),
+                    b"BZ" => Box::new(BzDecoder::new(Cursor::new(&b"BZ"[..]).chain(reader))),
+                    _ => {
+                        return Err(From::from(format!("unknown bundle compression {:?}",
+                                                      String::from_utf8_lossy(&compression))))
+                    }
+                };
+                Ok(Bundle {
+                    format: format!("HG10{}", String::from_utf8_lossy(&compression)),
+                    params: vec![],
+                    reader: reader,
+                    done: false,
+                    in_payload: false,
+                    left: 0,
+                })
+            }
+            b"HG20" => {
+                let size = try!(read_i32(&mut reader));
+                expect!(size >= 0, "negative bundle2 stream parameter size");
+                let mut data = vec![0; size as usize];
+                try!(read_exact(&mut reader, &mut data));
+                let params = try!(parse_params(&try!(String::from_utf8(data))));
+                let mut compression = None;
+                for &(ref name, ref value) in &params {
+                    match &name[..] {
+                        "Compression" => compression = Some(value.clone()),
+                        // Unknown parameters starting with a capital
+                        // can't be ignored
+                        _ if name.starts_with(|c: char| c.is_uppercase()) => {
+                            return Err(From::from(format!("unsupported bundle2 stream parameter {:?}", name)))
+                        }
+                        _ => (),
+                    }
+                }
+                let reader = match compression.as_ref().map(|c| &c[..]) {
+                    None | Some("UN") => reader,
+                    Some("GZ") => Box::new(ZlibDecoder::new(reader)),
+                    Some("BZ") => Box::new(BzDecoder::new(reader)),
+                    Some(c) => return Err(From::from(format!("unsupported bundle compression {:?}", c))),
+                };
+                Ok(Bundle {
+                    format: String::from("HG20"),
+                    params: params,
+                    reader: reader,
+                    done: false,
+                    in_payload: false,
+                    left: 0,
+                })
+            }
+            _ => Err(From::from("not a bundle")),
+        }
+    }
+
+    /// The next part without its payload, which is left to be read
+    /// with `payload` or `changegroup` instead of the iterator reading
+    /// it into memory. What's left of the previous part's payload is
+    /// skipped.
+    pub fn next_header(&mut self) -> Result<Option<Part>> {
+        if self.in_payload {
+            try!(io::copy(&mut self.payload(), &mut io::sink()));
+        }
+        if self.done {
+            return Ok(None);
+        }
+        if self.format != "HG20" {
+            // The rest is the changegroup
+            self.done = true;
+            self.in_payload = true;
+            return Ok(Some(Part {
+                name: String::from("changegroup"),
+                mandatory: true,
+                id: 0,
+                mandatory_params: vec![(String::from("version"), String::from("01"))],
+                advisory_params: vec![],
+                data: vec![],
+            }));
+        }
+
+        let size = try!(read_i32(&mut self.reader));
+        if size == 0 {
+            self.done = true;
+            return Ok(None);
+        }
+        expect!(size > 0, "bad bundle2 part header size {}", size);
+        let mut header = vec![0; size as usize];
+        try!(read_exact(&mut self.reader, &mut header));
+        let part = try!(parse_part_header(&header));
+        self.in_payload = true;
+        self.left = 0;
+        Ok(Some(part))
+    }
+
+    /// The payload of the part `next_header` returned, or what's left
+    /// of it.
+    pub fn payload(&mut self) -> Payload {
+        Payload { bundle: self }
+    }
+
+    /// The changegroup of the `changegroup` part `next_header`
+    /// returned, read from its payload as it goes.
+    pub fn changegroup(&mut self, part: &Part) -> Result<Changegroup<Payload>> {
+        expect!(part.name == "changegroup", "not a changegroup part: {}", part.name);
+        let version = try!(Version::from_name(part.param("version").unwrap_or("01")));
+        Ok(Changegroup::new(self.payload(), version))
+    }
+
+    /// Read what's left of the payload of `part`, the part
+    /// `next_header` returned, into its data.
+    pub fn read_payload(&mut self, part: &mut Part) -> Result<()> {
+        try!(self.payload().read_to_end(&mut part.data));
+        Ok(())
+    }
+
+    fn next_part(&mut self) -> Result<Option<Part>> {
+        let mut part = match try!(self.next_header()) {
+            Some(part) => part,
+            None => return Ok(None),
+        };
+        try!(self.read_payload(&mut part));
+        Ok(Some(part))
+    }
+}
+
+/// A reader of a part's payload, which in a version 2 bundle is split
+/// into chunks.
+pub struct Payload<'a> {
+    bundle: &'a mut Bundle,
+}
+
+impl<'a> Read for Payload<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bundle = &mut *self.bundle;
+        if !bundle.in_payload || buf.is_empty() {
+            return Ok(0);
+        }
+        if bundle.format != "HG20" {
+            let n = try!(bundle.reader.read(buf));
+            bundle.in_payload = n != 0;
+            return Ok(n);
+        }
+        if bundle.left == 0 {
+            let size = try!(read_i32(&mut bundle.reader).map_err(to_io_error));
+            // -1 announces an out of band part, sent when the sender
+            // failed midway
+            if size < 0 {
+                return Err(io::Error::new(ErrorKind::Other, "interrupted bundle2 part"));
+            }
+            if size == 0 {
+                bundle.in_payload = false;
+                return Ok(0);
+            }
+            bundle.left = size as usize;
+        }
+        let n = ::std::cmp::min(bundle.left, buf.len());
+        try!(read_exact(&mut bundle.reader, &mut buf[..n]).map_err(to_io_error));
+        bundle.left -= n;
+        Ok(n)
+    }
+}
+
+impl Iterator for Bundle {
+    type Item = Result<Part>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_part() {
+            Ok(part) => part.map(Ok),
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Write a bundle of the changesets `revs`, for a receiver which has
+/// their other ancestors.
+pub fn create(path: &Path, repo: &Repo, revs: &[i32], spec: Spec) -> Result<()> {
+    let mut out = BufWriter::new(try!(File::create(path)));
+    try!(write(&mut out, repo, revs, spec));
+    try!(out.flush());
+    Ok(())
+}
+
+pub fn write(out: &mut Write, repo: &Repo, revs: &[i32], spec: Spec) -> Result<()> {
+    if spec.version == Version::V1 {
+        try!(out.write_all(b"HG10"));
+        match spec.compression {
+            Compression::None => {
+                try!(out.write_all(b"UN"));
+                changegroup::write(repo, revs, Version::V1, out)
+            }
+            Compression::Gzip => {
+                try!(out.write_all(b"GZ"));
+                let mut encoder = ZlibEncoder::new(out, flate2::Compression::Default);
+                try!(changegroup::write(repo, revs, Version::V1, &mut encoder));
+                try!(encoder.finish());
+                Ok(())
+            }
+            Compression::Bzip2 => {
+                // The `BZ` of the header is the start of the stream
+                try!(out.write_all(b"BZ"));
+                let skip = Skip {
+                    inner: out,
+                    skip: 2,
+                };
+                let mut encoder = BzEncoder::new(skip, bzip2::Compression::Best);
+                try!(changegroup::write(repo, revs, Version::V1, &mut encoder));
+                try!(encoder.finish());
+                Ok(())
+            }
+        }
+    } else {
+        try!(out.write_all(b"HG20"));
+        let params: &[u8] = match spec.compression {
+            Compression::None => b"",
+            Compression::Gzip => b"Compression=GZ",
+            Compression::Bzip2 => b"Compression=BZ",
+        };
+        try!(out.write_i32::<BigEndian>(params.len() as i32));
+        try!(out.write_all(params));
+        match spec.compression {
+            Compression::None => write_parts(out, repo, revs, spec.version),
+            Compression::Gzip => {
+                let mut encoder = ZlibEncoder::new(out, flate2::Compression::Default);
+                try!(write_parts(&mut encoder, repo, revs, spec.version));
+                try!(encoder.finish());
+                Ok(())
+            }
+            Compression::Bzip2 => {
+                // Unlike version 1, the stream keeps its magic
+                let mut encoder = BzEncoder::new(out, bzip2::Compression::Best);
+                try!(write_parts(&mut encoder, repo, revs, spec.version));
+                try!(encoder.finish());
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The parts of a version 2 bundle and the end of the stream.
+fn write_parts(out: &mut Write, repo: &Repo, revs: &[i32], version: Version) -> Result<()> {
+    try!(write_part(out,
+                    "CHANGEGROUP",
+                    &[(String::from("version"), String::from(version.name()))],
+                    &[(String::from("nbchanges"), revs.len().to_string())],
+                    &mut |payload| changegroup::write(repo, revs, version, payload)));
+    try!(out.write_i32::<BigEndian>(0));
+    Ok(())
+}
+
+/// Write a part of a version 2 bundle, mandatory if its type has
+/// capitals, with the payload `payload` writes.
+pub fn write_part(out: &mut Write,
+                  name: &str,
+                  mandatory: &[(String, String)],
+                  advisory: &[(String, String)],
+                  payload: &mut FnMut(&mut Write) -> Result<()>)
+                  -> Result<()> {
+    try!(write_part_header(out, name, 0, mandatory, advisory));
+    let mut chunked = Chunked {
+        inner: out,
+        buffer: Vec::with_capacity(CHUNK_SIZE),
+    };
+    try!(payload(&mut chunked));
+    try!(chunked.emit());
+    try!(chunked.inner.write_i32::<BigEndian>(0));
+    Ok(())
+}
+
+/// Write a part header, preceded by its size, with the type capitalized
+/// if the part is mandatory.
+fn write_part_header(out: &mut Write,
+                     name: &str,
+                     id: u32,
+                     mandatory: &[(String, String)],
+                     advisory: &[(String, String)])
+                     -> Result<()> {
+    let mut header = vec![];
+    expect!(name.len() < 256, "bundle2 part type too long: {}", name);
+    header.push(name.len() as u8);
+    header.extend_from_slice(name.as_bytes());
+    try!(header.write_u32::<BigEndian>(id));
+    header.push(mandatory.len() as u8);
+    header.push(advisory.len() as u8);
+    let params: Vec<_> = mandatory.iter().chain(advisory).collect();
+    for &&(ref name, ref value) in &params {
+        expect!(name.len() < 256 && value.len() < 256, "bundle2 part parameter too long: {}", name);
+        header.push(name.len() as u8);
+        header.push(value.len() as u8);
+    }
+    for &&(ref name, ref value) in &params {
+        header.extend_from_slice(name.as_bytes());
+        header.extend_from_slice(value.as_bytes());
+    }
+    try!(out.write_i32::<BigEndian>(header.len() as i32));
+    try!(out.write_all(&header));
+    Ok(())
+}
+
+/// A writer which drops the first `skip` bytes written to it.
+struct Skip<W: Write> {
+    inner: W,
+    skip: usize,
+}
+
+impl<W: Write> Write for Skip<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if self.skip == 0 {
+            return self.inner.write(data);
+        }
+        let n = ::std::cmp::min(self.skip, data.len());
+        self.skip -= n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A writer of part payload chunks. Flushing writes out what is
+/// buffered as a chunk, and flushes the writer underneath, which a full
+/// chunk doesn't, so as not to cut short its compression.
+struct Chunked<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Chunked<W> {
+    /// Write out what is buffered as a chunk.
+    fn emit(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            try!(self.inner.write_i32::<BigEndian>(self.buffer.len() as i32));
+            try!(self.inner.write_all(&self.buffer));
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let n = ::std::cmp::min(CHUNK_SIZE - self.buffer.len(), data.len());
+        self.buffer.extend_from_slice(&data[..n]);
+        if self.buffer.len() == CHUNK_SIZE {
+            try!(self.emit());
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        try!(self.emit());
+        self.inner.flush()
+    }
+}
+
+/// Parse a part header: its type, id, parameter counts, the sizes of
+/// each parameter's name and value, and then the names and values.
+fn parse_part_header(header: &[u8]) -> Result<Part> {
+    let mut cursor = Cursor::new(header);
+    let len = try!(cursor.read_u8()) as usize;
+    let mut name = vec![0; len];
+    try!(read_exact(&mut cursor, &mut name));
+    let name = try!(String::from_utf8(name));
+    let id = try!(cursor.read_u32::<BigEndian>());
+    let mandatory_count = try!(cursor.read_u8()) as usize;
+    let advisory_count = try!(cursor.read_u8()) as usize;
+    let mut sizes = vec![];
+    for _ in 0..mandatory_count + advisory_count {
+        sizes.push((try!(cursor.read_u8()) as usize, try!(cursor.read_u8()) as usize));
+    }
+    let mut params = vec![];
+    for (name_size, value_size) in sizes {
+        let mut name = vec![0; name_size];
+        try!(read_exact(&mut cursor, &mut name));
+        let mut value = vec![0; value_size];
+        try!(read_exact(&mut cursor, &mut value));
+        params.push((try!(String::from_utf8(name)), try!(String::from_utf8(value))));
+    }
+    let advisory = params.split_off(mandatory_count);
+    Ok(Part {
+        mandatory: name.chars().any(|c| c.is_uppercase()),
+        name: name.to_lowercase(),
+        id: id,
+        mandatory_params: params,
+        advisory_params: advisory,
+        data: vec![],
+    })
+}
+
+/// Parse stream parameters, which may have no value.
+fn parse_params(data: &str) -> Result<Vec<(String, String)>> {
+    let mut result = vec![];
+    for param in data.split(' ').filter(|p| !p.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(i) => (&param[..i], &param[i + 1..]),
+            None => (param, ""),
+        };
+        result.push((try!(unquote(name)), try!(unquote(value))));
+    }
+    Ok(result)
+}
+
+/// URL quoting, leaving letters, digits and `_.-~/` as they are.
+pub fn quote(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    for &c in text.as_bytes() {
+        if (c as char).is_ascii_alphanumeric() || b"_.-~/".contains(&c) {
+            result.push(c as char);
+        } else {
+            result.push_str(&format!("%{:02X}", c));
+        }
+    }
+    result
+}
+
+/// Undo URL quoting.
+pub fn unquote(text: &str) -> Result<String> {
+    let bytes = text.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(c) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                result.push(c);
+                i += 3;
+                continue;
+            }
+        }
+        result.push(bytes[i]);
+        i += 1;
+    }
+    Ok(try!(String::from_utf8(result)))
+}
+
+/// An error reading a bundle, as an `io::Error` for `Read`.
+fn to_io_error(e: Box<Error>) -> io::Error {
+    io::Error::new(ErrorKind::Other, e.to_string())
+}
+
+fn read_i32<R: Read>(reader: &mut R) -> Result<i32> {
+    let mut data = [0; 4];
+    try!(read_exact(reader, &mut data));
+    Ok(try!((&data[..]).read_i32::<BigEndian>()))
+}
+
+fn read_exact<R: Read>(reader: &mut R, data: &mut [u8]) -> Result<()> {
+    match reader.read_exact(data) {
+        Ok(()) => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Err(From::from("bundle ended unexpectedly")),
+        Err(e) => Err(From::from(e)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Cursor, Write};
+    use super::{Bundle, Compression, Spec, CHUNK_SIZE, parse_params, parse_part_header, quote, unquote, write_part,
+                write_part_header};
+    use exchange::changegroup::Version;
+    use phases::Phase;
+
+    #[test]
+    fn test_spec() {
+        assert_eq!(Spec {
+                       compression: Compression::Bzip2,
+                       version: Version::V2,
+                       stream: false,
+                   },
+                   Spec::parse("bzip2-v2").unwrap());
+        assert_eq!(Spec {
+                       compression: Compression::None,
+                       version: Version::V2,
+                       stream: false,
+                   },
+                   Spec::parse("none").unwrap());
+        assert_eq!(Spec {
+                       compression: Compression::Bzip2,
+                       version: Version::V1,
+                       stream: false,
+                   },
+                   Spec::parse("v1").unwrap());
+        assert_eq!(Version::V3, Spec::parse("gzip-v3").unwrap().version);
+        assert!(Spec::parse("none-v2;stream=v2").unwrap().stream);
+        assert!(Spec::parse("zstd-v2").is_err());
+        assert!(Spec::parse("gzip-v4").is_err());
+        assert!(Spec::parse("bzip2-v2;stream=v2").is_err());
+        assert!(Spec::parse("none-v2;stream=v3").is_err());
+    }
+
+    #[test]
+    fn test_part_header() {
+        let mandatory = vec![(String::from("version"), String::from("02"))];
+        let advisory = vec![(String::from("nbchanges"), String::from("12"))];
+        let mut data = vec![];
+        write_part_header(&mut data, "CHANGEGROUP", 3, &mandatory, &advisory).unwrap();
+        assert_eq!(data.len() - 4, data[3] as usize);
+        let part = parse_part_header(&data[4..]).unwrap();
+        assert_eq!("changegroup", part.name);
+        assert!(part.mandatory);
+        assert_eq!(3, part.id);
+        assert_eq!(mandatory, part.mandatory_params);
+        assert_eq!(advisory, part.advisory_params);
+    }
+
+    #[test]
+    fn test_parse_params() {
+        assert_eq!(vec![(String::from("Compression"), String::from("BZ")),
+                        (String::from("a b"), String::new())],
+                   parse_params("Compression=BZ a%20b").unwrap());
+        assert_eq!("generaldelta%2Crevlogv1", quote("generaldelta,revlogv1"));
+        assert_eq!("a,b c", unquote(&quote("a,b c")).unwrap());
+    }
+
+    #[test]
+    fn test_bundle2() {
+        let mut data = Vec::from(&b"HG20\0\0\0\0"[..]);
+        // A phase-heads part with one advisory parameter, its payload in
+        // two chunks
+        data.extend_from_slice(b"\0\0\0\x16\x0bPHASE-HEADS\0\0\0\x07\0\x01\x01\x01xy");
+        data.extend_from_slice(b"\0\0\0\x04\0\0\0\x01");
+        data.extend_from_slice(b"\0\0\0\x14");
+        data.extend_from_slice(&[0xab; 20]);
+        data.extend_from_slice(b"\0\0\0\0\0\0\0\0");
+        let parts: Vec<_> = Bundle::read(Box::new(Cursor::new(data))).unwrap().map(|p| p.unwrap()).collect();
+        assert_eq!(1, parts.len());
+        assert_eq!("phase-heads", parts[0].name);
+        assert!(parts[0].mandatory);
+        assert_eq!(7, parts[0].id);
+        assert_eq!(Some("y"), parts[0].param("x"));
+        assert_eq!(vec![(Phase::Draft, vec![0xab; 20])], parts[0].phase_heads().unwrap());
+
+        assert!(Bundle::read(Box::new(Cursor::new(&b"HG20\0\0\0\x0eCompression=ZS"[..]))).is_err());
+        assert!(Bundle::read(Box::new(Cursor::new(&b"HG30"[..]))).is_err());
+    }
+
+    #[test]
+    fn test_next_header() {
+        let mut data = Vec::from(&b"HG20\0\0\0\0"[..]);
+        // An empty version 02 changegroup, its payload in two chunks
+        // which split a chunk of the changegroup
+        data.extend_from_slice(b"\0\0\0\x1d\x0bCHANGEGROUP\0\0\0\0\x01\0\x07\x02version02");
+        data.extend_from_slice(b"\0\0\0\x05\0\0\0\0\0");
+        data.extend_from_slice(b"\0\0\0\x07\0\0\0\0\0\0\0");
+        data.extend_from_slice(b"\0\0\0\0");
+        data.extend_from_slice(b"\0\0\0\x16\x0bPHASE-HEADS\0\0\0\x07\0\x01\x01\x01xy");
+        data.extend_from_slice(b"\0\0\0\x18\0\0\0\x01");
+        data.extend_from_slice(&[0xab; 20]);
+        data.extend_from_slice(b"\0\0\0\0\0\0\0\0");
+
+        let mut bundle = Bundle::read(Box::new(Cursor::new(data.clone()))).unwrap();
+        let part = bundle.next_header().unwrap().unwrap();
+        assert_eq!("changegroup", part.name);
+        assert_eq!(0, bundle.changegroup(&part).unwrap().count());
+        let mut part = bundle.next_header().unwrap().unwrap();
+        assert_eq!("phase-heads", part.name);
+        bundle.read_payload(&mut part).unwrap();
+        assert_eq!(vec![(Phase::Draft, vec![0xab; 20])], part.phase_heads().unwrap());
+        assert!(bundle.next_header().unwrap().is_none());
+
+        // A payload which isn't read is skipped
+        let mut bundle = Bundle::read(Box::new(Cursor::new(data))).unwrap();
+        assert_eq!("changegroup", bundle.next_header().unwrap().unwrap().name);
+        assert_eq!("phase-heads", bundle.next_header().unwrap().unwrap().name);
+        assert!(bundle.next_header().unwrap().is_none());
+    }
+
+    /// A writer which counts how often it's flushed.
+    struct Flushes {
+        data: Vec<u8>,
+        flushes: usize,
+    }
+
+    impl Write for Flushes {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            self.data.write(data)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_part() {
+        let mut out = Flushes {
+            data: vec![],
+            flushes: 0,
+        };
+        write_part(&mut out, "test", &[], &[], &mut |payload| {
+                Ok(try!(payload.write_all(&vec![b'x'; 2 * CHUNK_SIZE + 5])))
+            })
+            .unwrap();
+        assert_eq!(0, out.flushes);
+        // The header, three chunks and the end
+        let header = 4 + out.data[3] as usize;
+        let sizes: Vec<u8> = [0, CHUNK_SIZE + 4, 2 * CHUNK_SIZE + 8, 2 * CHUNK_SIZE + 17]
+            .iter()
+            .flat_map(|&at| out.data[header + at..header + at + 4].to_vec())
+            .collect();
+        assert_eq!(vec![0, 0, 0x80, 0, 0, 0, 0x80, 0, 0, 0, 0, 5, 0, 0, 0, 0], sizes);
+        assert_eq!(header + 2 * CHUNK_SIZE + 21, out.data.len());
+    }
+}
@@ -0,0 +1,722 @@
+//! Reading and writing changegroups, the stream of revisions `hg pull`
+//! receives and bundles contain.
+//!
+//! A changegroup is a sequence of chunks, each a 4-byte big endian
+//! length, which counts itself, followed by data. A chunk of length 0
+//! ends a group. The stream is a group of changelog revisions, then
+//! one of manifest revisions, then for each file a chunk holding its
+//! name followed by a group of its revisions, and finally an empty
+//! chunk. Version 3 also has directory manifest groups, introduced in
+//! the same way as files, after the root manifest group.
+//!
+//! Each revision chunk starts with a header of node ids:
+//!
+//! ```text
+//! 01: node p1 p2 linknode
+//! 02: node p1 p2 deltabase linknode
+//! 03: node p1 p2 deltabase linknode flags
+//! ```
+//!
+//! where `flags` is a 2-byte revlog flag field, and the rest of the
+//! chunk is a delta in the format `patch::apply` takes. In version 1,
+//! a delta is against the previous revision in the group, or against
+//! the first parent for the first one. A null base means the delta is
+//! against the empty text.
+//!
+//! When writing, a delta stored in a revlog is sent as it is if its
+//! base is one the receiver will have; otherwise one is computed.
+
+extern crate byteorder;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{ErrorKind, Read, Write};
+use std::rc::Rc;
+
+use self::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use ancestor;
+use diff;
+use graph::Graph;
+use patch;
+use repo::Repo;
+use revlog::{self, Revlog, TextCache, NULL_ID};
+use store::Store;
+use util::Result;
+
+/// How many texts to keep while computing deltas, or while applying
+/// those of a group.
+const CACHE_SIZE: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Version {
+    V1,
+    V2,
+    V3,
+}
+
+impl Version {
+    /// The version named as in a bundle or the wire protocol: `01`,
+    /// `02` or `03`.
+    pub fn from_name(name: &str) -> Result<Version> {
+        match name {
+            "01" => Ok(Version::V1),
+            "02" => Ok(Version::V2),
+            "03" => Ok(Version::V3),
+            _ => Err(From::from(format!("unknown changegroup version {:?}", name))),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Version::V1 => "01",
+            Version::V2 => "02",
+            Version::V3 => "03",
+        }
+    }
+
+    /// The size of a revision chunk's header.
+    fn header_size(&self) -> usize {
+        match *self {
+            Version::V1 => 80,
+            Version::V2 => 100,
+            Version::V3 => 102,
+        }
+    }
+}
+
+/// Which revlog a revision belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Changelog,
+    /// The root manifest, or a directory manifest in version 3
+    Manifest,
+    File,
+}
+
+/// A revision as sent, with its delta.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub kind: Kind,
+    /// The file, or the directory of a manifest with a trailing `/`, or
+    /// empty for the changelog and the root manifest
+    pub path: String,
+    pub node: Vec<u8>,
+    pub p1: Vec<u8>,
+    pub p2: Vec<u8>,
+    /// The revision the delta applies to, which in version 1 is implied
+    pub base: Vec<u8>,
+    /// The changeset which introduced the revision
+    pub linknode: Vec<u8>,
+    pub flags: u16,
+    pub delta: Vec<u8>,
+}
+
+/// A revision with its text, which includes any filelog metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Revision {
+    pub kind: Kind,
+    pub path: String,
+    pub node: Vec<u8>,
+    pub p1: Vec<u8>,
+    pub p2: Vec<u8>,
+    pub linknode: Vec<u8>,
+    pub flags: u16,
+    pub text: Rc<Vec<u8>>,
+}
+
+/// Where the reader is in the stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Section {
+    Changelog,
+    Manifest,
+    /// Before a directory manifest group, or the end of those
+    Directories,
+    Directory,
+    /// Before a file group, or the end of the stream
+    Files,
+    File,
+    Done,
+}
+
+/// The chunks of a changegroup, in order.
+pub struct Changegroup<R: Read> {
+    reader: R,
+    version: Version,
+    section: Section,
+    path: String,
+    /// The previous node of the group, the version 1 delta base
+    prev: Option<Vec<u8>>,
+}
+
+impl<R: Read> Changegroup<R> {
+    pub fn new(reader: R, version: Version) -> Changegroup<R> {
+        Changegroup {
+            reader: reader,
+            version: version,
+            section: Section::Changelog,
+            path: String::new(),
+            prev: None,
+        }
+    }
+
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// The revisions of the changegroup with their full texts. Deltas
+    /// against revisions which aren't in the changegroup are applied to
+    /// their texts in `repo`.
+    pub fn revisions(self, repo: Option<&Repo>) -> Revisions<R> {
+        Revisions {
+            chunks: self,
+            repo: repo,
+            group: None,
+            positions: HashMap::new(),
+            deltas: vec![],
+            texts: TextCache::new(0),
+            revlog: None,
+        }
+    }
+
+    /// The data of the next chunk, which is empty at the end of a group.
+    fn read_chunk(&mut self) -> Result<Vec<u8>> {
+        let len = match self.reader.read_i32::<BigEndian>() {
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => {
+                return Err(From::from("changegroup ended unexpectedly"))
+            }
+            Err(e) => return Err(From::from(e)),
+        };
+        if len == 0 {
+            return Ok(vec![]);
+        }
+        expect!(len > 4, "invalid changegroup chunk length {}", len);
+        let mut data = vec![0; len as usize - 4];
+        match self.reader.read_exact(&mut data) {
+            Ok(()) => Ok(data),
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => Err(From::from("changegroup ended unexpectedly")),
+            Err(e) => Err(From::from(e)),
+        }
+    }
+
+    fn parse_chunk(&mut self, kind: Kind, data: Vec<u8>) -> Result<Chunk> {
+        let size = self.version.header_size();
+        expect!(data.len() >= size, "changegroup chunk too short");
+        let node = |i: usize| Vec::from(&data[20 * i..20 * (i + 1)]);
+        let (base, linknode, flags) = match self.version {
+            Version::V1 => {
+                let base = match self.prev.take() {
+                    Some(prev) => prev,
+                    None => node(1),
+                };
+                (base, node(3), 0)
+            }
+            Version::V2 => (node(3), node(4), 0),
+            Version::V3 => (node(3), node(4), (data[100] as u16) << 8 | data[101] as u16),
+        };
+        let chunk = Chunk {
+            kind: kind,
+            path: self.path.clone(),
+            node: node(0),
+            p1: node(1),
+            p2: node(2),
+            base: base,
+            linknode: linknode,
+            flags: flags,
+            delta: Vec::from(&data[size..]),
+        };
+        self.prev = Some(chunk.node.clone());
+        Ok(chunk)
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Chunk>> {
+        loop {
+            let kind = match self.section {
+                Section::Changelog => Kind::Changelog,
+                Section::Manifest | Section::Directory => Kind::Manifest,
+                Section::File => Kind::File,
+                Section::Done => return Ok(None),
+                Section::Directories | Section::Files => {
+                    // The name of the next group, or the end
+                    let name = try!(self.read_chunk());
+                    self.section = match (self.section, name.is_empty()) {
+                        (Section::Directories, true) => Section::Files,
+                        (Section::Directories, false) => Section::Directory,
+                        (_, true) => Section::Done,
+                        (_, false) => Section::File,
+                    };
+                    self.path = try!(String::from_utf8(name));
+                    continue;
+                }
+            };
+            let data = try!(self.read_chunk());
+            if !data.is_empty() {
+                return self.parse_chunk(kind, data).map(Some);
+            }
+            self.prev = None;
+            self.section = match self.section {
+                Section::Changelog => Section::Manifest,
+                Section::Manifest if self.version == Version::V3 => Section::Directories,
+                Section::Manifest | Section::File => Section::Files,
+                Section::Directory => Section::Directories,
+                _ => unreachable!(),
+            };
+            self.path.clear();
+        }
+    }
+}
+
+impl<R: Read> Iterator for Changegroup<R> {
+    type Item = Result<Chunk>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_chunk() {
+            Ok(Some(chunk)) => Some(Ok(chunk)),
+            Ok(None) => None,
+            Err(e) => {
+                self.section = Section::Done;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// The revisions of a changegroup with their texts, which are computed
+/// from the texts of earlier revisions of the same group or of the
+/// local repo.
+///
+/// Only the latest texts of a group are kept. In version 1 a delta is
+/// always against the previous one, but otherwise it can be against
+/// any earlier revision, so the deltas of the group are kept too, and a
+/// base which has left the cache is rebuilt from the nearest earlier
+/// text which hasn't.
+pub struct Revisions<'a, R: Read> {
+    chunks: Changegroup<R>,
+    repo: Option<&'a Repo>,
+    /// The revlog the current group adds to
+    group: Option<(Kind, String)>,
+    /// The position in the group of each revision so far
+    positions: HashMap<Vec<u8>, i32>,
+    /// The delta base and delta of each revision so far, except in
+    /// version 1
+    deltas: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Recent texts of the group, by position
+    texts: TextCache,
+    /// The local revlog of the group, once needed, if there is one
+    revlog: Option<Option<Revlog>>,
+}
+
+impl<'a, R: Read> Revisions<'a, R> {
+    /// The text of a delta base from the local repo.
+    fn local_text(&mut self, kind: Kind, path: &str, node: &[u8]) -> Result<Option<Rc<Vec<u8>>>> {
+        let repo = match self.repo {
+            Some(repo) => repo,
+            None => return Ok(None),
+        };
+        // A missing revlog just has nothing to offer
+        if self.revlog.is_none() {
+            self.revlog = Some(match kind {
+                Kind::Changelog | Kind::Manifest if path.is_empty() => None,
+                Kind::Manifest => repo.store().revlog(&format!("meta/{}00manifest", path)).ok(),
+                Kind::File => repo.store().revlog(&Store::filelog_name(path)).ok(),
+                Kind::Changelog => unreachable!(),
+            });
+        }
+        let revlog = match (kind, path.is_empty()) {
+            (Kind::Changelog, _) => repo.changelog.revlog(),
+            (Kind::Manifest, true) => repo.manifestlog.revlog(),
+            _ => {
+                match *self.revlog.as_ref().unwrap() {
+                    Some(ref revlog) => revlog,
+                    None => return Ok(None),
+                }
+            }
+        };
+        match try!(revlog.rev(node)) {
+            Some(rev) => Ok(Some(try!(try!(revlog.index(rev)).text()))),
+            None => Ok(None),
+        }
+    }
+
+    /// The text of a delta base: the empty text, a text of the group,
+    /// or a local one.
+    fn base_text(&mut self, chunk: &Chunk) -> Result<Rc<Vec<u8>>> {
+        let mut node = chunk.base.clone();
+        let mut patches = vec![];
+        let base;
+        loop {
+            if node == NULL_ID {
+                base = Rc::new(vec![]);
+                break;
+            }
+            let pos = match self.positions.get(&node) {
+                Some(&pos) => pos,
+                None => {
+                    match try!(self.local_text(chunk.kind, &chunk.path, &node)) {
+                        Some(text) => {
+                            base = text;
+                            break;
+                        }
+                        None => return Err(From::from(format!("unknown delta base for {}", describe(chunk)))),
+                    }
+                }
+            };
+            if let Some(text) = self.texts.get(pos) {
+                base = text;
+                break;
+            }
+            // Not cached, so rebuilt from its own base
+            match self.deltas.get(pos as usize) {
+                Some(&(ref delta_base, ref delta)) => {
+                    patches.push(delta.clone());
+                    node = delta_base.clone();
+                }
+                None => return Err(From::from(format!("unknown delta base for {}", describe(chunk)))),
+            }
+        }
+        if patches.is_empty() {
+            return Ok(base);
+        }
+        patches.reverse();
+        Ok(Rc::new(try!(patch::apply((*base).clone(), patches))))
+    }
+
+    fn next_revision(&mut self, chunk: Chunk) -> Result<Revision> {
+        let group = (chunk.kind, chunk.path.clone());
+        if self.group.as_ref() != Some(&group) {
+            self.group = Some(group);
+            self.positions.clear();
+            self.deltas.clear();
+            let version = self.chunks.version;
+            self.texts = TextCache::new(if version == Version::V1 { 1 } else { CACHE_SIZE });
+            self.revlog = None;
+        }
+        let base = try!(self.base_text(&chunk));
+        let text = match patch::apply((*base).clone(), vec![chunk.delta.clone()]) {
+            Ok(text) => Rc::new(text),
+            Err(e) => return Err(From::from(format!("{}: {}", describe(&chunk), e))),
+        };
+        // Flags mark texts which are stored differently, and whose
+        // hash is of something else
+        expect!(chunk.flags != 0 || revlog::hash(&text, &chunk.p1, &chunk.p2) == chunk.node,
+                "integrity check failed for {}",
+                describe(&chunk));
+        let pos = self.positions.len() as i32;
+        self.positions.insert(chunk.node.clone(), pos);
+        self.texts.insert(pos, text.clone());
+        if self.chunks.version != Version::V1 {
+            self.deltas.push((chunk.base, chunk.delta));
+        }
+        Ok(Revision {
+            kind: chunk.kind,
+            path: chunk.path,
+            node: chunk.node,
+            p1: chunk.p1,
+            p2: chunk.p2,
+            linknode: chunk.linknode,
+            flags: chunk.flags,
+            text: text,
+        })
+    }
+}
+
+impl<'a, R: Read> Iterator for Revisions<'a, R> {
+    type Item = Result<Revision>;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.chunks.next() {
+            Some(Ok(chunk)) => Some(self.next_revision(chunk)),
+            Some(Err(e)) => Some(Err(e)),
+            None => None,
+        }
+    }
+}
+
+/// Write a changegroup of the changesets `revs`, with the manifest and
+/// file revisions they introduce. The receiver is assumed to have the
+/// ancestors of `revs` which aren't among them.
+pub fn write(repo: &Repo, revs: &[i32], version: Version, out: &mut Write) -> Result<()> {
+    let mut revs = revs.to_vec();
+    revs.sort();
+    revs.dedup();
+    let missing: HashSet<i32> = revs.iter().cloned().collect();
+    let graph = Graph::new(repo.changelog.revlog());
+    let mut common = HashSet::new();
+    for rev in try!(ancestor::ancestors(&graph, &revs, false)) {
+        let rev = try!(rev);
+        if !missing.contains(&rev) {
+            common.insert(rev);
+        }
+    }
+
+    // Each manifest and file revision is sent with the first of the
+    // changesets which refer to it, unless the receiver has it
+    let changelog = repo.changelog.revlog();
+    let manifests = repo.manifestlog.revlog();
+    let mut changesets = vec![];
+    let mut manifest_revs = BTreeMap::new();
+    let mut files: BTreeMap<String, (Revlog, BTreeMap<i32, Vec<u8>>)> = BTreeMap::new();
+    for &rev in &revs {
+        let linknode = Vec::from(try!(changelog.node(rev)));
+        changesets.push((rev, linknode.clone()));
+        let cs = try!(repo.changeset(rev));
+        let manifest_rev = match try!(manifests.rev(&cs.manifest)) {
+            Some(-1) | None => continue,
+            Some(manifest_rev) => manifest_rev,
+        };
+        if common.contains(&try!(manifests.index(manifest_rev)).chunk.link_rev()) {
+            continue;
+        }
+        manifest_revs.entry(manifest_rev).or_insert_with(|| linknode.clone());
+        let manifest = try!(repo.manifestlog.read(&cs.manifest));
+        for path in &cs.files {
+            let entry = match manifest.get(path) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            if !files.contains_key(path) {
+                let revlog = try!(repo.store().revlog(&Store::filelog_name(path)));
+                files.insert(path.clone(), (revlog, BTreeMap::new()));
+            }
+            let &mut (ref revlog, ref mut file_revs) = files.get_mut(path).unwrap();
+            let file_rev = match try!(revlog.rev(&entry.node)) {
+                Some(file_rev) => file_rev,
+                None => return Err(From::from(format!("{}: unknown file revision", path))),
+            };
+            if !common.contains(&try!(revlog.index(file_rev)).chunk.link_rev()) {
+                file_revs.entry(file_rev).or_insert_with(|| linknode.clone());
+            }
+        }
+    }
+    let manifest_revs: Vec<_> = manifest_revs.into_iter().collect();
+
+    try!(write_group(out, version, changelog, &changesets, &|rev| common.contains(&rev)));
+    try!(write_group(out,
+                     version,
+                     manifests,
+                     &manifest_revs,
+                     &|rev| manifests.index(rev).map(|e| common.contains(&e.chunk.link_rev())).unwrap_or(false)));
+    if version == Version::V3 {
+        // No directory manifests
+        try!(write_chunk(out, &[]));
+    }
+    for (path, (revlog, file_revs)) in files {
+        if file_revs.is_empty() {
+            continue;
+        }
+        try!(write_chunk(out, path.as_bytes()));
+        let file_revs: Vec<_> = file_revs.into_iter().collect();
+        try!(write_group(out,
+                         version,
+                         &revlog,
+                         &file_revs,
+                         &|rev| revlog.index(rev).map(|e| common.contains(&e.chunk.link_rev())).unwrap_or(false)));
+    }
+    write_chunk(out, &[])
+}
+
+/// Write a group of revisions of a revlog, in increasing order, with
+/// their linknodes. `known` tells whether the receiver has a rev.
+fn write_group(out: &mut Write,
+               version: Version,
+               revlog: &Revlog,
+               revs: &[(i32, Vec<u8>)],
+               known: &Fn(i32) -> bool)
+               -> Result<()> {
+    let mut cache = TextCache::new(CACHE_SIZE);
+    let mut sent = HashSet::new();
+    let mut prev = -1;
+    for &(rev, ref linknode) in revs {
+        let entry = try!(revlog.index(rev));
+        let p1 = entry.chunk.parent_1();
+        let stored = entry.delta_parent();
+        // A stored full text is better sent as a delta too
+        let base = {
+            let available = |r: i32| r != -1 && (sent.contains(&r) || known(r));
+            match version {
+                Version::V1 if prev == -1 => p1,
+                Version::V1 => prev,
+                _ if available(stored) => stored,
+                _ if available(p1) => p1,
+                _ => prev,
+            }
+        };
+        let delta = if base == -1 {
+            let text = if stored == -1 { Rc::new(entry.data()) } else { try!(revlog.cached_text(rev, &mut cache)) };
+            let mut delta = vec![];
+            for &n in &[0, 0, text.len() as u32] {
+                try!(delta.write_u32::<BigEndian>(n));
+            }
+            delta.extend_from_slice(&text);
+            delta
+        } else if base == stored {
+            entry.data()
+        } else {
+            let base_text = try!(revlog.cached_text(base, &mut cache));
+            diff::delta(&base_text, &try!(revlog.cached_text(rev, &mut cache)))
+        };
+
+        let mut data = vec![];
+        data.extend_from_slice(entry.chunk.c_node_id());
+        data.extend_from_slice(try!(entry.parent_1_id()));
+        data.extend_from_slice(try!(entry.parent_2_id()));
+        if version != Version::V1 {
+            data.extend_from_slice(if base == -1 { NULL_ID } else { try!(revlog.node(base)) });
+        }
+        data.extend_from_slice(linknode);
+        if version == Version::V3 {
+            try!(data.write_u16::<BigEndian>(entry.chunk.flags()));
+        }
+        data.extend_from_slice(&delta);
+        try!(write_chunk(out, &data));
+        sent.insert(rev);
+        prev = rev;
+    }
+    write_chunk(out, &[])
+}
+
+/// Write a chunk, or the end of a group if `data` is empty.
+fn write_chunk(out: &mut Write, data: &[u8]) -> Result<()> {
+    let len = if data.is_empty() { 0 } else { data.len() as u32 + 4 };
+    try!(out.write_u32::<BigEndian>(len));
+    try!(out.write_all(data));
+    Ok(())
+}
+
+/// A revision chunk for error messages.
+fn describe(chunk: &Chunk) -> String {
+    use rustc_serialize::hex::ToHex;
+    let what = match chunk.kind {
+        Kind::Changelog => String::from("changelog"),
+        Kind::Manifest => format!("manifest {}", chunk.path),
+        Kind::File => chunk.path.clone(),
+    };
+    format!("{} revision {}", what.trim_right(), &chunk.node.to_hex()[..12])
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Changegroup, Kind, Version, CACHE_SIZE};
+    use revlog::{hash, NULL_ID};
+
+    /// A chunk, or the end of a group if `data` is empty.
+    fn chunk(out: &mut Vec<u8>, data: &[u8]) {
+        let len = if data.is_empty() { 0 } else { data.len() as u32 + 4 };
+        out.extend_from_slice(&[(len >> 24) as u8, (len >> 16) as u8, (len >> 8) as u8, len as u8]);
+        out.extend_from_slice(data);
+    }
+
+    /// A delta replacing the whole of a text of length `old`.
+    fn full(old: usize, text: &[u8]) -> Vec<u8> {
+        let mut delta = vec![0, 0, 0, 0];
+        for &n in &[old as u32, text.len() as u32] {
+            delta.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+        }
+        delta.extend_from_slice(text);
+        delta
+    }
+
+    /// A delta appending to a text of length `old`.
+    fn append(old: usize, text: &[u8]) -> Vec<u8> {
+        let mut delta = full(old, text);
+        let end = delta[4..8].to_vec();
+        delta[..4].copy_from_slice(&end);
+        delta
+    }
+
+    fn revision(out: &mut Vec<u8>, version: Version, text: &[u8], p1: &[u8], base: &[u8], delta: &[u8]) -> Vec<u8> {
+        let node = hash(text, p1, NULL_ID);
+        let mut data = vec![];
+        data.extend_from_slice(&node);
+        data.extend_from_slice(p1);
+        data.extend_from_slice(NULL_ID);
+        if version != Version::V1 {
+            data.extend_from_slice(base);
+        }
+        data.extend_from_slice(&[7; 20]);
+        if version == Version::V3 {
+            data.extend_from_slice(&[0, 0]);
+        }
+        data.extend_from_slice(delta);
+        chunk(out, &data);
+        node
+    }
+
+    #[test]
+    fn test_revisions() {
+        for &version in &[Version::V1, Version::V3] {
+            let mut cg = vec![];
+            let c0 = revision(&mut cg, version, b"cs0", NULL_ID, NULL_ID, &full(0, b"cs0"));
+            revision(&mut cg, version, b"cs1", &c0, &c0, &full(3, b"cs1"));
+            chunk(&mut cg, b"");
+            revision(&mut cg, version, b"m", NULL_ID, NULL_ID, &full(0, b"m"));
+            chunk(&mut cg, b"");
+            if version == Version::V3 {
+                chunk(&mut cg, b"");
+            }
+            chunk(&mut cg, b"a.txt");
+            revision(&mut cg, version, b"a\n", NULL_ID, NULL_ID, &full(0, b"a\n"));
+            chunk(&mut cg, b"");
+            chunk(&mut cg, b"");
+
+            let revisions: Vec<_> = Changegroup::new(&cg[..], version)
+                .revisions(None)
+                .map(|r| r.unwrap())
+                .collect();
+            let summary: Vec<_> = revisions.iter()
+                .map(|r| (r.kind, &r.path[..], &r.text[..]))
+                .collect();
+            assert_eq!(vec![(Kind::Changelog, "", &b"cs0"[..]),
+                            (Kind::Changelog, "", b"cs1"),
+                            (Kind::Manifest, "", b"m"),
+                            (Kind::File, "a.txt", b"a\n")],
+                       summary);
+            assert_eq!(c0, revisions[1].p1);
+            assert_eq!(vec![7; 20], revisions[3].linknode);
+        }
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut cg = vec![];
+        revision(&mut cg, Version::V2, b"x", NULL_ID, &[1; 20], &full(0, b"x"));
+        let mut revisions = Changegroup::new(&cg[..], Version::V2).revisions(None);
+        assert!(revisions.next().unwrap().is_err());
+        // A delta beyond the end of its base
+        let mut cg = vec![];
+        revision(&mut cg, Version::V2, b"x", NULL_ID, NULL_ID, &full(3, b"x"));
+        let mut revisions = Changegroup::new(&cg[..], Version::V2).revisions(None);
+        assert!(revisions.next().unwrap().is_err());
+        // Truncated
+        let mut chunks = Changegroup::new(&cg[..10], Version::V2);
+        assert!(chunks.next().unwrap().is_err());
+        assert!(chunks.next().is_none());
+    }
+
+    #[test]
+    fn test_evicted_bases() {
+        // A chain longer than the cache, then a delta against its start
+        let mut cg = vec![];
+        let mut texts = vec![b"0\n".to_vec()];
+        let mut nodes = vec![revision(&mut cg, Version::V2, &texts[0], NULL_ID, NULL_ID, &full(0, &texts[0]))];
+        for i in 1..CACHE_SIZE + 8 {
+            let line = format!("{}\n", i).into_bytes();
+            let mut text = texts[i - 1].clone();
+            text.extend_from_slice(&line);
+            let node = revision(&mut cg, Version::V2, &text, &nodes[i - 1], &nodes[i - 1], &append(texts[i - 1].len(), &line));
+            texts.push(text);
+            nodes.push(node);
+        }
+        let mut text = texts[1].clone();
+        text.extend_from_slice(b"x\n");
+        revision(&mut cg, Version::V2, &text, &nodes[1], &nodes[1], &append(texts[1].len(), b"x\n"));
+        texts.push(text);
+        chunk(&mut cg, b"");
+        chunk(&mut cg, b"");
+        chunk(&mut cg, b"");
+
+        let revisions: Vec<_> = Changegroup::new(&cg[..], Version::V2)
+            .revisions(None)
+            .map(|r| (*r.unwrap().text).clone())
+            .collect();
+        assert_eq!(texts, revisions);
+    }
+}
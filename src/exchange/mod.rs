@@ -0,0 +1,5 @@
+//! The formats in which Mercurial moves history between repos.
+
+pub mod bundle;
+pub mod changegroup;
+pub mod streamclone;
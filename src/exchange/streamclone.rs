@@ -0,0 +1,392 @@
+//! Stream clones, version 2: a repo's store files copied as they are,
+//! which is much faster to apply than a changegroup of the same history
+//! since nothing is recomputed.
+//!
+//! A stream bundle is an uncompressed `HG20` bundle with a `stream2`
+//! part, whose parameters are the number of files, their total size and
+//! the requirements describing the store's format, comma separated and
+//! URL-quoted. Its payload is each file as
+//!
+//! ```text
+//! <vfs, 1 byte> <name size, uvarint> <data size, uvarint> <name> <data>
+//! ```
+//!
+//! where the vfs is `s` for the store, whose names are store paths before
+//! encoding, such as `data/foo.txt.i`, or `c` for `.hg/cache`. Unsigned varints hold 7 bits per
+//! byte, lowest first, with the top bit set on all but the last byte.
+
+extern crate byteorder;
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use self::byteorder::{BigEndian, WriteBytesExt};
+
+use exchange::bundle::{self, Bundle};
+use repo::Repo;
+use revlog::{self, TextCache};
+use store::{Encoding, Store};
+use util::Result;
+
+/// How many texts to keep while verifying a revlog.
+const CACHE_SIZE: usize = 32;
+
+/// Store files which aren't revlogs but are sent too.
+const EXTRA_FILES: &'static [&'static str] = &["phaseroots", "obsstore"];
+
+/// The requirements which describe the store's format, and so are sent
+/// with its files. Others, such as `dirstate-v2`, are left out. A store
+/// with any of these which isn't also supported isn't sent.
+const STORE_REQUIREMENTS: &'static [&'static str] = &["changelogv2",
+                                                      "dotencode",
+                                                      "fncache",
+                                                      "generaldelta",
+                                                      "persistent-nodemap",
+                                                      "revlog-compression-zstd",
+                                                      "revlogv1",
+                                                      "revlogv2",
+                                                      "sparserevlog",
+                                                      "store",
+                                                      "treemanifest"];
+
+/// The requirements a stream can be applied with.
+const SUPPORTED_REQUIREMENTS: &'static [&'static str] = &["dotencode",
+                                                          "fncache",
+                                                          "generaldelta",
+                                                          "revlogv1",
+                                                          "sparserevlog",
+                                                          "store"];
+
+/// Where a store file is sent: filelogs and directory manifests first,
+/// then the manifest, other files, and the changelog last, as hg does.
+fn rank(name: &str) -> u8 {
+    if name.starts_with("00changelog.") {
+        3
+    } else if EXTRA_FILES.contains(&name) {
+        2
+    } else if name.starts_with("00manifest.") {
+        1
+    } else {
+        0
+    }
+}
+
+/// How a store file is ordered for sizing: by rank, backwards, then
+/// with each revlog's index before its data.
+fn size_order(name: &str) -> (u8, &str, bool) {
+    let revlog = if name.ends_with(".i") || name.ends_with(".d") { &name[..name.len() - 2] } else { name };
+    (3 - rank(name), revlog, name.ends_with(".d"))
+}
+
+/// The store files of a repo to send, with their sizes, in order. Empty
+/// files are left out.
+///
+/// No lock is taken, and the store may be written to meanwhile. Since
+/// a writer appends to a revlog's data before its index, and to the
+/// changelog after what it refers to, sizes are taken the other way
+/// round, so that everything they cover is complete.
+fn files(repo: &Repo) -> Result<Vec<(String, u64)>> {
+    let store = repo.store();
+    let mut names = try!(store.walk());
+    names.extend(EXTRA_FILES.iter().map(|&name| String::from(name)));
+    names.sort_by(|a, b| size_order(a).cmp(&size_order(b)));
+    let mut result = vec![];
+    for name in names {
+        let size = match fs::metadata(store.join(&name)) {
+            Ok(metadata) => metadata.len(),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(From::from(e)),
+        };
+        if size > 0 {
+            result.push((name, size));
+        }
+    }
+    result.sort_by(|a, b| (rank(&a.0), &a.0).cmp(&(rank(&b.0), &b.0)));
+    Ok(result)
+}
+
+/// Write a stream bundle of a repo's store, returning how many files
+/// and bytes of data it holds.
+pub fn write(repo: &Repo, out: &mut Write) -> Result<(usize, u64)> {
+    let mut requires: Vec<&str> = repo.requires
+        .iter()
+        .map(|r| &r[..])
+        .filter(|r| STORE_REQUIREMENTS.contains(r))
+        .collect();
+    requires.sort();
+    for r in &requires {
+        expect!(SUPPORTED_REQUIREMENTS.contains(r),
+                "can't stream a store with unsupported format {}",
+                r);
+    }
+    let files = try!(files(repo));
+    let size: u64 = files.iter().map(|&(_, size)| size).sum();
+    try!(out.write_all(b"HG20"));
+    try!(out.write_i32::<BigEndian>(0));
+    let params = [(String::from("bytecount"), size.to_string()),
+                  (String::from("filecount"), files.len().to_string()),
+                  (String::from("requirements"), bundle::quote(&requires.join(",")))];
+    try!(bundle::write_part(out, "STREAM2", &params, &[], &mut |payload| {
+        for &(ref name, size) in &files {
+            try!(payload.write_all(b"s"));
+            try!(payload.write_all(&uvarint(name.len() as u64)));
+            try!(payload.write_all(&uvarint(size)));
+            try!(payload.write_all(name.as_bytes()));
+            // Revlogs are only appended to, so a file which has grown
+            // since is sent as it was
+            let file = try!(File::open(repo.store().join(name)));
+            let copied = try!(io::copy(&mut file.take(size), payload));
+            expect!(copied == size, "{}: file shrank while being sent", name);
+        }
+        Ok(())
+    }));
+    try!(out.write_i32::<BigEndian>(0));
+    Ok((files.len(), size))
+}
+
+/// Create a repo at `dest` from a stream bundle, without a working
+/// directory, and verify it, returning how many revisions it has.
+pub fn clone(path: &Path, dest: &Path) -> Result<usize> {
+    let hg = dest.join(".hg");
+    expect!(!hg.exists(), "destination {:?} already has a repository", dest);
+    let mut bundle = try!(Bundle::open(path));
+    expect!(bundle.format == "HG20", "not a stream bundle");
+    let mut found = false;
+    while let Some(part) = try!(bundle.next_header()) {
+        if part.name != "stream2" {
+            expect!(!part.mandatory, "unsupported bundle part {}", part.name);
+            continue;
+        }
+        expect!(!found, "more than one stream2 part");
+        found = true;
+        let param = |name: &str| match part.param(name) {
+            Some(value) => Ok(value),
+            None => Err(format!("stream2 part without {}", name)),
+        };
+        let count = try!(try!(param("filecount")).parse::<u64>());
+        let size = try!(try!(param("bytecount")).parse::<u64>());
+        let mut requires: Vec<String> = try!(bundle::unquote(try!(param("requirements"))))
+            .split(',')
+            .filter(|r| !r.is_empty())
+            .map(String::from)
+            .collect();
+        requires.sort();
+        for r in &requires {
+            expect!(SUPPORTED_REQUIREMENTS.contains(&&r[..]),
+                    "stream bundle requires unsupported format {}",
+                    r);
+        }
+
+        try!(fs::create_dir_all(&hg));
+        let mut data = requires.join("\n");
+        data.push('\n');
+        try!(try!(File::create(hg.join("requires"))).write_all(data.as_bytes()));
+        let store = Store::new(&hg, &requires);
+        try!(fs::create_dir_all(store.path()));
+        let (written, fncache) = try!(write_files(&mut bundle.payload(), &hg, &store, count));
+        expect!(written == size, "stream2 part has {} bytes of data, not {}", written, size);
+        if let Encoding::Fncache { .. } = store.encoding {
+            let mut data = String::new();
+            for name in fncache {
+                data.push_str(&name);
+                data.push('\n');
+            }
+            try!(try!(File::create(store.path().join("fncache"))).write_all(data.as_bytes()));
+        }
+    }
+    expect!(found, "not a stream bundle");
+
+    verify(&try!(Repo::open(dest)))
+}
+
+/// Lay the files of a `stream2` payload into a store and the cache
+/// under `hg`, returning how many bytes they hold and the names which
+/// belong in fncache.
+fn write_files(payload: &mut Read, hg: &Path, store: &Store, count: u64) -> Result<(u64, Vec<String>)> {
+    let mut size = 0;
+    let mut fncache = vec![];
+    for _ in 0..count {
+        let mut vfs = [0];
+        try!(payload.read_exact(&mut vfs));
+        expect!(&vfs == b"s" || &vfs == b"c", "unsupported stream2 vfs {:?}", vfs[0] as char);
+        let name_size = try!(read_uvarint(payload));
+        let data_size = try!(read_uvarint(payload));
+        let mut name = vec![0; name_size as usize];
+        try!(payload.read_exact(&mut name));
+        let name = try!(String::from_utf8(name));
+        expect!(!name.starts_with('/') && !name.split('/').any(|p| p == ".." || p.is_empty()),
+                "bad stream2 file name {:?}",
+                name);
+
+        let path = if &vfs == b"c" { hg.join("cache").join(&name) } else { store.join(&name) };
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir));
+        }
+        let mut file = try!(File::create(&path));
+        let copied = try!(io::copy(&mut payload.take(data_size), &mut file));
+        expect!(copied == data_size, "stream2 part ended in {}", name);
+        size += data_size;
+        if &vfs == b"s" && (name.starts_with("data/") || name.starts_with("meta/")) {
+            fncache.push(name);
+        }
+    }
+    let mut rest = [0];
+    expect!(try!(payload.read(&mut rest)) == 0, "stream2 part has more than its files");
+    Ok((size, fncache))
+}
+
+/// Check the hash of every revision of every revlog in a repo's store,
+/// returning how many there are. Revisions with flags are skipped, as
+/// their hashes are of something else.
+pub fn verify(repo: &Repo) -> Result<usize> {
+    let mut count = 0;
+    for name in try!(repo.store().walk()) {
+        if !name.ends_with(".i") {
+            continue;
+        }
+        let revlog = try!(repo.store().revlog(&name[..name.len() - 2]));
+        let mut cache = TextCache::new(CACHE_SIZE);
+        for rev in 0..revlog.len() as i32 {
+            let entry = try!(revlog.index(rev));
+            if entry.chunk.flags() != 0 {
+                continue;
+            }
+            let text = try!(revlog.cached_text(rev, &mut cache));
+            let node = revlog::hash(&text, try!(entry.parent_1_id()), try!(entry.parent_2_id()));
+            expect!(&node[..] == entry.chunk.c_node_id(),
+                    "{}: integrity check failed on revision {}",
+                    name,
+                    rev);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+fn uvarint(mut n: u64) -> Vec<u8> {
+    let mut result = vec![];
+    while n >= 0x80 {
+        result.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    result.push(n as u8);
+    result
+}
+
+fn read_uvarint(reader: &mut Read) -> Result<u64> {
+    let mut result = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0];
+        try!(reader.read_exact(&mut byte));
+        expect!(shift < 64, "uvarint too long");
+        result |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(result);
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::{self, Cursor, Read, Write};
+    use exchange::bundle::{write_part, Bundle};
+    use repo::Repo;
+    use testutil::{write_revlog, Rev, TempDir};
+    use super::{clone, read_uvarint, uvarint, write};
+
+    #[test]
+    fn test_uvarint() {
+        assert_eq!(vec![0], uvarint(0));
+        assert_eq!(vec![0x7f], uvarint(127));
+        assert_eq!(vec![0x80, 0x01], uvarint(128));
+        assert_eq!(vec![0xac, 0x02], uvarint(300));
+        for &n in &[0, 1, 300, 1 << 35, u64::max_value()] {
+            assert_eq!(n, read_uvarint(&mut &uvarint(n)[..]).unwrap());
+        }
+        assert!(read_uvarint(&mut &[0x80][..]).is_err());
+    }
+
+    #[test]
+    fn test_stream() {
+        let dir = TempDir::new("streamclone");
+        let source = dir.path().join("source");
+        let store = source.join(".hg/store");
+        fs::create_dir_all(store.join("data")).unwrap();
+        File::create(source.join(".hg/requires"))
+            .unwrap()
+            .write_all(b"dirstate-v2\ndotencode\nfncache\ngeneraldelta\nrevlogv1\nstore\n")
+            .unwrap();
+        File::create(store.join("fncache")).unwrap().write_all(b"data/a.i\n").unwrap();
+        for name in &["00changelog", "00manifest", "data/a"] {
+            write_revlog(&store.join(name), &[Rev::new(b"one\n", -1, -1), Rev::new(b"one\ntwo\n", 0, 0)], true, false);
+        }
+        let mut data = vec![];
+        let (count, _) = write(&Repo::open(&source).unwrap(), &mut data).unwrap();
+        assert_eq!(6, count);
+
+        // Only the store's requirements are sent, and the changelog last
+        let mut bundle = Bundle::read(Box::new(Cursor::new(data.clone()))).unwrap();
+        let part = bundle.next_header().unwrap().unwrap();
+        assert_eq!(Some("dotencode%2Cfncache%2Cgeneraldelta%2Crevlogv1%2Cstore"),
+                   part.param("requirements"));
+        let mut payload = bundle.payload();
+        let mut names = vec![];
+        for _ in 0..count {
+            let mut vfs = [0];
+            payload.read_exact(&mut vfs).unwrap();
+            let name_size = read_uvarint(&mut payload).unwrap();
+            let data_size = read_uvarint(&mut payload).unwrap();
+            let mut name = vec![0; name_size as usize];
+            payload.read_exact(&mut name).unwrap();
+            io::copy(&mut (&mut payload).take(data_size), &mut io::sink()).unwrap();
+            names.push(String::from_utf8(name).unwrap());
+        }
+        assert_eq!(vec!["data/a.d", "data/a.i", "00manifest.d", "00manifest.i", "00changelog.d", "00changelog.i"],
+                   names);
+
+        let path = dir.path().join("stream.hg");
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_eq!(6, clone(&path, &dir.path().join("dest")).unwrap());
+        let mut requires = String::new();
+        File::open(dir.path().join("dest/.hg/requires")).unwrap().read_to_string(&mut requires).unwrap();
+        assert_eq!("dotencode\nfncache\ngeneraldelta\nrevlogv1\nstore\n", requires);
+
+        // A format this can't read
+        let mut data = Vec::from(&b"HG20\0\0\0\0"[..]);
+        let params = [(String::from("bytecount"), String::from("0")),
+                      (String::from("filecount"), String::from("0")),
+                      (String::from("requirements"), String::from("revlogv1%2Cexp-unknown"))];
+        write_part(&mut data, "STREAM2", &params, &[], &mut |_| Ok(())).unwrap();
+        data.extend_from_slice(b"\0\0\0\0");
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert!(clone(&path, &dir.path().join("unknown")).is_err());
+        assert!(!dir.path().join("unknown/.hg").exists());
+
+        // Cache files go under .hg/cache
+        let mut data = Vec::from(&b"HG20\0\0\0\0"[..]);
+        let params = [(String::from("bytecount"), String::from("3")),
+                      (String::from("filecount"), String::from("1")),
+                      (String::from("requirements"), String::from("revlogv1%2Cstore"))];
+        write_part(&mut data, "STREAM2", &params, &[], &mut |payload| {
+            try!(payload.write_all(b"c"));
+            try!(payload.write_all(&uvarint(5)));
+            try!(payload.write_all(&uvarint(3)));
+            try!(payload.write_all(b"tags2one"));
+            Ok(())
+        }).unwrap();
+        data.extend_from_slice(b"\0\0\0\0");
+        File::create(&path).unwrap().write_all(&data).unwrap();
+        assert_eq!(0, clone(&path, &dir.path().join("cache")).unwrap());
+        let mut cache = String::new();
+        File::open(dir.path().join("cache/.hg/cache/tags2")).unwrap().read_to_string(&mut cache).unwrap();
+        assert_eq!("one", cache);
+
+        // A store the other end couldn't read isn't sent
+        File::create(source.join(".hg/requires")).unwrap().write_all(b"revlogv1\nstore\ntreemanifest\n").unwrap();
+        assert!(write(&Repo::open(&source).unwrap(), &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! A filelog is the revlog holding the history of one tracked file.
+//!
+//! The text of a filelog revision may begin with a metadata block,
+//! delimited by `\1\n` markers, holding `key: value` lines. Mercurial
+//! uses it to record where a file was copied or renamed from:
+//!
+//! ```text
+//! \1\n
+//! copy: path/to/source\n
+//! copyrev: 0123456789abcdef0123456789abcdef01234567\n
+//! \1\n
+//! file content...
+//! ```
+//!
+//! The node id is computed over the raw text, metadata included, so
+//! hash verification should go through the underlying `Revlog`.
+
+use std::collections::BTreeMap;
+use rustc_serialize::hex::FromHex;
+
+use revlog::Revlog;
+use util::Result;
+
+const META_MARKER: &'static [u8] = b"\x01\n";
+
+/// The `key: value` pairs from a metadata block.
+pub type Metadata = BTreeMap<String, String>;
+
+pub struct Filelog {
+    revlog: Revlog,
+}
+
+impl Filelog {
+    pub fn open(path: &str) -> Result<Filelog> {
+        let revlog = try!(Revlog::open(path));
+        Ok(Filelog::new(revlog))
+    }
+
+    pub fn new(revlog: Revlog) -> Filelog {
+        Filelog { revlog: revlog }
+    }
+
+    /// The underlying revlog, whose texts still contain the metadata.
+    pub fn revlog(&self) -> &Revlog {
+        &self.revlog
+    }
+
+    /// The content of the file at this rev, without metadata.
+    pub fn content(&self, rev: i32) -> Result<Vec<u8>> {
+        let entry = try!(self.revlog.index(rev));
+        let text = try!(entry.text());
+        let (_, offset) = try!(parse_meta(&text));
+        Ok(Vec::from(&text[offset..]))
+    }
+
+    /// The metadata stored with this rev, which is usually empty.
+    pub fn metadata(&self, rev: i32) -> Result<Metadata> {
+        let entry = try!(self.revlog.index(rev));
+        let (meta, _) = try!(parse_meta(&try!(entry.text())));
+        Ok(meta)
+    }
+
+    /// If this rev was copied or renamed from another file, the source
+    /// path and the source filelog node.
+    ///
+    /// As in Mercurial, this is only reported when the first parent is
+    /// null; a copy recorded alongside real history is ignored.
+    pub fn renamed(&self, rev: i32) -> Result<Option<(String, Vec<u8>)>> {
+        let entry = try!(self.revlog.index(rev));
+        if entry.chunk.parent_1() != -1 {
+            return Ok(None);
+        }
+        let (meta, _) = try!(parse_meta(&try!(entry.text())));
+        let path = match meta.get("copy") {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let node = match meta.get("copyrev") {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let node = try!(node.from_hex());
+        expect!(node.len() == 20, "bad copyrev {:?}", meta["copyrev"]);
+        Ok(Some((path.clone(), node)))
+    }
+}
+
+/// Split a metadata block off the start of a filelog text. Returns the
+/// parsed metadata and the offset at which the file content begins.
+pub fn parse_meta(text: &[u8]) -> Result<(Metadata, usize)> {
+    let mut meta = Metadata::new();
+    if !text.starts_with(META_MARKER) {
+        return Ok((meta, 0));
+    }
+    let end = match find(&text[2..], META_MARKER) {
+        Some(i) => i + 2,
+        None => return Err(From::from("unterminated filelog metadata")),
+    };
+    for line in text[2..end].split(|&c| c == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        let line = String::from_utf8_lossy(line);
+        match line.find(": ") {
+            Some(i) => {
+                meta.insert(String::from(&line[..i]), String::from(&line[i + 2..]));
+            }
+            None => expect!(false, "bad filelog metadata line {:?}", line),
+        }
+    }
+    Ok((meta, end + 2))
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::parse_meta;
+
+    #[test]
+    fn test_no_meta() {
+        let (meta, offset) = parse_meta(b"hello\n").unwrap();
+        assert!(meta.is_empty());
+        assert_eq!(0, offset);
+    }
+
+    #[test]
+    fn test_copy_meta() {
+        let text = b"\x01\ncopy: a/b\ncopyrev: 0123456789abcdef0123456789abcdef01234567\n\x01\nhi";
+        let (meta, offset) = parse_meta(text).unwrap();
+        assert_eq!("a/b", meta["copy"]);
+        assert_eq!("0123456789abcdef0123456789abcdef01234567", meta["copyrev"]);
+        assert_eq!(b"hi", &text[offset..]);
+    }
+
+    #[test]
+    fn test_empty_meta() {
+        // Content which itself begins with \1\n is escaped this way.
+        let text = b"\x01\n\x01\n\x01\nfoo";
+        let (meta, offset) = parse_meta(text).unwrap();
+        assert!(meta.is_empty());
+        assert_eq!(b"\x01\nfoo", &text[offset..]);
+    }
+}
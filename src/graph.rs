@@ -0,0 +1,168 @@
+//! Navigating the DAG formed by the parent pointers of a revlog.
+//!
+//! Parents always have lower rev numbers than their children, so the
+//! revs of a revlog are already in a topological order. The index only
+//! records parents; the children of every rev are worked out together
+//! the first time any are asked for.
+
+use std::cell::RefCell;
+
+use revlog::Revlog;
+use util::Result;
+
+pub struct Graph<'a> {
+    revlog: &'a Revlog,
+    /// Children of each rev, with those of the null rev at the end
+    children: RefCell<Option<Vec<Vec<i32>>>>,
+}
+
+impl<'a> Graph<'a> {
+    pub fn new(revlog: &'a Revlog) -> Graph<'a> {
+        Graph {
+            revlog: revlog,
+            children: RefCell::new(None),
+        }
+    }
+
+    pub fn revlog(&self) -> &'a Revlog {
+        self.revlog
+    }
+
+    pub fn len(&self) -> i32 {
+        self.revlog.len() as i32
+    }
+
+    /// The parents of a rev, leaving out null parents.
+    pub fn parents(&self, rev: i32) -> Result<Vec<i32>> {
+        if rev == -1 {
+            return Ok(vec![]);
+        }
+        let entry = try!(self.revlog.index(rev));
+        let (p1, p2) = (entry.chunk.parent_1(), entry.chunk.parent_2());
+        let mut result = vec![];
+        if p1 != -1 {
+            result.push(p1);
+        }
+        if p2 != -1 && p2 != p1 {
+            result.push(p2);
+        }
+        Ok(result)
+    }
+
+    /// The children of a rev, in increasing order. The children of the
+    /// null rev are the roots.
+    pub fn children(&self, rev: i32) -> Result<Vec<i32>> {
+        expect!(rev >= -1 && rev < self.len(), "rev {} out of range", rev);
+        if self.children.borrow().is_none() {
+            let len = self.len() as usize;
+            let mut children = vec![vec![]; len + 1];
+            for r in 0..self.len() {
+                let parents = try!(self.parents(r));
+                if parents.is_empty() {
+                    children[len].push(r);
+                }
+                for p in parents {
+                    children[p as usize].push(r);
+                }
+            }
+            *self.children.borrow_mut() = Some(children);
+        }
+        let children = self.children.borrow();
+        let children = children.as_ref().unwrap();
+        let index = if rev == -1 { children.len() - 1 } else { rev as usize };
+        Ok(children[index].clone())
+    }
+
+    /// Revs without children, in increasing order. Like Mercurial, an
+    /// empty revlog has the null rev as its only head.
+    pub fn heads(&self) -> Result<Vec<i32>> {
+        if self.len() == 0 {
+            return Ok(vec![-1]);
+        }
+        let mut has_child = vec![false; self.len() as usize];
+        for rev in 0..self.len() {
+            for p in try!(self.parents(rev)) {
+                has_child[p as usize] = true;
+            }
+        }
+        Ok((0..self.len()).filter(|&r| !has_child[r as usize]).collect())
+    }
+
+    /// Revs without parents, in increasing order.
+    pub fn roots(&self) -> Result<Vec<i32>> {
+        let mut result = vec![];
+        for rev in 0..self.len() {
+            if try!(self.parents(rev)).is_empty() {
+                result.push(rev);
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn is_head(&self, rev: i32) -> Result<bool> {
+        if rev == -1 {
+            return Ok(self.len() == 0);
+        }
+        if self.children.borrow().is_some() {
+            return Ok(try!(self.children(rev)).is_empty());
+        }
+        // Only later revs can be children
+        for r in rev + 1..self.len() {
+            if try!(self.parents(r)).contains(&rev) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use revlog::Revlog;
+    use testutil::{write_revlog, Rev, TempDir};
+    use super::Graph;
+
+    #[test]
+    fn test_graph() {
+        // 0 - 1 - 3 - 5
+        //  \    /
+        //    2       4
+        let dir = TempDir::new("graph");
+        let path = dir.path().join("a");
+        let texts: Vec<String> = (0..6).map(|rev| rev.to_string()).collect();
+        let parents = [(-1, -1), (0, -1), (0, -1), (1, 2), (-1, -1), (3, -1)];
+        let revs: Vec<Rev> = texts.iter()
+            .zip(&parents)
+            .map(|(text, &(p1, p2))| Rev { p2: p2, ..Rev::new(text.as_bytes(), p1, -1) })
+            .collect();
+        write_revlog(&path, &revs, false, true);
+        let revlog = Revlog::open(&format!("{}.i", path.display())).unwrap();
+        let graph = Graph::new(&revlog);
+        assert_eq!(6, graph.len());
+        assert_eq!(vec![1, 2], graph.parents(3).unwrap());
+        assert!(graph.parents(0).unwrap().is_empty());
+        assert!(graph.parents(-1).unwrap().is_empty());
+        assert_eq!(vec![4, 5], graph.heads().unwrap());
+        assert_eq!(vec![0, 4], graph.roots().unwrap());
+        // Before and after children are worked out
+        assert!(graph.is_head(4).unwrap());
+        assert!(!graph.is_head(2).unwrap());
+        assert_eq!(vec![1, 2], graph.children(0).unwrap());
+        assert_eq!(vec![3], graph.children(2).unwrap());
+        assert_eq!(vec![0, 4], graph.children(-1).unwrap());
+        assert!(graph.children(5).unwrap().is_empty());
+        assert!(graph.children(6).is_err());
+        assert!(graph.is_head(5).unwrap());
+        assert!(!graph.is_head(3).unwrap());
+        assert!(!graph.is_head(-1).unwrap());
+    }
+
+    #[test]
+    fn test_empty() {
+        let revlog = Revlog::empty();
+        let graph = Graph::new(&revlog);
+        assert_eq!(vec![-1], graph.heads().unwrap());
+        assert!(graph.roots().unwrap().is_empty());
+        assert!(graph.is_head(-1).unwrap());
+    }
+}
@@ -0,0 +1,197 @@
+//! Searching file contents across revisions, like `hg grep`.
+//!
+//! Each file is a job for one of a pool of threads. A thread opens the
+//! file's filelog through its own copy of the store, since revlogs can't
+//! be shared between threads, and reads the revisions it wants in
+//! order through a `TextCache`, so that each text is usually the one
+//! before it plus a patch. Binary texts, which contain a NUL, are not
+//! searched.
+//!
+//! Most texts don't match at all, so a text is first searched as a
+//! whole, with `^` and `$` matching at line boundaries, and only split
+//! into lines if that finds something.
+
+extern crate num_cpus;
+extern crate regex;
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+use self::regex::bytes::Regex;
+
+use diff;
+use filelog::{self, Filelog};
+use revlog::TextCache;
+use store::Store;
+use util::Result;
+
+/// How many texts each thread keeps for its current filelog.
+const CACHE_SIZE: usize = 32;
+
+/// Which revisions of a file to search.
+pub enum Revisions {
+    /// These revisions by filelog node, each reported as part of the
+    /// given changesets
+    Nodes(Vec<(Vec<u8>, Vec<i32>)>),
+    /// Every revision introduced by one of these changesets
+    Linked(Arc<HashSet<i32>>),
+}
+
+/// A matching line.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Match {
+    pub rev: i32,
+    pub path: String,
+    /// The line's number, from 1
+    pub number: usize,
+    /// The line, without its newline
+    pub line: Vec<u8>,
+}
+
+/// Search revisions of files for lines matching a regex, on as many
+/// threads as there are CPUs. Matches are sorted by changeset, path and
+/// line.
+pub fn grep(store: &Store, pattern: &str, files: Vec<(String, Revisions)>) -> Result<Vec<Match>> {
+    let regex = try!(Regex::new(pattern));
+    let whole = try!(Regex::new(&format!("(?m){}", pattern)));
+    let jobs = Arc::new(Mutex::new(files));
+    let (sender, receiver) = mpsc::channel();
+    let mut threads = vec![];
+    for _ in 0..num_cpus::get() {
+        let (store, regex, whole) = (store.clone(), regex.clone(), whole.clone());
+        let (jobs, sender) = (jobs.clone(), sender.clone());
+        threads.push(thread::spawn(move || {
+            loop {
+                let job = jobs.lock().unwrap().pop();
+                let (path, revisions) = match job {
+                    Some(job) => job,
+                    None => break,
+                };
+                // Errors can't be sent between threads
+                let result = search(&store, &regex, &whole, &path, revisions).map_err(|e| e.to_string());
+                if sender.send(result).is_err() {
+                    break;
+                }
+            }
+        }));
+    }
+    drop(sender);
+
+    let mut result = vec![];
+    let mut error = None;
+    for found in receiver {
+        match found {
+            Ok(found) => result.extend(found),
+            Err(e) => {
+                error = Some(e);
+                // Let the threads run out of jobs
+                jobs.lock().unwrap().clear();
+            }
+        }
+    }
+    for thread in threads {
+        expect!(thread.join().is_ok(), "grep thread panicked");
+    }
+    if let Some(e) = error {
+        return Err(From::from(e));
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Search the wanted revisions of one file.
+fn search(store: &Store,
+          regex: &Regex,
+          whole: &Regex,
+          path: &str,
+          revisions: Revisions)
+          -> Result<Vec<Match>> {
+    let filelog = Filelog::new(try!(store.revlog(&Store::filelog_name(path))));
+    let revlog = filelog.revlog();
+    let mut wanted = vec![];
+    match revisions {
+        Revisions::Nodes(nodes) => {
+            for (node, revs) in nodes {
+                match try!(revlog.rev(&node)) {
+                    Some(filerev) => wanted.push((filerev, revs)),
+                    None => return Err(From::from(format!("{}: filelog node not found", path))),
+                }
+            }
+        }
+        Revisions::Linked(revs) => {
+            for filerev in 0..revlog.len() as i32 {
+                let linkrev = try!(revlog.index(filerev)).chunk.link_rev();
+                if revs.contains(&linkrev) {
+                    wanted.push((filerev, vec![linkrev]));
+                }
+            }
+        }
+    }
+    wanted.sort();
+
+    let mut cache = TextCache::new(CACHE_SIZE);
+    let mut result = vec![];
+    for (filerev, revs) in wanted {
+        let text = try!(revlog.cached_text(filerev, &mut cache));
+        let (_, offset) = try!(filelog::parse_meta(&text));
+        let content = &text[offset..];
+        if content.contains(&0) || !whole.is_match(content) {
+            continue;
+        }
+        for (i, line) in diff::split_lines(content).into_iter().enumerate() {
+            let line = if line.ends_with(b"\n") { &line[..line.len() - 1] } else { line };
+            if !regex.is_match(line) {
+                continue;
+            }
+            for &rev in &revs {
+                result.push(Match {
+                    rev: rev,
+                    path: String::from(path),
+                    number: i + 1,
+                    line: Vec::from(line),
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use testutil::{Commit, RepoBuilder};
+    use super::{grep, Match, Revisions};
+
+    #[test]
+    fn test_grep() {
+        let mut builder = RepoBuilder::new("grep");
+        builder.commit(Commit::new(-1, -1).file("a", "foo\nbar\n").file("bin", "foo\0\n"));
+        builder.commit(Commit::new(0, -1).file("a", "bar\nfoo bar\nbaz foo"));
+        builder.commit(Commit::new(1, -1).copy("a", "c", "foo\n"));
+        let repo = builder.open();
+        let node = Vec::from(repo.filelog("a").unwrap().revlog().node(1).unwrap());
+
+        let found = |rev: i32, path: &str, number: usize, line: &str| {
+            Match {
+                rev: rev,
+                path: String::from(path),
+                number: number,
+                line: Vec::from(line),
+            }
+        };
+        let all: Arc<HashSet<i32>> = Arc::new((0..3).collect());
+        let files = vec![(String::from("a"), Revisions::Linked(all.clone())),
+                         (String::from("bin"), Revisions::Linked(all.clone())),
+                         (String::from("c"), Revisions::Linked(all.clone()))];
+        // The copy metadata of c isn't searched, nor is the binary file.
+        assert_eq!(vec![found(0, "a", 1, "foo"), found(1, "a", 2, "foo bar"), found(2, "c", 1, "foo")],
+                   grep(repo.store(), "^foo", files).unwrap());
+
+        let files = vec![(String::from("a"), Revisions::Nodes(vec![(node, vec![2, 1])]))];
+        assert_eq!(vec![found(1, "a", 3, "baz foo"), found(2, "a", 3, "baz foo")],
+                   grep(repo.store(), "foo$", files).unwrap());
+        assert!(grep(repo.store(), "(", vec![]).is_err());
+    }
+}
@@ -1,8 +1,37 @@
 //! Read-only support for Mercurial's revlog format.
 
 extern crate mmap;
+extern crate crypto;
+extern crate rustc_serialize;
 
 #[macro_use]
 mod util;
 pub mod revlog;
 pub mod patch;
+pub mod filelog;
+pub mod changelog;
+pub mod manifest;
+pub mod store;
+pub mod repo;
+pub mod date;
+pub mod template;
+pub mod stats;
+pub mod graph;
+pub mod ancestor;
+pub mod revset;
+pub mod branchmap;
+pub mod bookmarks;
+pub mod tags;
+pub mod phases;
+pub mod obsolete;
+pub mod dirstate;
+pub mod matcher;
+pub mod status;
+pub mod archive;
+pub mod diff;
+pub mod annotate;
+pub mod grep;
+pub mod exchange;
+pub mod bundlerepo;
+#[cfg(test)]
+mod testutil;
@@ -10,80 +10,63 @@ extern crate crypto;
 mod util;
 mod patch;
 mod revlog;
+mod filelog;
+mod changelog;
+mod manifest;
+mod store;
+mod repo;
+mod date;
+mod template;
+mod stats;
+mod graph;
+mod ancestor;
+mod revset;
+mod branchmap;
+mod bookmarks;
+mod tags;
+mod phases;
+mod obsolete;
+mod dirstate;
+mod matcher;
+mod status;
+mod archive;
+mod diff;
+mod annotate;
+mod grep;
+mod exchange;
+mod bundlerepo;
+#[cfg(test)]
+mod testutil;
+mod cmd;
 
-use std::{error, result};
-use rustc_serialize::hex::ToHex;
-use crypto::sha1::Sha1;
-use crypto::digest::Digest;
-
-fn read_revlog(path: &str) -> result::Result<(), Box<error::Error>> {
-    let revlog = try!(revlog::Revlog::open(path));
-
-    println!("   rev    offset  length  {} linkrev nodeid       p1           p2",
-             if revlog.generaldelta {
-                 "delta"
-             } else {
-                 " base"
-             });
-
-    let mut good = 0;
-    let mut bad = 0;
-    for entry in revlog.iter() {
-        let entry = try!(entry);
-
-        let p1 = entry.parent_1_id().unwrap();
-        let p2 = entry.parent_2_id().unwrap();
-        let node_id = entry.chunk.c_node_id().to_hex();
-        print_entry(&entry);
-        //println!("{:?}", String::from_utf8_lossy(&entry.data()));
-
-        let text = entry.text();
-        // println!("{:?}", String::from_utf8_lossy(&text));
-
-        let mut sha = Sha1::new();
-        let mut ps = vec![p1, p2];
-        ps.sort();
-        for p in &ps {
-            sha.input(p);
-        }
-        sha.input(&text);
-        let hex = sha.result_str();
-        if node_id != hex {
-            bad += 1;
-            println!("ERROR");
-            println!("{:?}", String::from_utf8_lossy(&text));
-        } else {
-            good += 1;
-            println!("verified {:?}", hex);
-        }
-        //assert_eq!(node_id, hex);
-    }
-    println!("{} hashes verified", good);
-    println!("{} hashes failed", bad);
-    Ok(())
-}
-
-fn print_entry(entry: &revlog::RevlogEntry) {
-    let p1 = entry.parent_1_id().unwrap();
-    let p2 = entry.parent_2_id().unwrap();
-    let node_id = entry.chunk.c_node_id().to_hex();
-    println!("{:6} {:9} {:7} {:6} {:7} {} {} {}",
-             entry.revno,
-             entry.offset(),
-             entry.chunk.comp_len(),
-             entry.base_rev(),
-             entry.chunk.link_rev(),
-             &node_id[..12],
-             &p1.to_hex()[..12],
-             &p2.to_hex()[..12]);
-}
+use std::process;
+use std::io::{self, Write};
 
+const USAGE: &'static str = "usage: cinnabar annotate|archive|branches|bundle|cat|diff|grep|log|status|debug ...";
 
 fn main() {
-    for path in std::env::args().skip(1) {
-        match read_revlog(&path) {
-            Ok(()) => (),
-            Err(e) => println!("Err({:?})", e),
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(|s| &s[..]) {
+        Some("annotate") => cmd::annotate::run(&args[1..]),
+        Some("archive") => cmd::archive::run(&args[1..]),
+        Some("branches") => cmd::branches::run(&args[1..]),
+        Some("bundle") => cmd::bundle::run(&args[1..]),
+        Some("cat") => cmd::cat::run(&args[1..]),
+        Some("diff") => cmd::diff::run(&args[1..]),
+        Some("grep") => cmd::grep::run(&args[1..]),
+        Some("log") => cmd::log::run(&args[1..]),
+        Some("status") => cmd::status::run(&args[1..]),
+        Some("debug") => cmd::debug::run(&args[1..]),
+        _ => Err(From::from(USAGE)),
+    };
+    if let Err(e) = result {
+        // Output was cut off, as by head
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if e.kind() == io::ErrorKind::BrokenPipe {
+                return;
+            }
         }
+        writeln!(io::stderr(), "cinnabar: {}", e).unwrap();
+        process::exit(1);
     }
 }
@@ -0,0 +1,168 @@
+//! The manifest lists every file in a changeset along with the node of
+//! its filelog revision. Each line of a manifest text is
+//!
+//! ```text
+//! <path>\0<node, hex><flag>\n
+//! ```
+//!
+//! where the optional flag is `x` for executables and `l` for symlinks.
+//! Lines are sorted by path.
+
+use rustc_serialize::hex::FromHex;
+
+use revlog::Revlog;
+use util::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flags {
+    Regular,
+    Executable,
+    Symlink,
+}
+
+impl Flags {
+    /// The flag character as written in the manifest.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Flags::Regular => "",
+            Flags::Executable => "x",
+            Flags::Symlink => "l",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// Node id of the file's filelog revision
+    pub node: Vec<u8>,
+    pub flags: Flags,
+}
+
+pub struct Manifest {
+    /// Sorted by path
+    entries: Vec<(String, ManifestEntry)>,
+    /// The paths of `entries` as stored, which is what they are sorted
+    /// by. A path which isn't UTF-8 is only lossily in `entries`.
+    keys: Vec<Vec<u8>>,
+}
+
+impl Manifest {
+    pub fn parse(text: &[u8]) -> Result<Manifest> {
+        let mut entries = vec![];
+        let mut keys = vec![];
+        for line in text.split(|&c| c == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let nul = match line.iter().position(|&c| c == 0) {
+                Some(i) => i,
+                None => return Err(From::from("manifest line without a node")),
+            };
+            let path = String::from_utf8_lossy(&line[..nul]).into_owned();
+            let rest = &line[nul + 1..];
+            expect!(rest.len() >= 40, "short node in manifest for {:?}", path);
+            let node = try!(String::from_utf8_lossy(&rest[..40]).from_hex());
+            let flags = match &rest[40..] {
+                b"" => Flags::Regular,
+                b"x" => Flags::Executable,
+                b"l" => Flags::Symlink,
+                other => {
+                    return Err(From::from(format!("unknown manifest flag {:?} for {:?}",
+                                                  String::from_utf8_lossy(other),
+                                                  path)))
+                }
+            };
+            keys.push(line[..nul].to_vec());
+            entries.push((path,
+                          ManifestEntry {
+                node: node,
+                flags: flags,
+            }));
+        }
+        Ok(Manifest {
+            entries: entries,
+            keys: keys,
+        })
+    }
+
+    pub fn empty() -> Manifest {
+        Manifest {
+            entries: vec![],
+            keys: vec![],
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<&ManifestEntry> {
+        match self.keys.binary_search_by(|k| (&k[..]).cmp(path.as_bytes())) {
+            Ok(i) => Some(&self.entries[i].1),
+            Err(_) => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// The files in path order.
+    pub fn iter(&self) -> ::std::slice::Iter<(String, ManifestEntry)> {
+        self.entries.iter()
+    }
+}
+
+pub struct Manifestlog {
+    revlog: Revlog,
+}
+
+impl Manifestlog {
+    pub fn new(revlog: Revlog) -> Manifestlog {
+        Manifestlog { revlog: revlog }
+    }
+
+    pub fn revlog(&self) -> &Revlog {
+        &self.revlog
+    }
+
+    /// The manifest with the given node id, as found in a changeset.
+    pub fn read(&self, node: &[u8]) -> Result<Manifest> {
+        let rev = match try!(self.revlog.rev(node)) {
+            Some(-1) => return Ok(Manifest::empty()),
+            Some(rev) => rev,
+            None => return Err(From::from("manifest node not found")),
+        };
+        let entry = try!(self.revlog.index(rev));
+        Manifest::parse(&try!(entry.text()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Flags, Manifest};
+
+    #[test]
+    fn test_parse() {
+        let text = b"a.txt\x000123456789abcdef0123456789abcdef01234567\n\
+                     bin/run\x000123456789abcdef0123456789abcdef01234567x\n\
+                     link\x000123456789abcdef0123456789abcdef01234567l\n";
+        let m = Manifest::parse(text).unwrap();
+        assert_eq!(3, m.len());
+        assert_eq!(Flags::Regular, m.get("a.txt").unwrap().flags);
+        assert_eq!(Flags::Executable, m.get("bin/run").unwrap().flags);
+        assert_eq!(Flags::Symlink, m.get("link").unwrap().flags);
+        assert!(m.get("bin").is_none());
+    }
+
+    #[test]
+    fn test_get_non_utf8() {
+        // Sorted as bytes, "\xc0" comes before "\u{e9}", but its lossy
+        // replacement character comes after
+        let text = b"a\x000123456789abcdef0123456789abcdef01234567\n\
+                     z\x000123456789abcdef0123456789abcdef01234567\n\
+                     \xc0\x000123456789abcdef0123456789abcdef01234567\n\
+                     \xc3\xa9\x000123456789abcdef0123456789abcdef01234567x\n";
+        let m = Manifest::parse(text).unwrap();
+        assert_eq!(4, m.len());
+        assert!(m.get("a").is_some());
+        assert!(m.get("z").is_some());
+        assert_eq!(Flags::Executable, m.get("\u{e9}").unwrap().flags);
+    }
+}
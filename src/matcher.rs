@@ -0,0 +1,450 @@
+//! Matching repo paths against Mercurial's file patterns.
+//!
+//! A pattern is a kind and its text. Glob patterns support `*`, which
+//! doesn't cross a `/`, `**`, which does, `?`, `[...]` classes and
+//! `{a,b}` alternatives. A glob or rootglob is anchored at the root of
+//! the repo, a relglob matches in any directory, and all of them also
+//! match everything below a directory they name. Regexps use the
+//! `regex` crate's syntax: a `re:` pattern is anchored at the start of
+//! the path and a `relre:` one may match anywhere. A `path:` pattern is
+//! a file or directory relative to the root, and `relpath:` is the same
+//! relative to the current directory; commands resolve both, and
+//! command line globs, to root-relative paths before matching.
+//!
+//! Ignore files hold one pattern per line. `syntax: glob`, `syntax:
+//! regexp` or `syntax: rootglob` sets the kind of the lines that follow
+//! (regexp by default; globs and regexps are unanchored), a line may
+//! override it with a `kind:` prefix, and `#` starts a comment unless
+//! written `\#`. Two more prefixes name other pattern files, relative
+//! to the one they're in: `include:` adds its patterns, and
+//! `subinclude:` adds them as if its directory were the root.
+
+extern crate regex;
+
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use matcher::regex::Regex;
+use repo;
+use util::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// `glob:`, anchored at the root
+    Glob,
+    /// `relglob:`, matching in any directory
+    RelGlob,
+    /// `re:`, anchored at the start of the path
+    Regexp,
+    /// `relre:`, matching anywhere in the path
+    RelRegexp,
+    /// `rootglob:`, a glob which stays anchored in ignore files
+    RootGlob,
+    /// `path:`, a file or directory relative to the root
+    Path,
+    /// `relpath:`, a file or directory relative to the current directory
+    RelPath,
+    /// `include:`, another pattern file
+    Include,
+    /// `subinclude:`, a pattern file for its own directory
+    SubInclude,
+}
+
+impl Kind {
+    pub fn from_name(name: &str) -> Option<Kind> {
+        match name {
+            "glob" => Some(Kind::Glob),
+            "relglob" => Some(Kind::RelGlob),
+            "re" => Some(Kind::Regexp),
+            "relre" => Some(Kind::RelRegexp),
+            "rootglob" => Some(Kind::RootGlob),
+            "path" => Some(Kind::Path),
+            "relpath" => Some(Kind::RelPath),
+            "include" => Some(Kind::Include),
+            "subinclude" => Some(Kind::SubInclude),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Pattern {
+    pub kind: Kind,
+    pub text: String,
+}
+
+impl Pattern {
+    pub fn new(kind: Kind, text: &str) -> Pattern {
+        Pattern {
+            kind: kind,
+            text: String::from(text),
+        }
+    }
+
+    /// Parse `kind:text`, using `default` when there is no known kind.
+    pub fn parse(pattern: &str, default: Kind) -> Pattern {
+        if let Some(i) = pattern.find(':') {
+            if let Some(kind) = Kind::from_name(&pattern[..i]) {
+                return Pattern::new(kind, &pattern[i + 1..]);
+            }
+        }
+        Pattern::new(default, pattern)
+    }
+
+    /// Whether this matches exactly one file or directory.
+    pub fn is_literal(&self) -> bool {
+        match self.kind {
+            Kind::Path | Kind::RelPath => true,
+            Kind::Glob | Kind::RootGlob => !self.text.contains(|c| "*?[{\\".contains(c)),
+            _ => false,
+        }
+    }
+
+    /// The pattern as a regexp to match against a whole path.
+    fn to_regex(&self) -> Result<String> {
+        Ok(match self.kind {
+            Kind::Glob | Kind::RootGlob => format!("^{}(?:/|$)", glob_to_regex(&self.text)),
+            Kind::RelGlob => format!("^(?:.*/)?{}(?:/|$)", glob_to_regex(&self.text)),
+            Kind::Regexp => {
+                if self.text.starts_with('^') {
+                    self.text.clone()
+                } else {
+                    format!("^(?:{})", self.text)
+                }
+            }
+            Kind::RelRegexp => self.text.clone(),
+            // The root contains everything
+            Kind::Path | Kind::RelPath if self.text.is_empty() => String::from("^"),
+            Kind::Path | Kind::RelPath => format!("^{}(?:/|$)", regex::quote(&self.text)),
+            Kind::Include | Kind::SubInclude => {
+                return Err(From::from(format!("{:?} patterns are only valid in pattern files", self.text)))
+            }
+        })
+    }
+}
+
+/// Translate a glob into a regexp, as Mercurial's `_globre` does.
+pub fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut result = String::new();
+    let mut group = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match c {
+            '*' => {
+                if chars.get(i) == Some(&'*') {
+                    i += 1;
+                    if chars.get(i) == Some(&'/') {
+                        i += 1;
+                        result.push_str("(?:.*/)?");
+                    } else {
+                        result.push_str(".*");
+                    }
+                } else {
+                    result.push_str("[^/]*");
+                }
+            }
+            '?' => result.push('.'),
+            '[' => {
+                let mut j = i;
+                if j < chars.len() && (chars[j] == '!' || chars[j] == ']') {
+                    j += 1;
+                }
+                while j < chars.len() && chars[j] != ']' {
+                    j += 1;
+                }
+                if j >= chars.len() {
+                    result.push_str("\\[");
+                } else {
+                    let mut class: String = chars[i..j].iter().collect::<String>().replace("\\", "\\\\");
+                    i = j + 1;
+                    if class.starts_with('!') {
+                        class = format!("^{}", &class[1..]);
+                    } else if class.starts_with('^') {
+                        class = format!("\\{}", class);
+                    }
+                    result.push_str(&format!("[{}]", class));
+                }
+            }
+            '{' => {
+                group += 1;
+                result.push_str("(?:");
+            }
+            '}' if group > 0 => {
+                group -= 1;
+                result.push(')');
+            }
+            ',' if group > 0 => result.push('|'),
+            '\\' => {
+                match chars.get(i) {
+                    Some(&next) => {
+                        i += 1;
+                        result.push_str(&regex::quote(&next.to_string()));
+                    }
+                    None => result.push_str("\\\\"),
+                }
+            }
+            c => result.push_str(&regex::quote(&c.to_string())),
+        }
+    }
+    result
+}
+
+/// Parse the lines of an ignore file into patterns, leaving any
+/// `include:` and `subinclude:` to the caller. Like Mercurial, an
+/// unknown `syntax:` is only warned about, by adding to `warnings`, and
+/// the lines after it keep the syntax before it.
+pub fn parse_ignore(data: &[u8], warnings: &mut Vec<String>) -> Result<Vec<Pattern>> {
+    // A `#` after an even number of backslashes starts a comment
+    let comment = Regex::new(r"((?:^|[^\\])(?:\\\\)*)#.*").unwrap();
+    let mut kind = Kind::RelRegexp;
+    let mut result = vec![];
+    for line in String::from_utf8_lossy(data).lines() {
+        let mut line = String::from(line);
+        if line.contains('#') {
+            if let Some(end) = comment.captures(&line).and_then(|c| c.pos(1)).map(|p| p.1) {
+                line.truncate(end);
+            }
+            // What's left of `\#` is a literal hash
+            line = line.replace("\\#", "#");
+        }
+        let line = line.trim_right();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with("syntax:") {
+            match line["syntax:".len()..].trim() {
+                "re" | "regexp" | "relre" => kind = Kind::RelRegexp,
+                "glob" | "relglob" => kind = Kind::RelGlob,
+                "rootglob" => kind = Kind::RootGlob,
+                s => warnings.push(format!("ignoring invalid syntax {:?}", s)),
+            }
+            continue;
+        }
+        let pattern = if line.starts_with("regexp:") {
+            Pattern::new(Kind::RelRegexp, &line["regexp:".len()..])
+        } else {
+            Pattern::parse(line, kind)
+        };
+        // In an ignore file, the kinds are unanchored
+        let pattern = match pattern.kind {
+            Kind::Glob => Pattern::new(Kind::RelGlob, &pattern.text),
+            Kind::Regexp => Pattern::new(Kind::RelRegexp, &pattern.text),
+            _ => pattern,
+        };
+        result.push(pattern);
+    }
+    Ok(result)
+}
+
+/// Compile the ignore file at `file`, whose patterns apply to the repo
+/// with working directory `root`, along with the files it includes. A
+/// missing file has no patterns, as Mercurial only warns about it.
+pub fn read_ignore(root: &Path, file: &Path) -> Result<Matcher> {
+    let mut subs = vec![];
+    let patterns = try!(read_pattern_file(root, file, &mut vec![], &mut subs));
+    let mut matcher = try!(Matcher::new(&patterns));
+    matcher.subs = subs;
+    Ok(matcher)
+}
+
+/// Read the patterns of a pattern file, following includes, and add a
+/// matcher for each subinclude to `subs`.
+fn read_pattern_file(root: &Path,
+                     file: &Path,
+                     seen: &mut Vec<PathBuf>,
+                     subs: &mut Vec<(String, Matcher)>)
+                     -> Result<Vec<Pattern>> {
+    let file = normalize(file);
+    expect!(!seen.contains(&file), "{:?} includes itself", file);
+    let data = match try!(repo::read_optional(&file)) {
+        Some(data) => data,
+        None => return Ok(vec![]),
+    };
+    seen.push(file.clone());
+    let dir = file.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut patterns = vec![];
+    let mut warnings = vec![];
+    let parsed = try!(parse_ignore(&data, &mut warnings));
+    for warning in warnings {
+        try!(writeln!(io::stderr(), "{}: {}", file.display(), warning));
+    }
+    for pattern in parsed {
+        match pattern.kind {
+            Kind::Include => {
+                patterns.extend(try!(read_pattern_file(root, &dir.join(&pattern.text), seen, subs)));
+            }
+            Kind::SubInclude => {
+                let sub_file = normalize(&dir.join(&pattern.text));
+                let sub_dir = sub_file.parent().unwrap_or(Path::new(""));
+                let prefix = match sub_dir.strip_prefix(root) {
+                    Ok(rel) => rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/"),
+                    Err(_) => return Err(From::from(format!("{:?} is not in the repository", sub_file))),
+                };
+                let sub_patterns = try!(read_pattern_file(root, &sub_file, seen, subs));
+                subs.push((prefix, try!(Matcher::new(&sub_patterns))));
+            }
+            _ => patterns.push(pattern),
+        }
+    }
+    seen.pop();
+    Ok(patterns)
+}
+
+/// Remove `.` and `..` components without touching the filesystem.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                result.pop();
+            }
+            c => result.push(c.as_os_str()),
+        }
+    }
+    result
+}
+
+/// A set of patterns compiled into one regexp, plus the matchers of
+/// subincluded files. A path matches if any pattern does.
+pub struct Matcher {
+    patterns: Vec<Pattern>,
+    regex: Option<Regex>,
+    /// Subincluded matchers and the repo directories they apply to
+    subs: Vec<(String, Matcher)>,
+}
+
+impl Matcher {
+    pub fn new(patterns: &[Pattern]) -> Result<Matcher> {
+        let mut matcher = Matcher::never();
+        matcher.patterns = patterns.to_vec();
+        if patterns.is_empty() {
+            return Ok(matcher);
+        }
+        let mut parts = vec![];
+        for pattern in patterns {
+            parts.push(format!("(?:{})", try!(pattern.to_regex())));
+        }
+        matcher.regex = match Regex::new(&parts.join("|")) {
+            Ok(regex) => Some(regex),
+            Err(e) => return Err(From::from(format!("invalid pattern: {}", e))),
+        };
+        Ok(matcher)
+    }
+
+    /// A matcher for nothing.
+    pub fn never() -> Matcher {
+        Matcher {
+            patterns: vec![],
+            regex: None,
+            subs: vec![],
+        }
+    }
+
+    /// The patterns given to `new`.
+    pub fn patterns(&self) -> &[Pattern] {
+        &self.patterns
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        if self.regex.as_ref().map_or(false, |r| r.is_match(path)) {
+            return true;
+        }
+        self.subs.iter().any(|&(ref prefix, ref sub)| {
+            if prefix.is_empty() {
+                sub.matches(path)
+            } else {
+                path.starts_with(&prefix[..]) && path[prefix.len()..].starts_with('/') &&
+                sub.matches(&path[prefix.len() + 1..])
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use testutil::TempDir;
+    use super::{Kind, Matcher, Pattern, parse_ignore, read_ignore};
+
+    fn matcher(patterns: &[&str]) -> Matcher {
+        let patterns: Vec<Pattern> = patterns.iter().map(|p| Pattern::parse(p, Kind::Glob)).collect();
+        Matcher::new(&patterns).unwrap()
+    }
+
+    #[test]
+    fn test_glob() {
+        let m = matcher(&["*.c", "src/**.h", "doc/{a,b}[0-9].txt"]);
+        assert!(m.matches("x.c"));
+        assert!(!m.matches("src/x.c"));
+        assert!(m.matches("src/a/b.h"));
+        assert!(m.matches("doc/b7.txt"));
+        assert!(!m.matches("doc/c7.txt"));
+        // A directory matches everything in it
+        assert!(matcher(&["src"]).matches("src/a/b.h"));
+        assert!(!matcher(&["src"]).matches("srcs"));
+        assert!(matcher(&["relglob:*.o"]).matches("a/b/c.o"));
+        assert!(matcher(&["re:a.*z"]).matches("abz/x"));
+        assert!(!matcher(&["re:b"]).matches("abc"));
+        assert!(matcher(&["path:a.b"]).matches("a.b/c"));
+        assert!(!matcher(&["path:a.b"]).matches("axb"));
+        assert!(matcher(&["path:"]).matches("x"));
+        assert!(Matcher::new(&[Pattern::parse("include:x", Kind::Glob)]).is_err());
+    }
+
+    #[test]
+    fn test_parse_ignore() {
+        let data = b"# comment\n\\.orig$\nsyntax: glob\n*.pyc  # compiled\nre:^build/\n";
+        let mut warnings = vec![];
+        let patterns = parse_ignore(data, &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(vec![Pattern::new(Kind::RelRegexp, "\\.orig$"),
+                        Pattern::new(Kind::RelGlob, "*.pyc"),
+                        Pattern::new(Kind::RelRegexp, "^build/")],
+                   patterns);
+        let m = Matcher::new(&patterns).unwrap();
+        assert!(m.matches("a/b.pyc"));
+        assert!(m.matches("x.c.orig"));
+        assert!(m.matches("build/x"));
+        assert!(!m.matches("src/build/x"));
+        let patterns = parse_ignore(b"syntax: rootglob\n*.o\n", &mut warnings).unwrap();
+        assert!(!Matcher::new(&patterns).unwrap().matches("a/b.o"));
+    }
+
+    #[test]
+    fn test_parse_ignore_syntax_and_comments() {
+        let mut warnings = vec![];
+        let data = b"syntax: glob\nsyntax: foo\n*.o\na\\#b\nc\\\\#d\ne\\\\\\#f # g\n";
+        let patterns = parse_ignore(data, &mut warnings).unwrap();
+        assert_eq!(vec![String::from("ignoring invalid syntax \"foo\"")], warnings);
+        assert_eq!(vec![Pattern::new(Kind::RelGlob, "*.o"),
+                        Pattern::new(Kind::RelGlob, "a#b"),
+                        Pattern::new(Kind::RelGlob, "c\\\\"),
+                        Pattern::new(Kind::RelGlob, "e\\\\#f")],
+                   patterns);
+    }
+
+    fn write(path: &Path, data: &str) {
+        fs::File::create(path).unwrap().write_all(data.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_read_ignore() {
+        let dir = TempDir::new("read-ignore");
+        let root = dir.path();
+        fs::create_dir(root.join("sub")).unwrap();
+        write(&root.join(".hgignore"), "include:common\nsubinclude:sub/.hgignore\n");
+        write(&root.join("common"), "syntax: glob\n*.o\n");
+        write(&root.join("sub/.hgignore"), "syntax: rootglob\nbuild\n");
+        let m = read_ignore(root, &root.join(".hgignore")).unwrap();
+        assert!(m.matches("a/b.o"));
+        assert!(m.matches("sub/build/x"));
+        assert!(!m.matches("build/x"));
+        assert!(!m.matches("sub/a/build"));
+    }
+}
@@ -0,0 +1,385 @@
+//! Obsolescence markers, as written by `hg amend`, `hg rebase` and the
+//! evolve extension.
+//!
+//! A marker records that a changeset, the precursor, was rewritten into
+//! some successors, or pruned if there are none. Markers are appended to
+//! the store's `obsstore`, which starts with a version byte:
+//!
+//! ```text
+//! version 0: <successor count, u8> <metadata size, u32> <flags, u8>
+//!            <precursor> <successors> <metadata>
+//! version 1: <marker size, u32> <date, f64> <tz in minutes, i16>
+//!            <flags, u16> <successor count, u8> <parent count, u8>
+//!            <metadata count, u8> <precursor> <successors> <parents>
+//!            <metadata sizes, u8 pairs> <metadata>
+//! ```
+//!
+//! All integers are big endian. Version 0 metadata is `key:value`
+//! pairs separated by `\0`, and also holds the date and parents.
+//!
+//! Markers only apply to changesets which aren't public. Those that are
+//! obsolete are hidden unless something still needs them, and the
+//! changesets left on top of obsolete ones are unstable.
+
+extern crate byteorder;
+
+use std::collections::HashSet;
+use std::io::{Cursor, Read};
+use rustc_serialize::hex::FromHex;
+
+use obsolete::byteorder::{BigEndian, ReadBytesExt};
+
+use ancestor;
+use graph::Graph;
+use phases::{Phase, Phases};
+use repo::{self, Repo};
+use util::Result;
+
+/// Marker flag: the marker fixes a bumped (phase-divergent) changeset
+pub const BUMPED_FIX: u16 = 1;
+/// Marker flag: nodes are 32 byte SHA-256 hashes
+pub const USING_SHA256: u16 = 2;
+
+/// A version 1 parent count meaning the parents weren't recorded
+const PARENTS_NONE: u8 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Marker {
+    pub precursor: Vec<u8>,
+    /// Empty for a prune
+    pub successors: Vec<Vec<u8>>,
+    pub flags: u16,
+    pub metadata: Vec<(String, String)>,
+    /// Seconds since the epoch and seconds west of UTC
+    pub date: (f64, i32),
+    /// The parents of the precursor, if they were recorded
+    pub parents: Option<Vec<Vec<u8>>>,
+}
+
+/// Parse the content of an obsstore.
+pub fn parse_markers(data: &[u8]) -> Result<Vec<Marker>> {
+    if data.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut cursor = Cursor::new(&data[1..]);
+    let mut markers = vec![];
+    while (cursor.position() as usize) < data.len() - 1 {
+        markers.push(match data[0] {
+            0 => try!(read_marker_v0(&mut cursor)),
+            1 => try!(read_marker_v1(&mut cursor)),
+            v => return Err(From::from(format!("unknown obsstore version {}", v))),
+        });
+    }
+    Ok(markers)
+}
+
+fn read_bytes(cursor: &mut Cursor<&[u8]>, len: usize) -> Result<Vec<u8>> {
+    let mut result = vec![0; len];
+    try!(cursor.read_exact(&mut result));
+    Ok(result)
+}
+
+fn read_marker_v0(cursor: &mut Cursor<&[u8]>) -> Result<Marker> {
+    let num_successors = try!(cursor.read_u8());
+    let meta_size = try!(cursor.read_u32::<BigEndian>());
+    let flags = try!(cursor.read_u8()) as u16;
+    let precursor = try!(read_bytes(cursor, 20));
+    let mut successors = vec![];
+    for _ in 0..num_successors {
+        successors.push(try!(read_bytes(cursor, 20)));
+    }
+    let meta = try!(read_bytes(cursor, meta_size as usize));
+
+    let mut metadata = vec![];
+    let mut date = (0.0, 0);
+    let mut parents: Vec<(String, String)> = vec![];
+    for pair in meta.split(|&c| c == 0).filter(|p| !p.is_empty()) {
+        let pair = String::from_utf8_lossy(pair);
+        let (key, value) = match pair.find(':') {
+            Some(i) => (String::from(&pair[..i]), String::from(&pair[i + 1..])),
+            None => return Err(From::from(format!("bad obsolete marker metadata {:?}", pair))),
+        };
+        match &key[..] {
+            "date" => {
+                let mut fields = value.split(' ');
+                if let (Some(Ok(time)), Some(Ok(tz))) = (fields.next().map(|t| t.parse()),
+                                                          fields.next().map(|t| t.parse())) {
+                    date = (time, tz);
+                }
+            }
+            "p0" | "p1" | "p2" => parents.push((key, value)),
+            _ => metadata.push((key, value)),
+        }
+    }
+    metadata.sort();
+
+    // The parents are p1 and p2 if p2 is there, just p1 if p1 is, and
+    // none if p0 is. Anything which isn't a node drops them all.
+    let has = |k: &str| parents.iter().any(|p| p.0 == k);
+    let wanted: &[&str] = if has("p2") {
+        &["p1", "p2"]
+    } else if has("p1") {
+        &["p1"]
+    } else {
+        &[]
+    };
+    let parents = if wanted.is_empty() && !has("p0") {
+        None
+    } else {
+        let mut nodes = vec![];
+        for key in wanted {
+            match parents.iter().find(|p| p.0 == *key).map(|p| p.1.from_hex()) {
+                Some(Ok(ref node)) if node.len() == 20 => nodes.push(node.clone()),
+                _ => {
+                    nodes.clear();
+                    break;
+                }
+            }
+        }
+        if nodes.len() == wanted.len() { Some(nodes) } else { None }
+    };
+
+    Ok(Marker {
+        precursor: precursor,
+        successors: successors,
+        flags: flags,
+        metadata: metadata,
+        date: date,
+        parents: parents,
+    })
+}
+
+fn read_marker_v1(cursor: &mut Cursor<&[u8]>) -> Result<Marker> {
+    let start = cursor.position();
+    let size = try!(cursor.read_u32::<BigEndian>());
+    let time = try!(cursor.read_f64::<BigEndian>());
+    let tz = try!(cursor.read_i16::<BigEndian>());
+    let flags = try!(cursor.read_u16::<BigEndian>());
+    let num_successors = try!(cursor.read_u8());
+    let num_parents = try!(cursor.read_u8());
+    let num_meta = try!(cursor.read_u8());
+    let node_size = if flags & USING_SHA256 != 0 { 32 } else { 20 };
+
+    let precursor = try!(read_bytes(cursor, node_size));
+    let mut successors = vec![];
+    for _ in 0..num_successors {
+        successors.push(try!(read_bytes(cursor, node_size)));
+    }
+    let parents = if num_parents == PARENTS_NONE {
+        None
+    } else {
+        let mut parents = vec![];
+        for _ in 0..num_parents {
+            parents.push(try!(read_bytes(cursor, node_size)));
+        }
+        Some(parents)
+    };
+    let mut sizes = vec![];
+    for _ in 0..num_meta {
+        let key_size = try!(cursor.read_u8()) as usize;
+        let value_size = try!(cursor.read_u8()) as usize;
+        sizes.push((key_size, value_size));
+    }
+    let mut metadata = vec![];
+    for (key_size, value_size) in sizes {
+        let key = try!(read_bytes(cursor, key_size));
+        let value = try!(read_bytes(cursor, value_size));
+        metadata.push((String::from_utf8_lossy(&key).into_owned(),
+                       String::from_utf8_lossy(&value).into_owned()));
+    }
+    expect!(cursor.position() - start == size as u64,
+            "obsolete marker size {} doesn't match its content",
+            size);
+
+    Ok(Marker {
+        precursor: precursor,
+        successors: successors,
+        flags: flags,
+        metadata: metadata,
+        date: (time, tz as i32 * 60),
+        parents: parents,
+    })
+}
+
+/// The markers of a repo.
+pub fn markers(repo: &Repo) -> Result<Vec<Marker>> {
+    match try!(repo::read_optional(&repo.store().path().join("obsstore"))) {
+        Some(data) => parse_markers(&data),
+        None => Ok(vec![]),
+    }
+}
+
+/// The changesets affected by obsolescence.
+pub struct Obsolescence {
+    /// Rewritten or pruned changesets which aren't public
+    pub obsolete: HashSet<i32>,
+    /// Changesets which aren't obsolete but descend from one that is
+    pub unstable: HashSet<i32>,
+    /// Obsolete changesets with no unstable descendants
+    pub extinct: HashSet<i32>,
+}
+
+impl Obsolescence {
+    pub fn compute(repo: &Repo) -> Result<Obsolescence> {
+        let phases = try!(Phases::read(repo));
+        let revlog = repo.changelog.revlog();
+        let mut obsolete = HashSet::new();
+        for marker in try!(markers(repo)) {
+            if let Some(rev) = try!(revlog.rev(&marker.precursor)) {
+                if rev != -1 && phases.phase(rev) != Phase::Public {
+                    obsolete.insert(rev);
+                }
+            }
+        }
+
+        let graph = Graph::new(revlog);
+        let mut unstable = HashSet::new();
+        if !obsolete.is_empty() {
+            let first = *obsolete.iter().min().unwrap();
+            // Parents come first, so instability spreads in one pass
+            for rev in first..graph.len() {
+                if obsolete.contains(&rev) || phases.phase(rev) == Phase::Public {
+                    continue;
+                }
+                let parents = try!(graph.parents(rev));
+                if parents.iter().any(|p| obsolete.contains(p) || unstable.contains(p)) {
+                    unstable.insert(rev);
+                }
+            }
+        }
+
+        let unstable_revs: Vec<i32> = unstable.iter().cloned().collect();
+        let mut suspended = HashSet::new();
+        for rev in try!(ancestor::ancestors(&graph, &unstable_revs, false)) {
+            let rev = try!(rev);
+            if obsolete.contains(&rev) {
+                suspended.insert(rev);
+            }
+        }
+        let extinct = obsolete.difference(&suspended).cloned().collect();
+
+        Ok(Obsolescence {
+            obsolete: obsolete,
+            unstable: unstable,
+            extinct: extinct,
+        })
+    }
+}
+
+/// The changesets the visible view of a repo hides, as `hg log` does.
+/// These are the obsolete ones and those in the archived and internal
+/// phases, except for any that are pinned, by being either working
+/// directory parent, a bookmark or a local tag, and any ancestors of
+/// changesets still shown.
+pub fn hidden(repo: &Repo) -> Result<HashSet<i32>> {
+    let phases = try!(Phases::read(repo));
+    let mut hidden = try!(Obsolescence::compute(repo)).obsolete;
+    for rev in 0..repo.changelog.len() {
+        if phases.phase(rev) >= Phase::Archived {
+            hidden.insert(rev);
+        }
+    }
+    if hidden.is_empty() {
+        return Ok(hidden);
+    }
+
+    let revlog = repo.changelog.revlog();
+    let (p1, p2) = try!(repo.working_parents());
+    let mut pinned = vec![p1, p2];
+    pinned.extend(try!(repo.bookmarks()).into_iter().map(|(_, node)| node));
+    if let Some(data) = try!(repo::read_optional(&repo.hg_path().join("localtags"))) {
+        pinned.extend(try!(repo::parse_node_names(&data)).into_iter().map(|(_, node)| node));
+    }
+    for node in pinned {
+        if let Some(rev) = try!(revlog.rev(&node)) {
+            hidden.remove(&rev);
+        }
+    }
+
+    // Ancestors of visible draft or secret changesets stay visible
+    let first = *hidden.iter().min().unwrap();
+    let mut visible = vec![];
+    for rev in first..repo.changelog.len() {
+        if !hidden.contains(&rev) && phases.phase(rev) != Phase::Public {
+            visible.push(rev);
+        }
+    }
+    let graph = Graph::new(revlog);
+    for rev in try!(ancestor::ancestors(&graph, &visible, false)) {
+        let rev = try!(rev);
+        if rev < first {
+            break;
+        }
+        hidden.remove(&rev);
+    }
+    Ok(hidden)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use rustc_serialize::hex::ToHex;
+    use testutil::{Commit, RepoBuilder};
+    use super::{hidden, parse_markers};
+
+    #[test]
+    fn test_parse_v0() {
+        let mut data = vec![0, 1, 0, 0, 0, 0, 0];
+        let meta = b"date:1460000000 0\0p1:0303030303030303030303030303030303030303\0user:josh";
+        data[5] = meta.len() as u8;
+        data.extend(vec![1; 20]);
+        data.extend(vec![2; 20]);
+        data.extend(meta.iter().cloned());
+        let markers = parse_markers(&data).unwrap();
+        assert_eq!(1, markers.len());
+        assert_eq!(vec![1; 20], markers[0].precursor);
+        assert_eq!(vec![vec![2; 20]], markers[0].successors);
+        assert_eq!((1460000000.0, 0), markers[0].date);
+        assert_eq!(Some(vec![vec![3; 20]]), markers[0].parents);
+        assert_eq!(vec![(String::from("user"), String::from("josh"))], markers[0].metadata);
+    }
+
+    #[test]
+    fn test_parse_v1() {
+        // A prune with no recorded parents and one metadata entry
+        let mut data = vec![1];
+        data.extend(&[0, 0, 0, 48]);
+        data.extend(&[0x41, 0xd5, 0xc1, 0x75, 0x40, 0, 0, 0]);
+        data.extend(&[0xff, 0xc4, 0, 0, 0, 3, 1]);
+        data.extend(vec![1; 20]);
+        data.extend(&[4, 3]);
+        data.extend(b"userbob");
+        let markers = parse_markers(&data).unwrap();
+        assert_eq!(1, markers.len());
+        assert!(markers[0].successors.is_empty());
+        assert_eq!(None, markers[0].parents);
+        assert_eq!((1460000000.0, -3600), markers[0].date);
+        assert_eq!(vec![(String::from("user"), String::from("bob"))], markers[0].metadata);
+        assert!(parse_markers(&data[..30]).is_err());
+    }
+
+    #[test]
+    fn test_hidden_pins_merge_parents() {
+        let mut builder = RepoBuilder::new("obsolete-pinned");
+        builder.commit(Commit::new(-1, -1).file("a", "0"));
+        for rev in 1..4 {
+            builder.commit(Commit::new(0, -1).file("a", &rev.to_string()));
+        }
+        let hg = builder.hg_path();
+        let mut roots = File::create(hg.join("phaseroots")).unwrap();
+        let mut obsstore = File::create(hg.join("obsstore")).unwrap();
+        obsstore.write_all(&[1]).unwrap();
+        for rev in 1..4 {
+            roots.write_all(format!("1 {}\n", builder.node(rev).to_hex()).as_bytes()).unwrap();
+            // Pruned, with no successors, parents or metadata
+            obsstore.write_all(&[0, 0, 0, 39, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 0]).unwrap();
+            obsstore.write_all(&builder.node(rev)).unwrap();
+        }
+        let mut dirstate = File::create(hg.join("dirstate")).unwrap();
+        dirstate.write_all(&builder.node(1)).unwrap();
+        dirstate.write_all(&builder.node(2)).unwrap();
+        let hidden: Vec<i32> = hidden(&builder.open()).unwrap().into_iter().collect();
+        assert_eq!(vec![3], hidden);
+    }
+}
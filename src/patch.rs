@@ -20,6 +20,7 @@ use std::io::{Cursor, Read};
 use self::bytes::Bytes;
 use self::bytes::buf::Source;
 use std::fmt;
+use util;
 
 struct DebugBytes<'a>(&'a Bytes);
 
@@ -33,7 +34,10 @@ impl<'a> fmt::Debug for DebugBytes<'a> {
     }
 }
 
-pub fn apply(base: Vec<u8>, patches: Vec<Vec<u8>>) -> Vec<u8> {
+/// Apply patches in sequence, failing rather than panicking on a hunk
+/// which is truncated, out of order, or beyond the end of the buffer,
+/// as one from a corrupt revlog or a bundle may be.
+pub fn apply(base: Vec<u8>, patches: Vec<Vec<u8>>) -> util::Result<Vec<u8>> {
     //println!("::: {:?}", String::from_utf8_lossy(&base));
     let mut buf: Bytes = From::from(base);
     for patch in patches {
@@ -43,9 +47,16 @@ pub fn apply(base: Vec<u8>, patches: Vec<Vec<u8>>) -> Vec<u8> {
         let mut next = Bytes::empty();
         //println!("current buf {:?}", DebugBytes(&buf));
         while cur.position() != patch_len {
-            assert!(cur.position() < patch_len);
+            expect!(patch_len - cur.position() >= 12, "truncated patch hunk");
             //println!("current next {:?}", DebugBytes(&next));
             let (a, b, c) = decode_header(&mut cur);
+            expect!(last <= a && a <= b && b <= buf.len(),
+                    "invalid patch hunk {}..{} after {} in {} bytes",
+                    a,
+                    b,
+                    last,
+                    buf.len());
+            expect!(c as u64 <= patch_len - cur.position(), "truncated patch hunk");
             let piece: Bytes = From::from(read_slice(&mut cur, c));
             //println!("+++ {} {} {} {:?}", a, b, c, DebugBytes(&piece));
             //println!("keep {:?}", DebugBytes(&buf.slice(last, a)));
@@ -64,16 +75,16 @@ pub fn apply(base: Vec<u8>, patches: Vec<Vec<u8>>) -> Vec<u8> {
     }
     let mut result = vec![];
     buf.copy_to(&mut result);
-    return result;
+    return Ok(result);
 }
 
-fn read_slice(src: &mut Cursor<Vec<u8>>, len: usize) -> Vec<u8> {
+fn read_slice<R: Read>(src: &mut R, len: usize) -> Vec<u8> {
     let mut buf = vec![0; len];
     src.read_exact(&mut buf[..]).unwrap();
     return buf;
 }
 
-fn decode_header(header: &mut Cursor<Vec<u8>>) -> (usize, usize, usize) {
+fn decode_header<R: Read>(header: &mut R) -> (usize, usize, usize) {
     let a = header.read_u32::<BigEndian>().unwrap() as usize;
     let b = header.read_u32::<BigEndian>().unwrap() as usize;
     let c = header.read_u32::<BigEndian>().unwrap() as usize;
@@ -82,11 +93,43 @@ fn decode_header(header: &mut Cursor<Vec<u8>>) -> (usize, usize, usize) {
 
 #[cfg(test)]
 mod test {
-    use super::decode_header;
+    use super::{apply, decode_header};
     use std::io::Cursor;
     #[test]
     fn test_header() {
         let mut hdr = Cursor::new(b"\x00\x00\x00\x2a\x00\x00\x00\x2b\x00\x00\x00\x2c" as &[u8]);
         assert_eq!((0x2a, 0x2b, 0x2c), decode_header(&mut hdr));
     }
+
+    /// A patch of hunks without their data.
+    fn hunks(hunks: &[(u32, u32, u32)]) -> Vec<u8> {
+        let mut patch = vec![];
+        for &(a, b, c) in hunks {
+            for &n in &[a, b, c] {
+                patch.extend_from_slice(&[(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]);
+            }
+            patch.extend(vec![b'x'; c as usize]);
+        }
+        patch
+    }
+
+    #[test]
+    fn test_apply() {
+        let base = Vec::from(&b"abcdef"[..]);
+        let patch = hunks(&[(1, 2, 0), (3, 3, 2), (6, 6, 1)]);
+        assert_eq!(&b"acxxdefx"[..], &apply(base.clone(), vec![patch.clone()]).unwrap()[..]);
+        // Overlapping, backwards and beyond the end
+        for bad in &[&[(2, 4, 0), (3, 5, 0)][..], &[(3, 2, 0)], &[(5, 7, 0)], &[(7, 7, 0)]] {
+            assert!(apply(base.clone(), vec![hunks(bad)]).is_err());
+        }
+        // Truncated in the header or the data
+        for len in 1..patch.len() {
+            if len != 12 && len != 26 {
+                assert!(apply(base.clone(), vec![patch[..len].to_vec()]).is_err(), "{}", len);
+            }
+        }
+        // The second patch applies to the result of the first
+        assert!(apply(base.clone(), vec![patch.clone(), hunks(&[(8, 8, 0)])]).is_ok());
+        assert!(apply(base.clone(), vec![patch.clone(), hunks(&[(9, 9, 0)])]).is_err());
+    }
 }
@@ -0,0 +1,174 @@
+//! Phases track how far a changeset has been shared. Public changesets
+//! are immutable; draft ones haven't been published; secret ones must
+//! never be exchanged, so anything exporting history should leave them
+//! out.
+//!
+//! Only the roots of each non-public phase are stored, in the store's
+//! `phaseroots` as lines of `<phase number> <node, hex>`. A changeset
+//! has the highest phase of any root it descends from, and is public if
+//! there is none.
+
+use rustc_serialize::hex::FromHex;
+
+use ancestor;
+use graph::Graph;
+use repo::{self, Repo};
+use util::Result;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Public,
+    Draft,
+    Secret,
+    /// Hidden instead of being stripped
+    Archived,
+    /// Internal changesets such as shelves
+    Internal,
+}
+
+impl Phase {
+    pub fn from_number(n: u32) -> Option<Phase> {
+        match n {
+            0 => Some(Phase::Public),
+            1 => Some(Phase::Draft),
+            2 => Some(Phase::Secret),
+            32 => Some(Phase::Archived),
+            96 => Some(Phase::Internal),
+            _ => None,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Phase> {
+        match name {
+            "public" => Some(Phase::Public),
+            "draft" => Some(Phase::Draft),
+            "secret" => Some(Phase::Secret),
+            "archived" => Some(Phase::Archived),
+            "internal" => Some(Phase::Internal),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Phase::Public => "public",
+            Phase::Draft => "draft",
+            Phase::Secret => "secret",
+            Phase::Archived => "archived",
+            Phase::Internal => "internal",
+        }
+    }
+}
+
+/// Parse `phaseroots`, leaving out roots of the public phase.
+pub fn parse_roots(data: &[u8]) -> Result<Vec<(Phase, Vec<u8>)>> {
+    let mut result = vec![];
+    for line in String::from_utf8_lossy(data).lines() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(' ').collect();
+        expect!(fields.len() == 2, "bad phaseroots line {:?}", line);
+        let phase = match fields[0].parse::<u32>().ok().and_then(Phase::from_number) {
+            Some(phase) => phase,
+            None => return Err(From::from(format!("unknown phase in phaseroots: {:?}", fields[0]))),
+        };
+        let node = try!(fields[1].from_hex());
+        expect!(node.len() == 20, "bad node in phaseroots: {:?}", fields[1]);
+        if phase != Phase::Public {
+            result.push((phase, node));
+        }
+    }
+    Ok(result)
+}
+
+/// The phase roots of a repo, as revs. Roots which aren't in the
+/// changelog are ignored. Changesets from a bundle which hasn't been
+/// applied are drafts, so the first of them are draft roots.
+pub fn roots(repo: &Repo) -> Result<Vec<(Phase, i32)>> {
+    let mut result = vec![];
+    if let Some(data) = try!(repo::read_optional(&repo.store().path().join("phaseroots"))) {
+        for (phase, node) in try!(parse_roots(&data)) {
+            if let Some(rev) = try!(repo.changelog.revlog().rev(&node)) {
+                result.push((phase, rev));
+            }
+        }
+    }
+    let stored = repo.changelog.revlog().stored_len();
+    for rev in stored..repo.changelog.len() {
+        let (p1, p2) = try!(repo.changelog.parents(rev));
+        if p1 < stored && p2 < stored {
+            result.push((Phase::Draft, rev));
+        }
+    }
+    Ok(result)
+}
+
+/// The phases of every changeset in a repo.
+pub struct Phases {
+    phases: Vec<Phase>,
+}
+
+impl Phases {
+    pub fn read(repo: &Repo) -> Result<Phases> {
+        let mut phases = vec![Phase::Public; repo.changelog.len() as usize];
+        let mut roots = try!(roots(repo));
+        // Lower phases first, so that higher ones overwrite them
+        roots.sort();
+        let graph = Graph::new(repo.changelog.revlog());
+        let mut i = 0;
+        while i < roots.len() {
+            let phase = roots[i].0;
+            let revs: Vec<i32> = roots[i..].iter().take_while(|r| r.0 == phase).map(|r| r.1).collect();
+            i += revs.len();
+            for rev in ancestor::descendants(&graph, &revs, true) {
+                phases[try!(rev) as usize] = phase;
+            }
+        }
+        Ok(Phases { phases: phases })
+    }
+
+    /// The phase of a rev. The null rev is public.
+    pub fn phase(&self, rev: i32) -> Phase {
+        if rev == -1 {
+            Phase::Public
+        } else {
+            self.phases[rev as usize]
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use rustc_serialize::hex::ToHex;
+    use testutil::{Commit, RepoBuilder};
+    use super::{parse_roots, Phase, Phases};
+
+    #[test]
+    fn test_parse_roots() {
+        let data = format!("1 {}\n2 {}\n0 {}\n", "01".repeat(20), "02".repeat(20), "03".repeat(20));
+        assert_eq!(vec![(Phase::Draft, vec![1; 20]), (Phase::Secret, vec![2; 20])],
+                   parse_roots(data.as_bytes()).unwrap());
+        assert!(parse_roots(b"7 0101\n").is_err());
+        assert!(Phase::Draft < Phase::Secret);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut builder = RepoBuilder::new("phases-read");
+        builder.commit(Commit::new(-1, -1).file("a", "0"));
+        builder.commit(Commit::new(0, -1).file("a", "1"));
+        builder.commit(Commit::new(1, -1).file("a", "2"));
+        builder.commit(Commit::new(0, -1).file("b", "3"));
+        let roots = format!("1 {}\n2 {}\n2 {}\n",
+                            builder.node(1).to_hex(),
+                            builder.node(2).to_hex(),
+                            "ff".repeat(20));
+        File::create(builder.hg_path().join("phaseroots")).unwrap().write_all(roots.as_bytes()).unwrap();
+        let phases = Phases::read(&builder.open()).unwrap();
+        let all: Vec<Phase> = (-1..4).map(|rev| phases.phase(rev)).collect();
+        assert_eq!(vec![Phase::Public, Phase::Public, Phase::Draft, Phase::Secret, Phase::Public], all);
+    }
+}
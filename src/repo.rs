@@ -0,0 +1,348 @@
+//! A Mercurial repository: the `.hg` directory and the revlogs in its
+//! store, with enough of the surrounding metadata to resolve the usual
+//! ways of naming a changeset.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use rustc_serialize::hex::FromHex;
+
+use ancestor;
+use bookmarks;
+use branchmap::BranchMap;
+use changelog::{Changelog, Changeset};
+use dirstate;
+use filelog::Filelog;
+use graph::Graph;
+use manifest::{Manifest, Manifestlog};
+use matcher::{self, Matcher};
+use obsolete;
+use phases::{self, Phase};
+use store::{Overlay, Store};
+use tags;
+use util::Result;
+
+/// Which changesets a repo shows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum View {
+    /// Leave out hidden changesets, as `hg log` does
+    Visible,
+    /// Show everything, as with `--hidden`
+    Unfiltered,
+}
+
+pub struct Repo {
+    root: PathBuf,
+    hg: PathBuf,
+    pub requires: Vec<String>,
+    store: Store,
+    pub changelog: Changelog,
+    pub manifestlog: Manifestlog,
+    pub view: View,
+    /// Computed on first use
+    hidden: RefCell<Option<Rc<HashSet<i32>>>>,
+}
+
+impl Repo {
+    /// Open the repository whose working directory is `root`.
+    pub fn open(root: &Path) -> Result<Repo> {
+        let hg = root.join(".hg");
+        expect!(hg.is_dir(), "repository {:?} not found", root);
+
+        let requires = match try!(read_optional(&hg.join("requires"))) {
+            Some(data) => {
+                String::from_utf8_lossy(&data)
+                    .lines()
+                    .filter(|l| !l.is_empty())
+                    .map(String::from)
+                    .collect()
+            }
+            None => vec![],
+        };
+
+        // A shared repo keeps its store in the source repo's .hg
+        let store_base = match try!(read_optional(&hg.join("sharedpath"))) {
+            Some(data) => PathBuf::from(String::from_utf8_lossy(&data).trim_right()),
+            None => hg.clone(),
+        };
+        let store = Store::new(&store_base, &requires);
+
+        let changelog = Changelog::new(try!(store.revlog_or_empty("00changelog")));
+        let manifestlog = Manifestlog::new(try!(store.revlog_or_empty("00manifest")));
+
+        Ok(Repo {
+            root: root.to_path_buf(),
+            hg: hg,
+            requires: requires,
+            store: store,
+            changelog: changelog,
+            manifestlog: manifestlog,
+            view: View::Visible,
+            hidden: RefCell::new(None),
+        })
+    }
+
+    /// This repo with revisions added to its revlogs, numbered after the
+    /// stored ones.
+    pub fn with_overlay(self, overlay: Overlay) -> Result<Repo> {
+        let store = self.store.with_overlay(overlay);
+        let changelog = Changelog::new(try!(store.revlog("00changelog")));
+        let manifestlog = Manifestlog::new(try!(store.revlog("00manifest")));
+        Ok(Repo {
+            store: store,
+            changelog: changelog,
+            manifestlog: manifestlog,
+            hidden: RefCell::new(None),
+            ..self
+        })
+    }
+
+    /// Open the repository containing `start`, looking in its parent
+    /// directories like Mercurial does.
+    pub fn find(start: &Path) -> Result<Repo> {
+        let mut dir = Some(start);
+        while let Some(d) = dir {
+            if d.join(".hg").is_dir() {
+                return Repo::open(d);
+            }
+            dir = d.parent();
+        }
+        Err(From::from(format!("no repository found in {:?} (.hg not found)", start)))
+    }
+
+    /// The root of the working directory.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The `.hg` directory.
+    pub fn hg_path(&self) -> &Path {
+        &self.hg
+    }
+
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// The changesets this repo's view leaves out.
+    pub fn hidden(&self) -> Result<Rc<HashSet<i32>>> {
+        if self.view == View::Unfiltered {
+            return Ok(Rc::new(HashSet::new()));
+        }
+        if self.hidden.borrow().is_none() {
+            *self.hidden.borrow_mut() = Some(Rc::new(try!(obsolete::hidden(self))));
+        }
+        Ok(self.hidden.borrow().as_ref().unwrap().clone())
+    }
+
+    /// The revs in this repo's view, in increasing order.
+    pub fn revs(&self) -> Result<Vec<i32>> {
+        let hidden = try!(self.hidden());
+        Ok((0..self.changelog.len()).filter(|r| !hidden.contains(r)).collect())
+    }
+
+    /// The most recent rev in this repo's view, or the null rev.
+    pub fn tip(&self) -> Result<i32> {
+        let hidden = try!(self.hidden());
+        Ok((0..self.changelog.len()).rev().find(|r| !hidden.contains(r)).unwrap_or(-1))
+    }
+
+    /// The heads of the changesets in this repo's view, in increasing
+    /// order.
+    pub fn heads(&self) -> Result<Vec<i32>> {
+        let revs = try!(self.revs());
+        if revs.is_empty() {
+            return Ok(vec![-1]);
+        }
+        let graph = Graph::new(self.changelog.revlog());
+        let mut has_child = HashSet::new();
+        for &rev in &revs {
+            has_child.extend(try!(graph.parents(rev)));
+        }
+        Ok(revs.into_iter().filter(|r| !has_child.contains(r)).collect())
+    }
+
+    pub fn filelog(&self, path: &str) -> Result<Filelog> {
+        let revlog = try!(self.store.revlog(&Store::filelog_name(path)));
+        Ok(Filelog::new(revlog))
+    }
+
+    pub fn changeset(&self, rev: i32) -> Result<Changeset> {
+        self.changelog.changeset(rev)
+    }
+
+    /// The manifest of a changeset.
+    pub fn manifest(&self, rev: i32) -> Result<Manifest> {
+        let cs = try!(self.changeset(rev));
+        self.manifestlog.read(&cs.manifest)
+    }
+
+    /// The content of a file in a changeset, or None if the changeset
+    /// doesn't contain it.
+    pub fn file_content(&self, rev: i32, path: &str) -> Result<Option<Vec<u8>>> {
+        let manifest = try!(self.manifest(rev));
+        let entry = match manifest.get(path) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let filelog = try!(self.filelog(path));
+        let filerev = match try!(filelog.revlog().rev(&entry.node)) {
+            Some(filerev) => filerev,
+            None => return Err(From::from(format!("{}: filelog node not found", path))),
+        };
+        Ok(Some(try!(filelog.content(filerev))))
+    }
+
+    /// The patterns of `.hgignore` and the files it includes, matching
+    /// the untracked files `status` shouldn't list.
+    pub fn ignore(&self) -> Result<Matcher> {
+        matcher::read_ignore(&self.root, &self.root.join(".hgignore"))
+    }
+
+    /// The first parent of the working directory, from the dirstate.
+    pub fn working_parent(&self) -> Result<Vec<u8>> {
+        Ok(try!(dirstate::read_parents(self)).0)
+    }
+
+    /// Both parents of the working directory, the second being the null
+    /// id unless a merge is in progress.
+    pub fn working_parents(&self) -> Result<(Vec<u8>, Vec<u8>)> {
+        dirstate::read_parents(self)
+    }
+
+    /// Bookmarks and the nodes they point to.
+    pub fn bookmarks(&self) -> Result<BTreeMap<String, Vec<u8>>> {
+        bookmarks::bookmarks(self)
+    }
+
+    pub fn active_bookmark(&self) -> Result<Option<String>> {
+        bookmarks::active(self)
+    }
+
+    /// Global and local tags and the nodes they point to, leaving out
+    /// `tip`.
+    pub fn tags(&self) -> Result<BTreeMap<String, Vec<u8>>> {
+        tags::tags(self)
+    }
+
+    /// The phase of a changeset. Use `Phases` for many changesets.
+    pub fn phase(&self, rev: i32) -> Result<Phase> {
+        let graph = Graph::new(self.changelog.revlog());
+        let mut result = Phase::Public;
+        for (phase, root) in try!(phases::roots(self)) {
+            if phase > result && try!(ancestor::is_ancestor(&graph, root, rev)) {
+                result = phase;
+            }
+        }
+        Ok(result)
+    }
+
+    /// The head a branch name refers to: its most recent open head.
+    pub fn branch_tip(&self, branch: &str) -> Result<Option<i32>> {
+        Ok(try!(BranchMap::read(self)).tip(branch))
+    }
+
+    /// Resolve a symbol naming a changeset to a rev. In order, this
+    /// tries: `null`, `tip` and `.`; a rev number; a full node id;
+    /// bookmarks, tags and branches; and finally a node id prefix. It's
+    /// an error to name a changeset outside this repo's view.
+    pub fn lookup(&self, symbol: &str) -> Result<i32> {
+        let rev = try!(self.resolve(symbol));
+        expect!(!try!(self.hidden()).contains(&rev), "hidden revision {:?}", symbol);
+        Ok(rev)
+    }
+
+    fn resolve(&self, symbol: &str) -> Result<i32> {
+        let len = self.changelog.len();
+        match symbol {
+            "null" => return Ok(-1),
+            "tip" => return self.tip(),
+            "." => {
+                let node = try!(self.working_parent());
+                return match try!(self.changelog.revlog().rev(&node)) {
+                    Some(rev) => Ok(rev),
+                    None => Err(From::from("working directory parent not found")),
+                };
+            }
+            _ => (),
+        }
+
+        if let Ok(rev) = symbol.parse::<i32>() {
+            // "007" is not a rev number, but may be a node prefix
+            if rev.to_string() == symbol {
+                let rev = if rev < 0 { rev + len } else { rev };
+                if rev >= 0 && rev < len {
+                    return Ok(rev);
+                }
+            }
+        }
+
+        if symbol.len() == 40 {
+            if let Ok(node) = symbol.from_hex() {
+                if let Some(rev) = try!(self.changelog.revlog().rev(&node)) {
+                    return Ok(rev);
+                }
+            }
+        }
+
+        if let Some(node) = try!(self.bookmarks()).get(symbol) {
+            return self.node_rev(node);
+        }
+        if let Some(node) = try!(self.tags()).get(symbol) {
+            return self.node_rev(node);
+        }
+        if let Some(rev) = try!(self.branch_tip(symbol)) {
+            return Ok(rev);
+        }
+
+        if symbol.chars().all(|c| c.is_digit(16)) {
+            if let Some(rev) = try!(self.changelog.revlog().lookup_prefix(symbol)) {
+                return Ok(rev);
+            }
+        }
+        Err(From::from(format!("unknown revision {:?}", symbol)))
+    }
+
+    fn node_rev(&self, node: &[u8]) -> Result<i32> {
+        match try!(self.changelog.revlog().rev(node)) {
+            Some(rev) => Ok(rev),
+            None => Err(From::from("named node is not in the changelog")),
+        }
+    }
+}
+
+/// Read a file that might not exist.
+pub fn read_optional(path: &Path) -> Result<Option<Vec<u8>>> {
+    let mut f = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(From::from(e)),
+    };
+    let mut data = vec![];
+    try!(f.read_to_end(&mut data));
+    Ok(Some(data))
+}
+
+/// Parse lines of `<node, hex> <name>`, as in bookmarks and tags files.
+pub fn parse_node_names(data: &[u8]) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut result = vec![];
+    for line in String::from_utf8_lossy(data).lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (node, name) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => continue,
+        };
+        let node = match node.from_hex() {
+            Ok(ref node) if node.len() == 20 => node.clone(),
+            _ => continue,
+        };
+        result.push((String::from(name), node));
+    }
+    Ok(result)
+}
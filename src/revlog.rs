@@ -3,6 +3,13 @@ extern crate flate2;
 use std::io::Read;
 use std::rc::Rc;
 use std::cell::{RefCell, Ref};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+use rustc_serialize::hex::ToHex;
 
 use patch;
 use util;
@@ -14,7 +21,20 @@ const REVLOGNG: u32 = 1;
 const REVLOGNGINLINEDATA: u32 = (1 << 16);
 const REVLOGGENERALDELTA: u32 = (1 << 17);
 
-const NULL_ID: &'static [u8] = &[0u8; 20];
+pub const NULL_ID: &'static [u8] = &[0u8; 20];
+
+/// Compute a node id: the SHA-1 of the two parent ids, smaller first,
+/// followed by the full text of the revision.
+pub fn hash(text: &[u8], p1: &[u8], p2: &[u8]) -> Vec<u8> {
+    let mut sha = Sha1::new();
+    let (a, b) = if p1 <= p2 { (p1, p2) } else { (p2, p1) };
+    sha.input(a);
+    sha.input(b);
+    sha.input(text);
+    let mut result = vec![0; 20];
+    sha.result(&mut result);
+    result
+}
 
 /// A low-level cursor into RevlogNG index entry.
 /// The memory representation of this type is exactly the 64 bytes in
@@ -26,7 +46,9 @@ const NULL_ID: &'static [u8] = &[0u8; 20];
 /// - Masking the version out of the first offset_flags
 /// - Distinguishing between offset and flags for the first rev
 /// - Selecting the first 20 bytes of c_node_id
-#[repr(C)]
+///
+/// Entries in an inline revlog are not aligned, hence `packed`.
+#[repr(C, packed)]
 pub struct RevlogChunk {
     offset_flags: u64,
     comp_len: i32,
@@ -46,7 +68,7 @@ impl RevlogChunk {
     fn offset(&self) -> u64 {
         self.offset_flags() >> 16
     }
-    fn flags(&self) -> u16 {
+    pub fn flags(&self) -> u16 {
         (self.offset_flags() & 0xFFFF) as u16
     }
     pub fn comp_len(&self) -> i32 {
@@ -95,7 +117,7 @@ impl<'a> RevlogEntry<'a> {
     // Precondition: inline
     fn inline_advance(self) -> Result<Option<RevlogEntry<'a>>> {
         let next = (self.byte_offset + self.chunk.comp_len() as isize + 64) as isize;
-        if next == self.revlog.index.len {
+        if next == self.revlog.index_len() {
             return Ok(None);
         }
         let result = try!(self.revlog.index_entry_at_byte(next as isize, None));
@@ -138,6 +160,25 @@ impl<'a> RevlogEntry<'a> {
         }
     }
 
+    /// The rev whose text this entry's delta applies to, or -1 if the
+    /// full text is stored. Without generaldelta, deltas are always
+    /// against the previous rev, and base_rev is where the chain starts.
+    pub fn delta_parent(&self) -> i32 {
+        if self.chunk.base_rev() == self.revno {
+            -1
+        } else if self.revlog.generaldelta {
+            self.chunk.base_rev()
+        } else {
+            self.revno - 1
+        }
+    }
+
+    /// The data stored with this entry as it is on disk, including the
+    /// byte which identifies its compression.
+    pub fn raw_data(&self) -> &'a [u8] {
+        self.data
+    }
+
     /// The data stored with this entry, uncompressed
     pub fn data(&self) -> Vec<u8> {
         if self.data.len() == 0 {
@@ -159,24 +200,37 @@ impl<'a> RevlogEntry<'a> {
     }
 
     /// The text of this revision, computed from the deltas.
-    pub fn text(&self) -> Rc<Vec<u8>> {
+    pub fn text(&self) -> Result<Rc<Vec<u8>>> {
         if let Some(ref found) = *self.full_text.borrow() {
-            return found.clone();
+            return Ok(found.clone());
         }
         let mut chain: Vec<_>;
-        chain = self.delta_chain().map(Result::unwrap).collect();
+        chain = try!(self.delta_chain().collect());
         let base = chain.pop().unwrap();
         chain.reverse();
         let patches: Vec<Vec<u8>>;
         patches = chain.iter().map(|rev| rev.data()).collect();
-        let text = patch::apply(base.data(), patches);
+        let text = try!(patch::apply(base.data(), patches));
         *self.full_text.borrow_mut() = Some(Rc::new(text));
         match *self.full_text.borrow() {
             None => unreachable!(),
-            Some(ref found) => found.clone(),
+            Some(ref found) => Ok(found.clone()),
         }
     }
 
+    /// Check the node id against the hash of the raw text. For filelogs
+    /// this includes any copy metadata at the start of the text. A text
+    /// whose deltas don't apply doesn't verify.
+    pub fn verify(&self) -> Result<bool> {
+        let p1 = try!(self.parent_1_id());
+        let p2 = try!(self.parent_2_id());
+        let text = match self.text() {
+            Ok(text) => text,
+            Err(_) => return Ok(false),
+        };
+        Ok(hash(&text, p1, p2) == self.chunk.c_node_id())
+    }
+
     /// The data stored with this entry and all previous entries in the
     /// delta chain
     pub fn delta_chain(&self) -> DeltaChain {
@@ -194,20 +248,56 @@ pub struct DeltaChain<'a> {
 impl<'a> Iterator for DeltaChain<'a> {
     type Item = Result<RevlogEntry<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur.is_none() {
-            return None;
-        }
-        let cur = self.cur.take().unwrap();
-        let next_rev = cur.base_rev();
-        self.cur = if next_rev == -1 || next_rev == cur.revno {
-            None
-        } else {
-            Some(cur.revlog.index(next_rev).unwrap())
+        let cur = match self.cur.take() {
+            Some(cur) => cur,
+            None => return None,
         };
+        let next_rev = cur.delta_parent();
+        if next_rev != -1 {
+            match cur.revlog.index(next_rev) {
+                Ok(entry) => self.cur = Some(entry),
+                Err(e) => return Some(Err(e)),
+            }
+        }
         return Some(Ok(cur));
     }
 }
 
+/// Texts already computed from one revlog. A text whose delta chain
+/// passes through one of them is computed from there rather than from
+/// the base of the chain, so that reading revs in order mostly costs a
+/// patch each. The oldest texts are dropped beyond a number of them.
+pub struct TextCache {
+    texts: HashMap<i32, Rc<Vec<u8>>>,
+    order: VecDeque<i32>,
+    capacity: usize,
+}
+
+impl TextCache {
+    pub fn new(capacity: usize) -> TextCache {
+        TextCache {
+            texts: HashMap::new(),
+            order: VecDeque::new(),
+            capacity: capacity,
+        }
+    }
+
+    pub fn get(&self, rev: i32) -> Option<Rc<Vec<u8>>> {
+        self.texts.get(&rev).cloned()
+    }
+
+    pub fn insert(&mut self, rev: i32, text: Rc<Vec<u8>>) {
+        if self.capacity == 0 || self.texts.insert(rev, text).is_some() {
+            return;
+        }
+        self.order.push_back(rev);
+        if self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.texts.remove(&oldest);
+        }
+    }
+}
+
 pub struct RevlogIterator<'a> {
     revlog: &'a Revlog,
     /// None if iter hasn't begun
@@ -218,23 +308,25 @@ impl<'a> Iterator for RevlogIterator<'a> {
     type Item = Result<RevlogEntry<'a>>;
     fn next(&mut self) -> Option<Self::Item> {
         let next = match self.cur {
+            None if self.revlog.index.is_none() => self.revlog.extra_entry(0),
             None => {
                 match self.revlog.index_entry_at_byte(0, None) {
                     Ok(entry) => Some(entry),
                     Err(e) => return Some(Err(e)),
                 }
             }
+            Some(ref prev) if prev.byte_offset < 0 => self.revlog.extra_entry(prev.revno + 1),
             Some(ref prev) => {
                 if self.revlog.inline() {
                     match prev.clone().inline_advance() {
-                        Ok(None) => None,
+                        Ok(None) => self.revlog.extra_entry(self.revlog.stored_len()),
                         Ok(Some(entry)) => Some(entry),
                         Err(e) => return Some(Err(e)),
                     }
                 } else {
                     let next_offset = prev.byte_offset + 64;
-                    if next_offset == self.revlog.index.len {
-                        None
+                    if next_offset == self.revlog.index_len() {
+                        self.revlog.extra_entry(self.revlog.stored_len())
                     } else {
                         match self.revlog.index_entry_at_byte(next_offset as isize, None) {
                             Ok(entry) => Some(entry),
@@ -250,9 +342,54 @@ impl<'a> Iterator for RevlogIterator<'a> {
     }
 }
 
+/// A revision kept in memory after those stored in a revlog's files,
+/// such as one from a bundle which hasn't been applied. Its data is its
+/// full text.
+pub struct ExtraRevision {
+    chunk: RevlogChunk,
+    data: Vec<u8>,
+}
+
+impl ExtraRevision {
+    /// The revision `rev` of a revlog, with parents and linkrev as revs.
+    pub fn new(rev: i32, node: &[u8], p1: i32, p2: i32, link_rev: i32, flags: u16, text: &[u8]) -> ExtraRevision {
+        // Stored as Mercurial stores uncompressed text
+        let data = if text.is_empty() || text[0] == b'\0' {
+            Vec::from(text)
+        } else {
+            let mut data = Vec::with_capacity(text.len() + 1);
+            data.push(b'u');
+            data.extend_from_slice(text);
+            data
+        };
+        let mut c_node_id = [0; 32];
+        c_node_id[..20].copy_from_slice(node);
+        ExtraRevision {
+            chunk: RevlogChunk {
+                offset_flags: (flags as u64).to_be(),
+                comp_len: (data.len() as i32).to_be(),
+                uncomp_len: (text.len() as i32).to_be(),
+                base_rev: rev.to_be(),
+                link_rev: link_rev.to_be(),
+                parent_1: p1.to_be(),
+                parent_2: p2.to_be(),
+                c_node_id: c_node_id,
+            },
+            data: data,
+        }
+    }
+}
+
+impl fmt::Debug for ExtraRevision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ExtraRevision({})", self.chunk.c_node_id().to_hex())
+    }
+}
+
 pub struct Revlog {
-    /// Mmap of the index file.
-    index: MappedData,
+    /// Mmap of the index file, which a revlog with only extra revisions
+    /// doesn't have.
+    index: Option<MappedData>,
     /// Revlog data may either be inline in the index, or in a separate
     /// file. Inline should only be found in small files, as it requires
     /// a linear scan.)
@@ -264,13 +401,23 @@ pub struct Revlog {
     offset_table: Vec<isize>,
     /// Has init finished being called?
     _incomplete: bool,
+    /// Mapping from node id to rev no, built on first use.
+    node_map: RefCell<Option<HashMap<Vec<u8>, i32>>>,
+    /// Revisions after the stored ones
+    extra: Arc<Vec<ExtraRevision>>,
 }
 
 impl Revlog {
     pub fn open(path: &str) -> Result<Revlog> {
         expect!(path.ends_with(".i"));
-        println!("=====");
-        println!("opening index: {:?}", path);
+        let mut data_path = String::from(&path[..path.len() - 2]);
+        data_path.push_str(".d");
+        Revlog::open_with_data(path, &data_path)
+    }
+
+    /// Open a revlog whose data file isn't named after the index, as
+    /// happens with hashed store paths.
+    pub fn open_with_data(path: &str, data_path: &str) -> Result<Revlog> {
         let index = try!(util::MappedData::open(path));
 
         // Read the flags from the first entry to store some
@@ -279,33 +426,50 @@ impl Revlog {
             let first_chunk: &RevlogChunk = index.extract_value(0);
             (first_chunk.offset_flags() >> 32) as u32
         };
-        println!("flags: {:08x}", flags);
         expect!(flags & REVLOGNG != 0);
         let inline = (flags & REVLOGNGINLINEDATA) != 0;
         let generaldelta = (flags & REVLOGGENERALDELTA) != 0;
-        println!("inline: {}", inline);
-        println!("generaldelta: {}", generaldelta);
 
         let data = if inline {
             None
         } else {
-            let mut y = String::from(&path[..path.len() - 2]);
-            y.push_str(".d");
-            println!("opening data: {:?}", y);
-            Some(try!(util::MappedData::open(&*y)))
+            Some(try!(util::MappedData::open(data_path)))
         };
 
         let mut result = Revlog {
-            index: index,
+            index: Some(index),
             data: data,
             generaldelta: generaldelta,
             offset_table: vec![],
             _incomplete: true,
+            node_map: RefCell::new(None),
+            extra: Arc::new(vec![]),
         };
         try!(result.init());
         return Ok(result);
     }
 
+    /// A revlog with no revisions, as one which doesn't exist yet.
+    pub fn empty() -> Revlog {
+        Revlog {
+            index: None,
+            data: None,
+            generaldelta: false,
+            offset_table: vec![],
+            _incomplete: false,
+            node_map: RefCell::new(None),
+            extra: Arc::new(vec![]),
+        }
+    }
+
+    /// This revlog with revisions following the stored ones, the first
+    /// of them numbered `stored_len()`.
+    pub fn with_extra(mut self, extra: Arc<Vec<ExtraRevision>>) -> Revlog {
+        self.extra = extra;
+        *self.node_map.borrow_mut() = None;
+        self
+    }
+
     fn init(&mut self) -> Result<()> {
         assert!(self._incomplete);
         if !self.inline() {
@@ -322,7 +486,8 @@ impl Revlog {
         Ok(())
     }
 
-    fn inline(&self) -> bool {
+    /// Whether the data is interleaved with the index.
+    pub fn inline(&self) -> bool {
         self.data.is_none()
     }
 
@@ -356,9 +521,10 @@ impl Revlog {
             expect!(offset % 64 == 0);
         }
 
-        let chunk: &RevlogChunk = self.index.extract_value(offset);
+        let index = self.index.as_ref().unwrap();
+        let chunk: &RevlogChunk = index.extract_value(offset);
         let data = match self.data {
-            None => self.index.extract_slice(offset + 64, chunk.comp_len() as usize),
+            None => index.extract_slice(offset + 64, chunk.comp_len() as usize),
             Some(ref data) => {
                 let offset = if offset == 0 {
                     0
@@ -414,17 +580,49 @@ impl Revlog {
         }
     }
 
+    /// The number of revs, including any extra ones.
     pub fn len(&self) -> isize {
+        self.stored_len() as isize + self.extra.len() as isize
+    }
+
+    /// The number of revs in the revlog's files.
+    pub fn stored_len(&self) -> i32 {
         if self.inline() {
             // We have a handy lookup table
-            self.offset_table.len() as isize
+            self.offset_table.len() as i32
         } else {
             // The index file is 64 bytes * the number of revs
-            self.index.len / 64
+            (self.index_len() / 64) as i32
         }
     }
 
+    fn index_len(&self) -> isize {
+        self.index.as_ref().map_or(0, |index| index.len)
+    }
+
+    /// The entry of an extra rev, or None past the last one.
+    fn extra_entry(&self, rev: i32) -> Option<RevlogEntry> {
+        let extra = match self.extra.get((rev - self.stored_len()) as usize) {
+            Some(extra) => extra,
+            None => return None,
+        };
+        Some(RevlogEntry {
+            revlog: &self,
+            revno: rev,
+            chunk: &extra.chunk,
+            byte_offset: -1,
+            data: &extra.data,
+            full_text: RefCell::new(None),
+        })
+    }
+
     pub fn index(&self, index: i32) -> Result<RevlogEntry> {
+        if index >= self.stored_len() {
+            return match self.extra_entry(index) {
+                Some(entry) => Ok(entry),
+                None => Err(From::from(format!("index {} is bigger than {}", index, self.len()))),
+            };
+        }
         if self.inline() {
             expect!(index >= 0, "index {} is out of bounds", index);
             expect!(index < self.offset_table.len() as i32,
@@ -437,4 +635,141 @@ impl Revlog {
             return self.index_entry_at_byte(64 * index as isize, Some(index));
         }
     }
+
+    /// The node id of a rev. The null rev -1 has the null id.
+    pub fn node(&self, rev: i32) -> Result<&[u8]> {
+        if rev == -1 {
+            return Ok(NULL_ID);
+        }
+        let entry = try!(self.index(rev));
+        Ok(entry.chunk.c_node_id())
+    }
+
+    /// Look up a rev by its full node id.
+    pub fn rev(&self, node: &[u8]) -> Result<Option<i32>> {
+        if node == NULL_ID {
+            return Ok(Some(-1));
+        }
+        if self.node_map.borrow().is_none() {
+            let mut map = HashMap::new();
+            for entry in self.iter() {
+                let entry = try!(entry);
+                map.insert(Vec::from(entry.chunk.c_node_id()), entry.revno);
+            }
+            *self.node_map.borrow_mut() = Some(map);
+        }
+        let map = self.node_map.borrow();
+        Ok(map.as_ref().unwrap().get(node).cloned())
+    }
+
+    /// The text of a rev, like `RevlogEntry::text`, but starting from a
+    /// text in the cache if its delta chain has one. The text is added
+    /// to the cache.
+    pub fn cached_text(&self, rev: i32, cache: &mut TextCache) -> Result<Rc<Vec<u8>>> {
+        let mut patches = vec![];
+        let mut base = None;
+        for entry in try!(self.index(rev)).delta_chain() {
+            let entry = try!(entry);
+            if let Some(text) = cache.texts.get(&entry.revno) {
+                base = Some((**text).clone());
+                break;
+            }
+            if entry.delta_parent() == -1 {
+                base = Some(entry.data());
+                break;
+            }
+            patches.push(entry.data());
+        }
+        patches.reverse();
+        let text = Rc::new(try!(patch::apply(base.unwrap(), patches)));
+        cache.insert(rev, text.clone());
+        Ok(text)
+    }
+
+    /// Look up a rev by a prefix of the hex of its node id. It's an
+    /// error for the prefix to match more than one rev.
+    pub fn lookup_prefix(&self, prefix: &str) -> Result<Option<i32>> {
+        let prefix = prefix.to_lowercase();
+        expect!(prefix.len() <= 40 && prefix.chars().all(|c| c.is_digit(16)),
+                "not a node prefix: {:?}",
+                prefix);
+        let mut found = None;
+        for entry in self.iter() {
+            let entry = try!(entry);
+            if entry.chunk.c_node_id().to_hex().starts_with(&prefix) {
+                expect!(found.is_none(), "ambiguous identifier: {:?}", prefix);
+                found = Some(entry.revno);
+            }
+        }
+        if found.is_none() && NULL_ID.to_hex().starts_with(&prefix) {
+            found = Some(-1);
+        }
+        Ok(found)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+    use testutil::{write_revlog, Rev, TempDir};
+    use super::{hash, ExtraRevision, Revlog, TextCache, NULL_ID};
+
+    #[test]
+    fn test_extra() {
+        let n0 = hash(b"\0binary", NULL_ID, NULL_ID);
+        let n1 = hash(b"text", &n0, NULL_ID);
+        let extra = vec![ExtraRevision::new(0, &n0, -1, -1, 3, 0, b"\0binary"),
+                         ExtraRevision::new(1, &n1, 0, -1, 4, 0, b"text")];
+        let revlog = Revlog::empty().with_extra(Arc::new(extra));
+        assert_eq!(0, revlog.stored_len());
+        assert_eq!(2, revlog.len());
+        assert_eq!(Some(1), revlog.rev(&n1).unwrap());
+        assert_eq!(2, revlog.iter().count());
+        let entry = revlog.index(1).unwrap();
+        assert_eq!(&b"text"[..], &entry.text().unwrap()[..]);
+        assert_eq!(&n0[..], entry.parent_1_id().unwrap());
+        assert_eq!(4, entry.chunk.link_rev());
+        assert!(entry.verify().unwrap());
+        assert_eq!(&b"\0binary"[..], &revlog.index(0).unwrap().text().unwrap()[..]);
+        assert!(revlog.index(2).is_err());
+    }
+
+    #[test]
+    fn test_cached_text() {
+        let dir = TempDir::new("cached-text");
+        let path = dir.path().join("a");
+        let text = |i: usize| -> Vec<u8> {
+            (0..50).flat_map(|j| format!("line {} of {}\n", j, if j % 7 == 0 { i } else { 0 }).into_bytes()).collect()
+        };
+        let texts: Vec<Vec<u8>> = (0..8).map(text).collect();
+        // Two chains, 0-1-2-3-7 with 4 off 1, and 5-6
+        let bases = [-1, 0, 1, 2, 1, -1, 5, 3];
+        let revs: Vec<Rev> = (0..8).map(|i| Rev::new(&texts[i], i as i32 - 1, bases[i])).collect();
+        write_revlog(&path, &revs, true, false);
+        let revlog = Revlog::open(&format!("{}.i", path.display())).unwrap();
+        let check = |rev: i32, cache: &mut TextCache, cached: &[i32]| {
+            let text = revlog.cached_text(rev, cache).unwrap();
+            assert_eq!(texts[rev as usize], *text);
+            assert_eq!(*revlog.index(rev).unwrap().text().unwrap(), *text);
+            let mut keys: Vec<i32> = cache.texts.keys().cloned().collect();
+            keys.sort();
+            assert_eq!(cached, &keys[..]);
+        };
+
+        let mut cache = TextCache::new(2);
+        check(3, &mut cache, &[3]);
+        // 7 is patched onto the cached 3, then 4 starts over from 0 and
+        // evicts 3
+        check(7, &mut cache, &[3, 7]);
+        check(4, &mut cache, &[4, 7]);
+        check(3, &mut cache, &[3, 4]);
+        check(2, &mut cache, &[2, 3]);
+        check(6, &mut cache, &[2, 6]);
+        check(6, &mut cache, &[2, 6]);
+
+        let mut cache = TextCache::new(0);
+        for rev in 0..8 {
+            check(rev, &mut cache, &[]);
+        }
+    }
 }
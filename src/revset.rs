@@ -0,0 +1,776 @@
+//! A practical subset of Mercurial's revset language.
+//!
+//! ```text
+//! x or y, x | y, x + y    revs in either, x's first
+//! x and y, x & y          revs of x that are also in y
+//! x - y                   revs of x that aren't in y
+//! not x, !x               revs not in x
+//! x::y, x..y              descendants of x that are ancestors of y
+//! x:y                     revs from x to y by number, in either direction
+//! x^, x^n                 the first or nth parent of x, where x^0 is x
+//! x~n                     the nth first-parent ancestor of x
+//! ```
+//!
+//! Either side of a range may be left out. Symbols are resolved with
+//! `Repo::lookup`, and the predicates are those in `FUNCTIONS`. Only the
+//! changesets in the repo's view are ever selected.
+//!
+//! Like Mercurial, an expression is evaluated against a subset of the
+//! repo: predicates filter the subset, keeping its order, and `x and y`
+//! evaluates `y` against the result of `x`.
+
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ancestor;
+use date;
+use graph::Graph;
+use matcher::{Kind, Matcher, Pattern};
+use obsolete::Obsolescence;
+use phases::{Phase, Phases};
+use repo::Repo;
+use util::Result;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Symbol(String),
+    /// A quoted string, which is only ever looked up as a symbol
+    String(String),
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Minus(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    /// `x::y`
+    Dag(Option<Box<Expr>>, Option<Box<Expr>>),
+    /// `x:y`
+    Range(Option<Box<Expr>>, Option<Box<Expr>>),
+    /// `x^n`
+    Parent(Box<Expr>, u32),
+    /// `x~n`
+    Ancestor(Box<Expr>, u32),
+    Func(String, Vec<Expr>),
+}
+
+const FUNCTIONS: &'static [&'static str] = &["all", "ancestors", "author", "bookmark", "branch",
+                                             "bundle", "date", "descendants", "draft", "extinct",
+                                             "file", "first", "heads", "keyword", "last", "limit",
+                                             "obsolete", "orphan", "phase", "public", "secret",
+                                             "tag", "unstable"];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Symbol(String),
+    String(String),
+    Op(&'static str),
+}
+
+const OPS: &'static [&'static str] = &["::", "..", ":", "(", ")", ",", "!", "&", "|", "+", "-", "^",
+                                       "~"];
+
+fn is_symbol_char(c: char) -> bool {
+    c.is_alphanumeric() || "-._/@".contains(c) || c as u32 > 127
+}
+
+fn tokenize(spec: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = spec.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if c.is_whitespace() {
+            i += 1;
+        } else if c == '\'' || c == '"' {
+            let mut s = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(From::from(format!("unterminated string in revset {:?}", spec))),
+                    Some(&d) if d == c => break,
+                    Some(&'\\') if i + 1 < chars.len() => {
+                        i += 1;
+                        s.push(chars[i]);
+                    }
+                    Some(&d) => s.push(d),
+                }
+                i += 1;
+            }
+            i += 1;
+            tokens.push(Token::String(s));
+        } else if (c == ':' && next == Some(':')) || (c == '.' && next == Some('.')) {
+            tokens.push(Token::Op(if c == ':' { "::" } else { ".." }));
+            i += 2;
+        } else if c == '-' && !next.map_or(false, |d| d.is_digit(10)) ||
+                  c != '-' && !is_symbol_char(c) {
+            match OPS.iter().find(|op| op.len() == 1 && op.starts_with(c)) {
+                Some(op) => tokens.push(Token::Op(op)),
+                None => return Err(From::from(format!("syntax error in revset {:?} at {:?}", spec, c))),
+            }
+            i += 1;
+        } else {
+            let start = i;
+            i += 1;
+            // A symbol stops short of `..`, so that `1..3` is a range
+            while i < chars.len() && is_symbol_char(chars[i]) &&
+                  !(chars[i] == '.' && chars.get(i + 1) == Some(&'.')) {
+                i += 1;
+            }
+            tokens.push(Token::Symbol(chars[start..i].iter().cloned().collect()));
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_op(&self, ops: &[&str]) -> Option<&'static str> {
+        match self.peek() {
+            Some(&Token::Op(op)) if ops.contains(&op) => Some(op),
+            Some(&Token::Symbol(ref s)) if ops.contains(&&s[..]) => {
+                // The keyword operators
+                ["and", "or", "not"].iter().find(|&&k| k == s).cloned()
+            }
+            _ => None,
+        }
+    }
+
+    fn expect_op(&mut self, op: &str) -> Result<()> {
+        match self.peek() {
+            Some(&Token::Op(o)) if o == op => (),
+            t => return Err(From::from(format!("revset syntax error: expected {:?}, found {:?}", op, t))),
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = try!(self.parse_and());
+        while self.peek_op(&["or", "|", "+"]).is_some() {
+            self.pos += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(try!(self.parse_and())));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = try!(self.parse_not());
+        while let Some(op) = self.peek_op(&["and", "&", "-"]) {
+            self.pos += 1;
+            let rhs = Box::new(try!(self.parse_not()));
+            expr = if op == "-" {
+                Expr::Minus(Box::new(expr), rhs)
+            } else {
+                Expr::And(Box::new(expr), rhs)
+            };
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.peek_op(&["not", "!"]).is_some() {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(try!(self.parse_not()))));
+        }
+        self.parse_range()
+    }
+
+    /// Whether the next token can start an operand of a range.
+    fn at_operand(&self) -> bool {
+        match self.peek() {
+            Some(&Token::Symbol(ref s)) => !["and", "or", "not"].contains(&&s[..]),
+            Some(&Token::String(_)) | Some(&Token::Op("(")) => true,
+            _ => false,
+        }
+    }
+
+    fn parse_range(&mut self) -> Result<Expr> {
+        let lhs = if self.peek_op(&["::", "..", ":"]).is_some() {
+            None
+        } else {
+            Some(Box::new(try!(self.parse_postfix())))
+        };
+        let op = match self.peek_op(&["::", "..", ":"]) {
+            Some(op) => op,
+            None => return Ok(*lhs.unwrap()),
+        };
+        self.pos += 1;
+        let rhs = if self.at_operand() {
+            Some(Box::new(try!(self.parse_postfix())))
+        } else {
+            None
+        };
+        Ok(if op == ":" {
+            Expr::Range(lhs, rhs)
+        } else {
+            Expr::Dag(lhs, rhs)
+        })
+    }
+
+    /// A primary followed by any number of `^` and `~`, each with an
+    /// optional count.
+    fn parse_postfix(&mut self) -> Result<Expr> {
+        let mut expr = try!(self.parse_primary());
+        while let Some(op) = self.peek_op(&["^", "~"]) {
+            self.pos += 1;
+            let n = match self.peek() {
+                Some(&Token::Symbol(ref s)) if s.chars().all(|c| c.is_digit(10)) => {
+                    match s.parse::<u32>() {
+                        Ok(n) => Some(n),
+                        Err(_) => return Err(From::from(format!("revset syntax error: bad count {:?}", s))),
+                    }
+                }
+                _ => None,
+            };
+            if n.is_some() {
+                self.pos += 1;
+            }
+            expr = if op == "^" {
+                let n = n.unwrap_or(1);
+                expect!(n <= 2, "revset syntax error: ^ only takes 0, 1 or 2");
+                Expr::Parent(Box::new(expr), n)
+            } else {
+                match n {
+                    Some(n) => Expr::Ancestor(Box::new(expr), n),
+                    None => return Err(From::from("revset syntax error: ~ expects a number")),
+                }
+            };
+        }
+        Ok(expr)
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr> {
+        let token = match self.peek() {
+            Some(token) => token.clone(),
+            None => return Err(From::from("revset syntax error: unexpected end of expression")),
+        };
+        self.pos += 1;
+        match token {
+            Token::String(s) => Ok(Expr::String(s)),
+            Token::Op("(") => {
+                let expr = try!(self.parse_or());
+                try!(self.expect_op(")"));
+                Ok(expr)
+            }
+            Token::Symbol(name) => {
+                if self.peek() != Some(&Token::Op("(")) {
+                    return Ok(Expr::Symbol(name));
+                }
+                expect!(FUNCTIONS.contains(&&name[..]), "unknown revset function {:?}", name);
+                self.pos += 1;
+                let mut args = vec![];
+                if self.peek() != Some(&Token::Op(")")) {
+                    args.push(try!(self.parse_or()));
+                    while self.peek() == Some(&Token::Op(",")) {
+                        self.pos += 1;
+                        args.push(try!(self.parse_or()));
+                    }
+                }
+                try!(self.expect_op(")"));
+                Ok(Expr::Func(name, args))
+            }
+            Token::Op(op) => Err(From::from(format!("revset syntax error: unexpected {:?}", op))),
+        }
+    }
+}
+
+pub fn parse(spec: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: try!(tokenize(spec)),
+        pos: 0,
+    };
+    let expr = try!(parser.parse_or());
+    if let Some(token) = parser.peek() {
+        return Err(From::from(format!("revset syntax error: unexpected {:?}", token)));
+    }
+    Ok(expr)
+}
+
+/// The revs selected by a revset, in the revset's order.
+pub fn revs(repo: &Repo, spec: &str) -> Result<Vec<i32>> {
+    let expr = try!(parse(spec));
+    evaluate(repo, &expr)
+}
+
+pub fn evaluate(repo: &Repo, expr: &Expr) -> Result<Vec<i32>> {
+    let context = Context {
+        repo: repo,
+        graph: Graph::new(repo.changelog.revlog()),
+        all: Subset::new(try!(repo.revs())),
+    };
+    context.eval(expr, &context.all)
+}
+
+/// The revs an expression is evaluated against, in order, with a set of
+/// them to test membership, built once.
+struct Subset {
+    revs: Vec<i32>,
+    members: HashSet<i32>,
+}
+
+impl Subset {
+    fn new(revs: Vec<i32>) -> Subset {
+        let members = revs.iter().cloned().collect();
+        Subset {
+            revs: revs,
+            members: members,
+        }
+    }
+}
+
+impl Deref for Subset {
+    type Target = [i32];
+    fn deref(&self) -> &[i32] {
+        &self.revs
+    }
+}
+
+struct Context<'a> {
+    repo: &'a Repo,
+    graph: Graph<'a>,
+    /// The revs in the repo's view
+    all: Subset,
+}
+
+impl<'a> Context<'a> {
+    fn eval(&self, expr: &Expr, subset: &Subset) -> Result<Vec<i32>> {
+        match *expr {
+            Expr::Symbol(ref s) |
+            Expr::String(ref s) => {
+                let rev = try!(self.repo.lookup(s));
+                Ok(self.restrict(vec![rev], subset))
+            }
+            Expr::Or(ref x, ref y) => {
+                let mut result = try!(self.eval(x, subset));
+                let mut seen: HashSet<i32> = result.iter().cloned().collect();
+                for rev in try!(self.eval(y, subset)) {
+                    if seen.insert(rev) {
+                        result.push(rev);
+                    }
+                }
+                Ok(result)
+            }
+            Expr::And(ref x, ref y) => {
+                let xs = Subset::new(try!(self.eval(x, subset)));
+                self.eval(y, &xs)
+            }
+            Expr::Minus(ref x, ref y) => {
+                let xs = Subset::new(try!(self.eval(x, subset)));
+                let ys: HashSet<i32> = try!(self.eval(y, &xs)).into_iter().collect();
+                Ok(xs.revs.into_iter().filter(|r| !ys.contains(r)).collect())
+            }
+            Expr::Not(ref x) => {
+                let xs: HashSet<i32> = try!(self.eval(x, subset)).into_iter().collect();
+                Ok(subset.iter().cloned().filter(|r| !xs.contains(r)).collect())
+            }
+            Expr::Range(ref x, ref y) => {
+                let start = match *x {
+                    Some(ref x) => try!(self.eval(x, &self.all)).first().cloned(),
+                    None => Some(0),
+                };
+                let end = match *y {
+                    Some(ref y) => try!(self.eval(y, &self.all)).last().cloned(),
+                    None => self.all.last().cloned(),
+                };
+                let range: Vec<i32> = match (start, end) {
+                    (Some(start), Some(end)) if start <= end => (start..end + 1).collect(),
+                    (Some(start), Some(end)) => (end..start + 1).rev().collect(),
+                    _ => vec![],
+                };
+                Ok(self.restrict(range, subset))
+            }
+            Expr::Dag(ref x, ref y) => {
+                let mut revs = match *y {
+                    Some(ref y) => {
+                        let heads = try!(self.eval(y, &self.all));
+                        try!(self.collect(try!(ancestor::ancestors(&self.graph, &heads, true))))
+                    }
+                    None => self.all.revs.clone(),
+                };
+                if let Some(ref x) = *x {
+                    let roots = try!(self.eval(x, &self.all));
+                    let descendants: HashSet<i32> =
+                        try!(self.collect(ancestor::descendants(&self.graph, &roots, true)))
+                            .into_iter()
+                            .collect();
+                    revs.retain(|r| descendants.contains(r));
+                }
+                revs.sort();
+                Ok(self.restrict(revs, subset))
+            }
+            Expr::Parent(ref x, n) => {
+                let mut revs = vec![];
+                for rev in try!(self.eval(x, &self.all)) {
+                    let (p1, p2) = try!(self.repo.changelog.parents(rev));
+                    match n {
+                        0 => revs.push(rev),
+                        1 => revs.push(p1),
+                        _ if p2 != -1 => revs.push(p2),
+                        _ => {}
+                    }
+                }
+                revs.sort();
+                revs.dedup();
+                Ok(self.restrict(revs, subset))
+            }
+            Expr::Ancestor(ref x, n) => {
+                let mut revs = vec![];
+                for mut rev in try!(self.eval(x, &self.all)) {
+                    for _ in 0..n {
+                        if rev == -1 {
+                            break;
+                        }
+                        rev = try!(self.repo.changelog.parents(rev)).0;
+                    }
+                    revs.push(rev);
+                }
+                revs.sort();
+                revs.dedup();
+                Ok(self.restrict(revs, subset))
+            }
+            Expr::Func(ref name, ref args) => self.func(name, args, subset),
+        }
+    }
+
+    /// The members of `revs` that are in `subset`, keeping the order of
+    /// `revs`. The null rev is only ever kept when the subset is the
+    /// whole repo, since it's not part of any other set.
+    fn restrict(&self, revs: Vec<i32>, subset: &Subset) -> Vec<i32> {
+        let full = subset.len() >= self.all.len();
+        revs.into_iter().filter(|r| (*r == -1 && full) || subset.members.contains(r)).collect()
+    }
+
+    fn collect<I: Iterator<Item = Result<i32>>>(&self, iter: I) -> Result<Vec<i32>> {
+        let mut result = vec![];
+        for rev in iter {
+            result.push(try!(rev));
+        }
+        Ok(result)
+    }
+
+    /// The members of `subset` for which `pred` holds.
+    fn filter<F>(&self, subset: &[i32], mut pred: F) -> Result<Vec<i32>>
+        where F: FnMut(i32) -> Result<bool>
+    {
+        let mut result = vec![];
+        for &rev in subset {
+            if rev != -1 && try!(pred(rev)) {
+                result.push(rev);
+            }
+        }
+        Ok(result)
+    }
+
+    fn func(&self, name: &str, args: &[Expr], subset: &Subset) -> Result<Vec<i32>> {
+        let nargs = |min: usize, max: usize| -> Result<()> {
+            expect!(args.len() >= min && args.len() <= max,
+                    "{}() takes {} to {} arguments, not {}",
+                    name,
+                    min,
+                    max,
+                    args.len());
+            Ok(())
+        };
+        match name {
+            "all" => {
+                try!(nargs(0, 0));
+                Ok(subset.iter().cloned().filter(|&r| r != -1).collect())
+            }
+            "ancestors" | "descendants" => {
+                try!(nargs(1, 1));
+                let revs = try!(self.eval(&args[0], &self.all));
+                let result = if name == "ancestors" {
+                    try!(self.collect(try!(ancestor::ancestors(&self.graph, &revs, true))))
+                } else {
+                    try!(self.collect(ancestor::descendants(&self.graph, &revs, true)))
+                };
+                let result: HashSet<i32> = result.into_iter().collect();
+                Ok(subset.iter().cloned().filter(|r| result.contains(r)).collect())
+            }
+            "heads" => {
+                try!(nargs(1, 1));
+                let revs = try!(self.eval(&args[0], &self.all));
+                let members: HashSet<i32> = revs.iter().cloned().collect();
+                let mut parents = HashSet::new();
+                for &rev in &revs {
+                    parents.extend(try!(self.graph.parents(rev)));
+                }
+                let heads: HashSet<i32> = members.difference(&parents).cloned().collect();
+                Ok(subset.iter().cloned().filter(|r| heads.contains(r)).collect())
+            }
+            "branch" => {
+                try!(nargs(1, 1));
+                // A branch name, or else a revset whose branches are meant
+                let mut branches = HashSet::new();
+                let name = match args[0] {
+                    Expr::Symbol(ref s) |
+                    Expr::String(ref s) => try!(self.repo.branch_tip(s)).map(|_| s.clone()),
+                    _ => None,
+                };
+                if let Some(name) = name {
+                    branches.insert(name);
+                } else {
+                    for rev in try!(self.eval(&args[0], &self.all)) {
+                        branches.insert(String::from(try!(self.repo.changeset(rev)).branch()));
+                    }
+                }
+                self.filter(subset, |rev| {
+                    Ok(branches.contains(try!(self.repo.changeset(rev)).branch()))
+                })
+            }
+            "author" => {
+                try!(nargs(1, 1));
+                let pattern = try!(string_arg(&args[0])).to_lowercase();
+                self.filter(subset, |rev| {
+                    Ok(try!(self.repo.changeset(rev)).user.to_lowercase().contains(&pattern))
+                })
+            }
+            "keyword" => {
+                try!(nargs(1, 1));
+                let pattern = try!(string_arg(&args[0])).to_lowercase();
+                self.filter(subset, |rev| {
+                    let cs = try!(self.repo.changeset(rev));
+                    Ok(cs.user.to_lowercase().contains(&pattern) ||
+                       cs.description.to_lowercase().contains(&pattern) ||
+                       cs.files.iter().any(|f| f.to_lowercase().contains(&pattern)))
+                })
+            }
+            "date" => {
+                try!(nargs(1, 1));
+                let now = match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(d) => d.as_secs() as i64,
+                    Err(_) => 0,
+                };
+                let (start, end) = try!(date::parse_range(&try!(string_arg(&args[0])), now));
+                self.filter(subset, |rev| {
+                    let time = try!(self.repo.changeset(rev)).time;
+                    Ok(time >= start && time <= end)
+                })
+            }
+            "file" => {
+                try!(nargs(1, 1));
+                // As in Mercurial, a pattern without a kind is a glob
+                let pattern = Pattern::parse(&try!(string_arg(&args[0])), Kind::Glob);
+                let matcher = try!(Matcher::new(&[pattern]));
+                self.filter(subset, |rev| Ok(try!(self.repo.changeset(rev)).touches(&matcher)))
+            }
+            "bookmark" | "tag" => {
+                try!(nargs(0, 1));
+                let names = if name == "bookmark" {
+                    try!(self.repo.bookmarks())
+                } else {
+                    try!(self.repo.tags())
+                };
+                let nodes: Vec<&Vec<u8>> = match args.first() {
+                    Some(arg) => {
+                        let wanted = try!(string_arg(arg));
+                        match names.get(&wanted) {
+                            Some(node) => vec![node],
+                            None => return Err(From::from(format!("{} {:?} does not exist", name, wanted))),
+                        }
+                    }
+                    None => names.values().collect(),
+                };
+                let mut revs = HashSet::new();
+                for node in nodes {
+                    if let Some(rev) = try!(self.repo.changelog.revlog().rev(node)) {
+                        revs.insert(rev);
+                    }
+                }
+                self.filter(subset, |rev| Ok(revs.contains(&rev)))
+            }
+            "bundle" => {
+                // Changesets from a bundle come after the stored ones
+                try!(nargs(0, 0));
+                let first = self.repo.changelog.revlog().stored_len();
+                self.filter(subset, |rev| Ok(rev >= first))
+            }
+            "phase" | "public" | "draft" | "secret" => {
+                let phase = if name == "phase" {
+                    try!(nargs(1, 1));
+                    let phase = try!(string_arg(&args[0]));
+                    match Phase::from_name(&phase) {
+                        Some(phase) => phase,
+                        None => return Err(From::from(format!("unknown phase {:?}", phase))),
+                    }
+                } else {
+                    try!(nargs(0, 0));
+                    Phase::from_name(name).unwrap()
+                };
+                let phases = try!(Phases::read(self.repo));
+                self.filter(subset, |rev| Ok(phases.phase(rev) == phase))
+            }
+            "obsolete" | "orphan" | "unstable" | "extinct" => {
+                try!(nargs(0, 0));
+                let obs = try!(Obsolescence::compute(self.repo));
+                let revs = match name {
+                    "obsolete" => obs.obsolete,
+                    "extinct" => obs.extinct,
+                    _ => obs.unstable,
+                };
+                self.filter(subset, |rev| Ok(revs.contains(&rev)))
+            }
+            "limit" | "first" | "last" => {
+                try!(nargs(1, 2));
+                let n = match args.get(1) {
+                    Some(arg) => {
+                        let n = try!(string_arg(arg));
+                        match n.parse::<usize>() {
+                            Ok(n) => n,
+                            Err(_) => return Err(From::from(format!("{}() expects a number, not {:?}", name, n))),
+                        }
+                    }
+                    None => 1,
+                };
+                let revs = try!(self.eval(&args[0], &self.all));
+                let revs: Vec<i32> = if name == "last" {
+                    let skip = revs.len().saturating_sub(n);
+                    revs.into_iter().skip(skip).collect()
+                } else {
+                    revs.into_iter().take(n).collect()
+                };
+                Ok(self.restrict(revs, subset))
+            }
+            _ => Err(From::from(format!("unknown revset function {:?}", name))),
+        }
+    }
+}
+
+fn string_arg(arg: &Expr) -> Result<String> {
+    match *arg {
+        Expr::Symbol(ref s) |
+        Expr::String(ref s) => Ok(s.clone()),
+        _ => Err(From::from(format!("expected a string, found {:?}", arg))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::io::Write;
+    use testutil::{Commit, RepoBuilder};
+    use super::{parse, revs, Expr};
+
+    fn sym(s: &str) -> Box<Expr> {
+        Box::new(Expr::Symbol(String::from(s)))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Expr::Or(Box::new(Expr::And(sym("a"), Box::new(Expr::Not(sym("b"))))), sym("c")),
+                   parse("a and not b or c").unwrap());
+        assert_eq!(Expr::Minus(Box::new(Expr::Dag(Some(sym("1")), None)), sym("tip")),
+                   parse("1:: - tip").unwrap());
+        assert_eq!(Expr::Range(None, Some(sym("-1"))), parse(":-1").unwrap());
+        assert_eq!(Expr::Dag(Some(sym("1")), Some(sym("3"))), parse("1..3").unwrap());
+        assert_eq!(Expr::Func(String::from("limit"),
+                              vec![Expr::Func(String::from("branch"), vec![Expr::String(String::from("a b"))]),
+                                   Expr::Symbol(String::from("2"))]),
+                   parse("limit(branch('a b'), 2)").unwrap());
+        assert_eq!(Expr::Ancestor(Box::new(Expr::Parent(sym("."), 1)), 2), parse(".^~2").unwrap());
+        assert_eq!(Expr::Range(Some(Box::new(Expr::Parent(sym("1"), 2))), None),
+                   parse("1^2:").unwrap());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("(1").is_err());
+        assert!(parse("1 2").is_err());
+        assert!(parse("nonsense(1)").is_err());
+        assert!(parse("'open").is_err());
+        assert!(parse("1^3").is_err());
+        assert!(parse("tip~").is_err());
+    }
+
+    /// ```text
+    /// 0 - 1 - 3 - 4
+    ///  \     /
+    ///   2 ---      (stable)
+    /// ```
+    fn fixture(name: &str) -> RepoBuilder {
+        let mut builder = RepoBuilder::new(name);
+        builder.commit(Commit::new(-1, -1).file("a", "1\n").user("Alice").description("initial"));
+        builder.commit(Commit::new(0, -1).file("a", "2\n").user("Bob").description("fix a bug"));
+        builder.commit(Commit::new(0, -1).file("b", "x\n").branch("stable").description("stable work"));
+        builder.commit(Commit::new(1, 2).file("c", "c\n").description("merge"));
+        builder.commit(Commit::new(3, -1).file("a", "3\n").description("more"));
+        builder
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let builder = fixture("revset-evaluate");
+        let repo = builder.open();
+        let check = |spec: &str, expected: &[i32]| {
+            assert_eq!(expected, &revs(&repo, spec).unwrap()[..], "{}", spec);
+        };
+        check("tip", &[4]);
+        check("0:2", &[0, 1, 2]);
+        check("2:0", &[2, 1, 0]);
+        check(":1", &[0, 1]);
+        check("3:", &[3, 4]);
+        check("1::4", &[1, 3, 4]);
+        check("::2", &[0, 2]);
+        check("2::", &[2, 3, 4]);
+        check("1..3", &[1, 3]);
+        check("0:2 and not 1", &[0, 2]);
+        check("4:0 and branch(default)", &[4, 3, 1, 0]);
+        check("4:0 and 1:3", &[1, 2, 3]);
+        check("4 or 1 | 4", &[4, 1]);
+        check("0:4 - 1::", &[0, 2]);
+        check("!(0:3)", &[4]);
+        check("not 4 and not 0", &[1, 2, 3]);
+        check("3^", &[1]);
+        check("3^2", &[2]);
+        check("3^0", &[3]);
+        check("1^2", &[]);
+        check("4~2 + 4~0", &[1, 4]);
+        check("0^", &[-1]);
+    }
+
+    #[test]
+    fn test_functions() {
+        let builder = fixture("revset-functions");
+        let repo = builder.open();
+        let check = |spec: &str, expected: &[i32]| {
+            assert_eq!(expected, &revs(&repo, spec).unwrap()[..], "{}", spec);
+        };
+        check("all()", &[0, 1, 2, 3, 4]);
+        check("branch(stable)", &[2]);
+        check("branch(3)", &[0, 1, 3, 4]);
+        check("author(bob)", &[1]);
+        check("keyword(BUG) or keyword(merge)", &[1, 3]);
+        check("file('b')", &[2]);
+        check("heads(0:3)", &[3]);
+        check("heads(0:2)", &[1, 2]);
+        check("ancestors(2)", &[0, 2]);
+        check("descendants(1)", &[1, 3, 4]);
+        check("limit(4:0, 2)", &[4, 3]);
+        check("last(0:4, 2)", &[3, 4]);
+        check("first(branch(default))", &[0]);
+        check("branch(tip~2 or 0)", &[0, 1, 3, 4]);
+        check("branch(3^2)", &[2]);
+        assert!(revs(&repo, "ancestors()").is_err());
+        assert!(revs(&repo, "branch(nonesuch)").is_err());
+    }
+
+    #[test]
+    fn test_working_parent() {
+        let builder = fixture("revset-working-parent");
+        let mut dirstate = File::create(builder.hg_path().join("dirstate")).unwrap();
+        dirstate.write_all(&builder.node(2)).unwrap();
+        dirstate.write_all(&[0; 20]).unwrap();
+        let repo = builder.open();
+        assert_eq!(vec![2], revs(&repo, ".").unwrap());
+        assert_eq!(vec![0, 1, 3, 4], revs(&repo, "branch(.^)").unwrap());
+        assert_eq!(vec![2], revs(&repo, "branch(.)").unwrap());
+    }
+}
@@ -0,0 +1,239 @@
+//! Statistics about how a revlog is stored: how many revs are full
+//! snapshots rather than deltas, how long the delta chains are and how
+//! much of the data file must be read to follow them, what each delta
+//! is computed against, and how well the data compresses.
+
+extern crate flate2;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use revlog::Revlog;
+use util::Result;
+
+/// Count, total, minimum and maximum of some quantity.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    pub count: u64,
+    pub total: u64,
+    pub min: u64,
+    pub max: u64,
+}
+
+impl Summary {
+    fn add(&mut self, n: u64) {
+        if self.count == 0 || n < self.min {
+            self.min = n;
+        }
+        if n > self.max {
+            self.max = n;
+        }
+        self.count += 1;
+        self.total += n;
+    }
+
+    pub fn average(&self) -> f64 {
+        if self.count == 0 {
+            0.0
+        } else {
+            self.total as f64 / self.count as f64
+        }
+    }
+}
+
+/// Stored data for one compression engine.
+#[derive(Clone, Copy, Debug)]
+pub struct EngineStats {
+    pub revs: u64,
+    /// Bytes on disk
+    pub compressed: u64,
+    /// Bytes after decompression, if the engine is supported
+    pub uncompressed: Option<u64>,
+}
+
+/// What deltas were computed against. A delta against the previous rev
+/// which is also a parent is counted as against that parent.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DeltaBases {
+    pub p1: u64,
+    pub p2: u64,
+    pub prev: u64,
+    pub other: u64,
+}
+
+#[derive(Debug, Default)]
+pub struct RevlogStats {
+    pub revs: u64,
+    pub merges: u64,
+    pub inline: bool,
+    pub generaldelta: bool,
+    /// Revs which store no data at all
+    pub empty: u64,
+    /// Stored sizes of full snapshots
+    pub full: Summary,
+    /// Stored sizes of deltas
+    pub deltas: Summary,
+    /// Number of revs read to rebuild each text
+    pub chain_length: Summary,
+    /// Bytes of the data file spanned by each delta chain
+    pub chain_span: Summary,
+    /// Total bytes of data on disk
+    pub stored_size: u64,
+    /// Total size of all the full texts
+    pub text_size: u64,
+    /// Keyed by engine name
+    pub engines: BTreeMap<&'static str, EngineStats>,
+    pub delta_bases: DeltaBases,
+}
+
+impl RevlogStats {
+    pub fn compute(revlog: &Revlog) -> Result<RevlogStats> {
+        let mut stats = RevlogStats::default();
+        stats.inline = revlog.inline();
+        stats.generaldelta = revlog.generaldelta;
+
+        // For each rev, its chain length and the rev at the chain's base
+        let mut chains: Vec<(u64, i32)> = Vec::with_capacity(revlog.len() as usize);
+        for entry in revlog.iter() {
+            let entry = try!(entry);
+            let rev = entry.revno;
+            let stored = entry.chunk.comp_len() as u64;
+            stats.revs += 1;
+            stats.stored_size += stored;
+            stats.text_size += entry.chunk.uncomp_len() as u64;
+            if entry.chunk.parent_2() != -1 {
+                stats.merges += 1;
+            }
+
+            // Earlier revs are already known, so the walk ends quickly
+            let mut length = 0;
+            let mut base = rev;
+            for link in entry.delta_chain() {
+                let link = try!(link);
+                if link.revno < rev {
+                    let (l, b) = chains[link.revno as usize];
+                    length += l;
+                    base = b;
+                    break;
+                }
+                length += 1;
+            }
+            chains.push((length, base));
+            stats.chain_length.add(length);
+            let start = try!(revlog.index(base)).offset();
+            stats.chain_span.add(entry.offset() + stored - start);
+
+            let parent = entry.delta_parent();
+            let data = entry.raw_data();
+            if data.is_empty() {
+                stats.empty += 1;
+            } else if parent == -1 {
+                stats.full.add(stored);
+            } else {
+                stats.deltas.add(stored);
+            }
+            if parent != -1 {
+                let bases = &mut stats.delta_bases;
+                if parent == entry.chunk.parent_1() {
+                    bases.p1 += 1;
+                } else if parent == entry.chunk.parent_2() {
+                    bases.p2 += 1;
+                } else if parent == rev - 1 {
+                    bases.prev += 1;
+                } else {
+                    bases.other += 1;
+                }
+            }
+
+            if !data.is_empty() {
+                let (name, uncompressed) = match data[0] {
+                    b'x' => ("zlib", Some(try!(inflated_len(data)))),
+                    b'u' => ("none", Some(stored - 1)),
+                    0 => ("none", Some(stored)),
+                    b'(' => ("zstd", None),
+                    _ => ("unknown", None),
+                };
+                let engine = stats.engines.entry(name).or_insert(EngineStats {
+                    revs: 0,
+                    compressed: 0,
+                    uncompressed: Some(0),
+                });
+                engine.revs += 1;
+                engine.compressed += stored;
+                engine.uncompressed = match (engine.uncompressed, uncompressed) {
+                    (Some(a), Some(b)) => Some(a + b),
+                    _ => None,
+                };
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Total size of the full texts over the size on disk.
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_size == 0 {
+            0.0
+        } else {
+            self.text_size as f64 / self.stored_size as f64
+        }
+    }
+}
+
+fn inflated_len(data: &[u8]) -> Result<u64> {
+    let mut reader = flate2::read::ZlibDecoder::new(data);
+    let mut result = vec![];
+    try!(reader.read_to_end(&mut result));
+    Ok(result.len() as u64)
+}
+
+#[cfg(test)]
+mod test {
+    use revlog::Revlog;
+    use testutil::{write_revlog, Rev, TempDir};
+    use super::RevlogStats;
+
+    #[test]
+    fn test_compute() {
+        let dir = TempDir::new("stats");
+        let path = dir.path().join("a");
+        let text: Vec<u8> = (0..200).flat_map(|i| format!("line {}\n", i).into_bytes()).collect();
+        let mut changed = text.clone();
+        changed.extend_from_slice(b"more\n");
+        let revs = [Rev::new(&text, -1, -1),
+                    Rev::new(&changed, 0, 0),
+                    Rev::new(b"line 0\n", 1, 1),
+                    Rev::new(&text, 2, -1),
+                    Rev::new(&changed, 3, 0)];
+        write_revlog(&path, &revs, true, false);
+        let stats = RevlogStats::compute(&Revlog::open(&format!("{}.i", path.display())).unwrap()).unwrap();
+        assert_eq!(5, stats.revs);
+        assert!(stats.generaldelta && !stats.inline);
+        assert_eq!((5, 9, 1, 3),
+                   (stats.chain_length.count, stats.chain_length.total, stats.chain_length.min, stats.chain_length.max));
+        assert_eq!(2, stats.full.count);
+        assert_eq!(3, stats.deltas.count);
+        assert_eq!((2, 0, 0, 1),
+                   (stats.delta_bases.p1, stats.delta_bases.p2, stats.delta_bases.prev, stats.delta_bases.other));
+        let zlib = stats.engines["zlib"];
+        assert_eq!(2, zlib.revs);
+        assert_eq!(Some(2 * text.len() as u64), zlib.uncompressed);
+        assert_eq!(3, stats.engines["none"].revs);
+        assert_eq!(stats.stored_size, zlib.compressed + stats.engines["none"].compressed);
+        assert_eq!((4 * text.len() + 2 * 5 + 7) as u64, stats.text_size);
+        assert!(stats.compression_ratio() > 2.0);
+    }
+
+    #[test]
+    fn test_chain_without_generaldelta() {
+        let dir = TempDir::new("stats-chain");
+        let path = dir.path().join("a");
+        let revs = [Rev::new(b"a\n", -1, -1), Rev::new(b"a\nb\n", 0, 0), Rev::new(b"b\n", 1, 1), Rev::new(b"c\n", 2, 2)];
+        write_revlog(&path, &revs, false, true);
+        let stats = RevlogStats::compute(&Revlog::open(&format!("{}.i", path.display())).unwrap()).unwrap();
+        assert!(stats.inline && !stats.generaldelta);
+        assert_eq!((4, 10, 1, 4),
+                   (stats.chain_length.count, stats.chain_length.total, stats.chain_length.min, stats.chain_length.max));
+        assert_eq!(3, stats.delta_bases.p1);
+        assert_eq!(stats.stored_size, stats.chain_span.max);
+    }
+}
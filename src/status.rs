@@ -0,0 +1,349 @@
+//! The state of the working directory relative to its first parent, as
+//! `hg status` reports it.
+//!
+//! Every file is walked, leaving out `.hg` and nested repos, and each
+//! one is looked up in the dirstate. A tracked file whose size and
+//! mtime still match the dirstate is clean without being read; one
+//! whose size or mode changed is modified. Otherwise it's compared with
+//! its text in the parent's filelog. Untracked files are unknown, or
+//! ignored if they or a directory containing them match `.hgignore`.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use dirstate::{Dirstate, Entry, State, SIZE_FROM_P2};
+use manifest::{Flags, Manifest};
+use matcher::Matcher;
+use repo::{self, Repo};
+use revlog::NULL_ID;
+use util::Result;
+
+/// Sizes and mtimes are stored in 31 bits.
+const RANGE_MASK: i64 = 0x7fffffff;
+
+/// Which of the lists that are expensive or long to fill are wanted.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    pub unknown: bool,
+    pub ignored: bool,
+    pub clean: bool,
+}
+
+/// Repo paths of each kind of change, in sorted order.
+#[derive(Debug, Default)]
+pub struct Status {
+    pub modified: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// Tracked but missing
+    pub deleted: Vec<String>,
+    pub unknown: Vec<String>,
+    pub ignored: Vec<String>,
+    pub clean: Vec<String>,
+}
+
+/// What the walk found at a path.
+struct Found {
+    size: i64,
+    mtime: i64,
+    mode: u32,
+}
+
+/// Compare the working directory with its first parent.
+pub fn status(repo: &Repo, options: Options) -> Result<Status> {
+    let dirstate = try!(Dirstate::read(repo));
+    let ignore = try!(repo.ignore());
+    let mut found = BTreeMap::new();
+    let mut ignored = BTreeSet::new();
+    try!(walk(repo.root(), "", &ignore, options.ignored, &mut found, &mut ignored));
+
+    let mut result = Status::default();
+    let mut lookup = vec![];
+    for (path, entry) in dirstate.iter() {
+        // Tracked files in skipped directories weren't walked
+        let file = match found.remove(path) {
+            Some(file) => Some(file),
+            None => try!(stat(&repo.root().join(path))),
+        };
+        let file = match file {
+            Some(file) => file,
+            None => {
+                if entry.state == State::Removed {
+                    result.removed.push(path.clone());
+                } else {
+                    result.deleted.push(path.clone());
+                }
+                continue;
+            }
+        };
+        match entry.state {
+            State::Added => result.added.push(path.clone()),
+            State::Removed => result.removed.push(path.clone()),
+            State::Merged => result.modified.push(path.clone()),
+            State::Normal => {
+                let copied = dirstate.copy_source(path).is_some();
+                match compare(entry, &file, copied) {
+                    Some(true) => result.modified.push(path.clone()),
+                    Some(false) => {
+                        if options.clean {
+                            result.clean.push(path.clone())
+                        }
+                    }
+                    None => lookup.push((path.clone(), file)),
+                }
+            }
+        }
+    }
+
+    // Files whose stat was inconclusive need their content compared
+    if !lookup.is_empty() {
+        let manifest = try!(parent_manifest(repo, &dirstate.p1));
+        for (path, file) in lookup {
+            if try!(content_differs(repo, &manifest, &path, &file)) {
+                result.modified.push(path);
+            } else if options.clean {
+                result.clean.push(path);
+            }
+        }
+        result.modified.sort();
+        result.clean.sort();
+    }
+
+    for path in found.into_iter().map(|(path, _)| path) {
+        if ignored.contains(&path) {
+            if options.ignored {
+                result.ignored.push(path);
+            }
+        } else if options.unknown {
+            result.unknown.push(path);
+        }
+    }
+    Ok(result)
+}
+
+/// Whether a normal file changed, judging by its stat alone, or None if
+/// that can't tell.
+fn compare(entry: &Entry, file: &Found, copied: bool) -> Option<bool> {
+    let size = entry.size as i64;
+    let size_changed = size >= 0 && size != file.size && size != file.size & RANGE_MASK;
+    let mode_changed = size >= 0 && (entry.mode as u32 ^ file.mode) & 0o100 != 0;
+    if size_changed || mode_changed || entry.size == SIZE_FROM_P2 || copied {
+        // The size of a symlink as stored may not be its length
+        if is_link(file.mode) && size_changed {
+            return None;
+        }
+        return Some(true);
+    }
+    let mtime = entry.mtime as i64;
+    if size < 0 || (mtime != file.mtime && mtime != file.mtime & RANGE_MASK) {
+        return None;
+    }
+    Some(false)
+}
+
+fn is_link(mode: u32) -> bool {
+    mode & 0o170000 == 0o120000
+}
+
+fn parent_manifest(repo: &Repo, p1: &[u8]) -> Result<Manifest> {
+    match try!(repo.changelog.revlog().rev(p1)) {
+        Some(rev) => repo.manifest(rev),
+        None if p1 == NULL_ID => Ok(Manifest::empty()),
+        None => Err(From::from("working directory parent not found")),
+    }
+}
+
+/// Compare a file's flags and content with its version in the parent.
+fn content_differs(repo: &Repo, manifest: &Manifest, path: &str, file: &Found) -> Result<bool> {
+    let entry = match manifest.get(path) {
+        Some(entry) => entry,
+        None => return Ok(true),
+    };
+    let flags = if is_link(file.mode) {
+        Flags::Symlink
+    } else if file.mode & 0o100 != 0 {
+        Flags::Executable
+    } else {
+        Flags::Regular
+    };
+    if flags != entry.flags {
+        return Ok(true);
+    }
+    let full = repo.root().join(path);
+    let content = if flags == Flags::Symlink {
+        Vec::from(try!(fs::read_link(&full)).as_os_str().as_bytes())
+    } else {
+        match try!(repo::read_optional(&full)) {
+            Some(content) => content,
+            None => return Ok(true),
+        }
+    };
+    let filelog = try!(repo.filelog(path));
+    let rev = match try!(filelog.revlog().rev(&entry.node)) {
+        Some(rev) => rev,
+        None => return Err(From::from(format!("{}: filelog node not found", path))),
+    };
+    Ok(content != try!(filelog.content(rev)))
+}
+
+/// Stat a file without following symlinks, or None if it's missing or
+/// not a file.
+fn stat(path: &Path) -> Result<Option<Found>> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        // ENOTDIR when a directory in the path is now a file
+        Err(ref e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(20) => return Ok(None),
+        Err(e) => return Err(From::from(e)),
+    };
+    if meta.is_dir() {
+        return Ok(None);
+    }
+    Ok(Some(Found {
+        size: meta.size() as i64,
+        mtime: meta.mtime(),
+        mode: meta.mode(),
+    }))
+}
+
+/// Collect the files under a directory of the working copy, by repo
+/// path, and note which of them are ignored. Ignored directories are
+/// only entered when ignored files are wanted.
+fn walk(root: &Path,
+        dir: &str,
+        ignore: &Matcher,
+        list_ignored: bool,
+        found: &mut BTreeMap<String, Found>,
+        ignored: &mut BTreeSet<String>)
+        -> Result<()> {
+    let full = if dir.is_empty() { root.to_path_buf() } else { root.join(dir) };
+    // Everything in an ignored directory is ignored
+    let dir_ignored = !dir.is_empty() && ignored.contains(dir);
+    for entry in try!(fs::read_dir(&full)) {
+        let entry = try!(entry);
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let path = if dir.is_empty() { name.clone() } else { format!("{}/{}", dir, name) };
+        let meta = try!(fs::symlink_metadata(entry.path()));
+        if meta.is_dir() {
+            if name == ".hg" || entry.path().join(".hg").is_dir() {
+                continue;
+            }
+            if dir_ignored || ignore.matches(&path) {
+                if !list_ignored {
+                    continue;
+                }
+                ignored.insert(path.clone());
+            }
+            try!(walk(root, &path, ignore, list_ignored, found, ignored));
+        } else if meta.is_file() || meta.file_type().is_symlink() {
+            if dir_ignored || ignore.matches(&path) {
+                ignored.insert(path.clone());
+            }
+            found.insert(path,
+                         Found {
+                             size: meta.size() as i64,
+                             mtime: meta.mtime(),
+                             mode: meta.mode(),
+                         });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    extern crate byteorder;
+
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::os::unix::fs::MetadataExt;
+    use self::byteorder::{BigEndian, WriteBytesExt};
+    use testutil::{Commit, RepoBuilder};
+    use super::{Found, Options, compare, status};
+    use dirstate::{Entry, State, MTIME_UNSET};
+
+    #[test]
+    fn test_compare() {
+        let entry = Entry {
+            state: State::Normal,
+            mode: 0o100644,
+            size: 12,
+            mtime: 1460000000,
+        };
+        let file = |size, mtime, mode| {
+            Found {
+                size: size,
+                mtime: mtime,
+                mode: mode,
+            }
+        };
+        assert_eq!(Some(false), compare(&entry, &file(12, 1460000000, 0o100644), false));
+        assert_eq!(Some(true), compare(&entry, &file(13, 1460000000, 0o100644), false));
+        assert_eq!(Some(true), compare(&entry, &file(12, 1460000000, 0o100755), false));
+        assert_eq!(Some(true), compare(&entry, &file(12, 1460000000, 0o100644), true));
+        assert_eq!(None, compare(&entry, &file(12, 1460000001, 0o100644), false));
+        let unset = Entry { mtime: MTIME_UNSET, ..entry };
+        assert_eq!(None, compare(&unset, &file(12, 1460000000, 0o100644), false));
+    }
+
+    #[test]
+    fn test_status() {
+        let mut builder = RepoBuilder::new("status");
+        builder.commit(Commit::new(-1, -1)
+            .file("a", "a\n")
+            .file("c", "c\n")
+            .file("dir/b", "b\n")
+            .file("same", "old\n"));
+        let root = builder.hg_path().parent().unwrap().to_path_buf();
+        let write = |path: &str, text: &str| {
+            File::create(root.join(path)).unwrap().write_all(text.as_bytes()).unwrap();
+        };
+        write("a", "a\n");
+        write("c", "d\n");
+        // A directory of tracked files replaced by a file
+        write("dir", "");
+        // Changed, but with the size and mtime in the dirstate
+        write("same", "new\n");
+        write(".hgignore", "syntax: glob\n*.o\nbuild\n");
+        write("x.o", "");
+        write("new", "");
+        fs::create_dir(root.join("build")).unwrap();
+        write("build/y", "");
+
+        let mut dirstate = builder.node(0);
+        dirstate.extend_from_slice(&[0; 20]);
+        let same_mtime = fs::metadata(root.join("same")).unwrap().mtime() as i32;
+        // Without mtimes, `a` and `c` need their content compared
+        for &(path, size, mtime) in &[("a", 2, MTIME_UNSET),
+                                      ("c", 2, MTIME_UNSET),
+                                      ("dir/b", 2, MTIME_UNSET),
+                                      ("same", 4, same_mtime)] {
+            dirstate.push(b'n');
+            for &n in &[0o100644, size, mtime, path.len() as i32] {
+                dirstate.write_i32::<BigEndian>(n).unwrap();
+            }
+            dirstate.extend_from_slice(path.as_bytes());
+        }
+        File::create(builder.hg_path().join("dirstate")).unwrap().write_all(&dirstate).unwrap();
+
+        let repo = builder.open();
+        let options = Options {
+            unknown: true,
+            ignored: true,
+            clean: true,
+        };
+        let result = status(&repo, options).unwrap();
+        assert_eq!(vec!["c"], result.modified);
+        assert_eq!(vec!["dir/b"], result.deleted);
+        assert_eq!(vec![".hgignore", "dir", "new"], result.unknown);
+        assert_eq!(vec!["build/y", "x.o"], result.ignored);
+        assert_eq!(vec!["a", "same"], result.clean);
+        assert!(result.added.is_empty() && result.removed.is_empty());
+
+        let result = status(&repo, Options::default()).unwrap();
+        assert!(result.unknown.is_empty() && result.ignored.is_empty() && result.clean.is_empty());
+    }
+}
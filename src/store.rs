@@ -0,0 +1,429 @@
+//! Locating revlogs inside `.hg`.
+//!
+//! The layout depends on the repo's requirements. Old repos keep
+//! revlogs directly in `.hg` under their real names. With `store` they
+//! move to `.hg/store` and file names are escaped, so that a tracked
+//! path is safe on case-insensitive filesystems. With `fncache` the
+//! escaping also covers Windows reserved names, and paths which would
+//! be too long are replaced by a hashed form under `dh/`.
+//!
+//! A store may also have an overlay of revisions kept in memory, which
+//! revlogs opened from it have after their stored ones.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use crypto::digest::Digest;
+use crypto::sha1::Sha1;
+
+use repo;
+use revlog::{ExtraRevision, Revlog};
+use util::Result;
+
+const MAX_STORE_PATH_LEN: usize = 120;
+const DIR_PREFIX_LEN: usize = 8;
+const MAX_SHORT_DIRS_LEN: usize = 8 * (DIR_PREFIX_LEN + 1) - 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    /// No `store` requirement
+    Plain,
+    /// `store`
+    Basic,
+    /// `store` and `fncache`
+    Fncache { dotencode: bool },
+}
+
+/// Revisions to add to revlogs, by revlog name.
+pub type Overlay = HashMap<String, Arc<Vec<ExtraRevision>>>;
+
+#[derive(Clone, Debug)]
+pub struct Store {
+    path: PathBuf,
+    pub encoding: Encoding,
+    overlay: Option<Arc<Overlay>>,
+}
+
+impl Store {
+    /// The store of the repo with the given `.hg` and requirements.
+    pub fn new(hg: &Path, requires: &[String]) -> Store {
+        let has = |name: &str| requires.iter().any(|r| r == name);
+        if !has("store") {
+            return Store {
+                path: hg.to_path_buf(),
+                encoding: Encoding::Plain,
+                overlay: None,
+            };
+        }
+        let encoding = if has("fncache") {
+            Encoding::Fncache { dotencode: has("dotencode") }
+        } else {
+            Encoding::Basic
+        };
+        Store {
+            path: hg.join("store"),
+            encoding: encoding,
+            overlay: None,
+        }
+    }
+
+    /// This store with revisions added to its revlogs.
+    pub fn with_overlay(mut self, overlay: Overlay) -> Store {
+        self.overlay = Some(Arc::new(overlay));
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path on disk of a file in the store, such as `00changelog.i`
+    /// or `data/foo.txt.i`.
+    pub fn join(&self, name: &str) -> PathBuf {
+        let encoded = match self.encoding {
+            Encoding::Plain => encode_dir(name),
+            Encoding::Basic => encode_filename(&encode_dir(name)),
+            Encoding::Fncache { dotencode } => hybrid_encode(name, dotencode),
+        };
+        self.path.join(encoded)
+    }
+
+    /// Open the revlog whose files are `name.i` and `name.d`, with any
+    /// revisions the overlay has for it. A revlog with only those needn't
+    /// exist on disk.
+    pub fn revlog(&self, name: &str) -> Result<Revlog> {
+        self.open_revlog(name, false)
+    }
+
+    /// Like `revlog`, for the changelog and manifest, which a repo
+    /// without commits doesn't have yet.
+    pub fn revlog_or_empty(&self, name: &str) -> Result<Revlog> {
+        self.open_revlog(name, true)
+    }
+
+    fn open_revlog(&self, name: &str, missing_ok: bool) -> Result<Revlog> {
+        let index = self.join(&format!("{}.i", name));
+        let data = self.join(&format!("{}.d", name));
+        let extra = self.overlay.as_ref().and_then(|overlay| overlay.get(name));
+        let len = match fs::metadata(&index) {
+            Ok(metadata) => Some(metadata.len()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(From::from(e)),
+        };
+        let revlog = match len {
+            // An empty index is an empty revlog, which can't be mapped
+            Some(0) => Revlog::empty(),
+            None if missing_ok || extra.is_some() => Revlog::empty(),
+            _ => try!(Revlog::open_with_data(&try!(path_str(&index)), &try!(path_str(&data)))),
+        };
+        Ok(match extra {
+            Some(extra) => revlog.with_extra(extra.clone()),
+            None => revlog,
+        })
+    }
+
+    /// The names of the revlog files in the store, such as
+    /// `data/foo.txt.i`, sorted. With fncache they are listed in the
+    /// `fncache` file, since hashed paths can't be decoded; otherwise
+    /// the store's directories are walked.
+    pub fn walk(&self) -> Result<Vec<String>> {
+        let mut names = vec![];
+        for name in &["00changelog.i", "00changelog.d", "00manifest.i", "00manifest.d"] {
+            if self.path.join(name).is_file() {
+                names.push(String::from(*name));
+            }
+        }
+        if let Encoding::Fncache { .. } = self.encoding {
+            if let Some(data) = try!(repo::read_optional(&self.path.join("fncache"))) {
+                for line in String::from_utf8_lossy(&data).lines() {
+                    // Entries can outlive their files, and some writers
+                    // only list the index of a revlog
+                    let mut candidates = vec![String::from(line)];
+                    if line.ends_with(".i") {
+                        candidates.push(format!("{}.d", &line[..line.len() - 2]));
+                    }
+                    for name in candidates {
+                        if !name.is_empty() && self.join(&name).is_file() {
+                            names.push(name);
+                        }
+                    }
+                }
+            }
+        } else {
+            for dir in &["data", "meta"] {
+                let mut found = vec![];
+                try!(walk_dir(&self.path.join(dir), dir, &mut found));
+                for name in found {
+                    let name = if self.encoding == Encoding::Basic {
+                        try!(decode_filename(&name))
+                    } else {
+                        name
+                    };
+                    names.push(decode_dir(&name));
+                }
+            }
+        }
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// The name in the store of the filelog for a tracked file.
+    pub fn filelog_name(path: &str) -> String {
+        format!("data/{}", path)
+    }
+}
+
+/// Add the paths of the files under `dir`, whose path is `prefix`.
+fn walk_dir(dir: &Path, prefix: &str, found: &mut Vec<String>) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in try!(fs::read_dir(dir)) {
+        let entry = try!(entry);
+        let name = match entry.file_name().into_string() {
+            Ok(name) => format!("{}/{}", prefix, name),
+            Err(name) => return Err(From::from(format!("bad store file name {:?}", name))),
+        };
+        if try!(entry.file_type()).is_dir() {
+            try!(walk_dir(&entry.path(), &name, found));
+        } else {
+            found.push(name);
+        }
+    }
+    Ok(())
+}
+
+pub fn path_str(path: &Path) -> Result<String> {
+    match path.to_str() {
+        Some(s) => Ok(String::from(s)),
+        None => Err(From::from(format!("path is not valid unicode: {:?}", path))),
+    }
+}
+
+/// Directories named like revlog files get a `.hg` suffix, so that
+/// `foo.i/` can't collide with the index for `foo`.
+pub fn encode_dir(path: &str) -> String {
+    if !path.contains(".hg/") && !path.contains(".i/") && !path.contains(".d/") {
+        return String::from(path);
+    }
+    path.replace(".hg/", ".hg.hg/").replace(".i/", ".i.hg/").replace(".d/", ".d.hg/")
+}
+
+/// Undo `encode_dir`.
+pub fn decode_dir(path: &str) -> String {
+    path.replace(".d.hg/", ".d/").replace(".i.hg/", ".i/").replace(".hg.hg/", ".hg/")
+}
+
+fn is_reserved(c: u8) -> bool {
+    c < 32 || c >= 126 || b"\\:*?\"<>|".contains(&c)
+}
+
+/// Escape uppercase letters as `_x`, underscores as `__`, and
+/// unprintable or reserved bytes as `~xx`.
+pub fn encode_filename(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    for &c in path.as_bytes() {
+        if is_reserved(c) {
+            result.push_str(&format!("~{:02x}", c));
+        } else if c == b'_' {
+            result.push_str("__");
+        } else if c >= b'A' && c <= b'Z' {
+            result.push('_');
+            result.push((c - b'A' + b'a') as char);
+        } else {
+            result.push(c as char);
+        }
+    }
+    result
+}
+
+/// Undo `encode_filename`.
+pub fn decode_filename(path: &str) -> Result<String> {
+    let bytes = path.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'_' if i + 1 < bytes.len() => {
+                let c = bytes[i + 1];
+                result.push(if c == b'_' { c } else { c.to_ascii_uppercase() });
+                i += 2;
+            }
+            b'~' if i + 2 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 1..i + 3]).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(c) => result.push(c),
+                    Err(_) => return Err(From::from(format!("bad store file name {:?}", path))),
+                }
+                i += 3;
+            }
+            c => {
+                result.push(c);
+                i += 1;
+            }
+        }
+    }
+    Ok(try!(String::from_utf8(result)))
+}
+
+/// Like `encode_filename`, but simply lowercasing. Only used when the
+/// name will be hashed anyway.
+fn lower_encode(path: &str) -> String {
+    let mut result = String::with_capacity(path.len());
+    for &c in path.as_bytes() {
+        if is_reserved(c) {
+            result.push_str(&format!("~{:02x}", c));
+        } else if c >= b'A' && c <= b'Z' {
+            result.push((c - b'A' + b'a') as char);
+        } else {
+            result.push(c as char);
+        }
+    }
+    result
+}
+
+/// Escape path components Windows won't allow: reserved device names,
+/// trailing periods and spaces, and with dotencode leading ones too.
+fn aux_encode(parts: &mut Vec<String>, dotencode: bool) {
+    for part in parts.iter_mut() {
+        if part.is_empty() {
+            continue;
+        }
+        let first = part.as_bytes()[0];
+        if dotencode && (first == b'.' || first == b' ') {
+            *part = format!("~{:02x}{}", first, &part[1..]);
+        } else {
+            let l = part.find('.').unwrap_or(part.len());
+            let reserved = {
+                let stem = &part[..l];
+                let b = stem.as_bytes();
+                (l == 3 && ["aux", "con", "prn", "nul"].contains(&stem)) ||
+                (l == 4 && b[3] >= b'1' && b[3] <= b'9' &&
+                 ["com", "lpt"].contains(&&stem[..3]))
+            };
+            if reserved {
+                *part = format!("{}~{:02x}{}", &part[..2], part.as_bytes()[2], &part[3..]);
+            }
+        }
+        let last = part.as_bytes()[part.len() - 1];
+        if last == b'.' || last == b' ' {
+            let n = part.len() - 1;
+            *part = format!("{}~{:02x}", &part[..n], last);
+        }
+    }
+}
+
+/// The fncache encoding of a store path.
+pub fn hybrid_encode(path: &str, dotencode: bool) -> String {
+    let path = encode_dir(path);
+    let mut parts: Vec<String> = encode_filename(&path).split('/').map(String::from).collect();
+    aux_encode(&mut parts, dotencode);
+    let result = parts.join("/");
+    if result.len() > MAX_STORE_PATH_LEN {
+        hash_encode(&path, dotencode)
+    } else {
+        result
+    }
+}
+
+/// The `dh/` form of an overlong store path: a shortened directory
+/// prefix, as much of the basename as fits, and the SHA-1 of the path.
+fn hash_encode(path: &str, dotencode: bool) -> String {
+    let mut sha = Sha1::new();
+    sha.input(path.as_bytes());
+    let digest = sha.result_str();
+
+    // Skip the data/ or meta/ prefix
+    let mut parts: Vec<String> = lower_encode(&path[5..]).split('/').map(String::from).collect();
+    aux_encode(&mut parts, dotencode);
+    let basename = parts.pop().unwrap();
+    let ext = match basename.rfind('.') {
+        Some(i) if i > 0 => &basename[i..],
+        _ => "",
+    };
+
+    let mut dirs = String::new();
+    for part in &parts {
+        let mut d = String::from(&part[..DIR_PREFIX_LEN.min(part.len())]);
+        if d.ends_with('.') || d.ends_with(' ') {
+            d.pop();
+            d.push('_');
+        }
+        if !dirs.is_empty() {
+            if dirs.len() + 1 + d.len() > MAX_SHORT_DIRS_LEN {
+                break;
+            }
+            dirs.push('/');
+        }
+        dirs.push_str(&d);
+    }
+    if !dirs.is_empty() {
+        dirs.push('/');
+    }
+
+    let result = format!("dh/{}{}{}", dirs, digest, ext);
+    if result.len() < MAX_STORE_PATH_LEN {
+        let space_left = MAX_STORE_PATH_LEN - result.len();
+        let filler = &basename[..space_left.min(basename.len())];
+        format!("dh/{}{}{}{}", dirs, filler, digest, ext)
+    } else {
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use super::{decode_dir, decode_filename, encode_dir, encode_filename, hybrid_encode, Store};
+
+    #[test]
+    fn test_encode_filename() {
+        assert_eq!("data/_f_o_o__bar~3a.txt.i", encode_filename("data/FOO_bar:.txt.i"));
+        assert_eq!("data/~7e~c3~a9.i", encode_filename("data/~\u{e9}.i"));
+        for path in &["data/FOO_bar:.txt.i", "data/~\u{e9}.i", "data/__x_"] {
+            assert_eq!(*path, decode_filename(&encode_filename(path)).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_encode_dir() {
+        assert_eq!("data/a.i.hg/b.i", encode_dir("data/a.i/b.i"));
+        assert_eq!("data/.hg.hg/x.i", encode_dir("data/.hg/x.i"));
+        assert_eq!("data/a.i/.hg/b.d/x.i", decode_dir(&encode_dir("data/a.i/.hg/b.d/x.i")));
+    }
+
+    #[test]
+    fn test_aux_encode() {
+        assert_eq!("data/au~78.txt.i", hybrid_encode("data/aux.txt.i", true));
+        assert_eq!("data/co~6d1/x.i", hybrid_encode("data/com1/x.i", true));
+        assert_eq!("data/~2ehgignore.i", hybrid_encode("data/.hgignore.i", true));
+        assert_eq!("data/.hgignore.i", hybrid_encode("data/.hgignore.i", false));
+        assert_eq!("data/foo~2e/x.i", hybrid_encode("data/foo./x.i", true));
+    }
+
+    #[test]
+    fn test_hash_encode() {
+        let long = format!("data/{}/{}.txt.i", "directory".repeat(3), "x".repeat(120));
+        let encoded = hybrid_encode(&long, true);
+        assert!(encoded.starts_with("dh/director/xxxx"));
+        assert!(encoded.ends_with(".i"));
+        assert_eq!(120, encoded.len());
+    }
+
+    #[test]
+    fn test_revlog_or_empty() {
+        let hg = env::temp_dir().join(format!("cinnabar-test-revlog-or-empty-{}", ::std::process::id()));
+        fs::create_dir_all(hg.join("store")).unwrap();
+        let store = Store::new(&hg, &[String::from("store")]);
+        assert_eq!(0, store.revlog_or_empty("00changelog").unwrap().len());
+        assert!(store.revlog("00changelog").is_err());
+        fs::File::create(hg.join("store/00changelog.i")).unwrap();
+        let len = store.revlog("00changelog").unwrap().len();
+        fs::remove_dir_all(&hg).unwrap();
+        assert_eq!(0, len);
+    }
+}
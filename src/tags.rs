@@ -0,0 +1,280 @@
+//! Tags, as Mercurial resolves them.
+//!
+//! Global tags live in `.hgtags`, which can differ between heads. The
+//! file of every head is read, oldest head first, and a later head's
+//! definition of a tag wins unless it's been superseded: each file keeps
+//! a history of the nodes a tag has pointed to, since a tag is moved by
+//! appending a new line. Local tags in `.hg/localtags` are merged in
+//! last by the same rules. A tag pointing to the null node is deleted.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use ancestor;
+use graph::Graph;
+use repo::{self, Repo};
+use revlog::NULL_ID;
+use util::Result;
+
+/// A tag's node, and the nodes it has pointed to before
+pub type TagHistory = (Vec<u8>, Vec<Vec<u8>>);
+
+/// Parse a tags file. A tag defined more than once points to its last
+/// definition, and the earlier ones are its history.
+pub fn read_tags(data: &[u8]) -> Result<BTreeMap<String, TagHistory>> {
+    let mut nodes: BTreeMap<String, Vec<Vec<u8>>> = BTreeMap::new();
+    for (name, node) in try!(repo::parse_node_names(data)) {
+        nodes.entry(name).or_insert_with(Vec::new).push(node);
+    }
+    Ok(nodes.into_iter()
+        .map(|(name, mut nodes)| {
+            let node = nodes.pop().unwrap();
+            (name, (node, nodes))
+        })
+        .collect())
+}
+
+/// Merge the tags of one file into those found so far, which come from
+/// older heads. Mercurial's `_updatetags`.
+pub fn update_tags(file_tags: BTreeMap<String, TagHistory>, all_tags: &mut BTreeMap<String, TagHistory>) {
+    for (name, (mut anode, mut ahist)) in file_tags {
+        let (bnode, bhist) = match all_tags.remove(&name) {
+            Some(existing) => existing,
+            None => {
+                all_tags.insert(name, (anode, ahist));
+                continue;
+            }
+        };
+        // The existing node wins if it supersedes ours, or if each
+        // supersedes the other and it has the longer history
+        if bnode != anode && bhist.contains(&anode) &&
+           (!ahist.contains(&bnode) || bhist.len() > ahist.len()) {
+            anode = bnode;
+        }
+        for node in bhist {
+            if !ahist.contains(&node) {
+                ahist.push(node);
+            }
+        }
+        all_tags.insert(name, (anode, ahist));
+    }
+}
+
+/// Tags from the `.hgtags` files of all heads in the repo's view.
+pub fn global_tags(repo: &Repo) -> Result<BTreeMap<String, TagHistory>> {
+    let mut all_tags = BTreeMap::new();
+    // The same file revision is often in several heads
+    let mut file_nodes = vec![];
+    for head in try!(repo.heads()) {
+        if head == -1 {
+            continue;
+        }
+        if let Some(entry) = try!(repo.manifest(head)).get(".hgtags") {
+            if !file_nodes.contains(&entry.node) {
+                file_nodes.push(entry.node.clone());
+            }
+        }
+    }
+    if file_nodes.is_empty() {
+        return Ok(all_tags);
+    }
+    let filelog = try!(repo.filelog(".hgtags"));
+    for node in file_nodes {
+        let rev = match try!(filelog.revlog().rev(&node)) {
+            Some(rev) => rev,
+            None => return Err(From::from(".hgtags: filelog node not found")),
+        };
+        update_tags(try!(read_tags(&try!(filelog.content(rev)))), &mut all_tags);
+    }
+    Ok(all_tags)
+}
+
+/// All tags except `tip`, with deleted tags and tags pointing to
+/// unknown changesets left out.
+pub fn tags(repo: &Repo) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut all_tags = try!(global_tags(repo));
+    if let Some(data) = try!(repo::read_optional(&repo.hg_path().join("localtags"))) {
+        update_tags(try!(read_tags(&data)), &mut all_tags);
+    }
+    let mut result = BTreeMap::new();
+    for (name, (node, _)) in all_tags {
+        if &node[..] != NULL_ID && try!(repo.changelog.revlog().rev(&node)).is_some() {
+            result.insert(name, node);
+        }
+    }
+    Ok(result)
+}
+
+/// The nearest global tags among the ancestors of a changeset, as
+/// Mercurial's `{latesttag}` finds them.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LatestTag {
+    /// Every tag on the tagged changeset, or `null` if there is none
+    pub tags: Vec<String>,
+    /// The length of the longest path to the tagged changeset
+    pub distance: u32,
+    /// How many changesets are ancestors of this one but not of the tagged one
+    pub changes: usize,
+}
+
+/// The number of changesets in `only(rev, tag_rev)`.
+fn changes_since(graph: &Graph, rev: i32, tag_rev: i32) -> Result<usize> {
+    let mut tag_ancestors = HashSet::new();
+    if tag_rev != -1 {
+        for r in try!(ancestor::ancestors(graph, &[tag_rev], true)) {
+            tag_ancestors.insert(try!(r));
+        }
+    }
+    let mut changes = 0;
+    for r in try!(ancestor::ancestors(graph, &[rev], true)) {
+        if !tag_ancestors.contains(&try!(r)) {
+            changes += 1;
+        }
+    }
+    Ok(changes)
+}
+
+pub fn latest_tag(repo: &Repo, rev: i32) -> Result<LatestTag> {
+    let mut tagged: HashMap<i32, Vec<String>> = HashMap::new();
+    for (name, (node, _)) in try!(global_tags(repo)) {
+        if &node[..] == NULL_ID {
+            continue;
+        }
+        if let Some(tag_rev) = try!(repo.changelog.revlog().rev(&node)) {
+            tagged.entry(tag_rev).or_insert_with(Vec::new).push(name);
+        }
+    }
+
+    // The date, distance, tags and rev of the latest tag of each rev,
+    // filled in parents first
+    let graph = Graph::new(repo.changelog.revlog());
+    let mut cache: HashMap<i32, (i64, u32, Vec<String>, i32)> = HashMap::new();
+    cache.insert(-1, (0, 0, vec![String::from("null")], -1));
+    let mut todo = vec![rev];
+    while let Some(r) = todo.pop() {
+        if cache.contains_key(&r) {
+            continue;
+        }
+        if let Some(names) = tagged.get(&r) {
+            let mut names = names.clone();
+            names.sort();
+            cache.insert(r, (try!(repo.changeset(r)).time, 0, names, r));
+            continue;
+        }
+        let mut parents = try!(graph.parents(r));
+        if parents.is_empty() {
+            parents.push(-1);
+        }
+        if let Some(&p) = parents.iter().find(|p| !cache.contains_key(p)) {
+            todo.push(r);
+            todo.push(p);
+            continue;
+        }
+        let mut latest = cache[&parents[0]].clone();
+        if parents.len() > 1 {
+            // Of two parents, the tag with the fewest changes since it
+            // wins, then the more recent one
+            let other = cache[&parents[1]].clone();
+            let key = |tag: &(i64, u32, Vec<String>, i32)| -> Result<(isize, i64)> {
+                Ok((-(try!(changes_since(&graph, r, tag.3)) as isize), tag.0))
+            };
+            if try!(key(&other)) > try!(key(&latest)) {
+                latest = other;
+            }
+        }
+        let (date, distance, names, tag_rev) = latest;
+        cache.insert(r, (date, distance + 1, names, tag_rev));
+    }
+
+    let (_, distance, names, tag_rev) = cache.remove(&rev).unwrap();
+    let changes = try!(changes_since(&graph, rev, tag_rev));
+    Ok(LatestTag {
+        tags: names,
+        distance: distance,
+        changes: changes,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use rustc_serialize::hex::ToHex;
+    use testutil::{Commit, RepoBuilder};
+    use super::{latest_tag, read_tags, update_tags, LatestTag};
+
+    fn line(n: u8, name: &str) -> String {
+        format!("{} {}\n", format!("{:02x}", n).repeat(20), name)
+    }
+
+    #[test]
+    fn test_read_tags() {
+        let data = line(1, "a") + &line(2, "b") + &line(3, "a");
+        let tags = read_tags(data.as_bytes()).unwrap();
+        assert_eq!((vec![3; 20], vec![vec![1; 20]]), tags["a"]);
+        assert_eq!((vec![2; 20], vec![]), tags["b"]);
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut all = BTreeMap::new();
+        // An older head moved the tag from 1 to 2
+        update_tags(read_tags((line(1, "a") + &line(2, "a")).as_bytes()).unwrap(), &mut all);
+        // A newer head still has the original definition, which loses
+        update_tags(read_tags(line(1, "a").as_bytes()).unwrap(), &mut all);
+        assert_eq!(vec![2; 20], all["a"].0);
+        // A definition the older head never saw wins
+        update_tags(read_tags(line(4, "a").as_bytes()).unwrap(), &mut all);
+        assert_eq!(vec![4; 20], all["a"].0);
+    }
+
+    #[test]
+    fn test_latest_tag() {
+        // 0 - 1 - 2 ----- 5 - 6
+        //  \            /
+        //   3 - 4 -----
+        // with v1 on 0, and v2 and v2.0 on 3, which is more recent
+        let mut builder = RepoBuilder::new("latest-tag");
+        builder.commit(Commit::new(-1, -1).file("a", "0"));
+        let v1 = format!("{} v1\n", builder.node(0).to_hex());
+        builder.commit(Commit::new(0, -1).file(".hgtags", &v1));
+        builder.commit(Commit::new(1, -1).file("a", "2"));
+        builder.commit(Commit::new(0, -1).file("b", "3"));
+        let v2 = format!("{} v2\n{} v2.0\n", builder.node(3).to_hex(), builder.node(3).to_hex());
+        builder.commit(Commit::new(3, -1).file(".hgtags", &v2));
+        builder.commit(Commit::new(2, 4).file(".hgtags", &format!("{}{}", v1, v2)));
+        builder.commit(Commit::new(5, -1).file("a", "6"));
+        let repo = builder.open();
+
+        let tag = |names: &[&str], distance: u32, changes: usize| {
+            LatestTag {
+                tags: names.iter().map(|&n| String::from(n)).collect(),
+                distance: distance,
+                changes: changes,
+            }
+        };
+        assert_eq!(tag(&["v1"], 0, 0), latest_tag(&repo, 0).unwrap());
+        assert_eq!(tag(&["v1"], 2, 2), latest_tag(&repo, 2).unwrap());
+        assert_eq!(tag(&["v2", "v2.0"], 0, 0), latest_tag(&repo, 3).unwrap());
+        assert_eq!(tag(&["v2", "v2.0"], 2, 4), latest_tag(&repo, 5).unwrap());
+        assert_eq!(tag(&["v2", "v2.0"], 3, 5), latest_tag(&repo, 6).unwrap());
+    }
+
+    #[test]
+    fn test_latest_tag_fewest_changes() {
+        // 0 - 1 - 2 ----- 5
+        //  \            /
+        //   3 - 4 -----
+        // with near on 2 and far on 3, which is more recent but has
+        // more changes since it
+        let mut builder = RepoBuilder::new("latest-tag-fewest");
+        builder.commit(Commit::new(-1, -1).file("a", "0"));
+        builder.commit(Commit::new(0, -1).file("a", "1"));
+        builder.commit(Commit::new(1, -1).file("a", "2"));
+        builder.commit(Commit::new(0, -1).file("b", "3"));
+        builder.commit(Commit::new(3, -1).file("b", "4"));
+        let tags = format!("{} near\n{} far\n", builder.node(2).to_hex(), builder.node(3).to_hex());
+        builder.commit(Commit::new(2, 4).file(".hgtags", &tags));
+        let repo = builder.open();
+        let latest = latest_tag(&repo, 5).unwrap();
+        assert_eq!((vec![String::from("near")], 1, 3), (latest.tags, latest.distance, latest.changes));
+    }
+}
@@ -0,0 +1,225 @@
+//! A small subset of Mercurial's template language.
+//!
+//! A template is literal text with expansions of the form `{keyword}` or
+//! `{keyword|filter|filter...}`. Literal text understands the escapes
+//! `\n`, `\t`, `\\` and `\{`. The keywords themselves are supplied by
+//! the caller through the `Keywords` trait.
+
+use date;
+use util::Result;
+
+/// The value of a keyword, before it is rendered as text.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Text(String),
+    Int(i64),
+    /// Seconds since the epoch and seconds west of UTC
+    Date(i64, i32),
+    List(Vec<String>),
+}
+
+impl Value {
+    fn render(self) -> String {
+        match self {
+            Value::Text(s) => s,
+            Value::Int(i) => i.to_string(),
+            Value::Date(time, tz) => format!("{} {}", time, tz),
+            Value::List(items) => items.join(" "),
+        }
+    }
+}
+
+pub trait Keywords {
+    /// The value of a keyword, or None if it isn't known.
+    fn keyword(&self, name: &str) -> Result<Option<Value>>;
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Literal(String),
+    Expansion { keyword: String, filters: Vec<String> },
+}
+
+const FILTERS: &'static [&'static str] = &["count", "date", "email", "firstline", "hgdate",
+                                           "isodate", "isodatesec", "lower", "person",
+                                           "rfc822date", "short", "shortdate", "strip",
+                                           "upper", "user"];
+
+#[derive(Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Template> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => {
+                    match chars.next() {
+                        Some('n') => literal.push('\n'),
+                        Some('t') => literal.push('\t'),
+                        Some(c) => literal.push(c),
+                        None => literal.push('\\'),
+                    }
+                }
+                '{' => {
+                    let mut expr = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => expr.push(c),
+                            None => return Err(From::from(format!("unterminated template expansion: {{{}", expr))),
+                        }
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(literal));
+                        literal = String::new();
+                    }
+                    let mut names = expr.split('|').map(|s| String::from(s.trim()));
+                    let keyword = names.next().unwrap();
+                    expect!(!keyword.is_empty(), "empty template expansion");
+                    let filters: Vec<String> = names.collect();
+                    for filter in &filters {
+                        expect!(FILTERS.contains(&&filter[..]), "unknown template filter {:?}", filter);
+                    }
+                    parts.push(Part::Expansion {
+                        keyword: keyword,
+                        filters: filters,
+                    });
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        Ok(Template { parts: parts })
+    }
+
+    pub fn render<K: Keywords>(&self, keywords: &K) -> Result<String> {
+        let mut result = String::new();
+        for part in &self.parts {
+            match *part {
+                Part::Literal(ref s) => result.push_str(s),
+                Part::Expansion { ref keyword, ref filters } => {
+                    let mut value = match try!(keywords.keyword(keyword)) {
+                        Some(value) => value,
+                        None => return Err(From::from(format!("unknown template keyword {:?}", keyword))),
+                    };
+                    for filter in filters {
+                        value = try!(apply_filter(filter, value));
+                    }
+                    result.push_str(&value.render());
+                }
+            }
+        }
+        Ok(result)
+    }
+}
+
+fn apply_filter(filter: &str, value: Value) -> Result<Value> {
+    if let Value::Date(time, tz) = value {
+        let formatted = match filter {
+            "date" => date::format_date(time, tz),
+            "hgdate" => format!("{} {}", time, tz),
+            "isodate" => date::iso_date(time, tz),
+            "isodatesec" => date::iso_date_sec(time, tz),
+            "rfc822date" => date::rfc822_date(time, tz),
+            "shortdate" => date::short_date(time, tz),
+            _ => return Err(From::from(format!("filter {} does not apply to a date", filter))),
+        };
+        return Ok(Value::Text(formatted));
+    }
+    if filter == "count" {
+        return match value {
+            Value::List(items) => Ok(Value::Int(items.len() as i64)),
+            Value::Text(s) => Ok(Value::Int(s.chars().count() as i64)),
+            _ => Err(From::from("filter count needs a list or text")),
+        };
+    }
+    let text = value.render();
+    let result = match filter {
+        "email" => email(&text),
+        "firstline" => text.lines().next().unwrap_or("").to_string(),
+        "lower" => text.to_lowercase(),
+        "person" => person(&text),
+        "short" => text.chars().take(12).collect(),
+        "strip" => text.trim().to_string(),
+        "upper" => text.to_uppercase(),
+        "user" => user(&text),
+        _ => return Err(From::from(format!("filter {} does not apply to text", filter))),
+    };
+    Ok(Value::Text(result))
+}
+
+/// The short form of an author: the user part of their address.
+pub fn user(author: &str) -> String {
+    let email = email(author);
+    match email.find('@') {
+        Some(i) => email[..i].to_string(),
+        None => email,
+    }
+}
+
+/// The address part of `Name <address>`.
+fn email(author: &str) -> String {
+    match (author.find('<'), author.rfind('>')) {
+        (Some(a), Some(b)) if a < b => author[a + 1..b].to_string(),
+        (Some(a), None) => author[a + 1..].to_string(),
+        _ => author.to_string(),
+    }
+}
+
+/// The name part of `Name <address>`, or the address if there's no name.
+fn person(author: &str) -> String {
+    match author.find('<') {
+        Some(i) if i > 0 => author[..i].trim().trim_matches('"').to_string(),
+        _ => {
+            let email = email(author);
+            match email.find('@') {
+                Some(i) => email[..i].to_string(),
+                None => email,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Keywords, Template, Value};
+    use util::Result;
+
+    struct Fixed;
+
+    impl Keywords for Fixed {
+        fn keyword(&self, name: &str) -> Result<Option<Value>> {
+            Ok(match name {
+                "rev" => Some(Value::Int(7)),
+                "node" => Some(Value::Text(String::from("0123456789abcdef0123456789abcdef01234567"))),
+                "author" => Some(Value::Text(String::from("Josh Lee <josh@example.com>"))),
+                "desc" => Some(Value::Text(String::from("First line\n\nbody"))),
+                "date" => Some(Value::Date(0, 18000)),
+                "files" => Some(Value::List(vec![String::from("a"), String::from("b")])),
+                _ => None,
+            })
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let t = Template::parse("{rev}:{node|short} {author|person} <{author|email}>\\n").unwrap();
+        assert_eq!("7:0123456789ab Josh Lee <josh@example.com>\n", t.render(&Fixed).unwrap());
+        let t = Template::parse("{desc|firstline|upper} {date|isodate} {files} {files|count}").unwrap();
+        assert_eq!("FIRST LINE 1969-12-31 19:00 -0500 a b 2", t.render(&Fixed).unwrap());
+    }
+
+    #[test]
+    fn test_errors() {
+        assert!(Template::parse("{rev").is_err());
+        assert!(Template::parse("{rev|nonsense}").is_err());
+        assert!(Template::parse("{nonsense}").unwrap().render(&Fixed).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+//! Fixtures for tests: revlogs written to a temporary directory, with
+//! full texts and deltas laid out the way Mercurial stores them, and
+//! repos built from them one changeset at a time.
+
+extern crate byteorder;
+extern crate flate2;
+
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use rustc_serialize::hex::ToHex;
+
+use self::byteorder::{BigEndian, WriteBytesExt};
+use self::flate2::Compression;
+use self::flate2::write::ZlibEncoder;
+
+use repo::Repo;
+use revlog::{self, NULL_ID};
+use store::Store;
+
+static TEMP_DIRS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// A fresh, empty directory for a test, which is removed when dropped.
+/// Its name is unique to the process and the call, so that tests
+/// running at the same time don't share one.
+pub struct TempDir {
+    path: PathBuf,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let count = TEMP_DIRS.fetch_add(1, Ordering::SeqCst);
+        let path = env::temp_dir().join(format!("cinnabar-test-{}-{}-{}", name, process::id(), count));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir_all(&path).unwrap();
+        TempDir { path: path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// One revision to write: its text, parents, and the rev its delta is
+/// against, or -1 for a full text. The linkrev defaults to the rev.
+pub struct Rev<'a> {
+    pub text: &'a [u8],
+    pub p1: i32,
+    pub p2: i32,
+    pub delta_base: i32,
+    pub link_rev: Option<i32>,
+}
+
+impl<'a> Rev<'a> {
+    pub fn new(text: &'a [u8], p1: i32, delta_base: i32) -> Rev<'a> {
+        Rev {
+            text: text,
+            p1: p1,
+            p2: -1,
+            delta_base: delta_base,
+            link_rev: None,
+        }
+    }
+}
+
+/// Write `path.i`, and `path.d` unless `inline`. Without generaldelta, a delta must be against the previous rev.
+/// Full texts are compressed when that saves space; deltas never are.
+/// Returns the node ids.
+pub fn write_revlog(path: &Path, revs: &[Rev], generaldelta: bool, inline: bool) -> Vec<Vec<u8>> {
+    let mut index = vec![];
+    let mut data = vec![];
+    let mut nodes: Vec<Vec<u8>> = vec![];
+    let mut chain_starts: Vec<i32> = vec![];
+    let node = |nodes: &Vec<Vec<u8>>, rev: i32| if rev == -1 {
+        Vec::from(NULL_ID)
+    } else {
+        nodes[rev as usize].clone()
+    };
+    for (rev, r) in revs.iter().enumerate() {
+        let rev = rev as i32;
+        let chunk = if r.delta_base == -1 {
+            let mut encoder = ZlibEncoder::new(vec![], Compression::Default);
+            encoder.write_all(r.text).unwrap();
+            let compressed = encoder.finish().unwrap();
+            if compressed.len() < r.text.len() {
+                compressed
+            } else if r.text.is_empty() || r.text[0] == b'\0' {
+                Vec::from(r.text)
+            } else {
+                let mut chunk = vec![b'u'];
+                chunk.extend_from_slice(r.text);
+                chunk
+            }
+        } else {
+            assert!(generaldelta || r.delta_base == rev - 1);
+            delta(revs[r.delta_base as usize].text, r.text)
+        };
+        let base_rev = if r.delta_base == -1 {
+            rev
+        } else if generaldelta {
+            r.delta_base
+        } else {
+            chain_starts[r.delta_base as usize]
+        };
+        chain_starts.push(base_rev);
+        let n = revlog::hash(r.text, &node(&nodes, r.p1), &node(&nodes, r.p2));
+
+        let offset = data.len() as u64;
+        if rev == 0 {
+            let mut version = 1u64;
+            if inline {
+                version |= 1 << 16;
+            }
+            if generaldelta {
+                version |= 1 << 17;
+            }
+            index.write_u64::<BigEndian>(version << 32).unwrap();
+        } else {
+            index.write_u64::<BigEndian>(offset << 16).unwrap();
+        }
+        index.write_i32::<BigEndian>(chunk.len() as i32).unwrap();
+        index.write_i32::<BigEndian>(r.text.len() as i32).unwrap();
+        index.write_i32::<BigEndian>(base_rev).unwrap();
+        index.write_i32::<BigEndian>(r.link_rev.unwrap_or(rev)).unwrap();
+        index.write_i32::<BigEndian>(r.p1).unwrap();
+        index.write_i32::<BigEndian>(r.p2).unwrap();
+        index.extend_from_slice(&n);
+        index.extend_from_slice(&[0; 12]);
+        if inline {
+            index.extend_from_slice(&chunk);
+        }
+        data.extend_from_slice(&chunk);
+        nodes.push(n);
+    }
+
+    let path = path.to_str().unwrap();
+    fs::File::create(format!("{}.i", path)).unwrap().write_all(&index).unwrap();
+    if !inline {
+        fs::File::create(format!("{}.d", path)).unwrap().write_all(&data).unwrap();
+    }
+    nodes
+}
+
+/// A delta replacing whatever differs between the common prefix and
+/// suffix of two texts, as a single hunk.
+fn delta(old: &[u8], new: &[u8]) -> Vec<u8> {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter().rev().zip(new[prefix..].iter().rev()).take_while(|&(a, b)| a == b).count();
+    let mut result = vec![];
+    result.write_u32::<BigEndian>(prefix as u32).unwrap();
+    result.write_u32::<BigEndian>((old.len() - suffix) as u32).unwrap();
+    result.write_u32::<BigEndian>((new.len() - suffix - prefix) as u32).unwrap();
+    result.extend_from_slice(&new[prefix..new.len() - suffix]);
+    result
+}
+
+/// A changeset to add to a `RepoBuilder`.
+pub struct Commit {
+    p1: i32,
+    p2: i32,
+    /// New contents and manifest flags, or None to remove the file
+    files: Vec<(String, Option<(Vec<u8>, &'static str)>)>,
+    /// Destination and source of copies
+    copies: Vec<(String, String)>,
+    branch: String,
+    close: bool,
+    user: String,
+    description: String,
+}
+
+impl Commit {
+    pub fn new(p1: i32, p2: i32) -> Commit {
+        Commit {
+            p1: p1,
+            p2: p2,
+            files: vec![],
+            copies: vec![],
+            branch: String::from("default"),
+            close: false,
+            user: String::from("test"),
+            description: String::new(),
+        }
+    }
+
+    pub fn file(mut self, path: &str, text: &str) -> Commit {
+        self.files.push((String::from(path), Some((Vec::from(text), ""))));
+        self
+    }
+
+    pub fn executable(mut self, path: &str, text: &str) -> Commit {
+        self.files.push((String::from(path), Some((Vec::from(text), "x"))));
+        self
+    }
+
+    pub fn symlink(mut self, path: &str, target: &str) -> Commit {
+        self.files.push((String::from(path), Some((Vec::from(target), "l"))));
+        self
+    }
+
+    pub fn remove(mut self, path: &str) -> Commit {
+        self.files.push((String::from(path), None));
+        self
+    }
+
+    /// Copy `source` from the first parent to `path`, with new text.
+    pub fn copy(mut self, source: &str, path: &str, text: &str) -> Commit {
+        self.copies.push((String::from(path), String::from(source)));
+        self.file(path, text)
+    }
+
+    pub fn branch(mut self, name: &str) -> Commit {
+        self.branch = String::from(name);
+        self
+    }
+
+    pub fn close(mut self) -> Commit {
+        self.close = true;
+        self
+    }
+
+    pub fn user(mut self, user: &str) -> Commit {
+        self.user = String::from(user);
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> Commit {
+        self.description = String::from(description);
+        self
+    }
+}
+
+/// Revisions for one revlog of a `RepoBuilder`.
+#[derive(Default)]
+struct Revisions {
+    /// Text, parents and linkrev of each
+    revisions: Vec<(Vec<u8>, i32, i32, i32)>,
+    nodes: Vec<Vec<u8>>,
+}
+
+impl Revisions {
+    fn node(&self, rev: i32) -> Vec<u8> {
+        if rev == -1 {
+            Vec::from(NULL_ID)
+        } else {
+            self.nodes[rev as usize].clone()
+        }
+    }
+
+    /// Add a text unless the same revision is there already, returning
+    /// its rev.
+    fn add(&mut self, text: &[u8], p1: i32, p2: i32, link_rev: i32) -> i32 {
+        let node = revlog::hash(text, &self.node(p1), &self.node(p2));
+        if let Some(rev) = self.nodes.iter().position(|n| *n == node) {
+            return rev as i32;
+        }
+        self.revisions.push((Vec::from(text), p1, p2, link_rev));
+        self.nodes.push(node);
+        self.nodes.len() as i32 - 1
+    }
+
+    /// Write the revisions as full texts.
+    fn write(&self, path: &Path) {
+        let revs: Vec<Rev> = self.revisions
+            .iter()
+            .map(|&(ref text, p1, p2, link_rev)| {
+                Rev {
+                    text: text,
+                    p1: p1,
+                    p2: p2,
+                    delta_base: -1,
+                    link_rev: Some(link_rev),
+                }
+            })
+            .collect();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        write_revlog(path, &revs, false, false);
+    }
+}
+
+/// Builds the history of a repo in memory, one changeset at a time, and
+/// writes its revlogs when opened. The `.hg` directory has no
+/// requirements, and other files can be written to it before opening.
+pub struct RepoBuilder {
+    dir: TempDir,
+    changelog: Revisions,
+    manifests: Revisions,
+    /// The manifest rev and files, with filelog revs and flags, of each
+    /// changeset
+    trees: Vec<(i32, BTreeMap<String, (i32, &'static str)>)>,
+    filelogs: HashMap<String, Revisions>,
+}
+
+impl RepoBuilder {
+    pub fn new(name: &str) -> RepoBuilder {
+        let dir = TempDir::new(name);
+        fs::create_dir(dir.path().join(".hg")).unwrap();
+        RepoBuilder {
+            dir: dir,
+            changelog: Revisions::default(),
+            manifests: Revisions::default(),
+            trees: vec![],
+            filelogs: HashMap::new(),
+        }
+    }
+
+    pub fn hg_path(&self) -> PathBuf {
+        self.dir.path().join(".hg")
+    }
+
+    /// The node id of a changeset.
+    pub fn node(&self, rev: i32) -> Vec<u8> {
+        self.changelog.node(rev)
+    }
+
+    /// Add a changeset, returning its rev. Its time is its rev in
+    /// minutes after the epoch. Files of the second parent which the
+    /// first doesn't have are kept.
+    pub fn commit(&mut self, commit: Commit) -> i32 {
+        let rev = self.changelog.nodes.len() as i32;
+        let (m1, files1) = self.tree(commit.p1);
+        let (m2, files2) = self.tree(commit.p2);
+        let mut files = files1.clone();
+        for (path, &file) in &files2 {
+            files.entry(path.clone()).or_insert(file);
+        }
+
+        for &(ref path, ref text) in &commit.files {
+            let (text, flags) = match *text {
+                Some((ref text, flags)) => (text, flags),
+                None => {
+                    files.remove(path);
+                    continue;
+                }
+            };
+            let mut data = vec![];
+            let (p1, p2) = match commit.copies.iter().find(|&&(ref dest, _)| dest == path) {
+                Some(&(_, ref source)) => {
+                    let source_node = self.filelogs[source].node(files1[source].0);
+                    let meta = format!("\x01\ncopy: {}\ncopyrev: {}\n\x01\n", source, source_node.to_hex());
+                    data.extend_from_slice(meta.as_bytes());
+                    (-1, -1)
+                }
+                None => (files1.get(path).map_or(-1, |f| f.0), files2.get(path).map_or(-1, |f| f.0)),
+            };
+            let (p1, p2) = if p1 == -1 || p1 == p2 { (p2, -1) } else { (p1, p2) };
+            data.extend_from_slice(text);
+            let filelog = self.filelogs.entry(path.clone()).or_insert_with(Revisions::default);
+            files.insert(path.clone(), (filelog.add(&data, p1, p2, rev), flags));
+        }
+
+        let mut manifest = vec![];
+        for (path, &(filerev, flags)) in &files {
+            manifest.extend_from_slice(path.as_bytes());
+            manifest.push(0);
+            manifest.extend_from_slice(self.filelogs[path].node(filerev).to_hex().as_bytes());
+            manifest.extend_from_slice(flags.as_bytes());
+            manifest.push(b'\n');
+        }
+        let manifest_rev = self.manifests.add(&manifest, m1, m2, rev);
+
+        let mut extra = vec![];
+        if commit.branch != "default" {
+            extra.push(format!("branch:{}", commit.branch));
+        }
+        if commit.close {
+            extra.push(String::from("close:1"));
+        }
+        let mut date = format!("{} 0", rev * 60);
+        if !extra.is_empty() {
+            date.push(' ');
+            date.push_str(&extra.join("\0"));
+        }
+        let mut changed: Vec<&str> = commit.files.iter().map(|&(ref path, _)| &path[..]).collect();
+        changed.sort();
+        let text = format!("{}\n{}\n{}\n{}\n\n{}",
+                           self.manifests.node(manifest_rev).to_hex(),
+                           commit.user,
+                           date,
+                           changed.join("\n"),
+                           commit.description);
+        assert_eq!(rev, self.changelog.add(text.as_bytes(), commit.p1, commit.p2, rev));
+        self.trees.push((manifest_rev, files));
+        rev
+    }
+
+    fn tree(&self, rev: i32) -> (i32, BTreeMap<String, (i32, &'static str)>) {
+        if rev == -1 {
+            (-1, BTreeMap::new())
+        } else {
+            self.trees[rev as usize].clone()
+        }
+    }
+
+    pub fn open(&self) -> Repo {
+        let store = Store::new(&self.hg_path(), &[]);
+        self.changelog.write(&store.join("00changelog"));
+        self.manifests.write(&store.join("00manifest"));
+        for (path, filelog) in &self.filelogs {
+            filelog.write(&store.join(&Store::filelog_name(path)));
+        }
+        Repo::open(self.dir.path()).unwrap()
+    }
+}